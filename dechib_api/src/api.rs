@@ -1,24 +1,28 @@
-use crate::types::DechibMessage;
-use anyhow::Error;
+use crate::types::{DechibMessage, MessageType};
+use dechib_core::session::{Identity, Session};
 use dechib_core::Instance;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
 use tokio::runtime::Runtime;
+use tokio::task::block_in_place;
 
 const MAX_BUFFER: usize = u16::MAX as usize;
 
-pub fn launch_server(_instance: Instance) -> anyhow::Result<()> {
+pub fn launch_server(instance: Instance) -> anyhow::Result<()> {
     let rt = Runtime::new()?;
 
     rt.block_on(async {
         let listener = TcpListener::bind("0.0.0.0:8080").await?;
         loop {
             let (mut socket, _) = listener.accept().await?;
+            // Every connection gets its own session so transactions, variables etc don't leak
+            // between clients
+            let mut session = instance.session();
             tokio::spawn(async move {
                 let mut buf = [0u8; MAX_BUFFER];
                 loop {
                     let n = match socket.read(&mut buf).await {
-                        Ok(n) if n == 0 => break,
+                        Ok(0) => break,
                         Ok(n) => n,
                         Err(e) => {
                             eprintln!("Failed to read from socket; err = {:?}", e);
@@ -28,9 +32,8 @@ pub fn launch_server(_instance: Instance) -> anyhow::Result<()> {
 
                     match DechibMessage::try_from(&buf[..n]) {
                         Ok(message) => {
-                            // TODO: need to implement message execution or auth logic
-                            if let Err(error) = socket.write_all(&message.message_content[..]).await
-                            {
+                            let response = handle_message(&mut session, message);
+                            if let Err(error) = socket.write_all(response.as_bytes()).await {
                                 eprintln!("Failed to write to socket; err = {:?}", error);
                                 break;
                             }
@@ -47,3 +50,25 @@ pub fn launch_server(_instance: Instance) -> anyhow::Result<()> {
         }
     })
 }
+
+fn handle_message(session: &mut Session, message: DechibMessage) -> String {
+    let content = String::from_utf8_lossy(&message.message_content);
+    let content = content.trim_end_matches('\0');
+    match message.message_type {
+        MessageType::Init => {
+            // TODO: actually authenticate the user
+            session.set_identity(Identity::new(content));
+            "OK".to_string()
+        }
+        MessageType::Message if session.identity().is_none() => {
+            "ERROR: connection must be initialised before sending queries".to_string()
+        }
+        MessageType::Message => {
+            // Queries can block waiting on the storage so don't hold up the other connections
+            match block_in_place(|| session.query(content)) {
+                Ok(result) => result.to_string(),
+                Err(e) => format!("ERROR: {}", e),
+            }
+        }
+    }
+}
@@ -47,9 +47,9 @@ impl TryFrom<&[u8]> for DechibMessage {
     type Error = Error;
 
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        let mut enumerable_value = value.into_iter().enumerate();
+        let mut enumerable_value = value.iter().enumerate();
         if let Some((_, val)) = enumerable_value.next() {
-            let val_char = char::try_from(val.to_owned()).unwrap_or_else(|err| panic!("{}", err));
+            let val_char = char::from(*val);
 
             match val_char {
                 'I' => Self::from_message_type(&mut enumerable_value, MessageType::Init),
@@ -66,6 +66,7 @@ impl TryFrom<&[u8]> for DechibMessage {
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -89,7 +90,7 @@ mod tests {
         let expected_error = "message_type is not \'I\' (init_mode) or \'M\' (message_mode)";
         let input: &[u8] = &[b'Z', 5, b'h', b'e', b'l', b'l', b'o'];
         match DechibMessage::try_from(input) {
-            Ok(v) => {
+            Ok(_) => {
                 panic!("should not have succeeded");
             }
             Err(error) => {
@@ -101,9 +102,9 @@ mod tests {
     #[test]
     fn should_fail_try_from_wrong_size() {
         let expected_error = "incoming message does not contain message_size";
-        let input: &[u8] = &[b'M'];
+        let input: &[u8] = b"M";
         match DechibMessage::try_from(input) {
-            Ok(v) => {
+            Ok(_) => {
                 panic!("should not have succeeded");
             }
             Err(error) => {
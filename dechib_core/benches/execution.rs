@@ -0,0 +1,73 @@
+//! Compares the row at a time executor against the vectorised one. Run with `cargo bench -p
+//! dechib_core`, `ROWS` changes how big the table is.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use dechib_core::Instance;
+use tempfile::tempdir;
+
+const QUERIES: &[(&str, &str)] = &[
+    ("scan", "SELECT id, category, price FROM items"),
+    (
+        "filter",
+        "SELECT id FROM items WHERE price > 500 AND category <> 'c3'",
+    ),
+    (
+        "projection",
+        "SELECT id, price * quantity - 1, name || '!' FROM items",
+    ),
+    (
+        "aggregate",
+        "SELECT category, count(*), sum(price), max(quantity) FROM items GROUP BY category",
+    ),
+];
+
+fn execution(c: &mut Criterion) {
+    let rows: usize = std::env::var("ROWS")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(50_000);
+    let dir = tempdir().unwrap();
+    let instance = Instance::new_with_path(dir.path());
+    let mut session = instance.session();
+    session
+        .execute("CREATE TABLE items (id INTEGER PRIMARY KEY, name TEXT, category TEXT, price NUMERIC, quantity INTEGER)")
+        .unwrap();
+    for start in (0..rows).step_by(1000) {
+        let values = (start..rows.min(start + 1000))
+            .map(|i| {
+                format!(
+                    "({}, 'item {}', 'c{}', {}, {})",
+                    i,
+                    i,
+                    i % 10,
+                    i % 1000,
+                    i % 17
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        session
+            .execute(&format!(
+                "INSERT INTO items (id, name, category, price, quantity) VALUES {}",
+                values
+            ))
+            .unwrap();
+    }
+    session.execute("ANALYZE").unwrap();
+
+    for (name, sql) in QUERIES {
+        let mut group = c.benchmark_group(*name);
+        group.sample_size(10);
+        for mode in ["row", "vectorised"] {
+            session
+                .execute(&format!("SET execution_mode = '{}'", mode))
+                .unwrap();
+            group.bench_with_input(BenchmarkId::new(mode, rows), sql, |b, sql| {
+                b.iter(|| session.query(sql).unwrap())
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, execution);
+criterion_main!(benches);
@@ -0,0 +1,776 @@
+//! Estimates how many rows each node in a plan produces and roughly how much work it takes to
+//! produce them. Costs are in made up units where reading a row from storage costs 1, they only
+//! mean anything compared with each other.
+use crate::expression::{Column, ScalarExpr};
+use crate::logical_plan::{Catalog, JoinKind, LogicalPlan, SetOperator};
+use crate::statistics::{ColumnStatistics, TableStatistics};
+use crate::types::Value;
+use sqlparser::ast::{BinaryOperator, UnaryOperator};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Rows assumed to be in a table which has never been analyzed
+pub const DEFAULT_ROW_COUNT: f64 = 1000.0;
+/// Distinct values assumed for a column we know nothing about
+pub const DEFAULT_DISTINCT: f64 = 200.0;
+const DEFAULT_EQ_SELECTIVITY: f64 = 0.005;
+const DEFAULT_RANGE_SELECTIVITY: f64 = 1.0 / 3.0;
+const DEFAULT_SELECTIVITY: f64 = 0.5;
+
+/// Reading a row from storage while scanning the table
+const ROW_READ_COST: f64 = 1.0;
+/// Looking up a single row by key
+const LOOKUP_COST: f64 = 4.0;
+/// Evaluating an expression or passing a row along
+const CPU_COST: f64 = 0.01;
+/// Adding a row to a hash table
+const HASH_BUILD_COST: f64 = 0.05;
+/// Writing a row to storage
+const ROW_WRITE_COST: f64 = 2.0;
+/// Rows on the build side of a hash join past which the table is assumed not to fit in memory
+const HASH_MEMORY_ROWS: f64 = 100_000.0;
+/// Writing a row out and reading it back again, for hash joins which don't fit in memory
+const SPILL_COST: f64 = 1.0;
+/// No idea how many times a recursive query will go round
+const RECURSIVE_ITERATIONS: f64 = 10.0;
+
+/// How a join gets done, for equi joins it's whichever is cheapest
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JoinMethod {
+    NestedLoop,
+    Hash,
+    Merge,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Estimate {
+    pub rows: f64,
+    pub cost: f64,
+}
+
+pub struct CostModel<'a> {
+    catalog: &'a dyn Catalog,
+    statistics: RefCell<BTreeMap<String, Option<Arc<TableStatistics>>>>,
+}
+
+impl<'a> CostModel<'a> {
+    pub fn new(catalog: &'a dyn Catalog) -> Self {
+        Self {
+            catalog,
+            statistics: RefCell::default(),
+        }
+    }
+
+    fn table_statistics(&self, table: &str) -> Option<Arc<TableStatistics>> {
+        self.statistics
+            .borrow_mut()
+            .entry(table.to_string())
+            .or_insert_with(|| {
+                self.catalog
+                    .table_statistics(table)
+                    .ok()
+                    .flatten()
+                    .map(Arc::new)
+            })
+            .clone()
+    }
+
+    /// Finds the statistics for a column by looking for the scan it comes from.
+    fn column_statistics(
+        &self,
+        column: &Column,
+        plans: &[&LogicalPlan],
+    ) -> Option<ColumnStatistics> {
+        plans
+            .iter()
+            .find_map(|plan| self.column_statistics_in(column, plan))
+    }
+
+    fn column_statistics_in(
+        &self,
+        column: &Column,
+        plan: &LogicalPlan,
+    ) -> Option<ColumnStatistics> {
+        match plan {
+            LogicalPlan::Scan { table, alias, .. } => {
+                let relation = alias.as_ref().unwrap_or(table);
+                if column.relation.as_ref() != Some(relation) {
+                    return None;
+                }
+                self.table_statistics(table)?
+                    .columns
+                    .get(&column.name)
+                    .cloned()
+            }
+            plan => plan
+                .inputs()
+                .into_iter()
+                .find_map(|input| self.column_statistics_in(column, input)),
+        }
+    }
+
+    fn distinct_count(&self, column: &Column, plans: &[&LogicalPlan]) -> f64 {
+        self.column_statistics(column, plans)
+            .map(|x| x.distinct_count as f64)
+            .unwrap_or(DEFAULT_DISTINCT)
+            .max(1.0)
+    }
+
+    /// The fraction of rows from `plan` which the predicate will be true for.
+    pub fn selectivity(&self, predicate: &ScalarExpr, plan: &LogicalPlan) -> f64 {
+        self.selectivity_in(predicate, &[plan])
+    }
+
+    /// Same as `selectivity` but the columns can come from any of the plans, for predicates over
+    /// the result of joining them.
+    pub fn selectivity_in(&self, predicate: &ScalarExpr, plans: &[&LogicalPlan]) -> f64 {
+        let sel = match predicate {
+            ScalarExpr::Literal(Value::Boolean(b)) => f64::from(u8::from(*b)),
+            ScalarExpr::Literal(_) => 0.0,
+            ScalarExpr::BinaryOp {
+                left,
+                op: BinaryOperator::And,
+                right,
+            } => self.selectivity_in(left, plans) * self.selectivity_in(right, plans),
+            ScalarExpr::BinaryOp {
+                left,
+                op: BinaryOperator::Or,
+                right,
+            } => {
+                let (l, r) = (
+                    self.selectivity_in(left, plans),
+                    self.selectivity_in(right, plans),
+                );
+                l + r - l * r
+            }
+            ScalarExpr::UnaryOp {
+                op: UnaryOperator::Not,
+                expr,
+            } => 1.0 - self.selectivity_in(expr, plans),
+            ScalarExpr::IsNull(e) | ScalarExpr::IsNotNull(e) => {
+                let nulls = match e.as_ref() {
+                    ScalarExpr::Column(c) => self
+                        .column_statistics(c, plans)
+                        .map(|x| x.null_fraction)
+                        .unwrap_or(DEFAULT_EQ_SELECTIVITY),
+                    _ => DEFAULT_EQ_SELECTIVITY,
+                };
+                if matches!(predicate, ScalarExpr::IsNull(_)) {
+                    nulls
+                } else {
+                    1.0 - nulls
+                }
+            }
+            ScalarExpr::BinaryOp { left, op, right } => {
+                self.comparison_selectivity(left, op, right, plans)
+            }
+            ScalarExpr::InList {
+                expr,
+                list,
+                negated,
+            } => {
+                let sel = list
+                    .iter()
+                    .map(|x| self.comparison_selectivity(expr, &BinaryOperator::Eq, x, plans))
+                    .sum::<f64>()
+                    .min(1.0);
+                if *negated {
+                    1.0 - sel
+                } else {
+                    sel
+                }
+            }
+            ScalarExpr::Between {
+                expr,
+                negated,
+                low,
+                high,
+            } => {
+                let sel = self.comparison_selectivity(expr, &BinaryOperator::GtEq, low, plans)
+                    + self.comparison_selectivity(expr, &BinaryOperator::LtEq, high, plans)
+                    - 1.0;
+                let sel = sel.clamp(0.0, 1.0);
+                if *negated {
+                    1.0 - sel
+                } else {
+                    sel
+                }
+            }
+            _ => DEFAULT_SELECTIVITY,
+        };
+        sel.clamp(0.0, 1.0)
+    }
+
+    fn comparison_selectivity(
+        &self,
+        left: &ScalarExpr,
+        op: &BinaryOperator,
+        right: &ScalarExpr,
+        plans: &[&LogicalPlan],
+    ) -> f64 {
+        // Get it into the form `column op value`
+        let (column, op, value) = match (left, right) {
+            (ScalarExpr::Column(l), ScalarExpr::Column(r)) => {
+                // Join style predicate, assume the smaller set of values is contained in the
+                // larger one
+                let distinct = self
+                    .distinct_count(l, plans)
+                    .max(self.distinct_count(r, plans));
+                return match op {
+                    BinaryOperator::Eq => 1.0 / distinct,
+                    BinaryOperator::NotEq => 1.0 - 1.0 / distinct,
+                    _ => DEFAULT_RANGE_SELECTIVITY,
+                };
+            }
+            (ScalarExpr::Column(c), ScalarExpr::Literal(v)) => (c, op.clone(), v),
+            (ScalarExpr::Literal(v), ScalarExpr::Column(c)) => (c, flip(op), v),
+            _ => {
+                return match op {
+                    BinaryOperator::Eq => DEFAULT_EQ_SELECTIVITY,
+                    BinaryOperator::NotEq => 1.0 - DEFAULT_EQ_SELECTIVITY,
+                    _ => DEFAULT_RANGE_SELECTIVITY,
+                }
+            }
+        };
+        let Some(stats) = self.column_statistics(column, plans) else {
+            return match op {
+                BinaryOperator::Eq => DEFAULT_EQ_SELECTIVITY,
+                BinaryOperator::NotEq => 1.0 - DEFAULT_EQ_SELECTIVITY,
+                _ => DEFAULT_RANGE_SELECTIVITY,
+            };
+        };
+        let not_null = 1.0 - stats.null_fraction;
+        let eq = stats.eq_selectivity(value);
+        let lt = stats.lt_selectivity(value);
+        match op {
+            BinaryOperator::Eq => eq,
+            BinaryOperator::NotEq => not_null - eq,
+            BinaryOperator::Lt => lt,
+            BinaryOperator::LtEq => lt + eq,
+            BinaryOperator::Gt => not_null - lt - eq,
+            BinaryOperator::GtEq => not_null - lt,
+            _ => DEFAULT_SELECTIVITY,
+        }
+    }
+
+    pub fn estimate(&self, plan: &LogicalPlan) -> Estimate {
+        match plan {
+            LogicalPlan::Scan {
+                table,
+                filters,
+                primary_key,
+                ..
+            } => {
+                let table_rows = self
+                    .table_statistics(table)
+                    .map(|x| x.row_count as f64)
+                    .unwrap_or(DEFAULT_ROW_COUNT);
+                let sel = filters
+                    .iter()
+                    .map(|f| self.selectivity(f, plan))
+                    .product::<f64>();
+                if primary_key.is_some() {
+                    Estimate {
+                        rows: sel.min(1.0 / table_rows.max(1.0)) * table_rows,
+                        cost: LOOKUP_COST,
+                    }
+                } else {
+                    let filter_cost = table_rows * CPU_COST * filters.len() as f64;
+                    Estimate {
+                        rows: table_rows * sel,
+                        cost: table_rows * ROW_READ_COST + filter_cost,
+                    }
+                }
+            }
+            LogicalPlan::Filter { predicate, input } => {
+                let input_est = self.estimate(input);
+                Estimate {
+                    rows: input_est.rows * self.selectivity(predicate, input),
+                    cost: input_est.cost + input_est.rows * CPU_COST,
+                }
+            }
+            LogicalPlan::Project { input, .. } => {
+                let input = self.estimate(input);
+                Estimate {
+                    rows: input.rows,
+                    cost: input.cost + input.rows * CPU_COST,
+                }
+            }
+            LogicalPlan::Join {
+                left,
+                right,
+                kind,
+                condition,
+                ..
+            } => self.estimate_join(left, right, *kind, condition.as_ref()),
+            LogicalPlan::Aggregate {
+                group_by, input, ..
+            } => {
+                let input_est = self.estimate(input);
+                let groups = if group_by.is_empty() {
+                    1.0
+                } else {
+                    group_by
+                        .iter()
+                        .map(|e| match e {
+                            ScalarExpr::Column(c) => self.distinct_count(c, &[input]),
+                            _ => DEFAULT_DISTINCT,
+                        })
+                        .product::<f64>()
+                        .min(input_est.rows)
+                };
+                Estimate {
+                    rows: groups,
+                    cost: input_est.cost + input_est.rows * HASH_BUILD_COST,
+                }
+            }
+            // Each window sorts the input on its own keys
+            LogicalPlan::Window { windows, input, .. } => {
+                let input = self.estimate(input);
+                let n = input.rows.max(2.0);
+                Estimate {
+                    rows: input.rows,
+                    cost: input.cost + windows.len() as f64 * n * n.log2() * CPU_COST,
+                }
+            }
+            LogicalPlan::Sort { input, .. } => {
+                let input = self.estimate(input);
+                let n = input.rows.max(2.0);
+                Estimate {
+                    rows: input.rows,
+                    cost: input.cost + n * n.log2() * CPU_COST,
+                }
+            }
+            LogicalPlan::TopN {
+                limit,
+                offset,
+                input,
+                ..
+            } => {
+                let input = self.estimate(input);
+                let k = (limit + offset) as f64;
+                Estimate {
+                    rows: input.rows.min(*limit as f64),
+                    cost: input.cost + input.rows * k.max(2.0).log2() * CPU_COST,
+                }
+            }
+            // Every row is read again once it's locked
+            LogicalPlan::LockRows { tables, input, .. } => {
+                let input = self.estimate(input);
+                Estimate {
+                    rows: input.rows,
+                    cost: input.cost + input.rows * tables.len() as f64 * LOOKUP_COST,
+                }
+            }
+            LogicalPlan::Limit {
+                limit,
+                offset,
+                input,
+                ..
+            } => {
+                let input = self.estimate(input);
+                let rows = (input.rows - *offset as f64).max(0.0);
+                Estimate {
+                    rows: limit.map(|l| rows.min(l as f64)).unwrap_or(rows),
+                    cost: input.cost,
+                }
+            }
+            // The scan doesn't know where its rows came from so it's just a guess
+            LogicalPlan::CteScan { .. } => Estimate {
+                rows: DEFAULT_ROW_COUNT,
+                cost: DEFAULT_ROW_COUNT * CPU_COST,
+            },
+            LogicalPlan::With { cte, input, .. } => {
+                let cte = self.estimate(cte);
+                let input = self.estimate(input);
+                Estimate {
+                    rows: input.rows,
+                    cost: cte.cost + cte.rows * CPU_COST + input.cost,
+                }
+            }
+            LogicalPlan::RecursiveCte {
+                anchor, recursive, ..
+            } => {
+                let anchor = self.estimate(anchor);
+                let recursive = self.estimate(recursive);
+                Estimate {
+                    rows: anchor.rows + recursive.rows * RECURSIVE_ITERATIONS,
+                    cost: anchor.cost + recursive.cost * RECURSIVE_ITERATIONS,
+                }
+            }
+            LogicalPlan::SetOperation {
+                op, left, right, ..
+            } => {
+                let left = self.estimate(left);
+                let right = self.estimate(right);
+                let rows = match op {
+                    SetOperator::Union => left.rows + right.rows,
+                    SetOperator::Intersect => left.rows.min(right.rows),
+                    SetOperator::Except => left.rows,
+                };
+                Estimate {
+                    rows,
+                    cost: left.cost + right.cost + (left.rows + right.rows) * CPU_COST,
+                }
+            }
+            LogicalPlan::Apply {
+                input, subquery, ..
+            } => {
+                let input = self.estimate(input);
+                // Correlated subqueries are run again for every row
+                let runs = if subquery.is_correlated() {
+                    input.rows
+                } else {
+                    1.0
+                };
+                Estimate {
+                    rows: input.rows,
+                    cost: input.cost + runs * self.estimate(subquery).cost,
+                }
+            }
+            LogicalPlan::Values { rows, .. } => Estimate {
+                rows: rows.len() as f64,
+                cost: rows.len() as f64 * CPU_COST,
+            },
+            LogicalPlan::Insert { input, .. }
+            | LogicalPlan::Update { input, .. }
+            | LogicalPlan::Delete { input, .. } => {
+                let input = self.estimate(input);
+                Estimate {
+                    rows: input.rows,
+                    cost: input.cost + input.rows * ROW_WRITE_COST,
+                }
+            }
+        }
+    }
+
+    fn estimate_join(
+        &self,
+        left: &LogicalPlan,
+        right: &LogicalPlan,
+        kind: JoinKind,
+        condition: Option<&ScalarExpr>,
+    ) -> Estimate {
+        let predicates = condition
+            .map(|c| c.clone().split_conjunction())
+            .unwrap_or_default();
+        let l = self.estimate(left);
+        let estimate = self.join_estimate(
+            l,
+            self.estimate(right),
+            kind,
+            &predicates.iter().collect::<Vec<_>>(),
+            condition.is_some_and(|c| is_equi_join(c, left, right)),
+            &[left, right],
+        );
+        if self.index_lookup(left, right, kind, condition).is_none() {
+            return estimate;
+        }
+        // The whole key is given so each row on the left matches one row at most
+        let rows = estimate.rows.min(l.rows);
+        Estimate {
+            rows,
+            cost: estimate.cost.min(self.index_join_cost(l, rows)),
+        }
+    }
+
+    /// Whether the join can look up rows on the right by their primary key for each row on the
+    /// left, which needs the right side to be a scan and the condition to give the whole key. The
+    /// left side expressions for each key column come back in the key's order, along with the
+    /// rest of the condition.
+    pub fn index_lookup(
+        &self,
+        left: &LogicalPlan,
+        right: &LogicalPlan,
+        kind: JoinKind,
+        condition: Option<&ScalarExpr>,
+    ) -> Option<(Vec<ScalarExpr>, Option<ScalarExpr>)> {
+        if matches!(kind, JoinKind::Right | JoinKind::Full | JoinKind::Cross) {
+            return None;
+        }
+        let LogicalPlan::Scan {
+            table,
+            schema,
+            primary_key: None,
+            ..
+        } = right
+        else {
+            return None;
+        };
+        let metadata = self.catalog.table_metadata(table).ok()?;
+        let key_columns = metadata
+            .iter()
+            .filter(|(_, desc)| desc.primary_key)
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        if key_columns.is_empty() {
+            return None;
+        }
+        let is_key = |e: &ScalarExpr, key: &str| match e {
+            ScalarExpr::Column(c) => {
+                matches!(schema.find(c), Ok(Some(i)) if schema.fields[i].name == key)
+            }
+            _ => false,
+        };
+        let from_left = |e: &ScalarExpr| {
+            let columns = e.columns();
+            !columns.is_empty()
+                && columns
+                    .iter()
+                    .all(|c| matches!(left.schema().find(c), Ok(Some(_))))
+        };
+        let mut terms = condition?.clone().split_conjunction();
+        let mut key = vec![];
+        for column in key_columns {
+            let position = terms.iter().position(|term| match term {
+                ScalarExpr::BinaryOp {
+                    left: a,
+                    op: BinaryOperator::Eq,
+                    right: b,
+                } => (is_key(a, column) && from_left(b)) || (is_key(b, column) && from_left(a)),
+                _ => false,
+            })?;
+            match terms.remove(position) {
+                ScalarExpr::BinaryOp {
+                    left: a, right: b, ..
+                } if is_key(&a, column) => key.push(*b),
+                ScalarExpr::BinaryOp { left: a, .. } => key.push(*a),
+                _ => unreachable!(),
+            }
+        }
+        Some((key, ScalarExpr::conjunction(terms)))
+    }
+
+    /// What a join doing a primary key lookup for every row on the left costs
+    pub fn index_join_cost(&self, l: Estimate, rows: f64) -> f64 {
+        l.cost + l.rows * LOOKUP_COST + rows * CPU_COST
+    }
+
+    /// Estimates a join from the estimates of its inputs, so join ordering can try lots of
+    /// combinations without building a plan for each one. `equi` is whether there's an equality
+    /// to join on and `inputs` is where to look for the statistics of the predicates' columns.
+    pub fn join_estimate(
+        &self,
+        l: Estimate,
+        r: Estimate,
+        kind: JoinKind,
+        predicates: &[&ScalarExpr],
+        equi: bool,
+        inputs: &[&LogicalPlan],
+    ) -> Estimate {
+        let sel = predicates
+            .iter()
+            .map(|p| self.selectivity_in(p, inputs))
+            .product::<f64>();
+        let matched = l.rows * r.rows * sel;
+        let rows = match kind {
+            JoinKind::Inner | JoinKind::Cross => matched,
+            JoinKind::Left => matched.max(l.rows),
+            JoinKind::Right => matched.max(r.rows),
+            JoinKind::Full => matched.max(l.rows).max(r.rows),
+            JoinKind::Semi => matched.min(l.rows),
+            JoinKind::Anti => l.rows - matched.min(l.rows),
+        };
+        let (_, cost) = self.join_method(l, r, rows, equi);
+        Estimate { rows, cost }
+    }
+
+    /// Picks how to do a join producing `rows` rows from inputs with these estimates, along with
+    /// what it costs. Joins without an equality to work with have to be nested loops.
+    pub fn join_method(
+        &self,
+        l: Estimate,
+        r: Estimate,
+        rows: f64,
+        equi: bool,
+    ) -> (JoinMethod, f64) {
+        if !equi {
+            // The right side is run again for every row on the left
+            let cost = l.cost + l.rows.max(1.0) * r.cost + l.rows * r.rows * CPU_COST;
+            return (JoinMethod::NestedLoop, cost);
+        }
+        // Building a table from the right and probing it with the left
+        let mut hash =
+            l.cost + r.cost + r.rows * HASH_BUILD_COST + l.rows * CPU_COST + rows * CPU_COST;
+        if r.rows > HASH_MEMORY_ROWS {
+            hash += (l.rows + r.rows) * SPILL_COST;
+        }
+        // Sorting both sides and walking through them together
+        let sort = |n: f64| n.max(2.0) * n.max(2.0).log2() * CPU_COST;
+        let merge = l.cost
+            + r.cost
+            + sort(l.rows)
+            + sort(r.rows)
+            + (l.rows + r.rows) * CPU_COST
+            + rows * CPU_COST;
+        if merge < hash {
+            (JoinMethod::Merge, merge)
+        } else {
+            (JoinMethod::Hash, hash)
+        }
+    }
+}
+
+/// Swaps the sides of a comparison so `a < b` becomes `b > a`
+pub fn flip(op: &BinaryOperator) -> BinaryOperator {
+    match op {
+        BinaryOperator::Lt => BinaryOperator::Gt,
+        BinaryOperator::LtEq => BinaryOperator::GtEq,
+        BinaryOperator::Gt => BinaryOperator::Lt,
+        BinaryOperator::GtEq => BinaryOperator::LtEq,
+        op => op.clone(),
+    }
+}
+
+/// Whether the condition has a `left = right` term where each side only uses columns from one of
+/// the join's inputs, these can be done with a hash join.
+pub fn is_equi_join(condition: &ScalarExpr, left: &LogicalPlan, right: &LogicalPlan) -> bool {
+    let from = |e: &ScalarExpr, plan: &LogicalPlan| {
+        let columns = e.columns();
+        !columns.is_empty()
+            && columns
+                .iter()
+                .all(|c| matches!(plan.schema().find(c), Ok(Some(_))))
+    };
+    condition
+        .clone()
+        .split_conjunction()
+        .iter()
+        .any(|term| match term {
+            ScalarExpr::BinaryOp {
+                left: a,
+                op: BinaryOperator::Eq,
+                right: b,
+            } => (from(a, left) && from(b, right)) || (from(a, right) && from(b, left)),
+            _ => false,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logical_plan::tests::{plan, test_catalog};
+    use crate::logical_plan::PlanBuilder;
+    use crate::optimiser::{Optimiser, PredicatePushdown};
+    use crate::types::ColumnDescriptors;
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+
+    /// A catalog where `users` has been analyzed
+    pub(crate) struct AnalyzedCatalog {
+        pub tables: BTreeMap<String, ColumnDescriptors>,
+        pub statistics: BTreeMap<String, TableStatistics>,
+    }
+
+    impl Catalog for AnalyzedCatalog {
+        fn table_metadata(&self, name: &str) -> anyhow::Result<ColumnDescriptors> {
+            self.tables.table_metadata(name)
+        }
+
+        fn table_statistics(&self, name: &str) -> anyhow::Result<Option<TableStatistics>> {
+            Ok(self.statistics.get(name).cloned())
+        }
+    }
+
+    fn analyzed_catalog() -> AnalyzedCatalog {
+        let mut users = TableStatistics {
+            row_count: 10_000,
+            ..Default::default()
+        };
+        users.columns.insert(
+            "age".to_string(),
+            ColumnStatistics::compute((0..10_000).map(|i| Value::from(i % 100)).collect()),
+        );
+        users.columns.insert(
+            "id".to_string(),
+            ColumnStatistics::compute((0..10_000).map(Value::from).collect()),
+        );
+        let mut statistics = BTreeMap::new();
+        statistics.insert("users".to_string(), users);
+        AnalyzedCatalog {
+            tables: test_catalog(),
+            statistics,
+        }
+    }
+
+    fn estimate(catalog: &dyn Catalog, sql: &str) -> Estimate {
+        let statement = Parser::parse_sql(&GenericDialect {}, sql)
+            .unwrap()
+            .remove(0);
+        let plan = PlanBuilder::new(catalog)
+            .plan_statement(&statement)
+            .unwrap();
+        let plan = Optimiser::with_rules(vec![Box::new(PredicatePushdown)])
+            .optimise(plan)
+            .unwrap();
+        CostModel::new(catalog).estimate(&plan)
+    }
+
+    #[test]
+    fn selectivity_uses_statistics() {
+        let catalog = analyzed_catalog();
+        let all = estimate(&catalog, "SELECT * FROM users");
+        assert_eq!(all.rows, 10_000.0);
+        let eq = estimate(&catalog, "SELECT * FROM users WHERE age = 5");
+        assert!((eq.rows - 100.0).abs() < 1.0, "{:?}", eq);
+        let range = estimate(&catalog, "SELECT * FROM users WHERE age < 25");
+        assert!((range.rows - 2500.0).abs() < 100.0, "{:?}", range);
+        let both = estimate(&catalog, "SELECT * FROM users WHERE age < 25 AND 5 = age");
+        assert!((both.rows - 25.0).abs() < 1.0, "{:?}", both);
+        let null = estimate(&catalog, "SELECT * FROM users WHERE age IS NULL");
+        assert_eq!(null.rows, 0.0);
+
+        // Without statistics we fall back to guesses
+        let catalog = test_catalog();
+        let eq = estimate(&catalog, "SELECT * FROM users WHERE age = 5");
+        assert_eq!(eq.rows, DEFAULT_ROW_COUNT * DEFAULT_EQ_SELECTIVITY);
+    }
+
+    #[test]
+    fn joins_and_aggregates() {
+        let catalog = analyzed_catalog();
+        let join = estimate(
+            &catalog,
+            "SELECT * FROM users u JOIN orders o ON u.id = o.user_id",
+        );
+        // Every order matches a single user
+        assert!((join.rows - DEFAULT_ROW_COUNT).abs() < 1.0, "{:?}", join);
+        let groups = estimate(&catalog, "SELECT age, count(*) FROM users GROUP BY age");
+        assert_eq!(groups.rows, 100.0);
+        let limit = estimate(&catalog, "SELECT * FROM users LIMIT 10 OFFSET 5");
+        assert_eq!(limit.rows, 10.0);
+    }
+
+    #[test]
+    fn hash_joins_are_cheaper() {
+        let catalog = test_catalog();
+        let plan = |sql| {
+            Optimiser::with_rules(vec![Box::new(PredicatePushdown)])
+                .optimise(plan(sql).unwrap())
+                .unwrap()
+        };
+        let model = CostModel::new(&catalog);
+        let equi = model.estimate(&plan(
+            "SELECT * FROM users u JOIN orders o ON u.id = o.user_id",
+        ));
+        let theta = model.estimate(&plan(
+            "SELECT * FROM users u JOIN orders o ON u.id < o.user_id",
+        ));
+        assert!(equi.cost < theta.cost, "{:?} {:?}", equi, theta);
+    }
+
+    #[test]
+    fn big_joins_merge() {
+        let catalog = test_catalog();
+        let model = CostModel::new(&catalog);
+        let scan = |rows: f64| Estimate { rows, cost: rows };
+        let (method, _) = model.join_method(scan(1e6), scan(100.0), 1e6, true);
+        assert_eq!(method, JoinMethod::Hash);
+        // Too big to hash in memory
+        let (method, _) = model.join_method(scan(1e6), scan(1e6), 1e6, true);
+        assert_eq!(method, JoinMethod::Merge);
+        let (method, _) = model.join_method(scan(10.0), scan(10.0), 10.0, false);
+        assert_eq!(method, JoinMethod::NestedLoop);
+    }
+}
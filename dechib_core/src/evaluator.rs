@@ -0,0 +1,785 @@
+//! Evaluates scalar expressions against rows. Expressions are compiled against the schema of the
+//! rows they'll be evaluated over first so column references become indexes into the row rather
+//! than having to be looked up by name for every row.
+use crate::expression::ScalarExpr;
+use crate::functions::{ScalarFunction, Volatility};
+use crate::logical_plan::{Catalog, PlanBuilder, Schema};
+use crate::types::{TypeClass, Value};
+use bigdecimal::{BigDecimal, RoundingMode, Zero};
+use sqlparser::ast::{BinaryOperator, DataType, ExactNumberInfo, Expr, UnaryOperator};
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+/// Division results are rounded to this many decimal places so things like `1 / 3` don't go on
+/// forever
+const MAX_SCALE: i64 = 20;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PhysicalExpr {
+    Column(usize),
+    Literal(Value),
+    BinaryOp {
+        left: Box<PhysicalExpr>,
+        op: BinaryOperator,
+        right: Box<PhysicalExpr>,
+    },
+    UnaryOp {
+        op: UnaryOperator,
+        expr: Box<PhysicalExpr>,
+    },
+    IsNull(Box<PhysicalExpr>),
+    IsNotNull(Box<PhysicalExpr>),
+    Between {
+        expr: Box<PhysicalExpr>,
+        negated: bool,
+        low: Box<PhysicalExpr>,
+        high: Box<PhysicalExpr>,
+    },
+    InList {
+        expr: Box<PhysicalExpr>,
+        list: Vec<PhysicalExpr>,
+        negated: bool,
+    },
+    Like {
+        expr: Box<PhysicalExpr>,
+        pattern: Box<PhysicalExpr>,
+        negated: bool,
+        case_insensitive: bool,
+        escape: Option<char>,
+    },
+    Case {
+        operand: Option<Box<PhysicalExpr>>,
+        when_then: Vec<(PhysicalExpr, PhysicalExpr)>,
+        else_result: Option<Box<PhysicalExpr>>,
+    },
+    Cast {
+        expr: Box<PhysicalExpr>,
+        data_type: DataType,
+    },
+    Coalesce(Vec<PhysicalExpr>),
+    NullIf(Box<PhysicalExpr>, Box<PhysicalExpr>),
+    /// `/` between two integers
+    IntegerDivide(Box<PhysicalExpr>, Box<PhysicalExpr>),
+    Function {
+        func: Arc<ScalarFunction>,
+        args: Vec<PhysicalExpr>,
+    },
+}
+
+impl PhysicalExpr {
+    pub fn new(expr: &ScalarExpr, schema: &Schema) -> anyhow::Result<Self> {
+        let compile = |e: &ScalarExpr| Self::new(e, schema).map(Box::new);
+        let compile_all = |exprs: &[ScalarExpr]| {
+            exprs
+                .iter()
+                .map(|e| Self::new(e, schema))
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+        let res = match expr {
+            ScalarExpr::Column(c) => match schema.find(c)? {
+                Some(i) => Self::Column(i),
+                None => anyhow::bail!("Column {} not found", c),
+            },
+            ScalarExpr::Literal(v) => Self::Literal(v.clone()),
+            ScalarExpr::BinaryOp { left, right, .. } if expr.is_integer_division(schema) => {
+                Self::IntegerDivide(compile(left)?, compile(right)?)
+            }
+            ScalarExpr::BinaryOp { left, op, right } => Self::BinaryOp {
+                left: compile(left)?,
+                op: op.clone(),
+                right: compile(right)?,
+            },
+            ScalarExpr::UnaryOp { op, expr } => Self::UnaryOp {
+                op: *op,
+                expr: compile(expr)?,
+            },
+            ScalarExpr::IsNull(e) => Self::IsNull(compile(e)?),
+            ScalarExpr::IsNotNull(e) => Self::IsNotNull(compile(e)?),
+            ScalarExpr::Between {
+                expr,
+                negated,
+                low,
+                high,
+            } => Self::Between {
+                expr: compile(expr)?,
+                negated: *negated,
+                low: compile(low)?,
+                high: compile(high)?,
+            },
+            ScalarExpr::InList {
+                expr,
+                list,
+                negated,
+            } => Self::InList {
+                expr: compile(expr)?,
+                list: compile_all(list)?,
+                negated: *negated,
+            },
+            ScalarExpr::Like {
+                expr,
+                pattern,
+                negated,
+                case_insensitive,
+                escape,
+            } => Self::Like {
+                expr: compile(expr)?,
+                pattern: compile(pattern)?,
+                negated: *negated,
+                case_insensitive: *case_insensitive,
+                escape: *escape,
+            },
+            ScalarExpr::Case {
+                operand,
+                when_then,
+                else_result,
+            } => Self::Case {
+                operand: operand.as_deref().map(compile).transpose()?,
+                when_then: when_then
+                    .iter()
+                    .map(|(when, then)| Ok((Self::new(when, schema)?, Self::new(then, schema)?)))
+                    .collect::<anyhow::Result<_>>()?,
+                else_result: else_result.as_deref().map(compile).transpose()?,
+            },
+            ScalarExpr::Cast { expr, data_type } => Self::Cast {
+                expr: compile(expr)?,
+                data_type: data_type.clone(),
+            },
+            ScalarExpr::Coalesce(args) => Self::Coalesce(compile_all(args)?),
+            ScalarExpr::NullIf(left, right) => Self::NullIf(compile(left)?, compile(right)?),
+            ScalarExpr::Function { func, args } => Self::Function {
+                func: func.clone(),
+                args: compile_all(args)?,
+            },
+            ScalarExpr::Aggregate(a) => {
+                anyhow::bail!("Aggregate {} can't be evaluated on a single row", a)
+            }
+            ScalarExpr::Window(w) => {
+                anyhow::bail!("Window function {} can't be evaluated on a single row", w)
+            }
+            ScalarExpr::OuterColumn(c, _) => {
+                anyhow::bail!("Column {} of the outer query can't be used here", c)
+            }
+            ScalarExpr::Subquery(_) | ScalarExpr::Exists { .. } | ScalarExpr::InSubquery { .. } => {
+                anyhow::bail!("Subqueries are not allowed here")
+            }
+        };
+        Ok(res)
+    }
+
+    pub fn evaluate(&self, row: &[Value]) -> anyhow::Result<Value> {
+        match self {
+            Self::Column(i) => Ok(row[*i].clone()),
+            Self::Literal(v) => Ok(v.clone()),
+            Self::BinaryOp { left, op, right } => {
+                let left = left.evaluate(row)?;
+                // Don't bother with the right side if the answer is already known
+                match (&left, op) {
+                    (Value::Boolean(false), BinaryOperator::And) => return Ok(left),
+                    (Value::Boolean(true), BinaryOperator::Or) => return Ok(left),
+                    _ => {}
+                }
+                binary_op(&left, op, &right.evaluate(row)?)
+            }
+            Self::UnaryOp { op, expr } => unary_op(*op, &expr.evaluate(row)?),
+            Self::IsNull(e) => Ok(Value::Boolean(e.evaluate(row)?.is_null())),
+            Self::IsNotNull(e) => Ok(Value::Boolean(!e.evaluate(row)?.is_null())),
+            Self::Between {
+                expr,
+                negated,
+                low,
+                high,
+            } => {
+                let value = expr.evaluate(row)?;
+                let low = binary_op(&value, &BinaryOperator::GtEq, &low.evaluate(row)?)?;
+                let high = binary_op(&value, &BinaryOperator::LtEq, &high.evaluate(row)?)?;
+                let res = binary_op(&low, &BinaryOperator::And, &high)?;
+                negate(res, *negated)
+            }
+            Self::InList {
+                expr,
+                list,
+                negated,
+            } => {
+                let value = expr.evaluate(row)?;
+                if value.is_null() {
+                    return Ok(Value::Null);
+                }
+                // If nothing matches but there's a null in the list we can't say it's not in
+                // there
+                let mut res = Value::Boolean(false);
+                for item in list {
+                    match binary_op(&value, &BinaryOperator::Eq, &item.evaluate(row)?)? {
+                        Value::Boolean(true) => {
+                            res = Value::Boolean(true);
+                            break;
+                        }
+                        Value::Null => res = Value::Null,
+                        _ => {}
+                    }
+                }
+                negate(res, *negated)
+            }
+            Self::Like {
+                expr,
+                pattern,
+                negated,
+                case_insensitive,
+                escape,
+            } => match (expr.evaluate(row)?, pattern.evaluate(row)?) {
+                (Value::Null, _) | (_, Value::Null) => Ok(Value::Null),
+                (Value::Text(text), Value::Text(pattern)) => {
+                    let res = like(&text, &pattern, *escape, *case_insensitive)?;
+                    Ok(Value::Boolean(res != *negated))
+                }
+                (text, pattern) => {
+                    anyhow::bail!("Can't match {} against pattern {}", text, pattern)
+                }
+            },
+            Self::Case {
+                operand,
+                when_then,
+                else_result,
+            } => {
+                let operand = operand.as_ref().map(|x| x.evaluate(row)).transpose()?;
+                for (when, then) in when_then {
+                    let when = when.evaluate(row)?;
+                    let matched = match &operand {
+                        Some(operand) => binary_op(operand, &BinaryOperator::Eq, &when)?,
+                        None => when,
+                    };
+                    match matched {
+                        Value::Boolean(true) => return then.evaluate(row),
+                        Value::Boolean(false) | Value::Null => {}
+                        v => anyhow::bail!("CASE conditions must be boolean but got {}", v),
+                    }
+                }
+                match else_result {
+                    Some(e) => e.evaluate(row),
+                    None => Ok(Value::Null),
+                }
+            }
+            Self::Cast { expr, data_type } => cast(&expr.evaluate(row)?, data_type),
+            Self::Coalesce(args) => {
+                for arg in args {
+                    let value = arg.evaluate(row)?;
+                    if !value.is_null() {
+                        return Ok(value);
+                    }
+                }
+                Ok(Value::Null)
+            }
+            Self::NullIf(left, right) => {
+                let left = left.evaluate(row)?;
+                match binary_op(&left, &BinaryOperator::Eq, &right.evaluate(row)?)? {
+                    Value::Boolean(true) => Ok(Value::Null),
+                    _ => Ok(left),
+                }
+            }
+            Self::IntegerDivide(left, right) => {
+                integer_divide(&left.evaluate(row)?, &right.evaluate(row)?)
+            }
+            Self::Function { func, args } => {
+                let args = args
+                    .iter()
+                    .map(|x| x.evaluate(row))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                func.invoke(&args)
+            }
+        }
+    }
+
+    /// Evaluates a predicate, only true counts as a match so null is treated as false.
+    pub fn matches(&self, row: &[Value]) -> anyhow::Result<bool> {
+        match self.evaluate(row)? {
+            Value::Boolean(b) => Ok(b),
+            Value::Null => Ok(false),
+            v => anyhow::bail!("Expected a boolean but got {}", v),
+        }
+    }
+}
+
+/// Compiles an expression which doesn't reference any columns, like the values in an `INSERT` or
+/// a column's default. If it's volatile it should be evaluated again for every row.
+pub fn compile_constant(
+    expr: &Expr,
+    catalog: &dyn Catalog,
+) -> anyhow::Result<(PhysicalExpr, Volatility)> {
+    let schema = Schema::default();
+    let expr = PlanBuilder::new(catalog).lower_expr(expr, &schema, false)?;
+    Ok((PhysicalExpr::new(&expr, &schema)?, expr.volatility()))
+}
+
+pub fn evaluate_constant(expr: &Expr, catalog: &dyn Catalog) -> anyhow::Result<Value> {
+    compile_constant(expr, catalog)?.0.evaluate(&[])
+}
+
+fn negate(value: Value, negated: bool) -> anyhow::Result<Value> {
+    if negated {
+        unary_op(UnaryOperator::Not, &value)
+    } else {
+        Ok(value)
+    }
+}
+
+/// Rounds results which could otherwise have an endless number of decimal places
+pub(crate) fn limit_scale(n: BigDecimal) -> BigDecimal {
+    if n.fractional_digit_count() <= MAX_SCALE {
+        return n;
+    }
+    // Drop any trailing zeros left over from rounding, without going into exponent form
+    let n = n
+        .with_scale_round(MAX_SCALE, RoundingMode::HalfUp)
+        .normalized();
+    if n.fractional_digit_count() < 0 {
+        n.with_scale(0)
+    } else {
+        n
+    }
+}
+
+/// Division between two integers, truncated towards zero like Postgres.
+fn integer_divide(left: &Value, right: &Value) -> anyhow::Result<Value> {
+    match (left, right) {
+        // Remainder takes the sign of the dividend so this truncates towards zero
+        (Value::Number(l), Value::Number(r)) if !r.is_zero() => Ok(Value::Number((l - l % r) / r)),
+        // Nulls, zero and the wrong types are the same as for any other division
+        _ => binary_op(left, &BinaryOperator::Divide, right),
+    }
+}
+
+pub fn binary_op(left: &Value, op: &BinaryOperator, right: &Value) -> anyhow::Result<Value> {
+    // Three valued logic, false AND null is false and true OR null is true
+    match (left, op, right) {
+        (Value::Boolean(false), BinaryOperator::And, _)
+        | (_, BinaryOperator::And, Value::Boolean(false)) => return Ok(Value::Boolean(false)),
+        (Value::Boolean(true), BinaryOperator::Or, _)
+        | (_, BinaryOperator::Or, Value::Boolean(true)) => return Ok(Value::Boolean(true)),
+        _ => {}
+    }
+    if left.is_null() || right.is_null() {
+        return Ok(Value::Null);
+    }
+    let cmp = |f: fn(Ordering) -> bool| match left.compare(right) {
+        Some(x) => Ok(Value::Boolean(f(x))),
+        None => anyhow::bail!("Can't compare {} and {}", left, right),
+    };
+    match (left, op, right) {
+        (_, BinaryOperator::Eq, _) => cmp(|x| x.is_eq()),
+        (_, BinaryOperator::NotEq, _) => cmp(|x| x.is_ne()),
+        (_, BinaryOperator::Lt, _) => cmp(|x| x.is_lt()),
+        (_, BinaryOperator::LtEq, _) => cmp(|x| x.is_le()),
+        (_, BinaryOperator::Gt, _) => cmp(|x| x.is_gt()),
+        (_, BinaryOperator::GtEq, _) => cmp(|x| x.is_ge()),
+        (Value::Boolean(l), BinaryOperator::And, Value::Boolean(r)) => Ok(Value::Boolean(*l && *r)),
+        (Value::Boolean(l), BinaryOperator::Or, Value::Boolean(r)) => Ok(Value::Boolean(*l || *r)),
+        (Value::Number(l), BinaryOperator::Plus, Value::Number(r)) => Ok(Value::Number(l + r)),
+        (Value::Number(l), BinaryOperator::Minus, Value::Number(r)) => Ok(Value::Number(l - r)),
+        (Value::Number(l), BinaryOperator::Multiply, Value::Number(r)) => Ok(Value::Number(l * r)),
+        (Value::Number(_), BinaryOperator::Divide | BinaryOperator::Modulo, Value::Number(r))
+            if r.is_zero() =>
+        {
+            anyhow::bail!("Division by zero")
+        }
+        (Value::Number(l), BinaryOperator::Divide, Value::Number(r)) => {
+            Ok(Value::Number(limit_scale(l / r)))
+        }
+        (Value::Number(l), BinaryOperator::Modulo, Value::Number(r)) => Ok(Value::Number(l % r)),
+        (Value::Text(l), BinaryOperator::StringConcat, Value::Text(r)) => {
+            Ok(Value::Text(format!("{}{}", l, r)))
+        }
+        _ => anyhow::bail!("Unsupported operation: {} {} {}", left, op, right),
+    }
+}
+
+pub fn unary_op(op: UnaryOperator, value: &Value) -> anyhow::Result<Value> {
+    match (op, value) {
+        (UnaryOperator::Not | UnaryOperator::Minus | UnaryOperator::Plus, Value::Null) => {
+            Ok(Value::Null)
+        }
+        (UnaryOperator::Not, Value::Boolean(b)) => Ok(Value::Boolean(!b)),
+        (UnaryOperator::Minus, Value::Number(n)) => Ok(Value::Number(-n)),
+        (UnaryOperator::Plus, Value::Number(_)) => Ok(value.clone()),
+        _ => anyhow::bail!("Unsupported operation: {} {}", op, value),
+    }
+}
+
+/// Converts the value to the type, following Postgres where there's a choice. Casting a number
+/// to an integer type rounds it.
+pub fn cast(value: &Value, data_type: &DataType) -> anyhow::Result<Value> {
+    let invalid = || anyhow::anyhow!("Can't cast {} to {}", value, data_type);
+    let class = TypeClass::of(data_type).ok_or_else(invalid)?;
+    let res = match (value, class) {
+        (Value::Null, _) => Value::Null,
+        (Value::Text(s), TypeClass::Text) => Value::Text(s.clone()),
+        (v, TypeClass::Text) => Value::Text(v.to_string()),
+        (v, TypeClass::Number) => {
+            let n = match v {
+                Value::Number(n) => n.clone(),
+                Value::Text(s) => s
+                    .trim()
+                    .parse::<BigDecimal>()
+                    .map_err(|_| anyhow::anyhow!("Invalid number: {}", s))?,
+                Value::Boolean(b) => BigDecimal::from(u8::from(*b)),
+                _ => return Err(invalid()),
+            };
+            let scale = match data_type {
+                DataType::Numeric(ExactNumberInfo::PrecisionAndScale(_, scale))
+                | DataType::Decimal(ExactNumberInfo::PrecisionAndScale(_, scale))
+                | DataType::Dec(ExactNumberInfo::PrecisionAndScale(_, scale)) => {
+                    Some(*scale as i64)
+                }
+                DataType::Numeric(_)
+                | DataType::Decimal(_)
+                | DataType::Dec(_)
+                | DataType::Float(_)
+                | DataType::Real
+                | DataType::Double
+                | DataType::DoublePrecision
+                | DataType::Float4
+                | DataType::Float8
+                | DataType::Float64 => None,
+                _ => Some(0),
+            };
+            match scale {
+                Some(scale) => Value::Number(n.with_scale_round(scale, RoundingMode::HalfUp)),
+                None => Value::Number(n),
+            }
+        }
+        (Value::Boolean(b), TypeClass::Boolean) => Value::Boolean(*b),
+        (Value::Number(n), TypeClass::Boolean) => Value::Boolean(!n.is_zero()),
+        (Value::Text(s), TypeClass::Boolean) => match s.trim().to_lowercase().as_str() {
+            "t" | "true" | "y" | "yes" | "on" | "1" => Value::Boolean(true),
+            "f" | "false" | "n" | "no" | "off" | "0" => Value::Boolean(false),
+            _ => anyhow::bail!("Invalid boolean: {}", s),
+        },
+        (Value::Bytes(b), TypeClass::Bytes) => Value::Bytes(b.clone()),
+        (Value::Text(s), TypeClass::Bytes) => Value::Bytes(s.as_bytes().to_vec()),
+        _ => return Err(invalid()),
+    };
+    Ok(res)
+}
+
+/// SQL `LIKE`, `%` matches any number of characters and `_` matches exactly one. Without an
+/// escape character backslash is used.
+pub fn like(
+    text: &str,
+    pattern: &str,
+    escape: Option<char>,
+    case_insensitive: bool,
+) -> anyhow::Result<bool> {
+    enum Token {
+        Any,
+        One,
+        Char(char),
+    }
+    let escape = escape.unwrap_or('\\');
+    let mut tokens = vec![];
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            c if c == escape => match chars.next() {
+                Some(c) => Token::Char(c),
+                None => anyhow::bail!("LIKE pattern must not end with the escape character"),
+            },
+            '%' => Token::Any,
+            '_' => Token::One,
+            c => Token::Char(c),
+        });
+    }
+    let eq =
+        |a: char, b: char| a == b || (case_insensitive && a.to_lowercase().eq(b.to_lowercase()));
+    let text = text.chars().collect::<Vec<_>>();
+    // When a character doesn't match we go back to the last `%` and let it swallow one more
+    // character
+    let (mut t, mut p) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        match tokens.get(p) {
+            Some(Token::Any) => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(Token::One) => {
+                t += 1;
+                p += 1;
+            }
+            Some(Token::Char(c)) if eq(*c, text[t]) => {
+                t += 1;
+                p += 1;
+            }
+            _ => match backtrack {
+                Some((any, start)) => {
+                    backtrack = Some((any, start + 1));
+                    p = any + 1;
+                    t = start + 1;
+                }
+                None => return Ok(false),
+            },
+        }
+    }
+    Ok(tokens[p..].iter().all(|x| matches!(x, Token::Any)))
+}
+
+/// Orders values for sorting, nulls come after everything else. Values of different types are
+/// treated as equal, which shouldn't happen once the plan has been type checked.
+pub fn sort_compare(a: &Value, b: &Value) -> Ordering {
+    match (a.is_null(), b.is_null()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => a.compare(b).unwrap_or(Ordering::Equal),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logical_plan::Field;
+    use crate::types::ColumnDescriptors;
+    use sqlparser::ast::DataType;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn evaluate() {
+        let schema = Schema::new(vec![
+            Field::new(Some("t".to_string()), "a", DataType::Integer(None)),
+            Field::new(Some("t".to_string()), "b", DataType::Boolean),
+        ]);
+        let compile = |e: ScalarExpr| PhysicalExpr::new(&e, &schema).unwrap();
+        let a = || ScalarExpr::column(Some("t"), "a");
+        let b = || ScalarExpr::column(Some("t"), "b");
+
+        let expr = compile(ScalarExpr::binary(
+            ScalarExpr::binary(a(), BinaryOperator::Multiply, ScalarExpr::literal(2)),
+            BinaryOperator::Gt,
+            ScalarExpr::literal(5),
+        ));
+        assert_eq!(
+            expr.evaluate(&[Value::from(3), Value::Null]).unwrap(),
+            Value::from(true)
+        );
+        assert_eq!(
+            expr.evaluate(&[Value::Null, Value::Null]).unwrap(),
+            Value::Null
+        );
+        assert!(!expr.matches(&[Value::Null, Value::Null]).unwrap());
+
+        let and = compile(ScalarExpr::binary(
+            b(),
+            BinaryOperator::And,
+            ScalarExpr::IsNull(Box::new(a())),
+        ));
+        assert_eq!(
+            and.evaluate(&[Value::from(1), Value::Null]).unwrap(),
+            Value::from(false)
+        );
+        assert_eq!(
+            and.evaluate(&[Value::Null, Value::Null]).unwrap(),
+            Value::Null
+        );
+        let or = compile(ScalarExpr::binary(
+            b(),
+            BinaryOperator::Or,
+            ScalarExpr::IsNotNull(Box::new(a())),
+        ));
+        assert_eq!(
+            or.evaluate(&[Value::from(1), Value::Null]).unwrap(),
+            Value::from(true)
+        );
+
+        assert!(PhysicalExpr::new(&ScalarExpr::column(Some("t"), "c"), &schema).is_err());
+        let bad = compile(ScalarExpr::binary(a(), BinaryOperator::Eq, b()));
+        assert!(bad.evaluate(&[Value::from(1), Value::from(true)]).is_err());
+    }
+
+    #[test]
+    fn integer_division() {
+        let schema = Schema::new(vec![
+            Field::new(None, "x", DataType::Integer(None)),
+            Field::new(None, "y", DataType::BigInt(None)),
+            Field::new(None, "z", DataType::Numeric(ExactNumberInfo::None)),
+        ]);
+        let column = |name| ScalarExpr::column(None, name);
+        let divide = |l, r| ScalarExpr::binary(column(l), BinaryOperator::Divide, column(r));
+        let evaluate = |expr: &ScalarExpr, row: &[Value]| {
+            PhysicalExpr::new(expr, &schema)
+                .unwrap()
+                .evaluate(row)
+                .unwrap()
+        };
+
+        // Two integers truncate towards zero and stay an integer, the wider of the two
+        let ints = divide("x", "y");
+        assert_eq!(ints.data_type(&schema), DataType::BigInt(None));
+        let row = |x: i64, y: i64| [Value::from(x), Value::from(y), Value::Null];
+        assert_eq!(evaluate(&ints, &row(10, 4)), Value::from(2));
+        assert_eq!(evaluate(&ints, &row(-10, 4)), Value::from(-2));
+        assert_eq!(evaluate(&ints, &row(10, -3)), Value::from(-3));
+        assert_eq!(evaluate(&ints, &row(12, 4)), Value::from(3));
+        let err = PhysicalExpr::new(&ints, &schema)
+            .unwrap()
+            .evaluate(&row(1, 0))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Division by zero");
+
+        // Anything else gives a number with a fractional part
+        let mixed = divide("x", "z");
+        assert_eq!(
+            mixed.data_type(&schema),
+            DataType::Numeric(ExactNumberInfo::None)
+        );
+        let row = [Value::from(10), Value::Null, Value::from(4)];
+        assert_eq!(evaluate(&mixed, &row), number("2.5"));
+
+        // The type of an arithmetic expression depends on both sides, not just the left
+        let literal = |s: &str| ScalarExpr::Literal(number(s));
+        let op = |l, op, r| ScalarExpr::binary(l, op, r);
+        let row = [Value::from(5), Value::from(2), Value::from(4)];
+        for (expr, data_type, expected) in [
+            (
+                op(
+                    op(column("x"), BinaryOperator::Multiply, literal("1.5")),
+                    BinaryOperator::Divide,
+                    column("y"),
+                ),
+                DataType::Numeric(ExactNumberInfo::None),
+                number("3.75"),
+            ),
+            (
+                op(
+                    op(column("x"), BinaryOperator::Plus, literal("0.5")),
+                    BinaryOperator::Divide,
+                    column("y"),
+                ),
+                DataType::Numeric(ExactNumberInfo::None),
+                number("2.75"),
+            ),
+            (
+                op(column("x"), BinaryOperator::Divide, literal("3")),
+                DataType::Integer(None),
+                Value::from(1),
+            ),
+            (
+                op(column("x"), BinaryOperator::Divide, literal("3.0")),
+                DataType::Numeric(ExactNumberInfo::None),
+                number("1.66666666666666666667"),
+            ),
+            (
+                op(
+                    op(column("x"), BinaryOperator::Multiply, column("y")),
+                    BinaryOperator::Divide,
+                    literal("3"),
+                ),
+                DataType::BigInt(None),
+                Value::from(3),
+            ),
+            (
+                op(
+                    op(column("z"), BinaryOperator::Minus, column("x")),
+                    BinaryOperator::Divide,
+                    column("y"),
+                ),
+                DataType::Numeric(ExactNumberInfo::None),
+                number("-0.5"),
+            ),
+        ] {
+            assert_eq!(expr.data_type(&schema), data_type, "{}", expr);
+            assert_eq!(evaluate(&expr, &row), expected, "{}", expr);
+        }
+    }
+
+    fn eval(sql: &str) -> anyhow::Result<Value> {
+        let expr = sqlparser::parser::Parser::new(&sqlparser::dialect::GenericDialect {})
+            .try_with_sql(sql)?
+            .parse_expr()?;
+        evaluate_constant(&expr, &BTreeMap::<String, ColumnDescriptors>::new())
+    }
+
+    fn number(s: &str) -> Value {
+        Value::Number(s.parse().unwrap())
+    }
+
+    #[test]
+    fn constants() {
+        assert_eq!(eval("1 + 2 * 3").unwrap(), Value::from(7));
+        assert_eq!(eval("7 % 3").unwrap(), Value::from(1));
+        // Integer literals divide like integers
+        assert_eq!(eval("1 / 4").unwrap(), Value::from(0));
+        assert_eq!(eval("7 / 2").unwrap(), Value::from(3));
+        assert_eq!(eval("1.0 / 4").unwrap(), number("0.25"));
+        assert_eq!(eval("2 / 3.0").unwrap(), number("0.66666666666666666667"));
+        assert!(eval("1 / 0").is_err());
+        assert!(eval("1 % 0").is_err());
+
+        // Three valued logic
+        assert_eq!(eval("NULL AND false").unwrap(), Value::from(false));
+        assert_eq!(eval("NULL AND true").unwrap(), Value::Null);
+        assert_eq!(eval("NULL OR true").unwrap(), Value::from(true));
+        assert_eq!(eval("NOT NULL").unwrap(), Value::Null);
+        assert_eq!(eval("NULL = NULL").unwrap(), Value::Null);
+        assert_eq!(eval("NULL IS NULL").unwrap(), Value::from(true));
+
+        assert_eq!(eval("3 BETWEEN 1 AND 5").unwrap(), Value::from(true));
+        assert_eq!(eval("3 NOT BETWEEN 1 AND 5").unwrap(), Value::from(false));
+        assert_eq!(eval("3 BETWEEN NULL AND 2").unwrap(), Value::from(false));
+        assert_eq!(eval("3 BETWEEN NULL AND 5").unwrap(), Value::Null);
+
+        assert_eq!(eval("2 IN (1, 2, 3)").unwrap(), Value::from(true));
+        assert_eq!(eval("4 IN (1, 2, 3)").unwrap(), Value::from(false));
+        assert_eq!(eval("4 IN (1, NULL)").unwrap(), Value::Null);
+        assert_eq!(eval("4 NOT IN (1, NULL)").unwrap(), Value::Null);
+        assert_eq!(eval("1 NOT IN (1, NULL)").unwrap(), Value::from(false));
+        assert_eq!(eval("NULL IN (1)").unwrap(), Value::Null);
+
+        assert_eq!(
+            eval("CASE WHEN 1 > 2 THEN 'a' WHEN NULL THEN 'b' ELSE 'c' END").unwrap(),
+            Value::from("c")
+        );
+        assert_eq!(
+            eval("CASE 2 WHEN 1 THEN 'a' WHEN 2 THEN 'b' END").unwrap(),
+            Value::from("b")
+        );
+        assert_eq!(eval("CASE 3 WHEN 1 THEN 'a' END").unwrap(), Value::Null);
+
+        assert_eq!(eval("COALESCE(NULL, 2, 1 / 0)").unwrap(), Value::from(2));
+        assert_eq!(eval("COALESCE(NULL, NULL)").unwrap(), Value::Null);
+        assert_eq!(eval("NULLIF(1, 1)").unwrap(), Value::Null);
+        assert_eq!(eval("NULLIF(1, 2)").unwrap(), Value::from(1));
+
+        assert_eq!(eval("CAST('12' AS INT) + 1").unwrap(), Value::from(13));
+        assert_eq!(eval("CAST(2.5 AS INT)").unwrap(), Value::from(3));
+        assert_eq!(
+            eval("CAST(1.005 AS NUMERIC(5, 2))").unwrap(),
+            number("1.01")
+        );
+        assert_eq!(eval("CAST(12 AS TEXT)").unwrap(), Value::from("12"));
+        assert_eq!(eval("CAST('yes' AS BOOLEAN)").unwrap(), Value::from(true));
+        assert_eq!(eval("CAST(0 AS BOOLEAN)").unwrap(), Value::from(false));
+        assert!(eval("CAST('twelve' AS INT)").is_err());
+        assert!(eval("CAST(true AS BYTEA)").is_err());
+
+        assert_eq!(eval("'abc' LIKE 'a%'").unwrap(), Value::from(true));
+        assert_eq!(eval("'abc' NOT LIKE 'a_c'").unwrap(), Value::from(false));
+        assert_eq!(eval("'ABC' ILIKE 'a%c'").unwrap(), Value::from(true));
+        assert_eq!(eval("'ABC' LIKE 'a%c'").unwrap(), Value::from(false));
+        assert_eq!(eval("NULL LIKE 'a%'").unwrap(), Value::Null);
+        assert!(eval("1 LIKE 'a%'").is_err());
+    }
+
+    #[test]
+    fn like_patterns() {
+        let like = |text, pattern| like(text, pattern, None, false).unwrap();
+        assert!(like("", ""));
+        assert!(like("", "%"));
+        assert!(!like("", "_"));
+        assert!(like("mississippi", "%iss%ppi"));
+        assert!(like("mississippi", "m%s%s%i"));
+        assert!(!like("mississippi", "m%s%s%x"));
+        assert!(like("100%", "100\\%"));
+        assert!(!like("1000", "100\\%"));
+        assert!(like("a_b", "a\\_b"));
+        assert!(!like("axb", "a\\_b"));
+        assert!(super::like("10%", "10!%", Some('!'), false).unwrap());
+        assert!(super::like("a", "a\\", None, false).is_err());
+    }
+}
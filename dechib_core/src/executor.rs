@@ -0,0 +1,3904 @@
+//! Runs physical plans. Operators follow the volcano model, each one pulls rows from its inputs
+//! one at a time with `next` so rows can be passed on as soon as they're produced. Only the
+//! operators which can't produce anything until they've seen all of their input (sorts,
+//! aggregates and the build side of joins) hold rows in memory.
+use crate::evaluator::{binary_op, limit_scale, sort_compare, PhysicalExpr};
+use crate::expression::{
+    AggregateExpr, AggregateFunction, FrameBound, FrameUnits, ScalarExpr, WindowExpr,
+    WindowFunction,
+};
+use crate::functions::{AggregateUdf, AggregatorState};
+use crate::lock_manager::{LockManager, LockStatus, RowId, TransactionId, WriteLocks};
+use crate::logical_plan;
+use crate::logical_plan::{ApplyKind, JoinKind, Schema, SetOperator, SortKey, ROW_KEY};
+use crate::physical_plan::{Metrics, Operator, PhysicalPlan};
+use crate::storage_engine::{
+    encode_key, ConflictAction, FlushedWrites, OnConflict, StorageEngine, WriteSet,
+};
+use crate::types::{Record, RowLocking, Value};
+use bigdecimal::{BigDecimal, ToPrimitive};
+use postcard::{from_bytes, to_allocvec};
+use sqlparser::ast::BinaryOperator;
+use std::cell::{Cell, RefCell};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tempfile::{NamedTempFile, TempPath};
+
+/// How many rows a table scan reads from storage at a time
+const SCAN_BATCH_SIZE: usize = 1024;
+
+/// How many times a recursive CTE can go round before we assume it's never going to stop
+pub const DEFAULT_MAX_RECURSION: u64 = 1000;
+
+/// How many bytes of rows a sort holds in memory before writing them out to disk
+pub const DEFAULT_WORK_MEM: usize = 4 << 20;
+
+/// How many rows an insert validates and stages at a time
+const INSERT_BATCH_SIZE: usize = 1024;
+
+pub type Row = Vec<Value>;
+
+/// Everything the operators need from outside the plan.
+pub struct ExecutionContext<'a> {
+    pub storage: &'a StorageEngine,
+    pub locks: &'a LockManager,
+    /// The transaction rows are locked for by `FOR UPDATE` and `FOR SHARE`
+    pub transaction: TransactionId,
+    /// Writes the transaction has made which aren't committed yet, it should still see them
+    pub writes: Option<&'a WriteSet>,
+    pub lock_timeout: Option<Duration>,
+    /// How many iterations a recursive CTE gets before it's cut off
+    pub max_recursion: u64,
+    /// Roughly how much memory in bytes a sort can use before it spills to disk
+    pub work_mem: usize,
+    /// Rows of the materialised CTEs and recursive work tables, keyed by their id
+    pub ctes: RefCell<HashMap<usize, Rc<Vec<Row>>>>,
+    /// Writes made by the statement. They're left for the caller to apply once it's finished so
+    /// the statement never reads its own changes.
+    pub staged: RefCell<WriteSet>,
+    /// Outside of a transaction nothing reads the statement's writes back, so an insert can flush
+    /// them as it goes rather than keeping them all staged
+    pub flushed: RefCell<Option<FlushedWrites>>,
+}
+
+impl ExecutionContext<'_> {
+    /// For locking what the statement writes as it's staged
+    pub fn write_locks(&self) -> WriteLocks<'_> {
+        WriteLocks {
+            locks: self.locks,
+            transaction: self.transaction,
+            timeout: self.lock_timeout,
+        }
+    }
+}
+
+pub trait PhysicalOperator {
+    /// Gets the operator ready to produce rows, it can be opened again after it's closed to
+    /// start over.
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()>;
+
+    /// The next row or `None` once there aren't any more.
+    fn next(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<Row>>;
+
+    fn close(&mut self) -> anyhow::Result<()>;
+}
+
+pub(crate) type BoxedOperator = Box<dyn PhysicalOperator>;
+
+/// Shared with the operator so it can keep updating the metrics as it runs
+pub(crate) type MetricsHandle = Rc<Cell<Metrics>>;
+
+/// Creates the operators to run the plan.
+pub fn build(plan: &PhysicalPlan) -> anyhow::Result<BoxedOperator> {
+    build_operator(plan, &mut None)
+}
+
+/// Runs the plan to completion, returning all the rows it produces.
+pub fn execute(plan: &PhysicalPlan, ctx: &ExecutionContext) -> anyhow::Result<Vec<Row>> {
+    let mut operator = build(plan)?;
+    drain(operator.as_mut(), ctx)
+}
+
+/// Runs the plan recording what each operator does in the plan's metrics, for `EXPLAIN ANALYZE`.
+pub fn execute_analyze(plan: &mut PhysicalPlan, ctx: &ExecutionContext) -> anyhow::Result<()> {
+    let mut metrics = Some(vec![]);
+    let mut operator = build_operator(plan, &mut metrics)?;
+    drain(operator.as_mut(), ctx)?;
+    assign_metrics(plan, metrics.unwrap_or_default());
+    Ok(())
+}
+
+/// Copies the metrics into the plan, they need to have been created walking the plan depth first
+/// with each node before its inputs.
+pub(crate) fn assign_metrics(plan: &mut PhysicalPlan, metrics: Vec<MetricsHandle>) {
+    fn assign(plan: &mut PhysicalPlan, metrics: &mut impl Iterator<Item = MetricsHandle>) {
+        plan.metrics = metrics.next().map(|x| x.get());
+        for input in &mut plan.inputs {
+            assign(input, metrics);
+        }
+    }
+    assign(plan, &mut metrics.into_iter());
+}
+
+/// A new set of metrics for the next node in the plan if we're collecting them
+pub(crate) fn new_metrics(metrics: &mut Option<Vec<MetricsHandle>>) -> Option<MetricsHandle> {
+    metrics.as_mut().map(|metrics| {
+        let handle = Rc::new(Cell::new(Metrics::default()));
+        metrics.push(handle.clone());
+        handle
+    })
+}
+
+pub(crate) fn instrument(operator: BoxedOperator, metrics: Option<MetricsHandle>) -> BoxedOperator {
+    match metrics {
+        Some(metrics) => Box::new(Instrumented {
+            inner: operator,
+            metrics,
+        }),
+        None => operator,
+    }
+}
+
+pub(crate) fn drain(
+    operator: &mut dyn PhysicalOperator,
+    ctx: &ExecutionContext,
+) -> anyhow::Result<Vec<Row>> {
+    operator.open(ctx)?;
+    let mut rows = vec![];
+    while let Some(row) = operator.next(ctx)? {
+        rows.push(row);
+    }
+    operator.close()?;
+    Ok(rows)
+}
+
+pub(crate) fn build_operator(
+    plan: &PhysicalPlan,
+    metrics: &mut Option<Vec<MetricsHandle>>,
+) -> anyhow::Result<BoxedOperator> {
+    let handle = new_metrics(metrics);
+    let mut inputs = vec![];
+    for (i, input) in plan.inputs.iter().enumerate() {
+        if is_deferred(plan, i) {
+            skip_metrics(input, metrics);
+        } else {
+            inputs.push(build_operator(input, metrics)?);
+        }
+    }
+    Ok(instrument(operator(plan, inputs)?, handle))
+}
+
+/// Correlated subqueries can't be built until the outer row's values are known, so the operator
+/// running them builds them itself.
+pub(crate) fn is_deferred(plan: &PhysicalPlan, input: usize) -> bool {
+    matches!(plan.operator, Operator::Apply { .. }) && input == 1 && plan.inputs[1].is_correlated()
+}
+
+/// Empty metrics for a part of the plan which isn't built, to keep the rest lined up
+pub(crate) fn skip_metrics(plan: &PhysicalPlan, metrics: &mut Option<Vec<MetricsHandle>>) {
+    new_metrics(metrics);
+    for input in &plan.inputs {
+        skip_metrics(input, metrics);
+    }
+}
+
+/// Creates the operator for a single node in the plan which reads from `inputs`.
+pub(crate) fn operator(
+    plan: &PhysicalPlan,
+    mut inputs: Vec<BoxedOperator>,
+) -> anyhow::Result<BoxedOperator> {
+    let input_schema = |i: usize| &plan.inputs[i].schema;
+    let compile_all = |exprs: &[_], schema| {
+        exprs
+            .iter()
+            .map(|e| PhysicalExpr::new(e, schema))
+            .collect::<anyhow::Result<Vec<_>>>()
+    };
+    let empty = Schema::default();
+    // Join conditions are over both inputs even for semi and anti joins which only output the left
+    let joined = || input_schema(0).join(input_schema(1));
+    let operator: BoxedOperator = match &plan.operator {
+        Operator::TableScan {
+            table,
+            filters,
+            lock,
+            ..
+        } => Box::new(TableScan {
+            filters: compile_all(filters, &plan.schema)?,
+            lock: lock.clone(),
+            ..TableScan::new(table, &plan.schema)
+        }),
+        Operator::IndexScan {
+            table,
+            key,
+            filters,
+            lock,
+            ..
+        } => Box::new(IndexScan {
+            table: table.clone(),
+            columns: plan.schema.fields.iter().map(|f| f.name.clone()).collect(),
+            key: compile_all(key, &empty)?,
+            filters: compile_all(filters, &plan.schema)?,
+            lock: lock.clone(),
+            done: false,
+        }),
+        Operator::Filter { predicate } => Box::new(Filter {
+            predicate: PhysicalExpr::new(predicate, input_schema(0))?,
+            input: inputs.remove(0),
+        }),
+        Operator::Projection { exprs } => Box::new(Projection {
+            exprs: compile_all(exprs, input_schema(0))?,
+            input: inputs.remove(0),
+        }),
+        Operator::NestedLoopJoin { kind, condition } => {
+            let filter = condition
+                .as_ref()
+                .map(|c| PhysicalExpr::new(c, &joined()))
+                .transpose()?;
+            Box::new(Join::new(
+                plan,
+                *kind,
+                JoinStrategy::NestedLoop,
+                filter,
+                inputs,
+            ))
+        }
+        Operator::HashJoin { kind, keys, filter } => {
+            let (left, right): (Vec<_>, Vec<_>) = keys.iter().cloned().unzip();
+            let strategy = JoinStrategy::Hash {
+                left: compile_all(&left, input_schema(0))?,
+                right: compile_all(&right, input_schema(1))?,
+                table: HashMap::new(),
+            };
+            let filter = filter
+                .as_ref()
+                .map(|c| PhysicalExpr::new(c, &joined()))
+                .transpose()?;
+            Box::new(Join::new(plan, *kind, strategy, filter, inputs))
+        }
+        Operator::MergeJoin { kind, keys, filter } => {
+            let (left, right): (Vec<_>, Vec<_>) = keys.iter().cloned().unzip();
+            let right_input = inputs.pop().expect("join has two inputs");
+            Box::new(MergeJoin {
+                kind: *kind,
+                left_keys: compile_all(&left, input_schema(0))?,
+                right_keys: compile_all(&right, input_schema(1))?,
+                filter: filter
+                    .as_ref()
+                    .map(|c| PhysicalExpr::new(c, &joined()))
+                    .transpose()?,
+                left: inputs.pop().expect("join has two inputs"),
+                right: right_input,
+                left_width: plan.inputs[0].schema.len(),
+                right_width: plan.inputs[1].schema.len(),
+                output: VecDeque::new(),
+            })
+        }
+        Operator::IndexJoin {
+            kind,
+            table,
+            key,
+            filters,
+            lock,
+            filter,
+            schema,
+            ..
+        } => Box::new(IndexJoin {
+            kind: *kind,
+            table: table.clone(),
+            columns: schema.fields.iter().map(|f| f.name.clone()).collect(),
+            key: compile_all(key, input_schema(0))?,
+            filters: compile_all(filters, schema)?,
+            lock: lock.clone(),
+            filter: filter
+                .as_ref()
+                .map(|c| PhysicalExpr::new(c, &input_schema(0).join(schema)))
+                .transpose()?,
+            input: inputs.remove(0),
+        }),
+        Operator::HashAggregate {
+            group_by,
+            aggregates,
+        } => {
+            let aggregates = aggregates
+                .iter()
+                .map(|a| {
+                    let args = a
+                        .exprs()
+                        .map(|e| PhysicalExpr::new(e, input_schema(0)))
+                        .collect::<anyhow::Result<_>>()?;
+                    Ok((a.clone(), args))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Box::new(HashAggregate {
+                group_by: compile_all(group_by, input_schema(0))?,
+                aggregates,
+                max_groups: MAX_HASH_GROUPS,
+                input: inputs.remove(0),
+                output: VecDeque::new(),
+            })
+        }
+        Operator::Window { windows } => Box::new(Window {
+            windows: windows
+                .iter()
+                .map(|w| WindowState::new(w, input_schema(0)))
+                .collect::<anyhow::Result<_>>()?,
+            input: inputs.remove(0),
+            output: VecDeque::new(),
+        }),
+        Operator::Sort { keys } => Box::new(Sort {
+            keys: SortKeys::new(keys, input_schema(0))?,
+            limit: None,
+            with_ties: false,
+            input: inputs.remove(0),
+            output: VecDeque::new(),
+            runs: vec![],
+            merge: None,
+            skip: 0,
+            remaining: None,
+            last: None,
+        }),
+        Operator::TopN {
+            keys,
+            limit,
+            offset,
+            with_ties,
+        } => Box::new(Sort {
+            keys: SortKeys::new(keys, input_schema(0))?,
+            limit: Some((*limit, *offset)),
+            with_ties: *with_ties,
+            input: inputs.remove(0),
+            output: VecDeque::new(),
+            runs: vec![],
+            merge: None,
+            skip: 0,
+            remaining: None,
+            last: None,
+        }),
+        Operator::LockRows { tables, recheck } => {
+            let schema = input_schema(0);
+            let tables = tables
+                .iter()
+                .map(|table| {
+                    let relation = Some(table.relation.as_str());
+                    let columns = schema
+                        .fields
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, f)| f.relation.as_deref() == relation && f.name != ROW_KEY)
+                        .map(|(i, f)| (i, f.name.clone()))
+                        .collect();
+                    Ok(LockedColumns {
+                        table: table.table.clone(),
+                        lock: table.lock.clone(),
+                        key: schema.index_of(&table.key())?,
+                        columns,
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?;
+            Box::new(LockRows {
+                tables,
+                recheck: compile_all(recheck, schema)?,
+                input: inputs.remove(0),
+            })
+        }
+        Operator::Limit { limit, offset } => Box::new(Limit {
+            limit: *limit,
+            offset: *offset,
+            seen: 0,
+            input: inputs.remove(0),
+        }),
+        Operator::Values { rows } => Box::new(Values {
+            rows: rows
+                .iter()
+                .map(|row| compile_all(row, &empty))
+                .collect::<anyhow::Result<_>>()?,
+            position: 0,
+        }),
+        Operator::CteScan { name, id } => Box::new(CteScan {
+            name: name.clone(),
+            id: *id,
+            rows: Rc::default(),
+            position: 0,
+        }),
+        Operator::With { id, .. } => {
+            let input = inputs.pop().expect("with has two inputs");
+            Box::new(With {
+                id: *id,
+                cte: inputs.pop().expect("with has two inputs"),
+                input,
+            })
+        }
+        Operator::RecursiveCte { name, id, distinct } => {
+            let recursive = inputs.pop().expect("recursive CTE has two inputs");
+            Box::new(RecursiveCte {
+                name: name.clone(),
+                id: *id,
+                distinct: *distinct,
+                anchor: inputs.pop().expect("recursive CTE has two inputs"),
+                recursive,
+                output: VecDeque::new(),
+            })
+        }
+        Operator::SetOperation { op, all } => {
+            let right = inputs.pop().expect("set operation has two inputs");
+            Box::new(SetOperation {
+                op: *op,
+                all: *all,
+                left: inputs.pop().expect("set operation has two inputs"),
+                right,
+                on_right: false,
+                counts: HashMap::new(),
+            })
+        }
+        Operator::Apply { kind } => {
+            let subquery = inputs.split_off(1).pop();
+            let outer = input_schema(0).clone();
+            let mode = match kind {
+                ApplyKind::Scalar => ApplyMode::Scalar,
+                ApplyKind::Exists => ApplyMode::Exists,
+                ApplyKind::In(expr) => ApplyMode::In(PhysicalExpr::new(expr, &outer)?),
+            };
+            Box::new(Apply {
+                mode,
+                correlated: plan.inputs[1]
+                    .is_correlated()
+                    .then(|| plan.inputs[1].clone()),
+                outer,
+                subquery,
+                cached: None,
+                input: inputs.remove(0),
+            })
+        }
+        Operator::Insert {
+            table,
+            columns,
+            on_conflict,
+        } => Box::new(Insert {
+            table: table.clone(),
+            columns: columns.clone(),
+            on_conflict: on_conflict.as_ref().map(compile_on_conflict).transpose()?,
+            returning: !plan.schema.is_empty(),
+            input: inputs.remove(0),
+            output: VecDeque::new(),
+        }),
+        Operator::Update { table, assignments } => Box::new(Update {
+            table: table.clone(),
+            assignments: assignments
+                .iter()
+                .map(|(column, expr)| {
+                    Ok((column.clone(), PhysicalExpr::new(expr, input_schema(0))?))
+                })
+                .collect::<anyhow::Result<_>>()?,
+            returning: !plan.schema.is_empty(),
+            input: inputs.remove(0),
+            output: VecDeque::new(),
+        }),
+        Operator::Delete { table } => Box::new(Delete {
+            table: table.clone(),
+            returning: !plan.schema.is_empty(),
+            input: inputs.remove(0),
+            output: VecDeque::new(),
+        }),
+    };
+    Ok(operator)
+}
+
+/// Keeps track of the rows produced and time spent in the wrapped operator.
+struct Instrumented {
+    inner: BoxedOperator,
+    metrics: MetricsHandle,
+}
+
+impl PhysicalOperator for Instrumented {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        let start = Instant::now();
+        let res = self.inner.open(ctx);
+        let mut metrics = self.metrics.get();
+        metrics.elapsed += start.elapsed();
+        metrics.loops += 1;
+        self.metrics.set(metrics);
+        res
+    }
+
+    fn next(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        let start = Instant::now();
+        let res = self.inner.next(ctx);
+        let mut metrics = self.metrics.get();
+        metrics.elapsed += start.elapsed();
+        if matches!(res, Ok(Some(_))) {
+            metrics.rows += 1;
+        }
+        self.metrics.set(metrics);
+        res
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.inner.close()
+    }
+}
+
+fn to_row(record: &Record, columns: &[String]) -> Row {
+    columns
+        .iter()
+        .map(|name| match record.columns.get(name) {
+            Some(value) => value.as_ref().clone(),
+            None => Value::Null,
+        })
+        .collect()
+}
+
+/// `to_row` for a row a scan found at `key`, the key goes in the `ROW_KEY` column if the rows
+/// are going to be locked further up.
+fn scan_row(key: &[u8], record: &Record, columns: &[String]) -> Row {
+    columns
+        .iter()
+        .map(|name| match record.columns.get(name) {
+            Some(value) => value.as_ref().clone(),
+            None if name == ROW_KEY => Value::Bytes(key.to_vec()),
+            None => Value::Null,
+        })
+        .collect()
+}
+
+/// The row as the transaction sees it, including its own uncommitted writes.
+fn read_row(ctx: &ExecutionContext, table: &str, key: &[u8]) -> anyhow::Result<Option<Record>> {
+    match ctx.writes.and_then(|writes| writes.get(table, key)) {
+        Some(Some(bytes)) => Ok(Some(from_bytes(bytes)?)),
+        Some(None) => Ok(None),
+        None => ctx.storage.get_row(table, key),
+    }
+}
+
+fn matches_all(filters: &[PhysicalExpr], row: &[Value]) -> anyhow::Result<bool> {
+    for filter in filters {
+        if !filter.matches(row)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Locks a row and reads it again, as it could have been changed by whoever held the lock
+/// before us. `None` if the row's been deleted or skipped because it's locked.
+fn lock_record(
+    ctx: &ExecutionContext,
+    table: &str,
+    key: &[u8],
+    lock: &RowLocking,
+) -> anyhow::Result<Option<Record>> {
+    let status = ctx.locks.lock(
+        ctx.transaction,
+        &RowId::new(table, key),
+        lock.mode,
+        lock.wait,
+        ctx.lock_timeout,
+    )?;
+    if status == LockStatus::Skipped {
+        return Ok(None);
+    }
+    read_row(ctx, table, key)
+}
+
+/// Locks a row the scan has found, `None` if the row should be skipped.
+fn lock_row(
+    ctx: &ExecutionContext,
+    table: &str,
+    key: &[u8],
+    lock: &RowLocking,
+    columns: &[String],
+    filters: &[PhysicalExpr],
+) -> anyhow::Result<Option<Row>> {
+    let Some(record) = lock_record(ctx, table, key, lock)? else {
+        return Ok(None);
+    };
+    let row = scan_row(key, &record, columns);
+    Ok(matches_all(filters, &row)?.then_some(row))
+}
+
+/// Reads every row in the table a batch at a time.
+pub(crate) struct TableScan {
+    table: String,
+    columns: Vec<String>,
+    filters: Vec<PhysicalExpr>,
+    lock: Option<RowLocking>,
+    batch: VecDeque<(Vec<u8>, Record)>,
+    last_key: Option<Vec<u8>>,
+    /// Whether we've read all the committed rows
+    finished: bool,
+    /// Rows written by the transaction we haven't come across in the committed rows yet
+    staged: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl TableScan {
+    /// Scans all the rows in the table without filtering or locking them
+    pub(crate) fn new(table: &str, schema: &Schema) -> Self {
+        Self {
+            table: table.to_string(),
+            columns: schema.fields.iter().map(|f| f.name.clone()).collect(),
+            filters: vec![],
+            lock: None,
+            batch: VecDeque::new(),
+            last_key: None,
+            finished: false,
+            staged: BTreeMap::new(),
+        }
+    }
+
+    /// The next row in the table, not filtered yet
+    fn next_record(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<(Vec<u8>, Record)>> {
+        loop {
+            if self.batch.is_empty() && !self.finished {
+                let batch = ctx.storage.scan_batch(
+                    &self.table,
+                    self.last_key.as_deref(),
+                    SCAN_BATCH_SIZE,
+                )?;
+                self.finished = batch.len() < SCAN_BATCH_SIZE;
+                self.last_key = batch.last().map(|(key, _)| key.clone());
+                self.batch.extend(batch);
+            }
+            // Rows the transaction inserted are merged in so everything comes out in key order
+            let staged_first = match (self.batch.front(), self.staged.first_key_value()) {
+                (Some((key, _)), Some((staged, _))) => staged < key,
+                (None, _) => true,
+                _ => false,
+            };
+            if !staged_first {
+                let (key, record) = self.batch.pop_front().unwrap();
+                match self.staged.remove(&key) {
+                    None => return Ok(Some((key, record))),
+                    Some(Some(bytes)) => return Ok(Some((key, from_bytes(&bytes)?))),
+                    // Deleted by the transaction
+                    Some(None) => continue,
+                }
+            }
+            match self.staged.pop_first() {
+                Some((key, Some(bytes))) => return Ok(Some((key, from_bytes(&bytes)?))),
+                Some((_, None)) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl PhysicalOperator for TableScan {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        self.batch.clear();
+        self.last_key = None;
+        self.finished = false;
+        self.staged = ctx
+            .writes
+            .map(|writes| {
+                writes
+                    .table(&self.table)
+                    .map(|(key, value)| (key.to_vec(), value.map(|x| x.to_vec())))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(())
+    }
+
+    fn next(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        while let Some((key, record)) = self.next_record(ctx)? {
+            let row = scan_row(&key, &record, &self.columns);
+            if !matches_all(&self.filters, &row)? {
+                continue;
+            }
+            match &self.lock {
+                None => return Ok(Some(row)),
+                Some(lock) => {
+                    let row = lock_row(ctx, &self.table, &key, lock, &self.columns, &self.filters)?;
+                    if row.is_some() {
+                        return Ok(row);
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.batch.clear();
+        self.staged.clear();
+        Ok(())
+    }
+}
+
+/// Looks up the single row with the primary key.
+struct IndexScan {
+    table: String,
+    columns: Vec<String>,
+    key: Vec<PhysicalExpr>,
+    filters: Vec<PhysicalExpr>,
+    lock: Option<RowLocking>,
+    done: bool,
+}
+
+impl PhysicalOperator for IndexScan {
+    fn open(&mut self, _ctx: &ExecutionContext) -> anyhow::Result<()> {
+        self.done = false;
+        Ok(())
+    }
+
+    fn next(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        if std::mem::replace(&mut self.done, true) {
+            return Ok(None);
+        }
+        let key = self
+            .key
+            .iter()
+            .map(|e| e.evaluate(&[]))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if key.iter().any(|x| x.is_null()) {
+            return Ok(None);
+        }
+        let key = encode_key(&key)?;
+        let Some(record) = read_row(ctx, &self.table, &key)? else {
+            return Ok(None);
+        };
+        let row = scan_row(&key, &record, &self.columns);
+        if !matches_all(&self.filters, &row)? {
+            return Ok(None);
+        }
+        match &self.lock {
+            None => Ok(Some(row)),
+            Some(lock) => lock_row(ctx, &self.table, &key, lock, &self.columns, &self.filters),
+        }
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+struct Filter {
+    predicate: PhysicalExpr,
+    input: BoxedOperator,
+}
+
+impl PhysicalOperator for Filter {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        self.input.open(ctx)
+    }
+
+    fn next(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        while let Some(row) = self.input.next(ctx)? {
+            if self.predicate.matches(&row)? {
+                return Ok(Some(row));
+            }
+        }
+        Ok(None)
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.input.close()
+    }
+}
+
+struct Projection {
+    exprs: Vec<PhysicalExpr>,
+    input: BoxedOperator,
+}
+
+impl PhysicalOperator for Projection {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        self.input.open(ctx)
+    }
+
+    fn next(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        match self.input.next(ctx)? {
+            Some(row) => Ok(Some(
+                self.exprs
+                    .iter()
+                    .map(|e| e.evaluate(&row))
+                    .collect::<anyhow::Result<_>>()?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.input.close()
+    }
+}
+
+enum JoinStrategy {
+    /// Every row on the left is checked against every row on the right
+    NestedLoop,
+    /// Right rows are put in a hash table by their keys, each left row only needs checking against
+    /// the rows with the same keys.
+    Hash {
+        left: Vec<PhysicalExpr>,
+        right: Vec<PhysicalExpr>,
+        table: HashMap<Vec<Value>, Vec<usize>>,
+    },
+}
+
+/// Joins read the whole of the right input when opened and then stream the left input past it.
+struct Join {
+    kind: JoinKind,
+    strategy: JoinStrategy,
+    /// Checked against the joined row, for hash joins this is any of the condition which isn't
+    /// one of the keys.
+    filter: Option<PhysicalExpr>,
+    left: BoxedOperator,
+    right: BoxedOperator,
+    left_width: usize,
+    right_width: usize,
+    right_rows: Vec<Row>,
+    right_matched: Vec<bool>,
+    current: Option<Row>,
+    /// The right rows the current left row could match, and how far through them we are
+    candidates: Vec<usize>,
+    position: usize,
+    current_matched: bool,
+    /// Once the left side runs out, how far through the right rows we are looking for ones that
+    /// never matched for right and full joins
+    unmatched: Option<usize>,
+}
+
+impl Join {
+    fn new(
+        plan: &PhysicalPlan,
+        kind: JoinKind,
+        strategy: JoinStrategy,
+        filter: Option<PhysicalExpr>,
+        mut inputs: Vec<BoxedOperator>,
+    ) -> Self {
+        let right = inputs.pop().expect("join has two inputs");
+        let left = inputs.pop().expect("join has two inputs");
+        Self {
+            kind,
+            strategy,
+            filter,
+            left,
+            right,
+            left_width: plan.inputs[0].schema.len(),
+            right_width: plan.inputs[1].schema.len(),
+            right_rows: vec![],
+            right_matched: vec![],
+            current: None,
+            candidates: vec![],
+            position: 0,
+            current_matched: false,
+            unmatched: None,
+        }
+    }
+
+    fn evaluate_keys(keys: &[PhysicalExpr], row: &[Value]) -> anyhow::Result<Option<Vec<Value>>> {
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            let value = key.evaluate(row)?;
+            // Null never equals anything
+            if value.is_null() {
+                return Ok(None);
+            }
+            values.push(value);
+        }
+        Ok(Some(values))
+    }
+
+    fn start_row(&mut self, row: Row) -> anyhow::Result<()> {
+        self.candidates = match &self.strategy {
+            JoinStrategy::NestedLoop => (0..self.right_rows.len()).collect(),
+            JoinStrategy::Hash { left, table, .. } => match Self::evaluate_keys(left, &row)? {
+                Some(key) => table.get(&key).cloned().unwrap_or_default(),
+                None => vec![],
+            },
+        };
+        self.position = 0;
+        self.current_matched = false;
+        self.current = Some(row);
+        Ok(())
+    }
+}
+
+impl PhysicalOperator for Join {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        self.right_rows = drain(self.right.as_mut(), ctx)?;
+        self.right_matched = vec![false; self.right_rows.len()];
+        if let JoinStrategy::Hash { right, table, .. } = &mut self.strategy {
+            table.clear();
+            for (i, row) in self.right_rows.iter().enumerate() {
+                if let Some(key) = Self::evaluate_keys(right, row)? {
+                    table.entry(key).or_default().push(i);
+                }
+            }
+        }
+        self.current = None;
+        self.unmatched = None;
+        self.left.open(ctx)
+    }
+
+    fn next(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        while self.unmatched.is_none() {
+            let Some(left) = &self.current else {
+                match self.left.next(ctx)? {
+                    Some(row) => self.start_row(row)?,
+                    None => self.unmatched = Some(0),
+                }
+                continue;
+            };
+            while self.position < self.candidates.len() {
+                let i = self.candidates[self.position];
+                self.position += 1;
+                let mut row = left.clone();
+                row.extend(self.right_rows[i].iter().cloned());
+                let matched = match &self.filter {
+                    Some(filter) => filter.matches(&row)?,
+                    None => true,
+                };
+                if matched {
+                    self.current_matched = true;
+                    self.right_matched[i] = true;
+                    match self.kind {
+                        // All that matters is whether there's a match, so stop at the first
+                        JoinKind::Semi => {
+                            self.position = self.candidates.len();
+                            row.truncate(self.left_width);
+                            return Ok(Some(row));
+                        }
+                        JoinKind::Anti => self.position = self.candidates.len(),
+                        _ => return Ok(Some(row)),
+                    }
+                }
+            }
+            let left = self.current.take().unwrap();
+            if !self.current_matched && matches!(self.kind, JoinKind::Left | JoinKind::Full) {
+                let mut row = left;
+                row.extend(std::iter::repeat(Value::Null).take(self.right_width));
+                return Ok(Some(row));
+            }
+            if !self.current_matched && self.kind == JoinKind::Anti {
+                return Ok(Some(left));
+            }
+        }
+        if matches!(self.kind, JoinKind::Right | JoinKind::Full) {
+            let position = self.unmatched.as_mut().unwrap();
+            while *position < self.right_rows.len() {
+                let i = *position;
+                *position += 1;
+                if !self.right_matched[i] {
+                    let mut row = vec![Value::Null; self.left_width];
+                    row.extend(self.right_rows[i].iter().cloned());
+                    return Ok(Some(row));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.right_rows.clear();
+        if let JoinStrategy::Hash { table, .. } = &mut self.strategy {
+            table.clear();
+        }
+        self.left.close()
+    }
+}
+
+/// Looks up the matching row on the right by its primary key for each row on the left, there can
+/// only be one so rows are joined as they come.
+struct IndexJoin {
+    kind: JoinKind,
+    table: String,
+    columns: Vec<String>,
+    key: Vec<PhysicalExpr>,
+    filters: Vec<PhysicalExpr>,
+    lock: Option<RowLocking>,
+    filter: Option<PhysicalExpr>,
+    input: BoxedOperator,
+}
+
+impl IndexJoin {
+    /// The row on the right for this row on the left, if there is one and it passes the filters
+    fn lookup(&self, ctx: &ExecutionContext, row: &[Value]) -> anyhow::Result<Option<Row>> {
+        let Some(key) = Join::evaluate_keys(&self.key, row)? else {
+            return Ok(None);
+        };
+        let key = encode_key(&key)?;
+        let Some(record) = read_row(ctx, &self.table, &key)? else {
+            return Ok(None);
+        };
+        let found = scan_row(&key, &record, &self.columns);
+        if !matches_all(&self.filters, &found)? {
+            return Ok(None);
+        }
+        match &self.lock {
+            None => Ok(Some(found)),
+            Some(lock) => lock_row(ctx, &self.table, &key, lock, &self.columns, &self.filters),
+        }
+    }
+}
+
+impl PhysicalOperator for IndexJoin {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        self.input.open(ctx)
+    }
+
+    fn next(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        while let Some(mut row) = self.input.next(ctx)? {
+            let left_width = row.len();
+            let mut matched = false;
+            if let Some(found) = self.lookup(ctx, &row)? {
+                row.extend(found);
+                matched = match &self.filter {
+                    Some(filter) => filter.matches(&row)?,
+                    None => true,
+                };
+                if !matched || matches!(self.kind, JoinKind::Semi | JoinKind::Anti) {
+                    row.truncate(left_width);
+                }
+            }
+            match (self.kind, matched) {
+                (JoinKind::Inner | JoinKind::Left | JoinKind::Semi, true)
+                | (JoinKind::Anti, false) => return Ok(Some(row)),
+                (JoinKind::Left, false) => {
+                    row.extend(std::iter::repeat(Value::Null).take(self.columns.len()));
+                    return Ok(Some(row));
+                }
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.input.close()
+    }
+}
+
+enum ApplyMode {
+    Scalar,
+    Exists,
+    In(PhysicalExpr),
+}
+
+/// Runs the subquery for every row of the input. Uncorrelated subqueries give the same rows every
+/// time so they're only run once, correlated ones are built again with each row's values.
+struct Apply {
+    mode: ApplyMode,
+    correlated: Option<PhysicalPlan>,
+    outer: Schema,
+    /// Only built when the subquery isn't correlated
+    subquery: Option<BoxedOperator>,
+    cached: Option<Vec<Row>>,
+    input: BoxedOperator,
+}
+
+impl Apply {
+    fn result(&self, row: &[Value], rows: &[Row]) -> anyhow::Result<Value> {
+        match &self.mode {
+            ApplyMode::Scalar => match rows {
+                [] => Ok(Value::Null),
+                [only] => Ok(only[0].clone()),
+                _ => {
+                    anyhow::bail!("More than one row returned by a subquery used as an expression")
+                }
+            },
+            ApplyMode::Exists => Ok(Value::Boolean(!rows.is_empty())),
+            ApplyMode::In(expr) => {
+                let value = expr.evaluate(row)?;
+                // Like IN with a list, if nothing matched but a null was involved we don't know
+                let mut res = Value::Boolean(false);
+                for found in rows {
+                    match binary_op(&value, &BinaryOperator::Eq, &found[0])? {
+                        Value::Boolean(true) => return Ok(Value::Boolean(true)),
+                        Value::Null => res = Value::Null,
+                        _ => {}
+                    }
+                }
+                Ok(res)
+            }
+        }
+    }
+}
+
+impl PhysicalOperator for Apply {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        self.cached = None;
+        self.input.open(ctx)
+    }
+
+    fn next(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        let Some(mut row) = self.input.next(ctx)? else {
+            return Ok(None);
+        };
+        let value = match &self.correlated {
+            Some(plan) => {
+                // Only the first couple of rows matter unless we're looking for a value
+                let limit = match self.mode {
+                    ApplyMode::Exists => 1,
+                    ApplyMode::Scalar => 2,
+                    ApplyMode::In(_) => usize::MAX,
+                };
+                let mut subquery = build(&plan.bind_outer(&self.outer, &row)?)?;
+                subquery.open(ctx)?;
+                let mut rows = vec![];
+                while rows.len() < limit {
+                    match subquery.next(ctx)? {
+                        Some(found) => rows.push(found),
+                        None => break,
+                    }
+                }
+                subquery.close()?;
+                self.result(&row, &rows)?
+            }
+            None => {
+                if self.cached.is_none() {
+                    let subquery = self.subquery.as_mut().expect("subquery is built");
+                    self.cached = Some(drain(subquery.as_mut(), ctx)?);
+                }
+                self.result(&row, self.cached.as_deref().unwrap_or_default())?
+            }
+        };
+        row.push(value);
+        Ok(Some(row))
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.cached = None;
+        self.input.close()
+    }
+}
+
+type KeyedRow = (Vec<Value>, Row);
+
+/// Reads both inputs and sorts them on the join keys, then steps through them together so only
+/// rows with equal keys are ever compared.
+struct MergeJoin {
+    kind: JoinKind,
+    left_keys: Vec<PhysicalExpr>,
+    right_keys: Vec<PhysicalExpr>,
+    filter: Option<PhysicalExpr>,
+    left: BoxedOperator,
+    right: BoxedOperator,
+    left_width: usize,
+    right_width: usize,
+    output: VecDeque<Row>,
+}
+
+impl MergeJoin {
+    /// The rows with their keys sorted, and the rows with a null key which can't match anything
+    fn sorted(
+        input: &mut dyn PhysicalOperator,
+        keys: &[PhysicalExpr],
+        ctx: &ExecutionContext,
+    ) -> anyhow::Result<(Vec<KeyedRow>, Vec<Row>)> {
+        let mut sorted = vec![];
+        let mut nulls = vec![];
+        for row in drain(input, ctx)? {
+            match Join::evaluate_keys(keys, &row)? {
+                Some(key) => sorted.push((key, row)),
+                None => nulls.push(row),
+            }
+        }
+        sorted.sort_by(|(a, _), (b, _)| compare_join_keys(a, b));
+        Ok((sorted, nulls))
+    }
+
+    fn pad_left(&self, row: &[Value]) -> Row {
+        let mut padded = vec![Value::Null; self.left_width];
+        padded.extend(row.iter().cloned());
+        padded
+    }
+
+    fn pad_right(&self, row: &[Value]) -> Row {
+        let mut padded = row.to_vec();
+        padded.extend(std::iter::repeat(Value::Null).take(self.right_width));
+        padded
+    }
+}
+
+impl PhysicalOperator for MergeJoin {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        let (left, left_nulls) = Self::sorted(self.left.as_mut(), &self.left_keys, ctx)?;
+        let (right, right_nulls) = Self::sorted(self.right.as_mut(), &self.right_keys, ctx)?;
+        let keep_left = matches!(self.kind, JoinKind::Left | JoinKind::Full);
+        let keep_right = matches!(self.kind, JoinKind::Right | JoinKind::Full);
+        let existence = matches!(self.kind, JoinKind::Semi | JoinKind::Anti);
+        let anti = self.kind == JoinKind::Anti;
+        let mut output = VecDeque::new();
+        let (mut i, mut j) = (0, 0);
+        while i < left.len() || j < right.len() {
+            let ord = match (left.get(i), right.get(j)) {
+                (Some((a, _)), Some((b, _))) => compare_join_keys(a, b),
+                (Some(_), None) => Ordering::Less,
+                _ => Ordering::Greater,
+            };
+            match ord {
+                Ordering::Less => {
+                    if keep_left {
+                        output.push_back(self.pad_right(&left[i].1));
+                    } else if anti {
+                        output.push_back(left[i].1.clone());
+                    }
+                    i += 1;
+                }
+                Ordering::Greater => {
+                    if keep_right {
+                        output.push_back(self.pad_left(&right[j].1));
+                    }
+                    j += 1;
+                }
+                Ordering::Equal => {
+                    // Every pair from the runs of rows with this key
+                    let key = &left[i].0;
+                    let left_end = i + left[i..].partition_point(|(k, _)| k == key);
+                    let right_end = j + right[j..].partition_point(|(k, _)| k == key);
+                    let mut right_matched = vec![false; right_end - j];
+                    for (_, left_row) in &left[i..left_end] {
+                        let mut matched = false;
+                        for (m, (_, right_row)) in right[j..right_end].iter().enumerate() {
+                            let mut row = left_row.clone();
+                            row.extend(right_row.iter().cloned());
+                            let keep = match &self.filter {
+                                Some(filter) => filter.matches(&row)?,
+                                None => true,
+                            };
+                            if keep {
+                                matched = true;
+                                right_matched[m] = true;
+                                if existence {
+                                    break;
+                                }
+                                output.push_back(row);
+                            }
+                        }
+                        if !matched && keep_left {
+                            output.push_back(self.pad_right(left_row));
+                        } else if existence && matched != anti {
+                            output.push_back(left_row.clone());
+                        }
+                    }
+                    if keep_right {
+                        for (m, (_, right_row)) in right[j..right_end].iter().enumerate() {
+                            if !right_matched[m] {
+                                output.push_back(self.pad_left(right_row));
+                            }
+                        }
+                    }
+                    i = left_end;
+                    j = right_end;
+                }
+            }
+        }
+        if keep_left {
+            output.extend(left_nulls.iter().map(|row| self.pad_right(row)));
+        } else if anti {
+            output.extend(left_nulls);
+        }
+        if keep_right {
+            output.extend(right_nulls.iter().map(|row| self.pad_left(row)));
+        }
+        self.output = output;
+        Ok(())
+    }
+
+    fn next(&mut self, _ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        Ok(self.output.pop_front())
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.output.clear();
+        Ok(())
+    }
+}
+
+/// A total order for join keys, which can't be null. Unlike `sort_compare` values of different
+/// types aren't equal, they're ordered by type so they never match.
+fn compare_join_keys(a: &[Value], b: &[Value]) -> Ordering {
+    let rank = |v: &Value| match v {
+        Value::Boolean(_) => 0,
+        Value::Number(_) => 1,
+        Value::Text(_) => 2,
+        Value::Bytes(_) => 3,
+        Value::Array(_) => 4,
+        Value::Null => 5,
+    };
+    for (a, b) in a.iter().zip(b) {
+        let ord = a.compare(b).unwrap_or_else(|| rank(a).cmp(&rank(b)));
+        if ord.is_ne() {
+            return ord;
+        }
+    }
+    Ordering::Equal
+}
+
+enum Accumulator {
+    Count(u64),
+    Sum(Option<BigDecimal>),
+    Avg(BigDecimal, u64),
+    Min(Option<Value>),
+    Max(Option<Value>),
+    /// The text so far and the delimiter before it, which is needed when merging
+    StringAgg(Option<(String, Value)>),
+    ArrayAgg(Vec<Value>),
+    BoolAnd(Option<bool>),
+    BoolOr(Option<bool>),
+    User(Arc<AggregateUdf>, AggregatorState),
+}
+
+pub(crate) struct AggregateState {
+    func: AggregateFunction,
+    accumulator: Accumulator,
+    /// The values seen so far for `DISTINCT` aggregates
+    seen: Option<HashSet<Vec<Value>>>,
+    order_by: Vec<SortKey>,
+    /// For aggregates with an `ORDER BY` the rows are held here with their sort keys and only
+    /// aggregated once they're all in
+    buffered: Vec<(Vec<Value>, Vec<Value>)>,
+    num_args: usize,
+}
+
+impl AggregateState {
+    pub(crate) fn new(aggregate: &AggregateExpr) -> Self {
+        let accumulator = match &aggregate.func {
+            AggregateFunction::Count => Accumulator::Count(0),
+            AggregateFunction::Sum => Accumulator::Sum(None),
+            AggregateFunction::Avg => Accumulator::Avg(BigDecimal::from(0), 0),
+            AggregateFunction::Min => Accumulator::Min(None),
+            AggregateFunction::Max => Accumulator::Max(None),
+            AggregateFunction::StringAgg => Accumulator::StringAgg(None),
+            AggregateFunction::ArrayAgg => Accumulator::ArrayAgg(vec![]),
+            AggregateFunction::BoolAnd => Accumulator::BoolAnd(None),
+            AggregateFunction::BoolOr => Accumulator::BoolOr(None),
+            AggregateFunction::User(udf) => Accumulator::User(udf.clone(), udf.init()),
+        };
+        Self {
+            func: aggregate.func.clone(),
+            accumulator,
+            seen: aggregate.distinct.then(HashSet::new),
+            order_by: aggregate.order_by.clone(),
+            buffered: vec![],
+            num_args: aggregate.args.len(),
+        }
+    }
+
+    /// Takes the values of the arguments followed by the values of any `ORDER BY` keys.
+    pub(crate) fn update(&mut self, mut args: Vec<Value>) -> anyhow::Result<()> {
+        if self.order_by.is_empty() {
+            return self.accumulate(args);
+        }
+        let keys = args.split_off(self.num_args);
+        self.buffered.push((keys, args));
+        Ok(())
+    }
+
+    fn accumulate(&mut self, args: Vec<Value>) -> anyhow::Result<()> {
+        // `count(*)` has no arguments so it counts everything
+        if self.func.skips_null(&args) {
+            return Ok(());
+        }
+        if let Some(seen) = &mut self.seen {
+            if !seen.insert(args.clone()) {
+                return Ok(());
+            }
+        }
+        self.add(args)
+    }
+
+    fn add(&mut self, args: Vec<Value>) -> anyhow::Result<()> {
+        let number = |v: &Value| match v {
+            Value::Number(n) => Ok(n.clone()),
+            v => anyhow::bail!("Can't add up {}", v),
+        };
+        let boolean = |v: &Value| match v {
+            Value::Boolean(b) => Ok(*b),
+            v => anyhow::bail!("{} isn't a boolean", v),
+        };
+        if let Accumulator::User(udf, state) = &mut self.accumulator {
+            return udf.accumulate(state, &args);
+        }
+        let mut args = args.into_iter();
+        let mut arg = || args.next().unwrap_or(Value::Null);
+        match &mut self.accumulator {
+            Accumulator::Count(n) => *n += 1,
+            Accumulator::Sum(sum) => {
+                let value = number(&arg())?;
+                *sum = Some(match sum.take() {
+                    Some(sum) => sum + value,
+                    None => value,
+                });
+            }
+            Accumulator::Avg(sum, count) => {
+                *sum += number(&arg())?;
+                *count += 1;
+            }
+            Accumulator::Min(min) => {
+                let value = arg();
+                if !matches!(min, Some(x) if sort_compare(&value, x).is_ge()) {
+                    *min = Some(value);
+                }
+            }
+            Accumulator::Max(max) => {
+                let value = arg();
+                if !matches!(max, Some(x) if sort_compare(&value, x).is_le()) {
+                    *max = Some(value);
+                }
+            }
+            Accumulator::StringAgg(s) => {
+                let (Value::Text(value), delimiter) = (arg(), arg()) else {
+                    anyhow::bail!("string_agg takes text");
+                };
+                match s {
+                    None => *s = Some((value, delimiter)),
+                    Some((s, _)) => {
+                        push_delimiter(s, &delimiter)?;
+                        s.push_str(&value);
+                    }
+                }
+            }
+            Accumulator::ArrayAgg(values) => values.push(arg()),
+            Accumulator::BoolAnd(all) => *all = Some(all.unwrap_or(true) && boolean(&arg())?),
+            Accumulator::BoolOr(any) => *any = Some(any.unwrap_or(false) || boolean(&arg())?),
+            Accumulator::User(..) => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Adds everything from another state for the same aggregate and group.
+    pub(crate) fn merge(&mut self, other: AggregateState) -> anyhow::Result<()> {
+        if !self.order_by.is_empty() {
+            self.buffered.extend(other.buffered);
+            return Ok(());
+        }
+        // The other state's result can't be used since it might have seen some of the same
+        // values, so go through its values again
+        if let (Some(mut seen), Some(other)) = (self.seen.take(), other.seen) {
+            let mut res = Ok(());
+            for args in other {
+                if res.is_ok() && seen.insert(args.clone()) {
+                    res = self.add(args);
+                }
+            }
+            self.seen = Some(seen);
+            return res;
+        }
+        match (&mut self.accumulator, other.accumulator) {
+            (Accumulator::Count(a), Accumulator::Count(b)) => *a += b,
+            (Accumulator::Sum(a), Accumulator::Sum(b)) => {
+                *a = match (a.take(), b) {
+                    (Some(a), Some(b)) => Some(a + b),
+                    (a, b) => a.or(b),
+                }
+            }
+            (Accumulator::Avg(sum, count), Accumulator::Avg(other_sum, other_count)) => {
+                *sum += other_sum;
+                *count += other_count;
+            }
+            (Accumulator::Min(a), Accumulator::Min(Some(b))) => {
+                if !matches!(a, Some(x) if sort_compare(&b, x).is_ge()) {
+                    *a = Some(b);
+                }
+            }
+            (Accumulator::Max(a), Accumulator::Max(Some(b))) => {
+                if !matches!(a, Some(x) if sort_compare(&b, x).is_le()) {
+                    *a = Some(b);
+                }
+            }
+            (Accumulator::StringAgg(a), Accumulator::StringAgg(Some((b, delimiter)))) => match a {
+                None => *a = Some((b, delimiter)),
+                Some((a, _)) => {
+                    push_delimiter(a, &delimiter)?;
+                    a.push_str(&b);
+                }
+            },
+            (Accumulator::ArrayAgg(a), Accumulator::ArrayAgg(b)) => a.extend(b),
+            (Accumulator::BoolAnd(a), Accumulator::BoolAnd(Some(b))) => {
+                *a = Some(a.unwrap_or(true) && b)
+            }
+            (Accumulator::BoolOr(a), Accumulator::BoolOr(Some(b))) => {
+                *a = Some(a.unwrap_or(false) || b)
+            }
+            (Accumulator::User(udf, a), Accumulator::User(_, b)) => udf.merge(a, b)?,
+            // The other state didn't see anything
+            (_, Accumulator::Min(None) | Accumulator::Max(None) | Accumulator::StringAgg(None))
+            | (_, Accumulator::BoolAnd(None) | Accumulator::BoolOr(None)) => {}
+            _ => anyhow::bail!("Can't merge states for different aggregates"),
+        }
+        Ok(())
+    }
+
+    /// A copy of the state so far, so a running aggregate can be finished at every row without
+    /// starting again. User defined aggregates can't be copied.
+    fn try_clone(&self) -> Option<Self> {
+        let accumulator = match &self.accumulator {
+            Accumulator::Count(n) => Accumulator::Count(*n),
+            Accumulator::Sum(sum) => Accumulator::Sum(sum.clone()),
+            Accumulator::Avg(sum, count) => Accumulator::Avg(sum.clone(), *count),
+            Accumulator::Min(x) => Accumulator::Min(x.clone()),
+            Accumulator::Max(x) => Accumulator::Max(x.clone()),
+            Accumulator::StringAgg(s) => Accumulator::StringAgg(s.clone()),
+            Accumulator::ArrayAgg(values) => Accumulator::ArrayAgg(values.clone()),
+            Accumulator::BoolAnd(b) => Accumulator::BoolAnd(*b),
+            Accumulator::BoolOr(b) => Accumulator::BoolOr(*b),
+            Accumulator::User(..) => return None,
+        };
+        Some(Self {
+            func: self.func.clone(),
+            accumulator,
+            seen: self.seen.clone(),
+            order_by: self.order_by.clone(),
+            buffered: self.buffered.clone(),
+            num_args: self.num_args,
+        })
+    }
+
+    /// Whether the state grows with the rows added to it, rather than staying the same size.
+    fn keeps_values(&self) -> bool {
+        self.seen.is_some()
+            || !self.order_by.is_empty()
+            || matches!(
+                self.accumulator,
+                Accumulator::StringAgg(_) | Accumulator::ArrayAgg(_)
+            )
+    }
+
+    /// The partial state as a value so it can be written to disk, `load` turns it back into a
+    /// state for the same aggregate.
+    fn save(self) -> anyhow::Result<Value> {
+        let accumulator = match self.accumulator {
+            Accumulator::Count(n) => Value::from(n as i64),
+            Accumulator::Sum(sum) => sum.map(Value::Number).unwrap_or(Value::Null),
+            Accumulator::Avg(sum, count) => {
+                Value::Array(vec![Value::Number(sum), Value::from(count as i64)])
+            }
+            Accumulator::Min(x) | Accumulator::Max(x) => x.unwrap_or(Value::Null),
+            Accumulator::StringAgg(s) => s
+                .map(|(s, delimiter)| Value::Array(vec![Value::Text(s), delimiter]))
+                .unwrap_or(Value::Null),
+            Accumulator::ArrayAgg(values) => Value::Array(values),
+            Accumulator::BoolAnd(b) | Accumulator::BoolOr(b) => {
+                b.map(Value::Boolean).unwrap_or(Value::Null)
+            }
+            Accumulator::User(udf, state) => udf.save(state)?,
+        };
+        let seen = match self.seen {
+            Some(seen) => Value::Array(seen.into_iter().map(Value::Array).collect()),
+            None => Value::Null,
+        };
+        let buffered = self
+            .buffered
+            .into_iter()
+            .map(|(keys, args)| Value::Array(vec![Value::Array(keys), Value::Array(args)]))
+            .collect();
+        Ok(Value::Array(vec![
+            accumulator,
+            seen,
+            Value::Array(buffered),
+        ]))
+    }
+
+    fn load(aggregate: &AggregateExpr, saved: Value) -> anyhow::Result<Self> {
+        let invalid = || anyhow::anyhow!("Invalid saved state for {}", aggregate.func);
+        let array = |value| match value {
+            Value::Array(values) => Ok(values),
+            _ => Err(invalid()),
+        };
+        let pair = |value| -> anyhow::Result<[Value; 2]> {
+            array(value)?.try_into().map_err(|_| invalid())
+        };
+        let number = |value| match value {
+            Value::Number(n) => Ok(n),
+            _ => Err(invalid()),
+        };
+        let count = |value| number(value)?.to_u64().ok_or_else(invalid);
+        let boolean = |value| match value {
+            Value::Boolean(b) => Ok(b),
+            _ => Err(invalid()),
+        };
+        let optional = |value: Value| (!value.is_null()).then_some(value);
+
+        let [accumulator, seen, buffered]: [Value; 3] =
+            array(saved)?.try_into().map_err(|_| invalid())?;
+        let mut state = Self::new(aggregate);
+        state.accumulator = match (state.accumulator, accumulator) {
+            (Accumulator::Count(_), n) => Accumulator::Count(count(n)?),
+            (Accumulator::Sum(_), sum) => Accumulator::Sum(optional(sum).map(number).transpose()?),
+            (Accumulator::Avg(..), avg) => {
+                let [sum, n] = pair(avg)?;
+                Accumulator::Avg(number(sum)?, count(n)?)
+            }
+            (Accumulator::Min(_), x) => Accumulator::Min(optional(x)),
+            (Accumulator::Max(_), x) => Accumulator::Max(optional(x)),
+            (Accumulator::StringAgg(_), Value::Null) => Accumulator::StringAgg(None),
+            (Accumulator::StringAgg(_), s) => match pair(s)? {
+                [Value::Text(s), delimiter] => Accumulator::StringAgg(Some((s, delimiter))),
+                _ => return Err(invalid()),
+            },
+            (Accumulator::ArrayAgg(_), values) => Accumulator::ArrayAgg(array(values)?),
+            (Accumulator::BoolAnd(_), b) => {
+                Accumulator::BoolAnd(optional(b).map(boolean).transpose()?)
+            }
+            (Accumulator::BoolOr(_), b) => {
+                Accumulator::BoolOr(optional(b).map(boolean).transpose()?)
+            }
+            (Accumulator::User(udf, _), saved) => {
+                let saved = udf.load(saved)?;
+                Accumulator::User(udf, saved)
+            }
+        };
+        if state.seen.is_some() {
+            state.seen = Some(
+                array(seen)?
+                    .into_iter()
+                    .map(array)
+                    .collect::<anyhow::Result<_>>()?,
+            );
+        }
+        state.buffered = array(buffered)?
+            .into_iter()
+            .map(|row| {
+                let [keys, args] = pair(row)?;
+                Ok((array(keys)?, array(args)?))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(state)
+    }
+
+    pub(crate) fn finish(mut self) -> anyhow::Result<Value> {
+        // Stable so rows with equal keys stay in the order they came in
+        let mut rows = std::mem::take(&mut self.buffered);
+        rows.sort_by(|(a, _), (b, _)| compare_keys(&self.order_by, a, b));
+        for (_, args) in rows {
+            self.accumulate(args)?;
+        }
+        let value = match self.accumulator {
+            Accumulator::Count(n) => Value::from(n as i64),
+            Accumulator::Sum(sum) => sum.map(Value::Number).unwrap_or(Value::Null),
+            Accumulator::Avg(_, 0) => Value::Null,
+            Accumulator::Avg(sum, count) => {
+                Value::Number(limit_scale(sum / BigDecimal::from(count)))
+            }
+            Accumulator::Min(x) | Accumulator::Max(x) => x.unwrap_or(Value::Null),
+            Accumulator::StringAgg(s) => s.map(|(s, _)| Value::Text(s)).unwrap_or(Value::Null),
+            // Like the others there's nothing to aggregate over no rows, not an empty array
+            Accumulator::ArrayAgg(values) if values.is_empty() => Value::Null,
+            Accumulator::ArrayAgg(values) => Value::Array(values),
+            Accumulator::BoolAnd(b) | Accumulator::BoolOr(b) => {
+                b.map(Value::Boolean).unwrap_or(Value::Null)
+            }
+            Accumulator::User(udf, state) => udf.finalize(state)?,
+        };
+        Ok(value)
+    }
+}
+
+/// `string_agg` treats a null delimiter as an empty one
+fn push_delimiter(s: &mut String, delimiter: &Value) -> anyhow::Result<()> {
+    match delimiter {
+        Value::Text(delimiter) => s.push_str(delimiter),
+        Value::Null => {}
+        _ => anyhow::bail!("string_agg takes text"),
+    }
+    Ok(())
+}
+
+/// How many groups are kept in the hash table before falling back to sorting.
+pub(crate) const MAX_HASH_GROUPS: usize = 1 << 16;
+
+/// The groups for a hash aggregate, shared by both executors. When the hash table is full, or
+/// goes over `work_mem`, and a new group turns up the partial results in the table are sorted by
+/// group and written out to disk and it starts again empty. At the end the runs on disk are merged
+/// like in a sort, combining the partial results for each group.
+pub(crate) struct Groups {
+    aggregates: Vec<AggregateExpr>,
+    max_groups: usize,
+    work_mem: usize,
+    index: HashMap<Vec<Value>, usize>,
+    /// Kept separately so groups come out in the order they were first seen
+    states: Vec<(Vec<Value>, Vec<AggregateState>)>,
+    /// Roughly how much memory the groups in the table take up
+    size: usize,
+    /// The order the runs are sorted in, ascending on each group by value
+    order: Option<Rc<[SortKey]>>,
+    runs: Vec<SortRun>,
+}
+
+impl Groups {
+    pub(crate) fn new(aggregates: &[AggregateExpr], max_groups: usize, work_mem: usize) -> Self {
+        Self {
+            aggregates: aggregates.to_vec(),
+            max_groups,
+            work_mem,
+            index: HashMap::new(),
+            states: vec![],
+            size: 0,
+            order: None,
+            runs: vec![],
+        }
+    }
+
+    /// Adds a row, `args` has the arguments for each aggregate.
+    pub(crate) fn update(&mut self, key: Vec<Value>, args: Vec<Vec<Value>>) -> anyhow::Result<()> {
+        let i = match self.index.get(&key) {
+            Some(i) => *i,
+            None => {
+                if self.states.len() >= self.max_groups || self.size > self.work_mem {
+                    self.spill()?;
+                }
+                self.size +=
+                    row_size(&key) + self.aggregates.len() * std::mem::size_of::<AggregateState>();
+                let states = self.aggregates.iter().map(AggregateState::new).collect();
+                self.index.insert(key.clone(), self.states.len());
+                self.states.push((key, states));
+                self.states.len() - 1
+            }
+        };
+        for (state, args) in self.states[i].1.iter_mut().zip(args) {
+            if state.keeps_values() {
+                self.size += row_size(&args);
+            }
+            state.update(args)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the partial results out to disk sorted by group, leaving the table empty
+    fn spill(&mut self) -> anyhow::Result<()> {
+        self.index.clear();
+        self.size = 0;
+        let mut states = std::mem::take(&mut self.states);
+        let Some(len) = states.first().map(|(key, _)| key.len()) else {
+            return Ok(());
+        };
+        let order = self.order.get_or_insert_with(|| {
+            // Only the direction matters when comparing
+            let key = SortKey {
+                expr: ScalarExpr::literal(Value::Null),
+                asc: true,
+                nulls_first: false,
+            };
+            vec![key; len].into()
+        });
+        states.sort_by(|(a, _), (b, _)| compare_keys(order, a, b));
+        let rows = states.into_iter().map(|(key, states)| {
+            let saved = states.into_iter().map(AggregateState::save);
+            Ok((key, saved.collect::<anyhow::Result<_>>()?))
+        });
+        self.runs.push(SortRun::write(rows)?);
+        Ok(())
+    }
+
+    /// The output rows, the group by values followed by the aggregates. Without a GROUP BY
+    /// there's always a single row, even if there's no input.
+    pub(crate) fn finish(mut self, grouped: bool) -> anyhow::Result<Vec<Row>> {
+        if self.states.is_empty() && !grouped {
+            let states = self.aggregates.iter().map(AggregateState::new).collect();
+            self.states.push((vec![], states));
+        }
+        if self.runs.is_empty() {
+            return self
+                .states
+                .into_iter()
+                .map(|(key, states)| finish_group(key, states))
+                .collect();
+        }
+        self.spill()?;
+        let order = self.order.clone().unwrap();
+        let mut merge = merge_runs(&order, &mut self.runs)?;
+        let mut rows = vec![];
+        let mut current: Option<(Vec<Value>, Vec<AggregateState>)> = None;
+        while let Some((key, saved)) = merge.next()? {
+            let states = self
+                .aggregates
+                .iter()
+                .zip(saved)
+                .map(|(aggregate, saved)| AggregateState::load(aggregate, saved))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            match &mut current {
+                Some((k, current)) if *k == key => {
+                    for (state, other) in current.iter_mut().zip(states) {
+                        state.merge(other)?;
+                    }
+                }
+                _ => {
+                    if let Some((key, states)) = current.replace((key, states)) {
+                        rows.push(finish_group(key, states)?);
+                    }
+                }
+            }
+        }
+        if let Some((key, states)) = current {
+            rows.push(finish_group(key, states)?);
+        }
+        Ok(rows)
+    }
+}
+
+fn finish_group(mut key: Vec<Value>, states: Vec<AggregateState>) -> anyhow::Result<Row> {
+    for state in states {
+        key.push(state.finish()?);
+    }
+    Ok(key)
+}
+
+/// Groups the rows in a hash table, outputs the group by values followed by the aggregates.
+struct HashAggregate {
+    group_by: Vec<PhysicalExpr>,
+    aggregates: Vec<(AggregateExpr, Vec<PhysicalExpr>)>,
+    max_groups: usize,
+    input: BoxedOperator,
+    output: VecDeque<Row>,
+}
+
+impl PhysicalOperator for HashAggregate {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        let aggregates = self
+            .aggregates
+            .iter()
+            .map(|(a, _)| a.clone())
+            .collect::<Vec<_>>();
+        let mut groups = Groups::new(&aggregates, self.max_groups, ctx.work_mem);
+        self.input.open(ctx)?;
+        while let Some(row) = self.input.next(ctx)? {
+            let key = self
+                .group_by
+                .iter()
+                .map(|e| e.evaluate(&row))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let args = self
+                .aggregates
+                .iter()
+                .map(|(_, args)| args.iter().map(|e| e.evaluate(&row)).collect())
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            groups.update(key, args)?;
+        }
+        self.input.close()?;
+        self.output = groups.finish(!self.group_by.is_empty())?.into();
+        Ok(())
+    }
+
+    fn next(&mut self, _ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        Ok(self.output.pop_front())
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.output.clear();
+        Ok(())
+    }
+}
+
+/// Compares two lists of values for the sort keys they came from.
+fn compare_keys(keys: &[SortKey], a: &[Value], b: &[Value]) -> Ordering {
+    for ((key, a), b) in keys.iter().zip(a).zip(b) {
+        let ord = match (a.is_null(), b.is_null()) {
+            (true, true) => Ordering::Equal,
+            (true, false) if key.nulls_first => Ordering::Less,
+            (true, false) => Ordering::Greater,
+            (false, true) if key.nulls_first => Ordering::Greater,
+            (false, true) => Ordering::Less,
+            (false, false) if key.asc => sort_compare(a, b),
+            (false, false) => sort_compare(b, a),
+        };
+        if ord.is_ne() {
+            return ord;
+        }
+    }
+    Ordering::Equal
+}
+
+struct SortKeys {
+    exprs: Vec<PhysicalExpr>,
+    keys: Rc<[SortKey]>,
+}
+
+impl SortKeys {
+    fn new(keys: &[SortKey], schema: &Schema) -> anyhow::Result<Self> {
+        Ok(Self {
+            exprs: keys
+                .iter()
+                .map(|k| PhysicalExpr::new(&k.expr, schema))
+                .collect::<anyhow::Result<_>>()?,
+            keys: keys.into(),
+        })
+    }
+
+    fn evaluate(&self, row: &[Value]) -> anyhow::Result<Vec<Value>> {
+        self.exprs.iter().map(|e| e.evaluate(row)).collect()
+    }
+
+    fn compare(&self, a: &[Value], b: &[Value]) -> Ordering {
+        compare_keys(&self.keys, a, b)
+    }
+}
+
+/// Roughly how much memory a row takes up
+fn row_size(row: &[Value]) -> usize {
+    row.iter()
+        .map(|value| {
+            std::mem::size_of::<Value>()
+                + match value {
+                    Value::Text(s) => s.len(),
+                    Value::Bytes(b) => b.len(),
+                    Value::Array(values) => row_size(values),
+                    _ => 0,
+                }
+        })
+        .sum()
+}
+
+/// How many sorted runs are merged at once. With more runs than this they're merged a batch at a
+/// time into bigger runs first, so a merge never has more than this many files open.
+const MERGE_FAN_IN: usize = 16;
+
+/// Sorted rows written out to a temporary file when a sort runs out of memory. The file is only
+/// open while the run is being written or merged and it's deleted as soon as the run is dropped.
+struct SortRun {
+    path: TempPath,
+}
+
+impl SortRun {
+    fn write(rows: impl IntoIterator<Item = anyhow::Result<KeyedRow>>) -> anyhow::Result<Self> {
+        let mut writer = BufWriter::new(NamedTempFile::new()?);
+        for row in rows {
+            let bytes = to_allocvec(&row?)?;
+            writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+            writer.write_all(&bytes)?;
+        }
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        Ok(Self {
+            path: file.into_temp_path(),
+        })
+    }
+
+    fn open(&self) -> anyhow::Result<RunReader> {
+        Ok(RunReader(BufReader::new(File::open(&self.path)?)))
+    }
+}
+
+struct RunReader(BufReader<File>);
+
+impl RunReader {
+    fn read(&mut self) -> anyhow::Result<Option<KeyedRow>> {
+        let mut len = [0; 4];
+        match self.0.read_exact(&mut len) {
+            Ok(()) => {
+                let mut bytes = vec![0; u32::from_le_bytes(len) as usize];
+                self.0.read_exact(&mut bytes)?;
+                Ok(Some(from_bytes(&bytes)?))
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// A row in one of the sort heaps, ordered by its sort key and then by `seq` so rows with equal
+/// keys stay in the order they arrived in.
+struct HeapRow {
+    keys: Rc<[SortKey]>,
+    key: Vec<Value>,
+    seq: usize,
+    row: Row,
+}
+
+impl HeapRow {
+    fn size(&self) -> usize {
+        row_size(&self.key) + row_size(&self.row)
+    }
+}
+
+impl Ord for HeapRow {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_keys(&self.keys, &self.key, &other.key).then(self.seq.cmp(&other.seq))
+    }
+}
+
+impl PartialOrd for HeapRow {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapRow {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for HeapRow {}
+
+/// Merges sorted runs a row at a time, the earliest run wins when keys are equal so the sort
+/// stays stable.
+struct RunMerge {
+    keys: Rc<[SortKey]>,
+    readers: Vec<RunReader>,
+    heap: BinaryHeap<Reverse<HeapRow>>,
+}
+
+impl RunMerge {
+    fn new(keys: &Rc<[SortKey]>, runs: &[SortRun]) -> anyhow::Result<Self> {
+        let mut merge = Self {
+            keys: keys.clone(),
+            readers: runs
+                .iter()
+                .map(SortRun::open)
+                .collect::<anyhow::Result<_>>()?,
+            heap: BinaryHeap::new(),
+        };
+        for run in 0..merge.readers.len() {
+            merge.refill(run)?;
+        }
+        Ok(merge)
+    }
+
+    fn refill(&mut self, run: usize) -> anyhow::Result<()> {
+        if let Some((key, row)) = self.readers[run].read()? {
+            self.heap.push(Reverse(HeapRow {
+                keys: self.keys.clone(),
+                key,
+                seq: run,
+                row,
+            }));
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> anyhow::Result<Option<KeyedRow>> {
+        let Some(Reverse(next)) = self.heap.pop() else {
+            return Ok(None);
+        };
+        self.refill(next.seq)?;
+        Ok(Some((next.key, next.row)))
+    }
+}
+
+/// Merges the runs in batches until there are few enough to merge in one go, then starts merging
+/// those.
+fn merge_runs(keys: &Rc<[SortKey]>, runs: &mut Vec<SortRun>) -> anyhow::Result<RunMerge> {
+    while runs.len() > MERGE_FAN_IN {
+        for batch in std::mem::take(runs).chunks(MERGE_FAN_IN) {
+            let mut merge = RunMerge::new(keys, batch)?;
+            let rows = std::iter::from_fn(|| merge.next().transpose());
+            runs.push(SortRun::write(rows)?);
+        }
+    }
+    RunMerge::new(keys, runs)
+}
+
+/// The rows which could still make it into the output of a top-N. The heap holds the best `keep`
+/// rows with the worst on top, with ties `tied` holds the rows equal to the worst one which didn't
+/// fit.
+struct TopRows {
+    keep: usize,
+    with_ties: bool,
+    heap: BinaryHeap<HeapRow>,
+    tied: Vec<HeapRow>,
+    size: usize,
+}
+
+impl TopRows {
+    fn push(&mut self, row: HeapRow) {
+        if self.heap.len() < self.keep {
+            self.size += row.size();
+            self.heap.push(row);
+            return;
+        }
+        let Some(worst) = self.heap.peek() else {
+            return;
+        };
+        match compare_keys(&row.keys, &row.key, &worst.key) {
+            Ordering::Less => {
+                self.size += row.size();
+                self.heap.push(row);
+                let evicted = self.heap.pop().unwrap();
+                let tied = self.with_ties
+                    && self
+                        .heap
+                        .peek()
+                        .is_some_and(|x| compare_keys(&x.keys, &x.key, &evicted.key).is_eq());
+                if tied {
+                    self.tied.push(evicted);
+                } else {
+                    self.size -= evicted.size();
+                    self.size -= self.tied.drain(..).map(|x| x.size()).sum::<usize>();
+                }
+            }
+            Ordering::Equal if self.with_ties => {
+                self.size += row.size();
+                self.tied.push(row);
+            }
+            _ => {}
+        }
+    }
+
+    /// The rows in sorted order
+    fn into_rows(self) -> Vec<KeyedRow> {
+        let mut rows = self.heap.into_sorted_vec();
+        let mut tied = self.tied;
+        tied.sort_by_key(|x| x.seq);
+        rows.extend(tied);
+        rows.into_iter().map(|x| (x.key, x.row)).collect()
+    }
+}
+
+/// Sorts all of its input, with a limit it keeps a heap of the rows which could make it into the
+/// output. A sort which goes over `work_mem` sorts what it has so far and writes it out to disk, at
+/// the end the runs are merged together as rows are asked for and any limit is applied then.
+struct Sort {
+    keys: SortKeys,
+    /// The limit and offset for a top-N
+    limit: Option<(u64, u64)>,
+    with_ties: bool,
+    input: BoxedOperator,
+    output: VecDeque<Row>,
+    runs: Vec<SortRun>,
+    merge: Option<RunMerge>,
+    /// Rows still to skip and to return from the merge when there's a limit
+    skip: u64,
+    remaining: Option<u64>,
+    /// The key of the last row returned from the merge, for `WITH TIES`
+    last: Option<Vec<Value>>,
+}
+
+impl Sort {
+    fn spill(&mut self, rows: &mut Vec<KeyedRow>) -> anyhow::Result<()> {
+        rows.sort_by(|(a, _), (b, _)| self.keys.compare(a, b));
+        self.runs.push(SortRun::write(rows.drain(..).map(Ok))?);
+        Ok(())
+    }
+}
+
+impl PhysicalOperator for Sort {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        self.runs.clear();
+        self.merge = None;
+        let mut top = self.limit.map(|(limit, offset)| TopRows {
+            keep: (limit + offset) as usize,
+            with_ties: self.with_ties,
+            heap: BinaryHeap::new(),
+            tied: vec![],
+            size: 0,
+        });
+        let mut rows: Vec<KeyedRow> = vec![];
+        let mut size = 0;
+        self.input.open(ctx)?;
+        let mut seq = 0;
+        while let Some(row) = self.input.next(ctx)? {
+            let key = self.keys.evaluate(&row)?;
+            match &mut top {
+                Some(top_rows) => {
+                    top_rows.push(HeapRow {
+                        keys: self.keys.keys.clone(),
+                        key,
+                        seq,
+                        row,
+                    });
+                    seq += 1;
+                    if top_rows.size <= ctx.work_mem {
+                        continue;
+                    }
+                    // Too many rows to keep in memory, carry on as a full sort from here and
+                    // apply the limit as rows come out of the merge
+                    rows = top.take().unwrap().into_rows();
+                    size = rows.iter().map(|(k, r)| row_size(k) + row_size(r)).sum();
+                }
+                None => {
+                    size += row_size(&key) + row_size(&row);
+                    rows.push((key, row));
+                }
+            }
+            if size > ctx.work_mem {
+                self.spill(&mut rows)?;
+                size = 0;
+            }
+        }
+        self.input.close()?;
+        let (limit, offset) = match self.limit {
+            Some((limit, offset)) => (Some(limit), offset),
+            None => (None, 0),
+        };
+        if !self.runs.is_empty() {
+            if !rows.is_empty() {
+                self.spill(&mut rows)?;
+            }
+            self.skip = offset;
+            self.remaining = limit;
+            self.last = None;
+            self.merge = Some(merge_runs(&self.keys.keys, &mut self.runs)?);
+            return Ok(());
+        }
+        let rows = match top {
+            Some(top) => top.into_rows(),
+            None => {
+                rows.sort_by(|(a, _), (b, _)| self.keys.compare(a, b));
+                rows
+            }
+        };
+        self.output = rows
+            .into_iter()
+            .skip(offset as usize)
+            .map(|(_, row)| row)
+            .collect();
+        Ok(())
+    }
+
+    fn next(&mut self, _ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        let Some(merge) = &mut self.merge else {
+            return Ok(self.output.pop_front());
+        };
+        while let Some((key, row)) = merge.next()? {
+            if self.skip > 0 {
+                self.skip -= 1;
+                continue;
+            }
+            match self.remaining {
+                // Past the limit only rows tied with the last one get through
+                Some(0) => {
+                    let tied = self.with_ties
+                        && self
+                            .last
+                            .as_ref()
+                            .is_some_and(|last| self.keys.compare(last, &key).is_eq());
+                    if !tied {
+                        break;
+                    }
+                }
+                Some(n) => self.remaining = Some(n - 1),
+                None => {}
+            }
+            if self.with_ties {
+                self.last = Some(key);
+            }
+            return Ok(Some(row));
+        }
+        self.merge = None;
+        self.runs.clear();
+        Ok(None)
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.output.clear();
+        self.merge = None;
+        self.runs.clear();
+        Ok(())
+    }
+}
+
+/// A window function compiled against the input
+struct WindowState {
+    window: WindowExpr,
+    args: Vec<PhysicalExpr>,
+    /// The partition keys followed by the `ORDER BY` keys
+    keys: SortKeys,
+    partition_len: usize,
+}
+
+impl WindowState {
+    fn new(window: &WindowExpr, schema: &Schema) -> anyhow::Result<Self> {
+        let keys = window
+            .partition_by
+            .iter()
+            .map(|expr| SortKey {
+                expr: expr.clone(),
+                asc: true,
+                nulls_first: false,
+            })
+            .chain(window.order_by.iter().cloned())
+            .collect::<Vec<_>>();
+        Ok(Self {
+            window: window.clone(),
+            args: window
+                .args
+                .iter()
+                .map(|e| PhysicalExpr::new(e, schema))
+                .collect::<anyhow::Result<_>>()?,
+            keys: SortKeys::new(&keys, schema)?,
+            partition_len: window.partition_by.len(),
+        })
+    }
+
+    /// The value of the window function for every row, in the same order as `rows`
+    fn evaluate(&self, rows: &[Row]) -> anyhow::Result<Vec<Value>> {
+        let keys = rows
+            .iter()
+            .map(|row| self.keys.evaluate(row))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let args = rows
+            .iter()
+            .map(|row| self.args.iter().map(|e| e.evaluate(row)).collect())
+            .collect::<anyhow::Result<Vec<Vec<_>>>>()?;
+        let mut order = (0..rows.len()).collect::<Vec<_>>();
+        order.sort_by(|a, b| self.keys.compare(&keys[*a], &keys[*b]));
+
+        let mut values = vec![Value::Null; rows.len()];
+        let mut start = 0;
+        while start < order.len() {
+            let first = &keys[order[start]][..self.partition_len];
+            let len = order[start..]
+                .iter()
+                .position(|i| keys[*i][..self.partition_len] != *first)
+                .unwrap_or(order.len() - start);
+            let partition = &order[start..start + len];
+            let partition_values = self.evaluate_partition(partition, &keys, &args)?;
+            for (i, value) in partition.iter().zip(partition_values) {
+                values[*i] = value;
+            }
+            start += len;
+        }
+        Ok(values)
+    }
+
+    /// `partition` is the indexes of its rows in sorted order
+    fn evaluate_partition(
+        &self,
+        partition: &[usize],
+        keys: &[Vec<Value>],
+        args: &[Vec<Value>],
+    ) -> anyhow::Result<Vec<Value>> {
+        // Rows are peers when they're equal on all the ORDER BY keys, `peers[i]` is where the
+        // group of peers row `i` is in starts and ends
+        let mut peers = Vec::with_capacity(partition.len());
+        let mut group_start = 0;
+        for i in 0..=partition.len() {
+            let same = i < partition.len()
+                && self
+                    .keys
+                    .compare(&keys[partition[i]], &keys[partition[group_start]])
+                    .is_eq();
+            if !same {
+                peers.extend(std::iter::repeat((group_start, i)).take(i - group_start));
+                group_start = i;
+            }
+        }
+
+        let arg = |i: usize, n: usize| args[partition[i]].get(n).cloned().unwrap_or(Value::Null);
+        let mut values = Vec::with_capacity(partition.len());
+        match &self.window.func {
+            WindowFunction::RowNumber => {
+                values.extend((1..=partition.len()).map(|n| Value::from(n as i64)))
+            }
+            WindowFunction::Rank => values.extend(
+                peers
+                    .iter()
+                    .map(|(start, _)| Value::from(*start as i64 + 1)),
+            ),
+            WindowFunction::DenseRank => {
+                let mut rank = 0;
+                for (i, (start, _)) in peers.iter().enumerate() {
+                    if i == *start {
+                        rank += 1;
+                    }
+                    values.push(Value::from(rank));
+                }
+            }
+            WindowFunction::Ntile => {
+                // The first `len % buckets` buckets get an extra row
+                let Some(buckets) = window_offset(&arg(0, 0), "ntile")? else {
+                    return Ok(vec![Value::Null; partition.len()]);
+                };
+                if buckets <= 0 {
+                    anyhow::bail!("Argument of ntile must be greater than zero");
+                }
+                let len = partition.len() as i64;
+                let (size, extra) = (len / buckets, len % buckets);
+                for i in 0..len {
+                    let bucket = if i < extra * (size + 1) {
+                        i / (size + 1)
+                    } else {
+                        extra + (i - extra * (size + 1)) / size
+                    };
+                    values.push(Value::from(bucket + 1));
+                }
+            }
+            WindowFunction::Lag | WindowFunction::Lead => {
+                let name = self.window.func.to_string();
+                for i in 0..partition.len() {
+                    let offset = match self.args.len() {
+                        1 => Some(1),
+                        _ => window_offset(&arg(i, 1), &name)?,
+                    };
+                    let Some(offset) = offset else {
+                        values.push(Value::Null);
+                        continue;
+                    };
+                    let target = match self.window.func {
+                        WindowFunction::Lag => i as i64 - offset,
+                        _ => i as i64 + offset,
+                    };
+                    values.push(match usize::try_from(target) {
+                        Ok(target) if target < partition.len() => arg(target, 0),
+                        _ => arg(i, 2),
+                    });
+                }
+            }
+            WindowFunction::FirstValue | WindowFunction::LastValue => {
+                for i in 0..partition.len() {
+                    let (start, end) = self.frame(i, partition, keys, &peers)?;
+                    values.push(match self.window.func {
+                        _ if start >= end => Value::Null,
+                        WindowFunction::FirstValue => arg(start, 0),
+                        _ => arg(end - 1, 0),
+                    });
+                }
+            }
+            WindowFunction::Aggregate(_) => {
+                let aggregate = self.window.aggregate().unwrap();
+                // Frames which start in the same place only need the new rows added, as long as
+                // the state can be copied to get the result so far
+                let mut running: Option<(usize, usize, AggregateState)> = None;
+                let mut last: Option<((usize, usize), Value)> = None;
+                for i in 0..partition.len() {
+                    let frame = self.frame(i, partition, keys, &peers)?;
+                    if let Some((_, value)) = last.as_ref().filter(|(x, _)| *x == frame) {
+                        values.push(value.clone());
+                        continue;
+                    }
+                    let (start, end) = frame;
+                    let (mut state, from) = match running.take() {
+                        Some((s, e, state)) if s == start && e <= end => (state, e),
+                        _ => (AggregateState::new(&aggregate), start),
+                    };
+                    for j in from..end {
+                        state.update(args[partition[j]].clone())?;
+                    }
+                    let value = match state.try_clone() {
+                        Some(copy) => {
+                            running = Some((start, end, state));
+                            copy.finish()?
+                        }
+                        None => state.finish()?,
+                    };
+                    values.push(value.clone());
+                    last = Some((frame, value));
+                }
+            }
+        }
+        Ok(values)
+    }
+
+    /// The rows in row `i`'s frame, as a range of positions in the partition
+    fn frame(
+        &self,
+        i: usize,
+        partition: &[usize],
+        keys: &[Vec<Value>],
+        peers: &[(usize, usize)],
+    ) -> anyhow::Result<(usize, usize)> {
+        let frame = &self.window.frame;
+        let len = partition.len();
+        let offset = |n: &Value| window_offset(n, "Frame offset").map(|x| x.unwrap_or(0) as usize);
+        let (start, end) = match frame.units {
+            FrameUnits::Rows => {
+                let start = match &frame.start {
+                    FrameBound::Preceding(None) => 0,
+                    FrameBound::Preceding(Some(n)) => i.saturating_sub(offset(n)?),
+                    FrameBound::CurrentRow => i,
+                    FrameBound::Following(Some(n)) => i.saturating_add(offset(n)?),
+                    FrameBound::Following(None) => len,
+                };
+                let end = match &frame.end {
+                    FrameBound::Preceding(None) => 0,
+                    FrameBound::Preceding(Some(n)) => (i + 1).saturating_sub(offset(n)?),
+                    FrameBound::CurrentRow => i + 1,
+                    FrameBound::Following(Some(n)) => (i + 1).saturating_add(offset(n)?),
+                    FrameBound::Following(None) => len,
+                };
+                (start, end)
+            }
+            FrameUnits::Range => {
+                let bound = |bound: &FrameBound, is_start: bool| -> anyhow::Result<usize> {
+                    let (n, preceding) = match bound {
+                        FrameBound::Preceding(None) => return Ok(0),
+                        FrameBound::Following(None) => return Ok(len),
+                        FrameBound::CurrentRow if is_start => return Ok(peers[i].0),
+                        FrameBound::CurrentRow => return Ok(peers[i].1),
+                        FrameBound::Preceding(Some(n)) => (n, true),
+                        FrameBound::Following(Some(n)) => (n, false),
+                    };
+                    // There's only one ORDER BY key, rows where it's null are only in the frames
+                    // of other null rows
+                    let key = &self.keys.keys[self.partition_len..];
+                    let value = &keys[partition[i]][self.partition_len];
+                    if value.is_null() {
+                        return Ok(if is_start { peers[i].0 } else { peers[i].1 });
+                    }
+                    let op = if preceding == key[0].asc {
+                        BinaryOperator::Minus
+                    } else {
+                        BinaryOperator::Plus
+                    };
+                    let target = [binary_op(value, &op, n)?];
+                    let position = partition.partition_point(|j| {
+                        let ord = compare_keys(key, &keys[*j][self.partition_len..], &target);
+                        if is_start {
+                            ord.is_lt()
+                        } else {
+                            ord.is_le()
+                        }
+                    });
+                    Ok(position)
+                };
+                (bound(&frame.start, true)?, bound(&frame.end, false)?)
+            }
+        };
+        let start = start.min(len);
+        Ok((start, end.clamp(start, len)))
+    }
+}
+
+/// An integer argument to a window function, `None` when it's null
+fn window_offset(value: &Value, name: &str) -> anyhow::Result<Option<i64>> {
+    match value {
+        Value::Null => Ok(None),
+        Value::Number(n) if n.is_integer() => n
+            .to_i64()
+            .map(Some)
+            .ok_or_else(|| anyhow::anyhow!("{} is out of range: {}", name, n)),
+        v => anyhow::bail!("{} must be an integer but got {}", name, v),
+    }
+}
+
+/// Works out every window function over the whole input, then outputs the rows with a column
+/// added for each one.
+struct Window {
+    windows: Vec<WindowState>,
+    input: BoxedOperator,
+    output: VecDeque<Row>,
+}
+
+impl PhysicalOperator for Window {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        let mut rows = vec![];
+        self.input.open(ctx)?;
+        while let Some(row) = self.input.next(ctx)? {
+            rows.push(row);
+        }
+        self.input.close()?;
+        let mut columns = self
+            .windows
+            .iter()
+            .map(|w| w.evaluate(&rows).map(Vec::into_iter))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        for mut row in rows {
+            row.extend(columns.iter_mut().map(|x| x.next().unwrap()));
+            self.output.push_back(row);
+        }
+        Ok(())
+    }
+
+    fn next(&mut self, _ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        Ok(self.output.pop_front())
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.output.clear();
+        Ok(())
+    }
+}
+
+/// A table whose rows `LockRows` locks
+struct LockedColumns {
+    table: String,
+    lock: RowLocking,
+    /// Where the row key is in the input rows
+    key: usize,
+    /// Where the table's columns are in the input rows, they're replaced with the values read
+    /// once the row is locked
+    columns: Vec<(usize, String)>,
+}
+
+/// Locks the rows each row came from as it's asked for, rather than as they're read, so rows that
+/// don't make it through a sort and limit aren't locked.
+struct LockRows {
+    tables: Vec<LockedColumns>,
+    recheck: Vec<PhysicalExpr>,
+    input: BoxedOperator,
+}
+
+impl PhysicalOperator for LockRows {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        self.input.open(ctx)
+    }
+
+    fn next(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        'rows: while let Some(mut row) = self.input.next(ctx)? {
+            for table in &self.tables {
+                // No row from the table on the outer side of a join
+                let Value::Bytes(key) = row[table.key].clone() else {
+                    continue;
+                };
+                let Some(record) = lock_record(ctx, &table.table, &key, &table.lock)? else {
+                    continue 'rows;
+                };
+                for (i, name) in &table.columns {
+                    if let Some(value) = record.columns.get(name) {
+                        row[*i] = value.as_ref().clone();
+                    }
+                }
+            }
+            if matches_all(&self.recheck, &row)? {
+                return Ok(Some(row));
+            }
+        }
+        Ok(None)
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.input.close()
+    }
+}
+
+struct Limit {
+    limit: Option<u64>,
+    offset: u64,
+    seen: u64,
+    input: BoxedOperator,
+}
+
+impl PhysicalOperator for Limit {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        self.seen = 0;
+        self.input.open(ctx)
+    }
+
+    fn next(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        while self.seen < self.offset {
+            if self.input.next(ctx)?.is_none() {
+                return Ok(None);
+            }
+            self.seen += 1;
+        }
+        if self
+            .limit
+            .is_some_and(|limit| self.seen >= self.offset + limit)
+        {
+            return Ok(None);
+        }
+        let row = self.input.next(ctx)?;
+        self.seen += 1;
+        Ok(row)
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.input.close()
+    }
+}
+
+#[derive(Default)]
+struct Values {
+    rows: Vec<Vec<PhysicalExpr>>,
+    position: usize,
+}
+
+impl PhysicalOperator for Values {
+    fn open(&mut self, _ctx: &ExecutionContext) -> anyhow::Result<()> {
+        self.position = 0;
+        Ok(())
+    }
+
+    fn next(&mut self, _ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        let Some(row) = self.rows.get(self.position) else {
+            return Ok(None);
+        };
+        self.position += 1;
+        Ok(Some(
+            row.iter()
+                .map(|e| e.evaluate(&[]))
+                .collect::<anyhow::Result<_>>()?,
+        ))
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+struct SetOperation {
+    op: SetOperator,
+    all: bool,
+    left: BoxedOperator,
+    right: BoxedOperator,
+    /// Whether a union has moved on to its second input
+    on_right: bool,
+    /// How many times each row was in the right input for `INTERSECT` and `EXCEPT`, and the rows
+    /// we've already output for the ones which drop duplicates
+    counts: HashMap<Row, usize>,
+}
+
+impl PhysicalOperator for SetOperation {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        self.on_right = false;
+        self.counts.clear();
+        if self.op == SetOperator::Union {
+            self.right.open(ctx)?;
+        } else {
+            for row in drain(self.right.as_mut(), ctx)? {
+                *self.counts.entry(row).or_default() += 1;
+            }
+        }
+        self.left.open(ctx)
+    }
+
+    fn next(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        loop {
+            let row = if self.on_right {
+                self.right.next(ctx)?
+            } else {
+                match self.left.next(ctx)? {
+                    Some(row) => Some(row),
+                    None if self.op == SetOperator::Union => {
+                        self.on_right = true;
+                        continue;
+                    }
+                    None => None,
+                }
+            };
+            let Some(row) = row else {
+                return Ok(None);
+            };
+            let keep = match (self.op, self.all) {
+                (SetOperator::Union, true) => true,
+                (SetOperator::Union, false) => self.counts.insert(row.clone(), 0).is_none(),
+                (SetOperator::Intersect, all) => match self.counts.get_mut(&row) {
+                    Some(count) if *count > 0 => {
+                        // Once a row's been output without ALL it can't match again
+                        *count = if all { *count - 1 } else { 0 };
+                        true
+                    }
+                    _ => false,
+                },
+                (SetOperator::Except, true) => match self.counts.get_mut(&row) {
+                    Some(count) if *count > 0 => {
+                        *count -= 1;
+                        false
+                    }
+                    _ => true,
+                },
+                (SetOperator::Except, false) => match self.counts.get(&row) {
+                    Some(_) => false,
+                    None => {
+                        self.counts.insert(row.clone(), 0);
+                        true
+                    }
+                },
+            };
+            if keep {
+                return Ok(Some(row));
+            }
+        }
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.counts.clear();
+        self.left.close()?;
+        if self.op == SetOperator::Union {
+            self.right.close()?;
+        }
+        Ok(())
+    }
+}
+
+/// Stages every row from the input as a new row of the table, with the same checks and generated
+/// values as `VALUES`. Rows are staged a batch at a time as the input produces them. With
+/// `RETURNING` it outputs the rows as they were stored.
+struct Insert {
+    table: String,
+    columns: Vec<String>,
+    on_conflict: Option<OnConflict>,
+    returning: bool,
+    input: BoxedOperator,
+    output: VecDeque<Row>,
+}
+
+fn compile_on_conflict(on_conflict: &logical_plan::OnConflict) -> anyhow::Result<OnConflict> {
+    let action = match &on_conflict.update {
+        None => ConflictAction::DoNothing,
+        Some(update) => ConflictAction::Update {
+            assignments: update
+                .assignments
+                .iter()
+                .map(|(column, expr)| {
+                    Ok((column.clone(), PhysicalExpr::new(expr, &update.schema)?))
+                })
+                .collect::<anyhow::Result<_>>()?,
+            selection: update
+                .selection
+                .as_ref()
+                .map(|x| PhysicalExpr::new(x, &update.schema))
+                .transpose()?,
+            repeatable: update.repeatable,
+        },
+    };
+    Ok(OnConflict {
+        target: on_conflict.target.clone(),
+        action,
+    })
+}
+
+impl PhysicalOperator for Insert {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        let mut writer = ctx.storage.insert_writer(
+            &self.table,
+            &self.columns,
+            self.on_conflict.as_ref(),
+            ctx.writes,
+            Some(ctx.write_locks()),
+            &ctx.staged.borrow(),
+        )?;
+        let columns = table_columns(ctx, &self.table)?;
+        let mut batch = vec![];
+        self.input.open(ctx)?;
+        loop {
+            let row = self.input.next(ctx)?;
+            let done = row.is_none();
+            batch.extend(row.map(|row| {
+                Record {
+                    columns: self
+                        .columns
+                        .iter()
+                        .cloned()
+                        .zip(row.into_iter().map(Arc::new))
+                        .collect(),
+                }
+            }));
+            if batch.len() == INSERT_BATCH_SIZE || (done && !batch.is_empty()) {
+                let stored =
+                    writer.stage(std::mem::take(&mut batch), &mut ctx.staged.borrow_mut())?;
+                if let Some(flushed) = ctx.flushed.borrow_mut().as_mut() {
+                    writer.flush(&mut ctx.staged.borrow_mut(), flushed)?;
+                }
+                if self.returning {
+                    self.output
+                        .extend(stored.iter().map(|record| to_row(record, &columns)));
+                }
+            }
+            if done {
+                break;
+            }
+        }
+        self.input.close()
+    }
+
+    fn next(&mut self, _ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        Ok(self.output.pop_front())
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.output.clear();
+        Ok(())
+    }
+}
+
+/// The table's columns in the order they're stored, which is the order a modification outputs
+/// them in
+fn table_columns(ctx: &ExecutionContext, table: &str) -> anyhow::Result<Vec<String>> {
+    Ok(ctx.storage.table_metadata(table)?.into_keys().collect())
+}
+
+/// Works out the key each row read from a table is stored under so it can be changed. That's the
+/// primary key, or without one the table is read through to match the rows up by value.
+enum RowKeys {
+    PrimaryKey(Vec<usize>),
+    /// Identical rows are told apart by handing out a different key each time
+    ByValue(HashMap<Vec<u8>, Vec<Vec<u8>>>),
+}
+
+impl RowKeys {
+    fn new(ctx: &ExecutionContext, table: &str) -> anyhow::Result<Self> {
+        let metadata = ctx.storage.table_metadata(table)?;
+        let primary_key = metadata
+            .values()
+            .enumerate()
+            .filter(|(_, desc)| desc.primary_key)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        if !primary_key.is_empty() {
+            return Ok(Self::PrimaryKey(primary_key));
+        }
+        let columns = metadata.keys().cloned().collect::<Vec<_>>();
+        let mut scan = TableScan::new(table, &Schema::for_table(table, &metadata));
+        let mut rows: HashMap<_, Vec<_>> = HashMap::new();
+        scan.open(ctx)?;
+        while let Some((key, record)) = scan.next_record(ctx)? {
+            let values = to_allocvec(&to_row(&record, &columns))?;
+            rows.entry(values).or_default().push(key);
+        }
+        scan.close()?;
+        Ok(Self::ByValue(rows))
+    }
+
+    /// `row` has the table's columns in order
+    fn key(&mut self, table: &str, row: &[Value]) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Self::PrimaryKey(columns) => {
+                encode_key(&columns.iter().map(|&i| row[i].clone()).collect::<Vec<_>>())
+            }
+            Self::ByValue(rows) => rows
+                .get_mut(&to_allocvec(row)?)
+                .and_then(|keys| keys.pop())
+                .ok_or_else(|| anyhow::anyhow!("Row is no longer in {}", table)),
+        }
+    }
+}
+
+/// Stages the new version of every row from the input, whose rows start with the table's columns.
+/// With `RETURNING` it outputs the rows as they were stored.
+struct Update {
+    table: String,
+    assignments: Vec<(String, PhysicalExpr)>,
+    returning: bool,
+    input: BoxedOperator,
+    output: VecDeque<Row>,
+}
+
+impl Update {
+    fn stage(
+        &mut self,
+        ctx: &ExecutionContext,
+        columns: &[String],
+        rows: Vec<(Vec<u8>, Record)>,
+    ) -> anyhow::Result<()> {
+        let stored = ctx.storage.stage_update(
+            &self.table,
+            rows,
+            ctx.writes,
+            Some(ctx.write_locks()),
+            &mut ctx.staged.borrow_mut(),
+        )?;
+        if self.returning {
+            self.output
+                .extend(stored.iter().map(|record| to_row(record, columns)));
+        }
+        Ok(())
+    }
+}
+
+impl PhysicalOperator for Update {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        let columns = table_columns(ctx, &self.table)?;
+        let mut keys = RowKeys::new(ctx, &self.table)?;
+        let mut batch = vec![];
+        self.input.open(ctx)?;
+        while let Some(row) = self.input.next(ctx)? {
+            let key = keys.key(&self.table, &row[..columns.len()])?;
+            let mut record = Record {
+                columns: columns
+                    .iter()
+                    .zip(&row)
+                    .map(|(column, value)| (column.clone(), Arc::new(value.clone())))
+                    .collect(),
+            };
+            for (column, expr) in &self.assignments {
+                record
+                    .columns
+                    .insert(column.clone(), Arc::new(expr.evaluate(&row)?));
+            }
+            batch.push((key, record));
+            if batch.len() == INSERT_BATCH_SIZE {
+                self.stage(ctx, &columns, std::mem::take(&mut batch))?;
+            }
+        }
+        self.input.close()?;
+        if !batch.is_empty() {
+            self.stage(ctx, &columns, batch)?;
+        }
+        Ok(())
+    }
+
+    fn next(&mut self, _ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        Ok(self.output.pop_front())
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.output.clear();
+        Ok(())
+    }
+}
+
+/// Stages the deletion of every row from the input, whose rows start with the table's columns.
+/// With `RETURNING` it outputs the deleted rows.
+struct Delete {
+    table: String,
+    returning: bool,
+    input: BoxedOperator,
+    output: VecDeque<Row>,
+}
+
+impl PhysicalOperator for Delete {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        let width = table_columns(ctx, &self.table)?.len();
+        let mut keys = RowKeys::new(ctx, &self.table)?;
+        self.input.open(ctx)?;
+        while let Some(mut row) = self.input.next(ctx)? {
+            row.truncate(width);
+            let key = keys.key(&self.table, &row)?;
+            ctx.staged.borrow_mut().delete(&self.table, key);
+            if self.returning {
+                self.output.push_back(row);
+            }
+        }
+        self.input.close()
+    }
+
+    fn next(&mut self, _ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        Ok(self.output.pop_front())
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.output.clear();
+        Ok(())
+    }
+}
+
+/// Reads the rows a `With` or `RecursiveCte` saved in the context
+struct CteScan {
+    name: String,
+    id: usize,
+    rows: Rc<Vec<Row>>,
+    position: usize,
+}
+
+impl PhysicalOperator for CteScan {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        let Some(rows) = ctx.ctes.borrow().get(&self.id).cloned() else {
+            anyhow::bail!("Rows for {} haven't been worked out yet", self.name);
+        };
+        self.rows = rows;
+        self.position = 0;
+        Ok(())
+    }
+
+    fn next(&mut self, _ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        let row = self.rows.get(self.position).cloned();
+        self.position += 1;
+        Ok(row)
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.rows = Rc::default();
+        Ok(())
+    }
+}
+
+/// Runs a CTE once so every `CteScan` of it in the input reads the same rows
+struct With {
+    id: usize,
+    cte: BoxedOperator,
+    input: BoxedOperator,
+}
+
+impl PhysicalOperator for With {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        let rows = drain(self.cte.as_mut(), ctx)?;
+        ctx.ctes.borrow_mut().insert(self.id, Rc::new(rows));
+        self.input.open(ctx)
+    }
+
+    fn next(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        self.input.next(ctx)
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.input.close()
+    }
+}
+
+/// Works out a recursive CTE up front. The recursive term only sees the rows from the previous
+/// iteration, and we stop once it doesn't produce anything new.
+struct RecursiveCte {
+    name: String,
+    id: usize,
+    distinct: bool,
+    anchor: BoxedOperator,
+    recursive: BoxedOperator,
+    output: VecDeque<Row>,
+}
+
+impl PhysicalOperator for RecursiveCte {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        // With UNION rows we've already seen are dropped, which is what stops cycles
+        let distinct = self.distinct;
+        let mut seen = HashSet::new();
+        let mut keep = |row: &Row| !distinct || seen.insert(row.clone());
+        let mut working: Vec<Row> = drain(self.anchor.as_mut(), ctx)?
+            .into_iter()
+            .filter(|row| keep(row))
+            .collect();
+        let mut output = working.clone();
+        let mut depth = 0;
+        while !working.is_empty() {
+            depth += 1;
+            if depth > ctx.max_recursion {
+                anyhow::bail!(
+                    "Recursive query {} went past max_recursion ({}) iterations, it might have a cycle",
+                    self.name,
+                    ctx.max_recursion
+                );
+            }
+            ctx.ctes.borrow_mut().insert(self.id, Rc::new(working));
+            working = drain(self.recursive.as_mut(), ctx)?
+                .into_iter()
+                .filter(|row| keep(row))
+                .collect();
+            output.extend(working.iter().cloned());
+        }
+        ctx.ctes.borrow_mut().remove(&self.id);
+        self.output = output.into();
+        Ok(())
+    }
+
+    fn next(&mut self, _ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        Ok(self.output.pop_front())
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.output.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::cost::CostModel;
+    use crate::expression::ScalarExpr;
+    use crate::logical_plan::PlanBuilder;
+    use crate::optimiser::Optimiser;
+    use crate::types::InsertOptions;
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+    use tempfile::{tempdir, TempDir};
+    use tracing_test::traced_test;
+
+    pub(crate) struct Fixture {
+        storage: StorageEngine,
+        locks: LockManager,
+        _dir: TempDir,
+    }
+
+    impl Fixture {
+        pub(crate) fn new() -> Self {
+            let dir = tempdir().unwrap();
+            let storage = StorageEngine::new_with_path(dir.path());
+            let engine = crate::query_engine::QueryEngine;
+            for sql in [
+                "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT NOT NULL, age INTEGER)",
+                "CREATE TABLE orders (id INTEGER PRIMARY KEY, user_id INTEGER NOT NULL, total NUMERIC)",
+            ] {
+                match engine.process_sql(sql).unwrap().remove(0) {
+                    crate::types::Command::CreateTable(opts) => storage.create_table(&opts).unwrap(),
+                    _ => unreachable!(),
+                }
+            }
+            let fixture = Self {
+                storage,
+                locks: LockManager::default(),
+                _dir: dir,
+            };
+            fixture.insert(
+                "INSERT INTO users (id, name, age) VALUES (1, 'Daniel', 31), (2, 'Guido', NULL), (3, 'Alan', 41), (4, 'Ada', 36)",
+            );
+            fixture.insert(
+                "INSERT INTO orders (id, user_id, total) VALUES (1, 1, 10), (2, 1, 5.5), (3, 3, 20), (4, 5, 1)",
+            );
+            fixture
+        }
+
+        pub(crate) fn insert(&self, sql: &str) {
+            let engine = crate::query_engine::QueryEngine;
+            match engine.process_sql(sql).unwrap().remove(0) {
+                crate::types::Command::Insert(insert) => {
+                    let opts = insert.evaluate(&self.storage).unwrap();
+                    self.storage.insert_rows(&opts).unwrap()
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        pub(crate) fn plan(&self, sql: &str) -> PhysicalPlan {
+            let statement = Parser::parse_sql(&GenericDialect {}, sql)
+                .unwrap()
+                .remove(0);
+            let plan = PlanBuilder::new(&self.storage)
+                .plan_statement(&statement)
+                .unwrap();
+            let plan = Optimiser::new(&self.storage).optimise(plan).unwrap();
+            PhysicalPlan::new(&plan, &CostModel::new(&self.storage)).unwrap()
+        }
+
+        pub(crate) fn context<'a>(&'a self, writes: Option<&'a WriteSet>) -> ExecutionContext<'a> {
+            ExecutionContext {
+                storage: &self.storage,
+                locks: &self.locks,
+                transaction: self.locks.begin(),
+                writes,
+                lock_timeout: None,
+                max_recursion: DEFAULT_MAX_RECURSION,
+                work_mem: DEFAULT_WORK_MEM,
+                ctes: RefCell::default(),
+                staged: RefCell::default(),
+                flushed: RefCell::default(),
+            }
+        }
+
+        pub(crate) fn query(&self, sql: &str) -> Vec<Row> {
+            execute(&self.plan(sql), &self.context(None)).unwrap()
+        }
+    }
+
+    pub(crate) fn row(values: &[&str]) -> Row {
+        values
+            .iter()
+            .map(|x| match *x {
+                "NULL" => Value::Null,
+                x => match x.parse::<BigDecimal>() {
+                    Ok(n) => Value::Number(n),
+                    Err(_) => Value::from(x),
+                },
+            })
+            .collect()
+    }
+
+    pub(crate) fn rows(rows: &[&[&str]]) -> Vec<Row> {
+        rows.iter().map(|x| row(x)).collect()
+    }
+
+    #[test]
+    #[traced_test]
+    fn scans_and_filters() {
+        let db = Fixture::new();
+        assert_eq!(
+            db.query("SELECT name, age FROM users WHERE age > 32 OR age IS NULL"),
+            rows(&[&["Guido", "NULL"], &["Alan", "41"], &["Ada", "36"]])
+        );
+        assert_eq!(
+            db.query("SELECT name, age + 1 FROM users WHERE id = 3"),
+            rows(&[&["Alan", "42"]])
+        );
+        assert!(db.query("SELECT name FROM users WHERE id = 10").is_empty());
+        assert_eq!(db.query("SELECT 1 + 1, 'a'"), rows(&[&["2", "a"]]));
+    }
+
+    #[test]
+    #[traced_test]
+    fn joins() {
+        let db = Fixture::new();
+        assert_eq!(
+            db.query("SELECT u.name, o.total FROM users u JOIN orders o ON u.id = o.user_id ORDER BY o.id"),
+            rows(&[&["Daniel", "10"], &["Daniel", "5.5"], &["Alan", "20"]])
+        );
+        assert_eq!(
+            db.query("SELECT u.name, o.id FROM users u LEFT JOIN orders o ON u.id = o.user_id AND o.total > 6 ORDER BY u.id"),
+            rows(&[&["Daniel", "1"], &["Guido", "NULL"], &["Alan", "3"], &["Ada", "NULL"]])
+        );
+        assert_eq!(
+            db.query("SELECT u.name, o.id FROM users u FULL JOIN orders o ON u.id = o.user_id WHERE u.id IS NULL OR o.id IS NULL ORDER BY u.name NULLS FIRST"),
+            rows(&[&["NULL", "4"], &["Ada", "NULL"], &["Guido", "NULL"]])
+        );
+        // Not an equi join so it has to be a nested loop
+        assert_eq!(
+            db.query("SELECT u.name, o.id FROM users u JOIN orders o ON u.id > o.user_id ORDER BY u.name, o.id"),
+            rows(&[&["Ada", "1"], &["Ada", "2"], &["Ada", "3"], &["Alan", "1"], &["Alan", "2"], &["Guido", "1"], &["Guido", "2"]])
+        );
+        assert_eq!(
+            db.query("SELECT count(*) FROM users, orders"),
+            rows(&[&["16"]])
+        );
+        assert_eq!(
+            db.query("SELECT * FROM users a RIGHT JOIN (SELECT user_id AS id, total FROM orders) b USING (id) ORDER BY b.total"),
+            rows(&[&["5", "NULL", "NULL", "1"], &["1", "31", "Daniel", "5.5"], &["1", "31", "Daniel", "10"], &["3", "41", "Alan", "20"]])
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn merge_join() {
+        fn to_merge(plan: &mut PhysicalPlan) {
+            if let Operator::HashJoin { kind, keys, filter } = &plan.operator {
+                plan.operator = Operator::MergeJoin {
+                    kind: *kind,
+                    keys: keys.clone(),
+                    filter: filter.clone(),
+                };
+            }
+            plan.inputs.iter_mut().for_each(to_merge);
+        }
+
+        let db = Fixture::new();
+        db.insert("INSERT INTO users (id, name, age) VALUES (5, 'Grace', 31), (6, 'Edsger', NULL)");
+        for sql in [
+            "SELECT u.name, o.id FROM users u JOIN orders o ON u.id = o.user_id",
+            "SELECT u.name, o.id FROM users u LEFT JOIN orders o ON u.id = o.user_id AND o.total > 6",
+            "SELECT u.name, o.id FROM users u RIGHT JOIN orders o ON u.id = o.user_id AND o.total < 15",
+            "SELECT a.name, b.name FROM users a FULL JOIN users b ON a.age = b.age AND a.id <> b.id",
+        ] {
+            let mut plan = db.plan(sql);
+            let mut expected = execute(&plan, &db.context(None)).unwrap();
+            to_merge(&mut plan);
+            assert!(plan.to_string().contains("MergeJoin"), "{}", plan);
+            let mut actual = execute(&plan, &db.context(None)).unwrap();
+            expected.sort_by_key(|row| format!("{:?}", row));
+            actual.sort_by_key(|row| format!("{:?}", row));
+            assert_eq!(actual, expected, "{}", sql);
+        }
+    }
+
+    #[test]
+    #[traced_test]
+    fn index_join() {
+        let db = Fixture::new();
+        // Filtering on the total leaves few enough orders to look the users up one by one
+        let left = |total| {
+            let sql = format!("SELECT o.id, u.name FROM orders o LEFT JOIN users u ON u.id = o.user_id AND u.age < 40 WHERE o.total = {}", total);
+            assert!(db.plan(&sql).to_string().contains("IndexJoin: Left"));
+            db.query(&sql)
+        };
+        assert_eq!(left(10), rows(&[&["1", "Daniel"]]));
+        assert_eq!(left(20), rows(&[&["3", "NULL"]]));
+        // There's no user 5
+        assert_eq!(left(1), rows(&[&["4", "NULL"]]));
+        let inner = |name| {
+            let sql = format!("SELECT o.id, u.name FROM orders o JOIN users u ON u.id = o.user_id WHERE o.total = 20 AND u.name = '{}'", name);
+            assert!(db.plan(&sql).to_string().contains("IndexJoin: Inner"));
+            db.query(&sql)
+        };
+        assert_eq!(inner("Alan"), rows(&[&["3", "Alan"]]));
+        assert!(inner("Ada").is_empty());
+    }
+
+    #[test]
+    #[traced_test]
+    fn subqueries() {
+        let db = Fixture::new();
+        let query = |sql: &str, plan: &str| {
+            assert!(db.plan(sql).to_string().contains(plan), "{}", db.plan(sql));
+            db.query(sql)
+        };
+        assert_eq!(
+            query(
+                "SELECT name FROM users WHERE id IN (SELECT user_id FROM orders) ORDER BY name",
+                "Semi"
+            ),
+            rows(&[&["Alan"], &["Daniel"]])
+        );
+        assert_eq!(
+            query(
+                "SELECT name FROM users u WHERE EXISTS (SELECT 1 FROM orders o WHERE o.user_id = u.id AND o.total > 6) ORDER BY name",
+                "Semi"
+            ),
+            rows(&[&["Alan"], &["Daniel"]])
+        );
+        assert_eq!(
+            query(
+                "SELECT name FROM users u WHERE NOT EXISTS (SELECT * FROM orders o WHERE o.user_id = u.id) ORDER BY name",
+                "Anti"
+            ),
+            rows(&[&["Ada"], &["Guido"]])
+        );
+        assert_eq!(
+            query(
+                "SELECT name FROM users WHERE id NOT IN (SELECT user_id FROM orders) ORDER BY name",
+                "Anti"
+            ),
+            rows(&[&["Ada"], &["Guido"]])
+        );
+        // Guido's age is null so none of the totals are definitely not in there
+        assert!(query(
+            "SELECT id FROM orders WHERE total NOT IN (SELECT age FROM users)",
+            "Anti"
+        )
+        .is_empty());
+        assert_eq!(
+            query(
+                "SELECT id FROM orders WHERE total NOT IN (SELECT age FROM users WHERE age IS NOT NULL) ORDER BY id",
+                "Anti"
+            ),
+            rows(&[&["1"], &["2"], &["3"], &["4"]])
+        );
+        assert_eq!(
+            query(
+                "SELECT name, (SELECT count(*) FROM orders o WHERE o.user_id = u.id) FROM users u ORDER BY name",
+                "Apply: Scalar"
+            ),
+            rows(&[&["Ada", "0"], &["Alan", "1"], &["Daniel", "2"], &["Guido", "0"]])
+        );
+        assert_eq!(
+            query(
+                "SELECT o.id FROM orders o WHERE o.total > (SELECT avg(total) FROM orders x WHERE x.user_id = o.user_id)",
+                "Apply: Scalar"
+            ),
+            rows(&[&["1"]])
+        );
+        assert_eq!(
+            query(
+                "SELECT name FROM users WHERE age > (SELECT avg(age) FROM users)",
+                "Apply: Scalar"
+            ),
+            rows(&[&["Alan"]])
+        );
+        let found = query(
+            "SELECT id, id IN (SELECT user_id FROM orders) FROM users ORDER BY id",
+            "Apply: users.id IN",
+        );
+        let found = found.into_iter().map(|x| x[1].clone()).collect::<Vec<_>>();
+        let expected = [true, false, true, false].map(Value::Boolean);
+        assert_eq!(found, expected);
+        let err = execute(
+            &db.plan("SELECT (SELECT id FROM orders)"),
+            &db.context(None),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("More than one row"));
+    }
+
+    #[test]
+    #[traced_test]
+    fn common_table_expressions() {
+        let db = Fixture::new();
+        assert_eq!(
+            db.query(
+                "WITH spent AS (SELECT user_id, sum(total) AS total FROM orders GROUP BY user_id) \
+                 SELECT u.name, a.total FROM users u JOIN spent a ON a.user_id = u.id \
+                 WHERE a.total = (SELECT max(total) FROM spent)"
+            ),
+            rows(&[&["Alan", "20"]])
+        );
+        let counter =
+            "WITH RECURSIVE n (x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n WHERE x < 10) \
+                       SELECT sum(x), count(*) FROM n";
+        assert_eq!(db.query(counter), rows(&[&["55", "10"]]));
+        // Going round a cycle stops once UNION has seen all the rows
+        let cycle = "WITH RECURSIVE edges (a, b) AS (VALUES (1, 2), (2, 3), (3, 1)), \
+                     reachable (node) AS (SELECT 1 UNION SELECT b FROM edges JOIN reachable ON a = node) \
+                     SELECT node FROM reachable ORDER BY node";
+        assert_eq!(db.query(cycle), rows(&[&["1"], &["2"], &["3"]]));
+        let err = execute(
+            &db.plan(&cycle.replace("UNION SELECT", "UNION ALL SELECT")),
+            &ExecutionContext {
+                max_recursion: 50,
+                ..db.context(None)
+            },
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("max_recursion"), "{}", err);
+    }
+
+    #[test]
+    #[traced_test]
+    fn external_sort() {
+        let db = Fixture::new();
+        let plan = db.plan(
+            "WITH RECURSIVE n (x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n WHERE x < 500) \
+             SELECT x, 'row ' || CAST(x AS TEXT) FROM n ORDER BY x % 7, x DESC",
+        );
+        // Small enough that every few rows get written out to disk
+        let spilled = execute(
+            &plan,
+            &ExecutionContext {
+                work_mem: 1024,
+                ..db.context(None)
+            },
+        )
+        .unwrap();
+        let mut expected = (1..=500).collect::<Vec<i64>>();
+        expected.sort_by_key(|x| (x % 7, -x));
+        let expected = expected
+            .iter()
+            .map(|x| vec![Value::from(*x), Value::from(format!("row {}", x))])
+            .collect::<Vec<_>>();
+        assert_eq!(spilled, expected);
+        assert_eq!(execute(&plan, &db.context(None)).unwrap(), expected);
+
+        // A top-N which outgrows work_mem carries on as an external sort
+        let plan = db.plan(
+            "WITH RECURSIVE n (x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n WHERE x < 500) \
+             SELECT x, 'row ' || CAST(x AS TEXT) FROM n ORDER BY x % 7 \
+             OFFSET 60 ROWS FETCH FIRST 10 ROWS WITH TIES",
+        );
+        let mut expected = (1..=500).collect::<Vec<i64>>();
+        expected.sort_by_key(|x| x % 7);
+        // Rows 60 to 69 stop one short of the last x % 7 = 0 row, which is kept as a tie
+        let expected = expected[60..71]
+            .iter()
+            .map(|x| vec![Value::from(*x), Value::from(format!("row {}", x))])
+            .collect::<Vec<_>>();
+        for work_mem in [1024, DEFAULT_WORK_MEM] {
+            let ctx = ExecutionContext {
+                work_mem,
+                ..db.context(None)
+            };
+            assert_eq!(execute(&plan, &ctx).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    #[traced_test]
+    fn set_operations() {
+        let db = Fixture::new();
+        let query = |sql: &str| db.query(&format!("{} ORDER BY user_id", sql));
+        assert_eq!(
+            query("SELECT user_id FROM orders UNION SELECT id FROM users"),
+            rows(&[&["1"], &["2"], &["3"], &["4"], &["5"]])
+        );
+        assert_eq!(
+            db.query("SELECT count(*) FROM (SELECT user_id FROM orders UNION ALL SELECT id FROM users) x"),
+            rows(&[&["8"]])
+        );
+        assert_eq!(
+            query("SELECT user_id FROM orders INTERSECT SELECT id FROM users"),
+            rows(&[&["1"], &["3"]])
+        );
+        assert_eq!(
+            query("SELECT user_id FROM orders INTERSECT ALL SELECT user_id FROM orders WHERE total > 5"),
+            rows(&[&["1"], &["1"], &["3"]])
+        );
+        assert_eq!(
+            query("SELECT user_id FROM orders EXCEPT SELECT id FROM users"),
+            rows(&[&["5"]])
+        );
+        assert_eq!(
+            query("SELECT user_id FROM orders EXCEPT ALL SELECT id FROM users"),
+            rows(&[&["1"], &["5"]])
+        );
+        // Unlike in a join nulls match each other
+        assert_eq!(
+            db.query("SELECT age FROM users INTERSECT SELECT NULL"),
+            vec![vec![Value::Null]]
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn window_functions() {
+        let db = Fixture::new();
+        assert_eq!(
+            db.query("SELECT id, row_number() OVER (ORDER BY id DESC), rank() OVER (ORDER BY user_id), dense_rank() OVER (ORDER BY user_id) FROM orders ORDER BY id"),
+            rows(&[&["1", "4", "1", "1"], &["2", "3", "1", "1"], &["3", "2", "3", "2"], &["4", "1", "4", "3"]])
+        );
+        assert_eq!(
+            db.query("SELECT id, lag(total) OVER (PARTITION BY user_id ORDER BY id), lead(id, 1, 0) OVER (ORDER BY id), first_value(total) OVER (PARTITION BY user_id ORDER BY id) FROM orders ORDER BY id"),
+            rows(&[&["1", "NULL", "2", "10"], &["2", "10", "3", "10"], &["3", "NULL", "4", "20"], &["4", "NULL", "0", "1"]])
+        );
+        assert_eq!(
+            db.query("SELECT id, sum(total) OVER (ORDER BY id), sum(total) OVER (ORDER BY id ROWS BETWEEN 1 PRECEDING AND 1 FOLLOWING), count(*) OVER () FROM orders ORDER BY id"),
+            rows(&[&["1", "10", "15.5", "4"], &["2", "15.5", "35.5", "4"], &["3", "35.5", "26.5", "4"], &["4", "36.5", "21", "4"]])
+        );
+        // Ages within 5 of each other, the null age is only in its own frame
+        assert_eq!(
+            db.query("SELECT id, count(*) OVER (ORDER BY age RANGE BETWEEN 5 PRECEDING AND 5 FOLLOWING), count(*) OVER (ORDER BY age DESC RANGE 5 PRECEDING) FROM users ORDER BY id"),
+            rows(&[&["1", "2", "2"], &["2", "1", "1"], &["3", "2", "1"], &["4", "3", "2"]])
+        );
+        assert_eq!(
+            db.query("SELECT id, ntile(3) OVER w, last_value(name) OVER w FROM users WINDOW w AS (ORDER BY id) ORDER BY id"),
+            rows(&[&["1", "1", "Daniel"], &["2", "1", "Guido"], &["3", "2", "Alan"], &["4", "3", "Ada"]])
+        );
+        assert_eq!(
+            db.query("SELECT user_id, sum(total), rank() OVER (ORDER BY sum(total) DESC) FROM orders GROUP BY user_id ORDER BY user_id"),
+            rows(&[&["1", "15.5", "2"], &["3", "20", "1"], &["5", "1", "3"]])
+        );
+        // Only the filter on the partition column can go below the window
+        assert_eq!(
+            db.query("SELECT * FROM (SELECT user_id, row_number() OVER (PARTITION BY user_id ORDER BY id) AS n FROM orders) x WHERE n = 1 AND user_id > 1 ORDER BY user_id"),
+            rows(&[&["3", "1"], &["5", "1"]])
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn insert_from_query() {
+        let db = Fixture::new();
+        // Enough rows to go over a batch
+        let plan = db.plan(
+            "INSERT INTO orders (id, user_id, total) \
+             WITH RECURSIVE n (i) AS (SELECT 0 UNION ALL SELECT i + 1 FROM n WHERE i < 49) \
+             SELECT 100 + a.i * 50 + b.i, 1, a.i FROM n a, n b",
+        );
+        let ctx = db.context(None);
+        assert_eq!(execute(&plan, &ctx).unwrap(), Vec::<Row>::new());
+        // Nothing is written until the caller applies the staged writes
+        assert_eq!(db.query("SELECT count(*) FROM orders"), rows(&[&["4"]]));
+        db.storage.commit(ctx.staged.take()).unwrap();
+        assert_eq!(
+            db.query("SELECT count(*), max(id), sum(total) FROM orders"),
+            rows(&[&["2504", "2599", "61286.5"]])
+        );
+
+        // Rows are checked like any other insert
+        let plan = db.plan("INSERT INTO users (id, name) SELECT id + 10, NULL FROM users");
+        let err = execute(&plan, &db.context(None)).unwrap_err();
+        assert!(
+            err.to_string().contains("doesn't match column type"),
+            "{}",
+            err
+        );
+        let err = PlanBuilder::new(&db.storage)
+            .plan_statement(
+                &Parser::parse_sql(
+                    &GenericDialect {},
+                    "INSERT INTO users (id, name) SELECT id, id FROM users",
+                )
+                .unwrap()
+                .remove(0),
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("expression is of type"), "{}", err);
+    }
+
+    #[test]
+    #[traced_test]
+    fn aggregates_sorts_and_limits() {
+        let db = Fixture::new();
+        assert_eq!(
+            db.query("SELECT user_id, count(*), sum(total), avg(total), min(id), max(id) FROM orders GROUP BY user_id ORDER BY user_id"),
+            rows(&[
+                &["1", "2", "15.5", "7.75", "1", "2"],
+                &["3", "1", "20", "20", "3", "3"],
+                &["5", "1", "1", "1", "4", "4"],
+            ])
+        );
+        assert_eq!(
+            db.query("SELECT count(*), count(age), sum(age), count(DISTINCT user_id) FROM users, orders WHERE users.id = 2 AND orders.id < 3"),
+            rows(&[&["2", "0", "NULL", "1"]])
+        );
+        assert_eq!(
+            db.query("SELECT count(*), max(age) FROM users WHERE id > 10"),
+            rows(&[&["0", "NULL"]])
+        );
+        assert_eq!(
+            db.query("SELECT name FROM users ORDER BY age DESC NULLS LAST"),
+            rows(&[&["Alan"], &["Ada"], &["Daniel"], &["Guido"]])
+        );
+        assert_eq!(
+            db.query("SELECT name FROM users ORDER BY age LIMIT 2 OFFSET 1"),
+            rows(&[&["Ada"], &["Alan"]])
+        );
+        assert_eq!(
+            db.query("SELECT name FROM users LIMIT 2 OFFSET 3"),
+            rows(&[&["Ada"]])
+        );
+        assert_eq!(
+            db.query("SELECT id FROM orders ORDER BY user_id FETCH FIRST ROW ONLY"),
+            rows(&[&["1"]])
+        );
+        assert_eq!(
+            db.query("SELECT id FROM orders ORDER BY user_id FETCH FIRST 1 ROW WITH TIES"),
+            rows(&[&["1"], &["2"]])
+        );
+        assert_eq!(
+            db.query("SELECT id FROM orders ORDER BY user_id DESC OFFSET 2 ROWS FETCH NEXT 1 ROW WITH TIES"),
+            rows(&[&["1"], &["2"]])
+        );
+        assert_eq!(
+            db.query("SELECT DISTINCT user_id FROM orders ORDER BY user_id DESC"),
+            rows(&[&["5"], &["3"], &["1"]])
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn more_aggregates() {
+        let db = Fixture::new();
+        assert_eq!(
+            db.query("SELECT string_agg(name, ', ' ORDER BY name), string_agg(name, NULL ORDER BY age DESC) FROM users"),
+            rows(&[&["Ada, Alan, Daniel, Guido", "GuidoAlanAdaDaniel"]])
+        );
+        assert_eq!(
+            db.query("SELECT array_agg(age ORDER BY users.id, orders.id), array_agg(DISTINCT user_id ORDER BY user_id DESC) FROM users, orders WHERE users.id < 3"),
+            vec![vec![
+                Value::Array(vec![Value::from(31), Value::from(31), Value::from(31), Value::from(31), Value::Null, Value::Null, Value::Null, Value::Null]),
+                Value::Array(vec![Value::from(5), Value::from(3), Value::from(1)]),
+            ]]
+        );
+        assert_eq!(
+            db.query("SELECT user_id, bool_and(total > 5), bool_or(total > 5), every(id < 3) FROM orders GROUP BY user_id ORDER BY user_id"),
+            vec![
+                vec![Value::from(1), Value::from(true), Value::from(true), Value::from(true)],
+                vec![Value::from(3), Value::from(true), Value::from(true), Value::from(false)],
+                vec![Value::from(5), Value::from(false), Value::from(false), Value::from(false)],
+            ]
+        );
+        // Nulls are left out of everything but array_agg, with nothing left it's null
+        assert_eq!(
+            db.query("SELECT string_agg(name, ','), array_agg(age), bool_or(age > 40), avg(age) FROM users WHERE age IS NULL"),
+            vec![vec![Value::from("Guido"), Value::Array(vec![Value::Null]), Value::Null, Value::Null]]
+        );
+        assert_eq!(
+            db.query("SELECT array_agg(age) FROM users WHERE id > 10"),
+            rows(&[&["NULL"]])
+        );
+        assert_eq!(
+            db.query("SELECT user_id, count(*) FROM orders GROUP BY user_id HAVING sum(total) > 10 ORDER BY user_id"),
+            rows(&[&["1", "2"], &["3", "1"]])
+        );
+        // Averages are exact up to a point rather than rounded to the inputs' scale
+        db.insert("INSERT INTO orders (id, user_id, total) VALUES (5, 7, 1), (6, 7, 1), (7, 7, 0.00000000000000000001)");
+        assert_eq!(
+            db.query("SELECT avg(total), sum(total) FROM orders WHERE user_id = 7"),
+            rows(&[&["0.66666666666666666667", "2.00000000000000000001"]])
+        );
+
+        let plan = |sql| {
+            let statement = Parser::parse_sql(&GenericDialect {}, sql)
+                .unwrap()
+                .remove(0);
+            PlanBuilder::new(&db.storage).plan_statement(&statement)
+        };
+        assert!(plan("SELECT sum(name) FROM users").is_err());
+        assert!(plan("SELECT string_agg(name) FROM users").is_err());
+        assert!(plan("SELECT bool_and(age) FROM users").is_err());
+        assert!(plan("SELECT sum(*) FROM users").is_err());
+
+        let array = Value::Array(vec![
+            Value::from("a b"),
+            Value::Null,
+            Value::from("NULL"),
+            Value::from(1),
+        ]);
+        assert_eq!(array.to_string(), r#"{"a b",NULL,"NULL",1}"#);
+    }
+
+    #[test]
+    fn group_overflow() {
+        use crate::functions::{Aggregator, ArgType, ReturnType, Signature};
+
+        struct Product;
+
+        impl Aggregator for Product {
+            type State = BigDecimal;
+
+            fn init(&self) -> BigDecimal {
+                BigDecimal::from(1)
+            }
+
+            fn accumulate(&self, state: &mut BigDecimal, args: &[Value]) -> anyhow::Result<()> {
+                match &args[0] {
+                    Value::Number(n) => *state *= n,
+                    v => anyhow::bail!("Can't multiply {}", v),
+                }
+                Ok(())
+            }
+
+            fn merge(&self, state: &mut BigDecimal, other: BigDecimal) -> anyhow::Result<()> {
+                *state *= other;
+                Ok(())
+            }
+
+            fn finalize(&self, state: BigDecimal) -> anyhow::Result<Value> {
+                Ok(Value::Number(state))
+            }
+
+            fn save(&self, state: BigDecimal) -> anyhow::Result<Value> {
+                Ok(Value::Number(state))
+            }
+
+            fn load(&self, value: Value) -> anyhow::Result<BigDecimal> {
+                match value {
+                    Value::Number(n) => Ok(n),
+                    v => anyhow::bail!("Invalid product state {}", v),
+                }
+            }
+        }
+
+        let product = AggregateUdf::new(
+            "product",
+            Signature::new(vec![ArgType::Number], ReturnType::SameAsArgument),
+            Product,
+        );
+        let aggregate = |func, distinct, order_by| AggregateExpr {
+            func,
+            args: vec![ScalarExpr::literal(1)],
+            distinct,
+            order_by,
+        };
+        let descending = vec![SortKey {
+            expr: ScalarExpr::literal(1),
+            asc: false,
+            nulls_first: false,
+        }];
+        let aggregates = [
+            AggregateExpr {
+                func: AggregateFunction::Count,
+                args: vec![],
+                distinct: false,
+                order_by: vec![],
+            },
+            aggregate(AggregateFunction::Sum, true, vec![]),
+            aggregate(AggregateFunction::Max, false, vec![]),
+            aggregate(AggregateFunction::ArrayAgg, true, descending),
+            aggregate(AggregateFunction::User(Arc::new(product)), false, vec![]),
+        ];
+        let input = (0..50)
+            .map(|i| {
+                let value = vec![Value::from(i % 3 + 1)];
+                let sorted = vec![Value::from(i % 3 + 1), Value::from(i % 3 + 1)];
+                let args = vec![vec![], value.clone(), value.clone(), sorted, value];
+                (vec![Value::from(i % 7)], args)
+            })
+            .collect::<Vec<_>>();
+        let run = |max_groups, work_mem| {
+            let mut groups = Groups::new(&aggregates, max_groups, work_mem);
+            for (key, args) in input.clone() {
+                groups.update(key, args).unwrap();
+            }
+            let mut output = groups.finish(true).unwrap();
+            output.sort_by(|a, b| sort_compare(&a[0], &b[0]));
+            output
+        };
+        let expected = run(MAX_HASH_GROUPS, DEFAULT_WORK_MEM);
+        assert_eq!(expected.len(), 7);
+        // Group 0 gets 0, 7, 14, 21, 28, 35, 42 and 49 which are 1, 2, 3, 1, 2, 3, 1, 2
+        let array = Value::Array(vec![Value::from(3), Value::from(2), Value::from(1)]);
+        let mut first = row(&["0", "8", "6", "3"]);
+        first.extend([array, Value::from(72)]);
+        assert_eq!(expected[0], first);
+        // Only two groups fit in the table so partial results are written to disk and merged
+        assert_eq!(run(2, DEFAULT_WORK_MEM), expected);
+        // Every row spills, more runs than can be merged in one go
+        assert_eq!(run(0, DEFAULT_WORK_MEM), expected);
+        assert_eq!(run(MAX_HASH_GROUPS, 0), expected);
+    }
+
+    #[test]
+    #[traced_test]
+    fn sees_own_writes() {
+        let db = Fixture::new();
+        let engine = crate::query_engine::QueryEngine;
+        let mut writes = WriteSet::default();
+        let crate::types::Command::Insert(insert) = engine
+            .process_sql("INSERT INTO users (id, name) VALUES (0, 'Grace'), (9, 'Linus')")
+            .unwrap()
+            .remove(0)
+        else {
+            unreachable!()
+        };
+        let opts: InsertOptions = insert.evaluate(&db.storage).unwrap();
+        db.storage
+            .stage_insert(&opts, None, None, None, &mut writes)
+            .unwrap();
+        let plan = db.plan("SELECT name FROM users");
+        assert_eq!(
+            execute(&plan, &db.context(Some(&writes))).unwrap(),
+            rows(&[
+                &["Grace"],
+                &["Daniel"],
+                &["Guido"],
+                &["Alan"],
+                &["Ada"],
+                &["Linus"]
+            ])
+        );
+        let plan = db.plan("SELECT name FROM users WHERE id = 9");
+        assert_eq!(
+            execute(&plan, &db.context(Some(&writes))).unwrap(),
+            rows(&[&["Linus"]])
+        );
+        // Nobody else sees them
+        assert_eq!(db.query("SELECT count(*) FROM users"), rows(&[&["4"]]));
+    }
+
+    #[test]
+    #[traced_test]
+    fn explain_analyze() {
+        let db = Fixture::new();
+        let mut plan = db
+            .plan("SELECT u.name FROM users u JOIN orders o ON u.id = o.user_id WHERE o.total > 6");
+        execute_analyze(&mut plan, &db.context(None)).unwrap();
+        let metrics = |plan: &PhysicalPlan| plan.metrics.unwrap();
+        assert_eq!(metrics(&plan).rows, 2);
+        assert_eq!(metrics(&plan).loops, 1);
+        let mut all = vec![];
+        fn walk<'a>(plan: &'a PhysicalPlan, all: &mut Vec<&'a PhysicalPlan>) {
+            all.push(plan);
+            for input in &plan.inputs {
+                walk(input, all);
+            }
+        }
+        walk(&plan, &mut all);
+        assert!(all.iter().all(|x| x.metrics.is_some()));
+        let scans = all
+            .iter()
+            .filter(|x| matches!(x.operator, Operator::TableScan { .. }))
+            .map(|x| metrics(x).rows)
+            .collect::<Vec<_>>();
+        assert_eq!(scans.iter().sum::<u64>(), 6, "{}", plan);
+    }
+}
@@ -0,0 +1,1064 @@
+//! Scalar expressions used in query plans. These are lowered from the sqlparser AST while
+//! planning, at which point any column references have been resolved against the schema of the
+//! plan they're evaluated over.
+use crate::functions::{AggregateUdf, ScalarFunction, Volatility};
+use crate::logical_plan::{list, LogicalPlan, Schema, SortKey};
+use crate::types::{is_integer_type, TypeClass, Value};
+use bigdecimal::ToPrimitive;
+use sqlparser::ast::{ArrayElemTypeDef, BinaryOperator, DataType, ExactNumberInfo, UnaryOperator};
+use std::fmt;
+use std::sync::Arc;
+
+/// A reference to a column. After planning `relation` is always set for columns which come from
+/// a table.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Column {
+    pub relation: Option<String>,
+    pub name: String,
+}
+
+impl Column {
+    pub fn new(relation: Option<impl Into<String>>, name: impl Into<String>) -> Self {
+        Self {
+            relation: relation.map(|x| x.into()),
+            name: name.into(),
+        }
+    }
+
+    pub fn unqualified(name: impl Into<String>) -> Self {
+        Self {
+            relation: None,
+            name: name.into(),
+        }
+    }
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.relation {
+            Some(relation) => write!(f, "{}.{}", relation, self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AggregateFunction {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+    StringAgg,
+    ArrayAgg,
+    BoolAnd,
+    BoolOr,
+    User(Arc<AggregateUdf>),
+}
+
+impl AggregateFunction {
+    pub fn from_name(name: &str) -> Option<Self> {
+        let func = match name.to_lowercase().as_str() {
+            "count" => Self::Count,
+            "sum" => Self::Sum,
+            "avg" => Self::Avg,
+            "min" => Self::Min,
+            "max" => Self::Max,
+            "string_agg" => Self::StringAgg,
+            "array_agg" => Self::ArrayAgg,
+            "bool_and" | "every" => Self::BoolAnd,
+            "bool_or" => Self::BoolOr,
+            _ => return None,
+        };
+        Some(func)
+    }
+
+    /// The number of arguments a built in aggregate takes, `count` can also have none for
+    /// `count(*)`
+    fn arity(&self) -> usize {
+        match self {
+            Self::StringAgg => 2,
+            _ => 1,
+        }
+    }
+
+    /// The types the arguments need to be, `None` where anything goes
+    fn arg_types(&self) -> &'static [Option<TypeClass>] {
+        match self {
+            Self::Sum | Self::Avg => &[Some(TypeClass::Number)],
+            Self::StringAgg => &[Some(TypeClass::Text), Some(TypeClass::Text)],
+            Self::BoolAnd | Self::BoolOr => &[Some(TypeClass::Boolean)],
+            Self::Count | Self::Min | Self::Max | Self::ArrayAgg | Self::User(_) => &[None],
+        }
+    }
+
+    /// Whether a row with a null argument is left out. `string_agg` only cares about the value,
+    /// a null delimiter just means there's nothing between values.
+    pub fn skips_null(&self, args: &[Value]) -> bool {
+        match self {
+            Self::ArrayAgg => false,
+            Self::User(udf) if !udf.strict => false,
+            Self::StringAgg => args[0].is_null(),
+            _ => args.iter().any(|x| x.is_null()),
+        }
+    }
+}
+
+impl fmt::Display for AggregateFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Count => "count",
+            Self::Sum => "sum",
+            Self::Avg => "avg",
+            Self::Min => "min",
+            Self::Max => "max",
+            Self::StringAgg => "string_agg",
+            Self::ArrayAgg => "array_agg",
+            Self::BoolAnd => "bool_and",
+            Self::BoolOr => "bool_or",
+            Self::User(udf) => &udf.name,
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A call to an aggregate function, `COUNT(*)` is represented with no arguments.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AggregateExpr {
+    pub func: AggregateFunction,
+    pub args: Vec<ScalarExpr>,
+    pub distinct: bool,
+    /// The order values are fed to the aggregate in, from `string_agg(x, ',' ORDER BY y)`
+    pub order_by: Vec<SortKey>,
+}
+
+impl AggregateExpr {
+    pub fn data_type(&self, schema: &Schema) -> DataType {
+        let arg = || {
+            self.args
+                .first()
+                .map(|x| x.data_type(schema))
+                .unwrap_or(DataType::Unspecified)
+        };
+        match &self.func {
+            AggregateFunction::Count => DataType::BigInt(None),
+            AggregateFunction::Sum | AggregateFunction::Avg => {
+                DataType::Numeric(ExactNumberInfo::None)
+            }
+            AggregateFunction::Min | AggregateFunction::Max => arg(),
+            AggregateFunction::StringAgg => DataType::Text,
+            AggregateFunction::ArrayAgg => {
+                DataType::Array(ArrayElemTypeDef::SquareBracket(Box::new(arg()), None))
+            }
+            AggregateFunction::BoolAnd | AggregateFunction::BoolOr => DataType::Boolean,
+            AggregateFunction::User(udf) => {
+                let args = self
+                    .args
+                    .iter()
+                    .map(|x| x.data_type(schema))
+                    .collect::<Vec<_>>();
+                udf.signature.return_type(&args)
+            }
+        }
+    }
+
+    /// Checks the number and types of the arguments.
+    pub fn check(&self, schema: &Schema) -> anyhow::Result<()> {
+        if let AggregateFunction::User(udf) = &self.func {
+            let args = self
+                .args
+                .iter()
+                .map(|x| x.data_type(schema))
+                .collect::<Vec<_>>();
+            return udf.check(&args).map(|_| ());
+        }
+        if self.args.is_empty() && self.func == AggregateFunction::Count {
+            return Ok(());
+        }
+        if self.args.is_empty() {
+            anyhow::bail!("{}(*) is not valid", self.func);
+        }
+        let arity = self.func.arity();
+        if self.args.len() != arity {
+            anyhow::bail!(
+                "{} takes {} argument{}",
+                self.func,
+                arity,
+                if arity == 1 { "" } else { "s" }
+            );
+        }
+        for (arg, expected) in self.args.iter().zip(self.func.arg_types()) {
+            let data_type = arg.data_type(schema);
+            if let (Some(expected), Some(actual)) = (expected, TypeClass::of(&data_type)) {
+                if *expected != actual {
+                    anyhow::bail!("{} can't take an argument of type {}", self.func, data_type);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The arguments followed by the `ORDER BY` expressions
+    pub fn exprs(&self) -> impl Iterator<Item = &ScalarExpr> {
+        self.args
+            .iter()
+            .chain(self.order_by.iter().map(|x| &x.expr))
+    }
+}
+
+impl fmt::Display for AggregateExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}(", self.func)?;
+        if self.distinct {
+            write!(f, "DISTINCT ")?;
+        }
+        if self.args.is_empty() && self.func == AggregateFunction::Count {
+            write!(f, "*")?;
+        }
+        let args = self.args.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        write!(f, "{}", args.join(", "))?;
+        if !self.order_by.is_empty() {
+            let keys = self
+                .order_by
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>();
+            write!(f, " ORDER BY {}", keys.join(", "))?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Functions which can be used with `OVER`, which includes all of the aggregates.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WindowFunction {
+    RowNumber,
+    Rank,
+    DenseRank,
+    Ntile,
+    Lag,
+    Lead,
+    FirstValue,
+    LastValue,
+    Aggregate(AggregateFunction),
+}
+
+impl WindowFunction {
+    /// Only the functions which need `OVER`, aggregates are looked up separately
+    pub fn from_name(name: &str) -> Option<Self> {
+        let func = match name.to_lowercase().as_str() {
+            "row_number" => Self::RowNumber,
+            "rank" => Self::Rank,
+            "dense_rank" => Self::DenseRank,
+            "ntile" => Self::Ntile,
+            "lag" => Self::Lag,
+            "lead" => Self::Lead,
+            "first_value" => Self::FirstValue,
+            "last_value" => Self::LastValue,
+            _ => return None,
+        };
+        Some(func)
+    }
+}
+
+impl fmt::Display for WindowFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::RowNumber => "row_number",
+            Self::Rank => "rank",
+            Self::DenseRank => "dense_rank",
+            Self::Ntile => "ntile",
+            Self::Lag => "lag",
+            Self::Lead => "lead",
+            Self::FirstValue => "first_value",
+            Self::LastValue => "last_value",
+            Self::Aggregate(func) => return write!(f, "{}", func),
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameUnits {
+    Rows,
+    /// Bounds are worked out from the `ORDER BY` value, rows with equal values are all in or out
+    Range,
+}
+
+/// One end of a window frame, the offsets are always numbers and `None` means unbounded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FrameBound {
+    Preceding(Option<Value>),
+    CurrentRow,
+    Following(Option<Value>),
+}
+
+impl fmt::Display for FrameBound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Preceding(None) => write!(f, "UNBOUNDED PRECEDING"),
+            Self::Preceding(Some(n)) => write!(f, "{} PRECEDING", n),
+            Self::CurrentRow => write!(f, "CURRENT ROW"),
+            Self::Following(None) => write!(f, "UNBOUNDED FOLLOWING"),
+            Self::Following(Some(n)) => write!(f, "{} FOLLOWING", n),
+        }
+    }
+}
+
+/// Which rows of the partition aggregates, `first_value` and `last_value` see.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WindowFrame {
+    pub units: FrameUnits,
+    pub start: FrameBound,
+    pub end: FrameBound,
+}
+
+impl Default for WindowFrame {
+    /// Everything up to the current row and any rows equal to it, which is the whole partition
+    /// when there's no `ORDER BY`
+    fn default() -> Self {
+        Self {
+            units: FrameUnits::Range,
+            start: FrameBound::Preceding(None),
+            end: FrameBound::CurrentRow,
+        }
+    }
+}
+
+impl fmt::Display for WindowFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let units = match self.units {
+            FrameUnits::Rows => "ROWS",
+            FrameUnits::Range => "RANGE",
+        };
+        write!(f, "{} BETWEEN {} AND {}", units, self.start, self.end)
+    }
+}
+
+/// A call to a function with `OVER`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WindowExpr {
+    pub func: WindowFunction,
+    pub args: Vec<ScalarExpr>,
+    pub partition_by: Vec<ScalarExpr>,
+    pub order_by: Vec<SortKey>,
+    pub frame: WindowFrame,
+}
+
+impl WindowExpr {
+    /// The aggregate to run over each row's frame for aggregates used as window functions
+    pub fn aggregate(&self) -> Option<AggregateExpr> {
+        match &self.func {
+            WindowFunction::Aggregate(func) => Some(AggregateExpr {
+                func: func.clone(),
+                args: self.args.clone(),
+                distinct: false,
+                order_by: vec![],
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn data_type(&self, schema: &Schema) -> DataType {
+        match &self.func {
+            WindowFunction::RowNumber
+            | WindowFunction::Rank
+            | WindowFunction::DenseRank
+            | WindowFunction::Ntile => DataType::BigInt(None),
+            WindowFunction::Lag
+            | WindowFunction::Lead
+            | WindowFunction::FirstValue
+            | WindowFunction::LastValue => self.args[0].data_type(schema),
+            WindowFunction::Aggregate(_) => self.aggregate().unwrap().data_type(schema),
+        }
+    }
+
+    /// Checks the number and types of the arguments.
+    pub fn check(&self, schema: &Schema) -> anyhow::Result<()> {
+        if let Some(aggregate) = self.aggregate() {
+            return aggregate.check(schema);
+        }
+        let (min, max) = match self.func {
+            WindowFunction::Lag | WindowFunction::Lead => (1, 3),
+            WindowFunction::Ntile | WindowFunction::FirstValue | WindowFunction::LastValue => {
+                (1, 1)
+            }
+            _ => (0, 0),
+        };
+        if !(min..=max).contains(&self.args.len()) {
+            match (min, max) {
+                (0, 0) => anyhow::bail!("{} doesn't take any arguments", self.func),
+                (1, 1) => anyhow::bail!("{} takes 1 argument", self.func),
+                _ => anyhow::bail!("{} takes {} to {} arguments", self.func, min, max),
+            }
+        }
+        // The number of buckets and how far to look back or forward
+        let number = match self.func {
+            WindowFunction::Ntile => self.args.first(),
+            WindowFunction::Lag | WindowFunction::Lead => self.args.get(1),
+            _ => None,
+        };
+        if let Some(arg) = number {
+            let data_type = arg.data_type(schema);
+            if TypeClass::of(&data_type).is_some_and(|x| x != TypeClass::Number) {
+                anyhow::bail!("{} can't take an argument of type {}", self.func, data_type);
+            }
+        }
+        Ok(())
+    }
+
+    /// The arguments followed by the `PARTITION BY` and `ORDER BY` expressions
+    pub fn exprs(&self) -> impl Iterator<Item = &ScalarExpr> {
+        self.args
+            .iter()
+            .chain(&self.partition_by)
+            .chain(self.order_by.iter().map(|x| &x.expr))
+    }
+
+    pub fn map_exprs(
+        self,
+        mut f: impl FnMut(ScalarExpr) -> anyhow::Result<ScalarExpr>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            args: self
+                .args
+                .into_iter()
+                .map(&mut f)
+                .collect::<Result<_, _>>()?,
+            partition_by: self
+                .partition_by
+                .into_iter()
+                .map(&mut f)
+                .collect::<Result<_, _>>()?,
+            order_by: self
+                .order_by
+                .into_iter()
+                .map(|key| {
+                    Ok(SortKey {
+                        expr: f(key.expr)?,
+                        ..key
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?,
+            ..self
+        })
+    }
+}
+
+/// Integers stay integers, the wider of the two if they're different, otherwise any number with a
+/// fractional part makes the result a numeric.
+fn arithmetic_type(left: DataType, right: DataType) -> DataType {
+    match (left, right) {
+        (l, r) if is_integer_type(&l) && is_integer_type(&r) => match l == r {
+            true => l,
+            false => DataType::BigInt(None),
+        },
+        // A null has the type of the other side
+        (DataType::Unspecified, ty) | (ty, DataType::Unspecified) if is_integer_type(&ty) => ty,
+        _ => DataType::Numeric(ExactNumberInfo::None),
+    }
+}
+
+impl fmt::Display for WindowExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.func {
+            // Labelled the same as the plain aggregate
+            WindowFunction::Aggregate(AggregateFunction::Count) if self.args.is_empty() => {
+                write!(f, "{}(*) OVER (", self.func)?
+            }
+            _ => write!(f, "{}({}) OVER (", self.func, list(&self.args))?,
+        }
+        let mut clauses = vec![];
+        if !self.partition_by.is_empty() {
+            clauses.push(format!("PARTITION BY {}", list(&self.partition_by)));
+        }
+        if !self.order_by.is_empty() {
+            clauses.push(format!("ORDER BY {}", list(&self.order_by)));
+        }
+        if self.frame != WindowFrame::default() {
+            clauses.push(self.frame.to_string());
+        }
+        write!(f, "{})", clauses.join(" "))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScalarExpr {
+    Column(Column),
+    Literal(Value),
+    BinaryOp {
+        left: Box<ScalarExpr>,
+        op: BinaryOperator,
+        right: Box<ScalarExpr>,
+    },
+    UnaryOp {
+        op: UnaryOperator,
+        expr: Box<ScalarExpr>,
+    },
+    IsNull(Box<ScalarExpr>),
+    IsNotNull(Box<ScalarExpr>),
+    Between {
+        expr: Box<ScalarExpr>,
+        negated: bool,
+        low: Box<ScalarExpr>,
+        high: Box<ScalarExpr>,
+    },
+    InList {
+        expr: Box<ScalarExpr>,
+        list: Vec<ScalarExpr>,
+        negated: bool,
+    },
+    Like {
+        expr: Box<ScalarExpr>,
+        pattern: Box<ScalarExpr>,
+        negated: bool,
+        /// `ILIKE`
+        case_insensitive: bool,
+        escape: Option<char>,
+    },
+    /// With an operand this is a simple `CASE x WHEN 1 THEN ...` comparing the operand against
+    /// each `WHEN`, otherwise each `WHEN` is a condition.
+    Case {
+        operand: Option<Box<ScalarExpr>>,
+        when_then: Vec<(ScalarExpr, ScalarExpr)>,
+        else_result: Option<Box<ScalarExpr>>,
+    },
+    Cast {
+        expr: Box<ScalarExpr>,
+        data_type: DataType,
+    },
+    Coalesce(Vec<ScalarExpr>),
+    NullIf(Box<ScalarExpr>, Box<ScalarExpr>),
+    /// A call to a scalar function, the arguments have already been checked against its
+    /// signature.
+    Function {
+        func: Arc<ScalarFunction>,
+        args: Vec<ScalarExpr>,
+    },
+    /// Only valid while planning, these are replaced with a reference to the output of the
+    /// aggregate operator computing them.
+    Aggregate(AggregateExpr),
+    /// Like aggregates these are only valid while planning, they're replaced with a reference to
+    /// the output of the window operator.
+    Window(Box<WindowExpr>),
+    /// A column of the query a subquery is in, which is a constant as far as the subquery is
+    /// concerned. They're replaced with the outer row's values before the subquery is run.
+    OuterColumn(Column, DataType),
+    /// A subquery with a single column used as a value, null if it doesn't return any rows.
+    /// Subqueries are all turned into joins or `Apply` nodes by the optimiser.
+    Subquery(Box<LogicalPlan>),
+    Exists {
+        subquery: Box<LogicalPlan>,
+        negated: bool,
+    },
+    InSubquery {
+        expr: Box<ScalarExpr>,
+        subquery: Box<LogicalPlan>,
+        negated: bool,
+    },
+}
+
+impl ScalarExpr {
+    pub fn column(relation: Option<&str>, name: &str) -> Self {
+        Self::Column(Column::new(relation, name))
+    }
+
+    pub fn literal(value: impl Into<Value>) -> Self {
+        Self::Literal(value.into())
+    }
+
+    pub fn binary(left: ScalarExpr, op: BinaryOperator, right: ScalarExpr) -> Self {
+        Self::BinaryOp {
+            left: Box::new(left),
+            op,
+            right: Box::new(right),
+        }
+    }
+
+    /// The direct children of this expression, subqueries' plans aren't included
+    pub fn children(&self) -> Vec<&ScalarExpr> {
+        match self {
+            Self::Column(_)
+            | Self::Literal(_)
+            | Self::OuterColumn(..)
+            | Self::Subquery(_)
+            | Self::Exists { .. } => vec![],
+            Self::InSubquery { expr, .. } => vec![expr],
+            Self::BinaryOp { left, right, .. } => vec![left, right],
+            Self::UnaryOp { expr, .. }
+            | Self::IsNull(expr)
+            | Self::IsNotNull(expr)
+            | Self::Cast { expr, .. } => vec![expr],
+            Self::Between {
+                expr, low, high, ..
+            } => vec![expr, low, high],
+            Self::InList { expr, list, .. } => std::iter::once(expr.as_ref()).chain(list).collect(),
+            Self::Like { expr, pattern, .. } => vec![expr, pattern],
+            Self::Case {
+                operand,
+                when_then,
+                else_result,
+            } => operand
+                .as_deref()
+                .into_iter()
+                .chain(when_then.iter().flat_map(|(when, then)| [when, then]))
+                .chain(else_result.as_deref())
+                .collect(),
+            Self::Coalesce(args) | Self::Function { args, .. } => args.iter().collect(),
+            Self::NullIf(left, right) => vec![left, right],
+            Self::Aggregate(agg) => agg.exprs().collect(),
+            Self::Window(window) => window.exprs().collect(),
+        }
+    }
+
+    /// Applies `f` to every child of this expression, replacing them with the result.
+    pub fn map_children(
+        self,
+        mut f: impl FnMut(ScalarExpr) -> anyhow::Result<ScalarExpr>,
+    ) -> anyhow::Result<ScalarExpr> {
+        let expr = match self {
+            Self::Column(_)
+            | Self::Literal(_)
+            | Self::OuterColumn(..)
+            | Self::Subquery(_)
+            | Self::Exists { .. } => self,
+            Self::InSubquery {
+                expr,
+                subquery,
+                negated,
+            } => Self::InSubquery {
+                expr: Box::new(f(*expr)?),
+                subquery,
+                negated,
+            },
+            Self::BinaryOp { left, op, right } => Self::BinaryOp {
+                left: Box::new(f(*left)?),
+                op,
+                right: Box::new(f(*right)?),
+            },
+            Self::UnaryOp { op, expr } => Self::UnaryOp {
+                op,
+                expr: Box::new(f(*expr)?),
+            },
+            Self::IsNull(expr) => Self::IsNull(Box::new(f(*expr)?)),
+            Self::IsNotNull(expr) => Self::IsNotNull(Box::new(f(*expr)?)),
+            Self::Between {
+                expr,
+                negated,
+                low,
+                high,
+            } => Self::Between {
+                expr: Box::new(f(*expr)?),
+                negated,
+                low: Box::new(f(*low)?),
+                high: Box::new(f(*high)?),
+            },
+            Self::InList {
+                expr,
+                list,
+                negated,
+            } => Self::InList {
+                expr: Box::new(f(*expr)?),
+                list: list.into_iter().map(&mut f).collect::<Result<_, _>>()?,
+                negated,
+            },
+            Self::Like {
+                expr,
+                pattern,
+                negated,
+                case_insensitive,
+                escape,
+            } => Self::Like {
+                expr: Box::new(f(*expr)?),
+                pattern: Box::new(f(*pattern)?),
+                negated,
+                case_insensitive,
+                escape,
+            },
+            Self::Case {
+                operand,
+                when_then,
+                else_result,
+            } => Self::Case {
+                operand: operand.map(|x| f(*x).map(Box::new)).transpose()?,
+                when_then: when_then
+                    .into_iter()
+                    .map(|(when, then)| Ok((f(when)?, f(then)?)))
+                    .collect::<anyhow::Result<_>>()?,
+                else_result: else_result.map(|x| f(*x).map(Box::new)).transpose()?,
+            },
+            Self::Cast { expr, data_type } => Self::Cast {
+                expr: Box::new(f(*expr)?),
+                data_type,
+            },
+            Self::Coalesce(args) => {
+                Self::Coalesce(args.into_iter().map(&mut f).collect::<Result<_, _>>()?)
+            }
+            Self::NullIf(left, right) => Self::NullIf(Box::new(f(*left)?), Box::new(f(*right)?)),
+            Self::Function { func, args } => Self::Function {
+                func,
+                args: args.into_iter().map(&mut f).collect::<Result<_, _>>()?,
+            },
+            Self::Aggregate(agg) => Self::Aggregate(AggregateExpr {
+                args: agg.args.into_iter().map(&mut f).collect::<Result<_, _>>()?,
+                order_by: agg
+                    .order_by
+                    .into_iter()
+                    .map(|key| {
+                        Ok(SortKey {
+                            expr: f(key.expr)?,
+                            ..key
+                        })
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+                ..agg
+            }),
+            Self::Window(window) => Self::Window(Box::new(window.map_exprs(f)?)),
+        };
+        Ok(expr)
+    }
+
+    /// Rewrites the expression bottom up, `f` is called on every node after its children have
+    /// been rewritten.
+    pub fn transform_up(
+        self,
+        f: &mut impl FnMut(ScalarExpr) -> anyhow::Result<ScalarExpr>,
+    ) -> anyhow::Result<ScalarExpr> {
+        let expr = self.map_children(|child| child.transform_up(f))?;
+        f(expr)
+    }
+
+    /// Rewrites the expression top down, if `f` returns `Some` that replaces the node and its
+    /// children aren't visited.
+    pub fn transform_down(
+        self,
+        f: &mut impl FnMut(&ScalarExpr) -> anyhow::Result<Option<ScalarExpr>>,
+    ) -> anyhow::Result<ScalarExpr> {
+        match f(&self)? {
+            Some(expr) => Ok(expr),
+            None => self.map_children(|child| child.transform_down(f)),
+        }
+    }
+
+    /// Calls `f` on this expression and everything below it
+    pub fn walk<'a>(&'a self, f: &mut impl FnMut(&'a ScalarExpr)) {
+        f(self);
+        for child in self.children() {
+            child.walk(f);
+        }
+    }
+
+    /// All the columns this expression references
+    pub fn columns(&self) -> Vec<&Column> {
+        let mut res = vec![];
+        self.walk(&mut |expr| {
+            if let Self::Column(c) = expr {
+                if !res.contains(&c) {
+                    res.push(c);
+                }
+            }
+        });
+        res
+    }
+
+    /// Replaces the plans of any subqueries in the expression with the result of `f`
+    pub fn map_subqueries(
+        self,
+        f: &mut impl FnMut(LogicalPlan) -> anyhow::Result<LogicalPlan>,
+    ) -> anyhow::Result<ScalarExpr> {
+        self.transform_down(&mut |e| {
+            let e = match e {
+                Self::Subquery(subquery) => Self::Subquery(Box::new(f(subquery.as_ref().clone())?)),
+                Self::Exists { subquery, negated } => Self::Exists {
+                    subquery: Box::new(f(subquery.as_ref().clone())?),
+                    negated: *negated,
+                },
+                Self::InSubquery {
+                    expr,
+                    subquery,
+                    negated,
+                } => Self::InSubquery {
+                    expr: Box::new(expr.as_ref().clone().map_subqueries(f)?),
+                    subquery: Box::new(f(subquery.as_ref().clone())?),
+                    negated: *negated,
+                },
+                _ => return Ok(None),
+            };
+            Ok(Some(e))
+        })
+    }
+
+    pub fn contains_subquery(&self) -> bool {
+        let mut found = false;
+        self.walk(&mut |expr| {
+            found |= matches!(
+                expr,
+                Self::Subquery(_) | Self::Exists { .. } | Self::InSubquery { .. }
+            )
+        });
+        found
+    }
+
+    /// Whether the expression refers to any columns of an outer query
+    pub fn is_correlated(&self) -> bool {
+        let mut found = false;
+        self.walk(&mut |expr| found |= matches!(expr, Self::OuterColumn(..)));
+        found
+    }
+
+    pub fn contains_aggregate(&self) -> bool {
+        let mut found = false;
+        self.walk(&mut |expr| found |= matches!(expr, Self::Aggregate(_)));
+        found
+    }
+
+    pub fn contains_window(&self) -> bool {
+        let mut found = false;
+        self.walk(&mut |expr| found |= matches!(expr, Self::Window(_)));
+        found
+    }
+
+    /// The most volatile of the functions called by the expression
+    pub fn volatility(&self) -> Volatility {
+        let mut res = Volatility::Immutable;
+        self.walk(&mut |expr| {
+            if let Self::Function { func, .. } = expr {
+                res = res.max(func.volatility);
+            }
+        });
+        res
+    }
+
+    /// Splits a predicate into the expressions that are ANDed together.
+    pub fn split_conjunction(self) -> Vec<ScalarExpr> {
+        match self {
+            Self::BinaryOp {
+                left,
+                op: BinaryOperator::And,
+                right,
+            } => {
+                let mut res = left.split_conjunction();
+                res.extend(right.split_conjunction());
+                res
+            }
+            e => vec![e],
+        }
+    }
+
+    /// ANDs the expressions together, the inverse of [`ScalarExpr::split_conjunction`].
+    pub fn conjunction(exprs: impl IntoIterator<Item = ScalarExpr>) -> Option<ScalarExpr> {
+        exprs
+            .into_iter()
+            .reduce(|acc, x| ScalarExpr::binary(acc, BinaryOperator::And, x))
+    }
+
+    /// Whether this is a division between two integers, which gives an integer truncated towards
+    /// zero rather than a number with a fractional part.
+    pub fn is_integer_division(&self, schema: &Schema) -> bool {
+        match self {
+            Self::BinaryOp {
+                op: BinaryOperator::Divide,
+                ..
+            } => is_integer_type(&self.data_type(schema)),
+            _ => false,
+        }
+    }
+
+    pub fn data_type(&self, schema: &Schema) -> DataType {
+        match self {
+            Self::Column(c) => schema
+                .field(c)
+                .map(|x| x.datatype.clone())
+                .unwrap_or(DataType::Unspecified),
+            // Whole numbers are integers like in Postgres, so `x / 2` truncates
+            Self::Literal(Value::Number(n)) if n.fractional_digit_count() <= 0 => {
+                if n.to_i32().is_some() {
+                    DataType::Integer(None)
+                } else if n.to_i64().is_some() {
+                    DataType::BigInt(None)
+                } else {
+                    DataType::Numeric(ExactNumberInfo::None)
+                }
+            }
+            Self::Literal(v) => v.data_type(),
+            Self::BinaryOp { left, op, right } => match op {
+                BinaryOperator::Plus
+                | BinaryOperator::Minus
+                | BinaryOperator::Multiply
+                | BinaryOperator::Divide
+                | BinaryOperator::Modulo => {
+                    arithmetic_type(left.data_type(schema), right.data_type(schema))
+                }
+                BinaryOperator::StringConcat => DataType::Text,
+                _ => DataType::Boolean,
+            },
+            Self::UnaryOp { op, expr } => match op {
+                UnaryOperator::Not => DataType::Boolean,
+                _ => expr.data_type(schema),
+            },
+            Self::IsNull(_)
+            | Self::IsNotNull(_)
+            | Self::Between { .. }
+            | Self::InList { .. }
+            | Self::Like { .. } => DataType::Boolean,
+            Self::Case {
+                when_then,
+                else_result,
+                ..
+            } => when_then
+                .iter()
+                .map(|(_, then)| then)
+                .chain(else_result.as_deref())
+                .map(|x| x.data_type(schema))
+                .find(|x| *x != DataType::Unspecified)
+                .unwrap_or(DataType::Unspecified),
+            Self::Cast { data_type, .. } => data_type.clone(),
+            Self::Coalesce(args) => args
+                .iter()
+                .map(|x| x.data_type(schema))
+                .find(|x| *x != DataType::Unspecified)
+                .unwrap_or(DataType::Unspecified),
+            Self::NullIf(left, _) => left.data_type(schema),
+            Self::Function { func, args } => func
+                .signature
+                .return_type(&args.iter().map(|x| x.data_type(schema)).collect::<Vec<_>>()),
+            Self::Aggregate(agg) => agg.data_type(schema),
+            Self::Window(window) => window.data_type(schema),
+            Self::OuterColumn(_, data_type) => data_type.clone(),
+            Self::Subquery(subquery) => subquery.schema().fields[0].datatype.clone(),
+            Self::Exists { .. } | Self::InSubquery { .. } => DataType::Boolean,
+        }
+    }
+}
+
+/// How tightly an operator binds, used to work out where brackets are needed when printing.
+fn precedence(op: &BinaryOperator) -> u8 {
+    match op {
+        BinaryOperator::Or => 1,
+        BinaryOperator::And => 2,
+        BinaryOperator::Eq
+        | BinaryOperator::NotEq
+        | BinaryOperator::Lt
+        | BinaryOperator::LtEq
+        | BinaryOperator::Gt
+        | BinaryOperator::GtEq => 4,
+        BinaryOperator::StringConcat => 5,
+        BinaryOperator::Plus | BinaryOperator::Minus => 6,
+        _ => 7,
+    }
+}
+
+impl fmt::Display for ScalarExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Column(c) => write!(f, "{}", c),
+            Self::Literal(Value::Text(s)) => write!(f, "'{}'", s.replace('\'', "''")),
+            Self::Literal(v) => write!(f, "{}", v),
+            Self::BinaryOp { left, op, right } => {
+                let prec = precedence(op);
+                match left.as_ref() {
+                    Self::BinaryOp { op: inner, .. } if precedence(inner) < prec => {
+                        write!(f, "({})", left)?
+                    }
+                    _ => write!(f, "{}", left)?,
+                }
+                write!(f, " {} ", op)?;
+                match right.as_ref() {
+                    Self::BinaryOp { op: inner, .. } if precedence(inner) <= prec => {
+                        write!(f, "({})", right)
+                    }
+                    _ => write!(f, "{}", right),
+                }
+            }
+            Self::UnaryOp { op, expr } => match (op, expr.as_ref()) {
+                (UnaryOperator::Not, _) => write!(f, "NOT {}", expr),
+                (_, Self::BinaryOp { .. }) => write!(f, "{}({})", op, expr),
+                _ => write!(f, "{}{}", op, expr),
+            },
+            Self::IsNull(expr) => write!(f, "{} IS NULL", expr),
+            Self::IsNotNull(expr) => write!(f, "{} IS NOT NULL", expr),
+            Self::Between {
+                expr,
+                negated,
+                low,
+                high,
+            } => write!(
+                f,
+                "{} {}BETWEEN {} AND {}",
+                expr,
+                if *negated { "NOT " } else { "" },
+                low,
+                high
+            ),
+            Self::InList {
+                expr,
+                list: values,
+                negated,
+            } => write!(
+                f,
+                "{} {}IN ({})",
+                expr,
+                if *negated { "NOT " } else { "" },
+                list(values)
+            ),
+            Self::Like {
+                expr,
+                pattern,
+                negated,
+                case_insensitive,
+                escape,
+            } => {
+                write!(
+                    f,
+                    "{} {}{} {}",
+                    expr,
+                    if *negated { "NOT " } else { "" },
+                    if *case_insensitive { "ILIKE" } else { "LIKE" },
+                    pattern
+                )?;
+                if let Some(escape) = escape {
+                    write!(f, " ESCAPE '{}'", escape)?;
+                }
+                Ok(())
+            }
+            Self::Case {
+                operand,
+                when_then,
+                else_result,
+            } => {
+                write!(f, "CASE")?;
+                if let Some(operand) = operand {
+                    write!(f, " {}", operand)?;
+                }
+                for (when, then) in when_then {
+                    write!(f, " WHEN {} THEN {}", when, then)?;
+                }
+                if let Some(else_result) = else_result {
+                    write!(f, " ELSE {}", else_result)?;
+                }
+                write!(f, " END")
+            }
+            Self::Cast { expr, data_type } => write!(f, "CAST({} AS {})", expr, data_type),
+            Self::Coalesce(args) => write!(f, "coalesce({})", list(args)),
+            Self::NullIf(left, right) => write!(f, "nullif({}, {})", left, right),
+            Self::Function { func, args } => write!(f, "{}({})", func.name, list(args)),
+            Self::Aggregate(agg) => write!(f, "{}", agg),
+            Self::Window(window) => write!(f, "{}", window),
+            Self::OuterColumn(c, _) => write!(f, "outer({})", c),
+            Self::Subquery(_) => write!(f, "(<subquery>)"),
+            Self::Exists { negated, .. } => {
+                write!(
+                    f,
+                    "{}EXISTS (<subquery>)",
+                    if *negated { "NOT " } else { "" }
+                )
+            }
+            Self::InSubquery { expr, negated, .. } => write!(
+                f,
+                "{} {}IN (<subquery>)",
+                expr,
+                if *negated { "NOT " } else { "" }
+            ),
+        }
+    }
+}
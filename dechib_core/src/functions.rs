@@ -0,0 +1,896 @@
+//! Scalar functions which can be called from SQL. Each function declares the types it takes and
+//! returns so calls can be checked while planning, before we've looked at any rows. User defined
+//! aggregates live here too, the built in ones are part of the executor.
+use crate::evaluator::{binary_op, limit_scale};
+use crate::types::{TypeClass, Value};
+use bigdecimal::{BigDecimal, RoundingMode, ToPrimitive, Zero};
+use sqlparser::ast::{BinaryOperator, DataType, ExactNumberInfo};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+
+/// Implements a function, it's given the arguments which have already been evaluated.
+pub type FunctionImpl = Arc<dyn Fn(&[Value]) -> anyhow::Result<Value> + Send + Sync>;
+
+/// The type a function accepts for an argument
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArgType {
+    Text,
+    Boolean,
+    Number,
+    Bytes,
+    Any,
+}
+
+impl ArgType {
+    fn class(&self) -> Option<TypeClass> {
+        match self {
+            Self::Text => Some(TypeClass::Text),
+            Self::Boolean => Some(TypeClass::Boolean),
+            Self::Number => Some(TypeClass::Number),
+            Self::Bytes => Some(TypeClass::Bytes),
+            Self::Any => None,
+        }
+    }
+
+    /// If we don't know the type, say for a `NULL`, we let it through and it gets checked again
+    /// when the function is called.
+    pub fn accepts(&self, data_type: &DataType) -> bool {
+        match (self.class(), TypeClass::of(data_type)) {
+            (Some(expected), Some(actual)) => expected == actual,
+            _ => true,
+        }
+    }
+
+    fn accepts_value(&self, value: &Value) -> bool {
+        value.is_null() || self.accepts(&value.data_type())
+    }
+}
+
+impl fmt::Display for ArgType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Self::Text => "text",
+            Self::Boolean => "boolean",
+            Self::Number => "numeric",
+            Self::Bytes => "bytea",
+            Self::Any => "any",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Whether a function always gives the same result for the same arguments, same as Postgres.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Volatility {
+    /// Always the same, calls with constant arguments are evaluated when the query is planned
+    Immutable,
+    /// The same within a statement but might change between them, say if it looks something up
+    Stable,
+    /// Could be different every time it's called, like `random()`, so it's called once per row
+    Volatile,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReturnType {
+    Exact(DataType),
+    /// Whatever type the first argument with a known type has, for things like `abs`
+    SameAsArgument,
+}
+
+/// The arguments and return type of a function. The last `optional` arguments can be left out,
+/// and for a variadic function the last argument can be repeated any number of times.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Signature {
+    pub args: Vec<ArgType>,
+    pub optional: usize,
+    pub variadic: bool,
+    pub return_type: ReturnType,
+}
+
+impl Signature {
+    pub fn new(args: Vec<ArgType>, return_type: ReturnType) -> Self {
+        Self {
+            args,
+            optional: 0,
+            variadic: false,
+            return_type,
+        }
+    }
+
+    pub fn with_optional(mut self, optional: usize) -> Self {
+        assert!(optional <= self.args.len());
+        self.optional = optional;
+        self
+    }
+
+    pub fn variadic(mut self) -> Self {
+        assert!(!self.args.is_empty());
+        self.variadic = true;
+        self
+    }
+
+    /// The type expected for the nth argument, `None` if there can't be that many.
+    pub fn arg(&self, n: usize) -> Option<ArgType> {
+        match self.args.get(n) {
+            Some(arg) => Some(*arg),
+            None if self.variadic => self.args.last().copied(),
+            None => None,
+        }
+    }
+
+    fn accepts_count(&self, count: usize) -> bool {
+        count >= self.args.len() - self.optional && (self.variadic || count <= self.args.len())
+    }
+
+    pub fn return_type(&self, args: &[DataType]) -> DataType {
+        match &self.return_type {
+            ReturnType::Exact(ty) => ty.clone(),
+            ReturnType::SameAsArgument => args
+                .iter()
+                .find(|x| **x != DataType::Unspecified)
+                .cloned()
+                .unwrap_or(DataType::Unspecified),
+        }
+    }
+
+    /// Checks the types of the arguments to a call of the function `name`, returning what the
+    /// call will return.
+    pub fn check(&self, name: &str, args: &[DataType]) -> anyhow::Result<DataType> {
+        if !self.accepts_count(args.len()) {
+            anyhow::bail!(
+                "Wrong number of arguments to {}, expected {}",
+                name,
+                self.display(name)
+            );
+        }
+        for (i, ty) in args.iter().enumerate() {
+            let expected = self.arg(i).unwrap();
+            if !expected.accepts(ty) {
+                anyhow::bail!(
+                    "Argument {} to {} must be {} but got {}",
+                    i + 1,
+                    name,
+                    expected,
+                    ty
+                );
+            }
+        }
+        Ok(self.return_type(args))
+    }
+
+    /// Same as `check` but for the values actually passed in, in case a type wasn't known
+    /// when planning.
+    fn check_values(&self, name: &str, args: &[Value]) -> anyhow::Result<()> {
+        for (i, value) in args.iter().enumerate() {
+            match self.arg(i) {
+                Some(expected) if expected.accepts_value(value) => {}
+                Some(expected) => anyhow::bail!(
+                    "Argument {} to {} must be {} but got {}",
+                    i + 1,
+                    name,
+                    expected,
+                    value
+                ),
+                None => anyhow::bail!("Too many arguments to {}", name),
+            }
+        }
+        Ok(())
+    }
+
+    fn display(&self, name: &str) -> String {
+        let required = self.args.len() - self.optional;
+        let mut args = self
+            .args
+            .iter()
+            .enumerate()
+            .map(|(i, x)| {
+                if i >= required {
+                    format!("[{}]", x)
+                } else {
+                    x.to_string()
+                }
+            })
+            .collect::<Vec<_>>();
+        if self.variadic {
+            args.push("...".to_string());
+        }
+        format!("{}({})", name, args.join(", "))
+    }
+}
+
+pub struct ScalarFunction {
+    pub name: String,
+    pub signature: Signature,
+    /// Most functions return null if any of their arguments are null, in which case we don't
+    /// bother calling them.
+    pub strict: bool,
+    pub volatility: Volatility,
+    implementation: FunctionImpl,
+}
+
+impl ScalarFunction {
+    pub fn new(
+        name: impl Into<String>,
+        signature: Signature,
+        volatility: Volatility,
+        implementation: impl Fn(&[Value]) -> anyhow::Result<Value> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name: name.into().to_lowercase(),
+            signature,
+            strict: true,
+            volatility,
+            implementation: Arc::new(implementation),
+        }
+    }
+
+    /// Nulls are passed to the function instead of it returning null
+    pub fn called_on_null(mut self) -> Self {
+        self.strict = false;
+        self
+    }
+
+    /// Checks the arguments against the signature, returning what the call will return.
+    pub fn check(&self, args: &[DataType]) -> anyhow::Result<DataType> {
+        self.signature.check(&self.name, args)
+    }
+
+    pub fn invoke(&self, args: &[Value]) -> anyhow::Result<Value> {
+        if self.strict && args.iter().any(|x| x.is_null()) {
+            return Ok(Value::Null);
+        }
+        self.signature.check_values(&self.name, args)?;
+        (self.implementation)(args)
+    }
+}
+
+impl fmt::Debug for ScalarFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ScalarFunction")
+            .field("name", &self.name)
+            .field("signature", &self.signature)
+            .field("strict", &self.strict)
+            .field("volatility", &self.volatility)
+            .finish()
+    }
+}
+
+/// Functions are identified by name, there's only ever one function with a given name
+impl PartialEq for ScalarFunction {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for ScalarFunction {}
+
+/// Implements an aggregate function. Each group gets its own `State` from `init` which the rows
+/// are added to, and `finalize` turns it into the result. Partial states for the same group can
+/// be combined with `merge`, in which case the order rows are seen in isn't guaranteed.
+pub trait Aggregator: Send + Sync + 'static {
+    type State: 'static;
+
+    fn init(&self) -> Self::State;
+
+    fn accumulate(&self, state: &mut Self::State, args: &[Value]) -> anyhow::Result<()>;
+
+    /// Adds everything in `other` to `state`
+    fn merge(&self, state: &mut Self::State, other: Self::State) -> anyhow::Result<()>;
+
+    fn finalize(&self, state: Self::State) -> anyhow::Result<Value>;
+
+    /// Turns a partial state into a value so it can be written to disk when there are too many
+    /// groups to keep in memory.
+    fn save(&self, state: Self::State) -> anyhow::Result<Value>;
+
+    /// Turns a value from `save` back into the partial state.
+    fn load(&self, value: Value) -> anyhow::Result<Self::State>;
+}
+
+/// `Aggregator` with the state type erased so different aggregates can be stored together.
+trait DynAggregator: Send + Sync {
+    fn init(&self) -> Box<dyn Any>;
+    fn accumulate(&self, state: &mut dyn Any, args: &[Value]) -> anyhow::Result<()>;
+    fn merge(&self, state: &mut dyn Any, other: Box<dyn Any>) -> anyhow::Result<()>;
+    fn finalize(&self, state: Box<dyn Any>) -> anyhow::Result<Value>;
+    fn save(&self, state: Box<dyn Any>) -> anyhow::Result<Value>;
+    fn load(&self, value: Value) -> anyhow::Result<Box<dyn Any>>;
+}
+
+impl<A: Aggregator> DynAggregator for A {
+    fn init(&self) -> Box<dyn Any> {
+        Box::new(Aggregator::init(self))
+    }
+
+    fn accumulate(&self, state: &mut dyn Any, args: &[Value]) -> anyhow::Result<()> {
+        Aggregator::accumulate(self, state.downcast_mut().unwrap(), args)
+    }
+
+    fn merge(&self, state: &mut dyn Any, other: Box<dyn Any>) -> anyhow::Result<()> {
+        Aggregator::merge(
+            self,
+            state.downcast_mut().unwrap(),
+            *other.downcast().unwrap(),
+        )
+    }
+
+    fn finalize(&self, state: Box<dyn Any>) -> anyhow::Result<Value> {
+        Aggregator::finalize(self, *state.downcast().unwrap())
+    }
+
+    fn save(&self, state: Box<dyn Any>) -> anyhow::Result<Value> {
+        Aggregator::save(self, *state.downcast().unwrap())
+    }
+
+    fn load(&self, value: Value) -> anyhow::Result<Box<dyn Any>> {
+        Ok(Box::new(Aggregator::load(self, value)?))
+    }
+}
+
+/// The state of a user defined aggregate for a single group.
+pub struct AggregatorState(Box<dyn Any>);
+
+pub struct AggregateUdf {
+    pub name: String,
+    pub signature: Signature,
+    /// Like the built in aggregates rows with a null argument are skipped by default
+    pub strict: bool,
+    implementation: Arc<dyn DynAggregator>,
+}
+
+impl AggregateUdf {
+    pub fn new(name: impl Into<String>, signature: Signature, aggregator: impl Aggregator) -> Self {
+        Self {
+            name: name.into().to_lowercase(),
+            signature,
+            strict: true,
+            implementation: Arc::new(aggregator),
+        }
+    }
+
+    /// Rows with null arguments are passed to the aggregate instead of being skipped
+    pub fn called_on_null(mut self) -> Self {
+        self.strict = false;
+        self
+    }
+
+    /// Checks the arguments against the signature, returning the type of the result.
+    pub fn check(&self, args: &[DataType]) -> anyhow::Result<DataType> {
+        self.signature.check(&self.name, args)
+    }
+
+    pub(crate) fn init(&self) -> AggregatorState {
+        AggregatorState(self.implementation.init())
+    }
+
+    pub(crate) fn accumulate(
+        &self,
+        state: &mut AggregatorState,
+        args: &[Value],
+    ) -> anyhow::Result<()> {
+        self.signature.check_values(&self.name, args)?;
+        self.implementation.accumulate(state.0.as_mut(), args)
+    }
+
+    pub(crate) fn merge(
+        &self,
+        state: &mut AggregatorState,
+        other: AggregatorState,
+    ) -> anyhow::Result<()> {
+        self.implementation.merge(state.0.as_mut(), other.0)
+    }
+
+    pub(crate) fn finalize(&self, state: AggregatorState) -> anyhow::Result<Value> {
+        self.implementation.finalize(state.0)
+    }
+
+    pub(crate) fn save(&self, state: AggregatorState) -> anyhow::Result<Value> {
+        self.implementation.save(state.0)
+    }
+
+    pub(crate) fn load(&self, value: Value) -> anyhow::Result<AggregatorState> {
+        Ok(AggregatorState(self.implementation.load(value)?))
+    }
+}
+
+impl fmt::Debug for AggregateUdf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AggregateUdf")
+            .field("name", &self.name)
+            .field("signature", &self.signature)
+            .field("strict", &self.strict)
+            .finish()
+    }
+}
+
+impl PartialEq for AggregateUdf {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for AggregateUdf {}
+
+#[derive(Clone, Debug, Default)]
+pub struct FunctionRegistry {
+    functions: BTreeMap<String, Arc<ScalarFunction>>,
+    aggregates: BTreeMap<String, Arc<AggregateUdf>>,
+}
+
+impl FunctionRegistry {
+    pub fn get(&self, name: &str) -> Option<Arc<ScalarFunction>> {
+        self.functions.get(&name.to_lowercase()).cloned()
+    }
+
+    pub fn aggregate(&self, name: &str) -> Option<Arc<AggregateUdf>> {
+        self.aggregates.get(&name.to_lowercase()).cloned()
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        self.functions.contains_key(name) || self.aggregates.contains_key(name)
+    }
+
+    pub fn register(&mut self, func: ScalarFunction) -> anyhow::Result<()> {
+        if self.exists(&func.name) {
+            anyhow::bail!("Function {} already exists", func.name);
+        }
+        self.functions.insert(func.name.clone(), Arc::new(func));
+        Ok(())
+    }
+
+    pub fn register_aggregate(&mut self, func: AggregateUdf) -> anyhow::Result<()> {
+        if self.exists(&func.name) {
+            anyhow::bail!("Function {} already exists", func.name);
+        }
+        self.aggregates.insert(func.name.clone(), Arc::new(func));
+        Ok(())
+    }
+
+    fn alias(&mut self, alias: &str, name: &str) {
+        let func = self.functions[name].as_ref();
+        let func = ScalarFunction {
+            name: alias.to_string(),
+            signature: func.signature.clone(),
+            strict: func.strict,
+            volatility: func.volatility,
+            implementation: func.implementation.clone(),
+        };
+        self.functions.insert(func.name.clone(), Arc::new(func));
+    }
+
+    pub fn builtin() -> &'static FunctionRegistry {
+        static BUILTIN: OnceLock<FunctionRegistry> = OnceLock::new();
+        BUILTIN.get_or_init(builtin_functions)
+    }
+}
+
+fn text(value: &Value) -> &str {
+    match value {
+        Value::Text(s) => s,
+        v => unreachable!("{} should have been checked to be text", v),
+    }
+}
+
+fn number(value: &Value) -> &BigDecimal {
+    match value {
+        Value::Number(n) => n,
+        v => unreachable!("{} should have been checked to be a number", v),
+    }
+}
+
+/// An integer argument like a position or number of digits
+fn int(value: &Value, name: &str) -> anyhow::Result<i64> {
+    let n = number(value);
+    if !n.is_integer() {
+        anyhow::bail!("{} must be an integer but got {}", name, n);
+    }
+    n.to_i64()
+        .ok_or_else(|| anyhow::anyhow!("{} is out of range: {}", name, n))
+}
+
+fn trim(args: &[Value], start: bool, end: bool) -> Value {
+    let chars = args.get(1).map(text).unwrap_or(" ");
+    let pattern = |c: char| chars.contains(c);
+    let mut s = text(&args[0]);
+    if start {
+        s = s.trim_start_matches(pattern);
+    }
+    if end {
+        s = s.trim_end_matches(pattern);
+    }
+    Value::from(s)
+}
+
+fn round(n: &BigDecimal, digits: i64, mode: RoundingMode) -> BigDecimal {
+    let res = n.with_scale_round(digits, mode);
+    // Negative scales print in exponent form
+    if digits < 0 {
+        res.with_scale(0)
+    } else {
+        res
+    }
+}
+
+/// Raising to an integer power is done exactly as long as the result won't be silly big,
+/// everything else goes through floats
+fn power(base: &BigDecimal, exponent: &BigDecimal) -> anyhow::Result<BigDecimal> {
+    if base.is_zero() && exponent.sign() == bigdecimal::num_bigint::Sign::Minus {
+        anyhow::bail!("Zero raised to a negative power is undefined");
+    }
+    if let Some(exp) = exponent.to_i64().filter(|_| exponent.is_integer()) {
+        if exp.unsigned_abs() <= 1024 {
+            let mut res = BigDecimal::from(1);
+            let mut base = base.clone();
+            let mut n = exp.unsigned_abs();
+            while n > 0 {
+                if n & 1 == 1 {
+                    res *= &base;
+                }
+                base = &base * &base;
+                n >>= 1;
+            }
+            return Ok(if exp < 0 {
+                limit_scale(BigDecimal::from(1) / res)
+            } else {
+                res
+            });
+        }
+    }
+    let res = base
+        .to_f64()
+        .zip(exponent.to_f64())
+        .map(|(b, e)| b.powf(e))
+        .filter(|x| x.is_finite())
+        .ok_or_else(|| anyhow::anyhow!("Can't raise {} to the power of {}", base, exponent))?;
+    Ok(res.to_string().parse()?)
+}
+
+/// The largest or smallest argument, nulls are ignored
+fn extreme(args: &[Value], op: BinaryOperator) -> anyhow::Result<Value> {
+    let mut res = Value::Null;
+    for arg in args.iter().filter(|x| !x.is_null()) {
+        if res.is_null() || binary_op(arg, &op, &res)? == Value::Boolean(true) {
+            res = arg.clone();
+        }
+    }
+    Ok(res)
+}
+
+fn builtin_functions() -> FunctionRegistry {
+    use ArgType::*;
+    use Volatility::Immutable;
+    let text_type = || ReturnType::Exact(DataType::Text);
+    let int_type = || ReturnType::Exact(DataType::Integer(None));
+    let numeric_type = || ReturnType::Exact(DataType::Numeric(ExactNumberInfo::None));
+    let mut registry = FunctionRegistry::default();
+    let mut add = |func: ScalarFunction| {
+        registry.functions.insert(func.name.clone(), Arc::new(func));
+    };
+
+    add(ScalarFunction::new(
+        "lower",
+        Signature::new(vec![Text], text_type()),
+        Immutable,
+        |args| Ok(Value::from(text(&args[0]).to_lowercase())),
+    ));
+    add(ScalarFunction::new(
+        "upper",
+        Signature::new(vec![Text], text_type()),
+        Immutable,
+        |args| Ok(Value::from(text(&args[0]).to_uppercase())),
+    ));
+    add(ScalarFunction::new(
+        "length",
+        Signature::new(vec![Text], int_type()),
+        Immutable,
+        |args| Ok(Value::from(text(&args[0]).chars().count() as i64)),
+    ));
+    add(ScalarFunction::new(
+        "substr",
+        Signature::new(vec![Text, Number, Number], text_type()).with_optional(1),
+        Immutable,
+        |args| {
+            // Positions start at 1 but the start can be before that, in which case the length
+            // is still counted from the start
+            let start = int(&args[1], "Substring start")?;
+            let end = match args.get(2) {
+                Some(len) => {
+                    let len = int(len, "Substring length")?;
+                    if len < 0 {
+                        anyhow::bail!("Substring length can't be negative");
+                    }
+                    start.saturating_add(len)
+                }
+                None => i64::MAX,
+            };
+            let res = text(&args[0])
+                .chars()
+                .zip(1..)
+                .filter(|(_, i)| *i >= start && *i < end)
+                .map(|(c, _)| c)
+                .collect::<String>();
+            Ok(Value::from(res))
+        },
+    ));
+    add(ScalarFunction::new(
+        "trim",
+        Signature::new(vec![Text, Text], text_type()).with_optional(1),
+        Immutable,
+        |args| Ok(trim(args, true, true)),
+    ));
+    add(ScalarFunction::new(
+        "ltrim",
+        Signature::new(vec![Text, Text], text_type()).with_optional(1),
+        Immutable,
+        |args| Ok(trim(args, true, false)),
+    ));
+    add(ScalarFunction::new(
+        "rtrim",
+        Signature::new(vec![Text, Text], text_type()).with_optional(1),
+        Immutable,
+        |args| Ok(trim(args, false, true)),
+    ));
+    add(ScalarFunction::new(
+        "replace",
+        Signature::new(vec![Text, Text, Text], text_type()),
+        Immutable,
+        |args| {
+            let (s, from) = (text(&args[0]), text(&args[1]));
+            if from.is_empty() {
+                return Ok(Value::from(s));
+            }
+            Ok(Value::from(s.replace(from, text(&args[2]))))
+        },
+    ));
+    add(ScalarFunction::new(
+        "concat",
+        Signature::new(vec![Any], text_type()).variadic(),
+        Immutable,
+        |args| {
+            let res = args
+                .iter()
+                .filter(|x| !x.is_null())
+                .map(|x| x.to_string())
+                .collect::<String>();
+            Ok(Value::from(res))
+        },
+    )
+    .called_on_null());
+    add(ScalarFunction::new(
+        "position",
+        Signature::new(vec![Text, Text], int_type()),
+        Immutable,
+        |args| {
+            let (needle, haystack) = (text(&args[0]), text(&args[1]));
+            let res = match haystack.find(needle) {
+                Some(i) => haystack[..i].chars().count() as i64 + 1,
+                None => 0,
+            };
+            Ok(Value::from(res))
+        },
+    ));
+
+    add(ScalarFunction::new(
+        "abs",
+        Signature::new(vec![Number], ReturnType::SameAsArgument),
+        Immutable,
+        |args| Ok(Value::Number(number(&args[0]).abs())),
+    ));
+    add(ScalarFunction::new(
+        "round",
+        Signature::new(vec![Number, Number], ReturnType::SameAsArgument).with_optional(1),
+        Immutable,
+        |args| {
+            let digits = match args.get(1) {
+                Some(digits) => int(digits, "Number of digits")?,
+                None => 0,
+            };
+            let res = round(number(&args[0]), digits, RoundingMode::HalfUp);
+            Ok(Value::Number(res))
+        },
+    ));
+    add(ScalarFunction::new(
+        "floor",
+        Signature::new(vec![Number], ReturnType::SameAsArgument),
+        Immutable,
+        |args| {
+            Ok(Value::Number(round(
+                number(&args[0]),
+                0,
+                RoundingMode::Floor,
+            )))
+        },
+    ));
+    add(ScalarFunction::new(
+        "ceil",
+        Signature::new(vec![Number], ReturnType::SameAsArgument),
+        Immutable,
+        |args| {
+            Ok(Value::Number(round(
+                number(&args[0]),
+                0,
+                RoundingMode::Ceiling,
+            )))
+        },
+    ));
+    add(ScalarFunction::new(
+        "mod",
+        Signature::new(vec![Number, Number], ReturnType::SameAsArgument),
+        Immutable,
+        |args| binary_op(&args[0], &BinaryOperator::Modulo, &args[1]),
+    ));
+    add(ScalarFunction::new(
+        "power",
+        Signature::new(vec![Number, Number], numeric_type()),
+        Immutable,
+        |args| Ok(Value::Number(power(number(&args[0]), number(&args[1]))?)),
+    ));
+    add(ScalarFunction::new(
+        "sqrt",
+        Signature::new(vec![Number], numeric_type()),
+        Immutable,
+        |args| match number(&args[0]).sqrt() {
+            Some(n) => Ok(Value::Number(limit_scale(n))),
+            None => anyhow::bail!("Can't take the square root of a negative number"),
+        },
+    ));
+
+    add(ScalarFunction::new(
+        "greatest",
+        Signature::new(vec![Any], ReturnType::SameAsArgument).variadic(),
+        Immutable,
+        |args| extreme(args, BinaryOperator::Gt),
+    )
+    .called_on_null());
+    add(ScalarFunction::new(
+        "least",
+        Signature::new(vec![Any], ReturnType::SameAsArgument).variadic(),
+        Immutable,
+        |args| extreme(args, BinaryOperator::Lt),
+    )
+    .called_on_null());
+    add(ScalarFunction::new(
+        "ifnull",
+        Signature::new(vec![Any, Any], ReturnType::SameAsArgument),
+        Immutable,
+        |args| match &args[0] {
+            Value::Null => Ok(args[1].clone()),
+            v => Ok(v.clone()),
+        },
+    )
+    .called_on_null());
+
+    registry.alias("substring", "substr");
+    registry.alias("char_length", "length");
+    registry.alias("ceiling", "ceil");
+    registry.alias("pow", "power");
+    registry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evaluator::evaluate_constant;
+    use crate::types::ColumnDescriptors;
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+
+    fn eval(sql: &str) -> anyhow::Result<Value> {
+        let expr = Parser::new(&GenericDialect {})
+            .try_with_sql(sql)?
+            .parse_expr()?;
+        evaluate_constant(&expr, &BTreeMap::<String, ColumnDescriptors>::new())
+    }
+
+    fn number(s: &str) -> Value {
+        Value::Number(s.parse().unwrap())
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(eval("lower('HeLLo')").unwrap(), Value::from("hello"));
+        assert_eq!(eval("UPPER('straße')").unwrap(), Value::from("STRASSE"));
+        assert_eq!(eval("length('héllo')").unwrap(), Value::from(5));
+        assert_eq!(eval("char_length('')").unwrap(), Value::from(0));
+        assert_eq!(eval("substr('hello', 2)").unwrap(), Value::from("ello"));
+        assert_eq!(eval("substr('hello', 2, 3)").unwrap(), Value::from("ell"));
+        assert_eq!(eval("substr('hello', 0, 2)").unwrap(), Value::from("h"));
+        assert_eq!(
+            eval("SUBSTRING('hello' FROM 4)").unwrap(),
+            Value::from("lo")
+        );
+        assert_eq!(
+            eval("SUBSTRING('hello' FROM 1 FOR 1)").unwrap(),
+            Value::from("h")
+        );
+        assert!(eval("substr('hello', 1, -1)").is_err());
+        assert!(eval("substr('hello', 1.5)").is_err());
+        assert_eq!(eval("trim('  hi  ')").unwrap(), Value::from("hi"));
+        assert_eq!(
+            eval("TRIM(LEADING 'x' FROM 'xxhix')").unwrap(),
+            Value::from("hix")
+        );
+        assert_eq!(eval("rtrim('hi!?!', '!?')").unwrap(), Value::from("hi"));
+        assert_eq!(
+            eval("replace('a-b-c', '-', '+')").unwrap(),
+            Value::from("a+b+c")
+        );
+        assert_eq!(eval("replace('abc', '', '+')").unwrap(), Value::from("abc"));
+        assert_eq!(
+            eval("concat('a', NULL, 1, true)").unwrap(),
+            Value::from("a1true")
+        );
+        assert_eq!(eval("POSITION('l' IN 'héllo')").unwrap(), Value::from(3));
+        assert_eq!(eval("POSITION('z' IN 'hello')").unwrap(), Value::from(0));
+        assert_eq!(eval("upper(NULL)").unwrap(), Value::Null);
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(eval("abs(-2.5)").unwrap(), number("2.5"));
+        assert_eq!(eval("round(2.5)").unwrap(), Value::from(3));
+        assert_eq!(eval("round(-2.5)").unwrap(), Value::from(-3));
+        assert_eq!(eval("round(3.14159, 2)").unwrap(), number("3.14"));
+        assert_eq!(eval("round(1250, -2)").unwrap(), Value::from(1300));
+        assert_eq!(eval("round(1250, -2)").unwrap().to_string(), "1300");
+        assert_eq!(eval("floor(-1.5)").unwrap(), Value::from(-2));
+        assert_eq!(eval("FLOOR(1.5)").unwrap(), Value::from(1));
+        assert_eq!(eval("CEIL(-1.5)").unwrap(), Value::from(-1));
+        assert_eq!(eval("ceiling(1.1)").unwrap(), Value::from(2));
+        assert_eq!(eval("mod(-7, 3)").unwrap(), Value::from(-1));
+        assert!(eval("mod(1, 0)").is_err());
+        assert_eq!(eval("power(2, 10)").unwrap(), Value::from(1024));
+        assert_eq!(eval("power(2, -2)").unwrap(), number("0.25"));
+        assert_eq!(eval("pow(4, 0.5)").unwrap(), Value::from(2));
+        assert!(eval("power(0, -1)").is_err());
+        assert_eq!(eval("sqrt(16)").unwrap(), Value::from(4));
+        assert_eq!(eval("sqrt(16)").unwrap().to_string(), "4");
+        assert_eq!(eval("sqrt(2)").unwrap(), number("1.41421356237309504880"));
+        assert!(eval("sqrt(-1)").is_err());
+    }
+
+    #[test]
+    fn conditionals() {
+        assert_eq!(eval("greatest(1, 3, NULL, 2)").unwrap(), Value::from(3));
+        assert_eq!(eval("least('b', 'a', 'c')").unwrap(), Value::from("a"));
+        assert_eq!(eval("greatest(NULL, NULL)").unwrap(), Value::Null);
+        assert!(eval("greatest(1, 'a')").is_err());
+        assert_eq!(eval("ifnull(NULL, 2)").unwrap(), Value::from(2));
+        assert_eq!(eval("ifnull(1, 2)").unwrap(), Value::from(1));
+    }
+
+    #[test]
+    fn signatures() {
+        assert!(eval("nope(1)").is_err());
+        assert!(eval("upper()").is_err());
+        assert!(eval("upper('a', 'b')").is_err());
+        assert!(eval("upper(1)").is_err());
+        assert!(eval("abs('1')").is_err());
+        assert!(eval("upper(length('a'))").is_err());
+        assert!(eval("upper(1) OVER ()").is_err());
+
+        let func = FunctionRegistry::builtin().get("SUBSTR").unwrap();
+        assert_eq!(
+            func.check(&[DataType::Varchar(None), DataType::Integer(None)])
+                .unwrap(),
+            DataType::Text
+        );
+        assert!(func.check(&[DataType::Varchar(None)]).is_err());
+        assert!(func
+            .check(&[DataType::Boolean, DataType::Integer(None)])
+            .is_err());
+        assert_eq!(
+            FunctionRegistry::builtin()
+                .get("abs")
+                .unwrap()
+                .check(&[DataType::SmallInt(None)])
+                .unwrap(),
+            DataType::SmallInt(None)
+        );
+        // Types are checked again when the function's called as there might not be a type when
+        // planning
+        assert!(func.invoke(&[Value::from(1), Value::from(1)]).is_err());
+    }
+}
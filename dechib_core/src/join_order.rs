@@ -0,0 +1,648 @@
+//! Picks the order to join relations in. Inner and cross joins can be done in any order so a
+//! group of them is flattened into the relations being joined and the predicates between them,
+//! then we use DPccp from "Building Query Compilers" (Moerkotte) to find the cheapest join tree
+//! without considering cross products unless we have to. That's exponential so past a certain
+//! number of relations we just greedily join whichever pair gives the fewest rows.
+use crate::cost::{CostModel, Estimate};
+use crate::expression::ScalarExpr;
+use crate::logical_plan::{Catalog, JoinKind, LogicalPlan};
+use crate::optimiser::OptimiserRule;
+use sqlparser::ast::BinaryOperator;
+use std::collections::HashMap;
+use tracing::debug;
+
+/// Past this many relations we use the greedy ordering instead
+pub const DEFAULT_DP_LIMIT: usize = 10;
+/// Relations are tracked in a `u64` bitset
+const MAX_RELATIONS: usize = 64;
+
+pub struct JoinReorder<'a> {
+    catalog: &'a dyn Catalog,
+    dp_limit: usize,
+}
+
+impl<'a> JoinReorder<'a> {
+    pub fn new(catalog: &'a dyn Catalog) -> Self {
+        Self {
+            catalog,
+            dp_limit: DEFAULT_DP_LIMIT,
+        }
+    }
+
+    /// Sets the most relations we'll use dynamic programming for.
+    pub fn with_dp_limit(mut self, dp_limit: usize) -> Self {
+        self.dp_limit = dp_limit;
+        self
+    }
+
+    fn reorder(&self, model: &CostModel, plan: LogicalPlan) -> anyhow::Result<LogicalPlan> {
+        if !is_reorderable(&plan) {
+            return plan.map_inputs(|input| self.reorder(model, input));
+        }
+        let mut relations = vec![];
+        let mut predicates = vec![];
+        flatten(plan.clone(), &mut relations, &mut predicates);
+        let mut relations = relations
+            .into_iter()
+            .map(|x| self.reorder(model, x))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        // So we come up with the same plan when we see the output again
+        relations.sort_by_cached_key(|x| x.to_string());
+        predicates.sort_by_cached_key(|x| x.to_string());
+        if relations.len() < 3 || relations.len() > MAX_RELATIONS {
+            // Swapping the sides of a single join is left to `JoinCommute`
+            return plan.map_inputs(|input| self.reorder(model, input));
+        }
+        let Some(graph) = JoinGraph::new(relations, predicates) else {
+            return plan.map_inputs(|input| self.reorder(model, input));
+        };
+        let mut planner = Planner::new(model, &graph);
+        let all = below(graph.relations.len() - 1);
+        if graph.relations.len() <= self.dp_limit {
+            planner.dp_ccp();
+        }
+        if !planner.best.contains_key(&all) {
+            debug!(
+                relations = graph.relations.len(),
+                "using greedy join ordering"
+            );
+            planner.greedy();
+        }
+        let mut reordered = planner.build(all);
+        let remaining = graph
+            .predicates
+            .iter()
+            .filter(|p| p.relations == 0)
+            .map(|p| p.expr.clone());
+        if let Some(predicate) = ScalarExpr::conjunction(remaining) {
+            reordered = LogicalPlan::Filter {
+                predicate,
+                input: Box::new(reordered),
+            };
+        }
+        // Put the columns back in the order the rest of the plan expects them in
+        let schema = plan.schema();
+        if reordered.schema() != schema {
+            reordered = LogicalPlan::Project {
+                exprs: schema
+                    .fields
+                    .iter()
+                    .map(|f| ScalarExpr::Column(f.column()))
+                    .collect(),
+                schema: schema.clone(),
+                input: Box::new(reordered),
+            };
+        }
+        Ok(reordered)
+    }
+}
+
+impl OptimiserRule for JoinReorder<'_> {
+    fn name(&self) -> &'static str {
+        "join_reorder"
+    }
+
+    fn rewrite(&self, plan: LogicalPlan) -> anyhow::Result<LogicalPlan> {
+        self.reorder(&CostModel::new(self.catalog), plan)
+    }
+}
+
+fn is_reorderable(plan: &LogicalPlan) -> bool {
+    matches!(
+        plan,
+        LogicalPlan::Join {
+            kind: JoinKind::Inner | JoinKind::Cross,
+            ..
+        }
+    )
+}
+
+/// Projections which only shuffle the columns around, like the ones `JoinCommute` and this rule
+/// add, can be looked through as the columns are found by name anyway.
+fn is_reordering_projection(plan: &LogicalPlan) -> bool {
+    match plan {
+        LogicalPlan::Project {
+            exprs,
+            schema,
+            input,
+        } => {
+            exprs.len() == input.schema().len()
+                && exprs.iter().zip(&schema.fields).all(|(e, f)| match e {
+                    ScalarExpr::Column(c) => *c == f.column(),
+                    _ => false,
+                })
+        }
+        _ => false,
+    }
+}
+
+/// Collects the relations under a group of inner joins and all the predicates between them.
+fn flatten(plan: LogicalPlan, relations: &mut Vec<LogicalPlan>, predicates: &mut Vec<ScalarExpr>) {
+    match plan {
+        LogicalPlan::Join {
+            left,
+            right,
+            kind: JoinKind::Inner | JoinKind::Cross,
+            condition,
+            ..
+        } => {
+            flatten(*left, relations, predicates);
+            flatten(*right, relations, predicates);
+            predicates.extend(condition.map(|x| x.split_conjunction()).unwrap_or_default());
+        }
+        LogicalPlan::Filter { predicate, input } if is_reorderable(&input) => {
+            predicates.extend(predicate.split_conjunction());
+            flatten(*input, relations, predicates);
+        }
+        LogicalPlan::Project { input, .. } if is_reordering_projection(&plan) => {
+            flatten(*input, relations, predicates);
+        }
+        plan => relations.push(plan),
+    }
+}
+
+struct Predicate {
+    expr: ScalarExpr,
+    /// The relations the predicate uses
+    relations: u64,
+    /// For `a = b` the relations used by each side, these can be used for hash joins
+    equi: Option<(u64, u64)>,
+}
+
+struct JoinGraph {
+    relations: Vec<LogicalPlan>,
+    predicates: Vec<Predicate>,
+    /// The relations each relation shares a predicate with
+    neighbours: Vec<u64>,
+}
+
+impl JoinGraph {
+    /// `None` if a predicate uses a column we can't pin to a single relation.
+    fn new(mut relations: Vec<LogicalPlan>, predicates: Vec<ScalarExpr>) -> Option<Self> {
+        let relations_of = |e: &ScalarExpr| {
+            let mut set = 0u64;
+            for column in e.columns() {
+                let mut found = relations
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, r)| matches!(r.schema().find(column), Ok(Some(_))));
+                match (found.next(), found.next()) {
+                    (Some((i, _)), None) => set |= 1 << i,
+                    _ => return None,
+                }
+            }
+            Some(set)
+        };
+        let mut resolved = vec![];
+        for expr in predicates {
+            let equi = match &expr {
+                ScalarExpr::BinaryOp {
+                    left,
+                    op: BinaryOperator::Eq,
+                    right,
+                } => Some((relations_of(left)?, relations_of(right)?)),
+                _ => None,
+            };
+            let predicate = Predicate {
+                relations: relations_of(&expr)?,
+                equi: equi.filter(|(l, r)| *l != 0 && *r != 0 && l & r == 0),
+                expr,
+            };
+            resolved.push(predicate);
+        }
+
+        // Predicates on a single relation are applied before joining it
+        let mut predicates = vec![];
+        for predicate in resolved {
+            if predicate.relations.count_ones() == 1 {
+                let i = predicate.relations.trailing_zeros() as usize;
+                let input = relations[i].clone();
+                relations[i] = LogicalPlan::Filter {
+                    predicate: predicate.expr,
+                    input: Box::new(input),
+                };
+            } else {
+                predicates.push(predicate);
+            }
+        }
+
+        let mut neighbours = vec![0u64; relations.len()];
+        for predicate in &predicates {
+            for i in bits(predicate.relations) {
+                neighbours[i] |= predicate.relations & !(1 << i);
+            }
+        }
+        // If there's no predicates connecting two parts of the query we have to use a cross
+        // product somewhere, let them join with anything in the other parts.
+        let mut components: Vec<u64> = vec![];
+        for i in 0..relations.len() {
+            if components.iter().any(|c| c & (1 << i) != 0) {
+                continue;
+            }
+            let mut component = 1u64 << i;
+            loop {
+                let next = bits(component).fold(component, |acc, j| acc | neighbours[j]);
+                if next == component {
+                    break;
+                }
+                component = next;
+            }
+            components.push(component);
+        }
+        if components.len() > 1 {
+            for component in &components {
+                for i in bits(*component) {
+                    neighbours[i] |= !component & below(relations.len() - 1);
+                }
+            }
+        }
+        Some(Self {
+            relations,
+            predicates,
+            neighbours,
+        })
+    }
+
+    /// Relations next to `set` which aren't in it or `excluded`
+    fn neighbourhood(&self, set: u64, excluded: u64) -> u64 {
+        bits(set).fold(0, |acc, i| acc | self.neighbours[i]) & !set & !excluded
+    }
+
+    /// All the connected subgraphs containing `set`, grown only with relations not in `excluded`
+    fn enumerate_csg_rec(&self, set: u64, excluded: u64, out: &mut Vec<u64>) {
+        let neighbourhood = self.neighbourhood(set, excluded);
+        if neighbourhood == 0 {
+            return;
+        }
+        for subset in subsets(neighbourhood) {
+            out.push(set | subset);
+        }
+        for subset in subsets(neighbourhood) {
+            self.enumerate_csg_rec(set | subset, excluded | neighbourhood, out);
+        }
+    }
+
+    fn connected_subgraphs(&self) -> Vec<u64> {
+        let mut out = vec![];
+        for i in (0..self.relations.len()).rev() {
+            out.push(1 << i);
+            self.enumerate_csg_rec(1 << i, below(i), &mut out);
+        }
+        out
+    }
+
+    /// The connected subgraphs which are connected to `set` without overlapping it, each pair is
+    /// only produced once.
+    fn connected_complements(&self, set: u64) -> Vec<u64> {
+        let excluded = below(set.trailing_zeros() as usize) | set;
+        let neighbourhood = self.neighbourhood(set, excluded);
+        let mut out = vec![];
+        for i in bits(neighbourhood).collect::<Vec<_>>().into_iter().rev() {
+            out.push(1 << i);
+            self.enumerate_csg_rec(1 << i, excluded | (below(i) & neighbourhood), &mut out);
+        }
+        out
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Best {
+    estimate: Estimate,
+    split: Option<(u64, u64)>,
+}
+
+struct Planner<'a, 'b> {
+    model: &'a CostModel<'b>,
+    graph: &'a JoinGraph,
+    best: HashMap<u64, Best>,
+}
+
+impl<'a, 'b> Planner<'a, 'b> {
+    fn new(model: &'a CostModel<'b>, graph: &'a JoinGraph) -> Self {
+        let best = graph
+            .relations
+            .iter()
+            .enumerate()
+            .map(|(i, r)| {
+                let best = Best {
+                    estimate: model.estimate(r),
+                    split: None,
+                };
+                (1 << i, best)
+            })
+            .collect();
+        Self { model, graph, best }
+    }
+
+    /// The predicates which can be applied when joining the two sets but not before
+    fn predicates(&self, left: u64, right: u64) -> impl Iterator<Item = &'a Predicate> {
+        let set = left | right;
+        self.graph.predicates.iter().filter(move |p| {
+            p.relations != 0
+                && p.relations & set == p.relations
+                && p.relations & left != p.relations
+                && p.relations & right != p.relations
+        })
+    }
+
+    fn estimate(&self, left: u64, right: u64) -> Estimate {
+        let predicates = self.predicates(left, right).collect::<Vec<_>>();
+        let hashable = predicates.iter().any(|p| match p.equi {
+            Some((a, b)) => (a & left == a && b & right == b) || (a & right == a && b & left == b),
+            None => false,
+        });
+        let inputs = bits(left | right)
+            .map(|i| &self.graph.relations[i])
+            .collect::<Vec<_>>();
+        let kind = if predicates.is_empty() {
+            JoinKind::Cross
+        } else {
+            JoinKind::Inner
+        };
+        self.model.join_estimate(
+            self.best[&left].estimate,
+            self.best[&right].estimate,
+            kind,
+            &predicates.iter().map(|p| &p.expr).collect::<Vec<_>>(),
+            hashable,
+            &inputs,
+        )
+    }
+
+    /// Tries joining the sets both ways round, keeping it if it's the cheapest way to get their
+    /// union so far.
+    fn consider(&mut self, a: u64, b: u64) {
+        for (left, right) in [(a, b), (b, a)] {
+            let estimate = self.estimate(left, right);
+            let set = left | right;
+            if !matches!(self.best.get(&set), Some(x) if x.estimate.cost <= estimate.cost) {
+                let best = Best {
+                    estimate,
+                    split: Some((left, right)),
+                };
+                self.best.insert(set, best);
+            }
+        }
+    }
+
+    fn dp_ccp(&mut self) {
+        let mut pairs = vec![];
+        for set in self.graph.connected_subgraphs() {
+            for complement in self.graph.connected_complements(set) {
+                pairs.push((set, complement));
+            }
+        }
+        // Make sure we've found the best plan for both halves before using them
+        pairs.sort_by_key(|(a, b)| (a | b).count_ones());
+        for (a, b) in pairs {
+            self.consider(a, b);
+        }
+    }
+
+    /// Greedy operator ordering, repeatedly joins the pair of connected sets which produces the
+    /// fewest rows until there's only one left.
+    fn greedy(&mut self) {
+        let mut sets = (0..self.graph.relations.len())
+            .map(|i| 1u64 << i)
+            .collect::<Vec<_>>();
+        while sets.len() > 1 {
+            let mut chosen = None;
+            let mut fewest = f64::INFINITY;
+            for (i, a) in sets.iter().enumerate() {
+                for b in &sets[i + 1..] {
+                    if self.graph.neighbourhood(*a, 0) & b == 0 {
+                        continue;
+                    }
+                    let rows = self.estimate(*a, *b).rows;
+                    if rows < fewest {
+                        fewest = rows;
+                        chosen = Some((*a, *b));
+                    }
+                }
+            }
+            // The graph is always connected so there's always a pair
+            let (a, b) = chosen.expect("join graph is connected");
+            self.consider(a, b);
+            sets.retain(|x| *x != a && *x != b);
+            sets.push(a | b);
+        }
+    }
+
+    fn build(&self, set: u64) -> LogicalPlan {
+        match self.best[&set].split {
+            None => self.graph.relations[set.trailing_zeros() as usize].clone(),
+            Some((left, right)) => {
+                let condition =
+                    ScalarExpr::conjunction(self.predicates(left, right).map(|p| p.expr.clone()));
+                let kind = if condition.is_some() {
+                    JoinKind::Inner
+                } else {
+                    JoinKind::Cross
+                };
+                LogicalPlan::join(self.build(left), self.build(right), kind, condition)
+            }
+        }
+    }
+}
+
+/// The set of relations numbered `0..=i`
+fn below(i: usize) -> u64 {
+    if i >= 63 {
+        u64::MAX
+    } else {
+        (1 << (i + 1)) - 1
+    }
+}
+
+fn bits(set: u64) -> impl Iterator<Item = usize> {
+    (0..MAX_RELATIONS).filter(move |i| set & (1 << i) != 0)
+}
+
+/// Every non-empty subset of the set
+fn subsets(set: u64) -> impl Iterator<Item = u64> {
+    let mut next = Some(set).filter(|x| *x != 0);
+    std::iter::from_fn(move || {
+        let current = next?;
+        next = Some((current - 1) & set).filter(|x| *x != 0);
+        Some(current)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logical_plan::tests::{assert_plan_eq, plan, test_catalog};
+    use crate::optimiser::{Optimiser, PredicatePushdown};
+    use crate::types::{ColumnDescriptor, ColumnDescriptors};
+    use sqlparser::ast::DataType;
+    use std::collections::BTreeMap;
+    use tracing_test::traced_test;
+
+    #[test]
+    fn subgraph_enumeration() {
+        // A chain a - b - c - d
+        let graph = JoinGraph {
+            relations: vec![
+                LogicalPlan::Values {
+                    rows: vec![],
+                    schema: Default::default(),
+                };
+                4
+            ],
+            predicates: vec![],
+            neighbours: vec![0b0010, 0b0101, 0b1010, 0b0100],
+        };
+        let mut subgraphs = graph.connected_subgraphs();
+        subgraphs.sort();
+        assert_eq!(
+            subgraphs,
+            vec![0b0001, 0b0010, 0b0011, 0b0100, 0b0110, 0b0111, 0b1000, 0b1100, 0b1110, 0b1111]
+        );
+        let mut pairs = vec![];
+        for set in graph.connected_subgraphs() {
+            for complement in graph.connected_complements(set) {
+                assert_eq!(set & complement, 0);
+                pairs.push((set, complement));
+            }
+        }
+        // A chain of n relations has (n^3 - n) / 6 pairs
+        assert_eq!(pairs.len(), 10);
+    }
+
+    /// A star with a big fact table in the middle and small dimension tables around it
+    fn star_catalog(dimensions: usize) -> BTreeMap<String, ColumnDescriptors> {
+        let column = ColumnDescriptor {
+            datatype: DataType::Integer(None),
+            ..Default::default()
+        };
+        let mut catalog = BTreeMap::new();
+        let mut fact = BTreeMap::new();
+        for i in 0..dimensions {
+            fact.insert(format!("d{}", i), column.clone());
+            let mut dimension = BTreeMap::new();
+            dimension.insert("id".to_string(), column.clone());
+            dimension.insert("x".to_string(), column.clone());
+            catalog.insert(format!("d{}", i), dimension);
+        }
+        catalog.insert("fact".to_string(), fact);
+        catalog
+    }
+
+    fn star_query(dimensions: usize) -> String {
+        let mut sql = "SELECT * FROM ".to_string();
+        sql.push_str(
+            &(0..dimensions)
+                .map(|i| format!("d{}", i))
+                .collect::<Vec<_>>()
+                .join(", "),
+        );
+        sql.push_str(", fact WHERE ");
+        sql.push_str(
+            &(0..dimensions)
+                .map(|i| format!("fact.d{i} = d{i}.id AND d{i}.x = 1"))
+                .collect::<Vec<_>>()
+                .join(" AND "),
+        );
+        sql
+    }
+
+    fn optimise(catalog: &dyn Catalog, rule: JoinReorder, sql: &str) -> LogicalPlan {
+        let statement =
+            sqlparser::parser::Parser::parse_sql(&sqlparser::dialect::GenericDialect {}, sql)
+                .unwrap()
+                .remove(0);
+        let plan = crate::logical_plan::PlanBuilder::new(catalog)
+            .plan_statement(&statement)
+            .unwrap();
+        Optimiser::with_rules(vec![Box::new(PredicatePushdown), Box::new(rule)])
+            .optimise(plan)
+            .unwrap()
+    }
+
+    #[test]
+    #[traced_test]
+    fn avoids_cross_products() {
+        let catalog = test_catalog();
+        // Written so the textual order needs a cross product
+        let plan = optimise(
+            &catalog,
+            JoinReorder::new(&catalog),
+            "SELECT u.name, o.total, p.total FROM users u, orders o, orders p WHERE u.id = o.user_id AND u.id = p.user_id AND o.id = 3",
+        );
+        assert_plan_eq(
+            &plan,
+            "
+Projection: u.name, o.total, p.total
+  Projection: u.age, u.id, u.name, o.id, o.total, o.user_id, p.id, p.total, p.user_id
+    Join: Inner ON u.id = p.user_id
+      Scan: orders AS p
+      Join: Inner ON u.id = o.user_id
+        Scan: users AS u
+        Scan: orders AS o filters=[o.id = 3]",
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn dp_and_greedy() {
+        let catalog = star_catalog(4);
+        let sql = star_query(4);
+        let dp = optimise(&catalog, JoinReorder::new(&catalog), &sql);
+        let greedy = optimise(&catalog, JoinReorder::new(&catalog).with_dp_limit(2), &sql);
+        let model = CostModel::new(&catalog);
+        let textual = {
+            let statement =
+                sqlparser::parser::Parser::parse_sql(&sqlparser::dialect::GenericDialect {}, &sql)
+                    .unwrap()
+                    .remove(0);
+            let plan = crate::logical_plan::PlanBuilder::new(&catalog)
+                .plan_statement(&statement)
+                .unwrap();
+            PredicatePushdown.rewrite(plan).unwrap()
+        };
+        let (dp, greedy, textual) = (
+            model.estimate(&dp),
+            model.estimate(&greedy),
+            model.estimate(&textual),
+        );
+        assert!(dp.cost <= greedy.cost, "{:?} {:?}", dp, greedy);
+        assert!(greedy.cost < textual.cost, "{:?} {:?}", greedy, textual);
+        assert!((dp.rows - textual.rows).abs() < 1e-6);
+
+        // Too many relations for DP to finish in reasonable time
+        let catalog = star_catalog(20);
+        let plan = optimise(&catalog, JoinReorder::new(&catalog), &star_query(20));
+        assert!(CostModel::new(&catalog).estimate(&plan).cost.is_finite());
+    }
+
+    #[test]
+    #[traced_test]
+    fn relation_limit() {
+        let catalog = star_catalog(1);
+        let sql = |n: usize| {
+            let relations = (0..n).map(|i| format!("d0 AS a{}", i)).collect::<Vec<_>>();
+            format!("SELECT * FROM {}", relations.join(", "))
+        };
+        // Exactly as many relations as fit in the bitset still get reordered
+        let plan = optimise(&catalog, JoinReorder::new(&catalog), &sql(MAX_RELATIONS));
+        assert!(logs_contain("using greedy join ordering"));
+        assert_eq!(plan.schema().len(), 2 * MAX_RELATIONS);
+        // Past that the joins are left as they are
+        let plan = optimise(
+            &catalog,
+            JoinReorder::new(&catalog),
+            &sql(MAX_RELATIONS + 1),
+        );
+        assert_eq!(plan.schema().len(), 2 * (MAX_RELATIONS + 1));
+    }
+
+    #[test]
+    #[traced_test]
+    fn leaves_outer_joins_alone() {
+        let sql = "SELECT * FROM users u LEFT JOIN orders o ON u.id = o.user_id";
+        let catalog = test_catalog();
+        let before = PredicatePushdown.rewrite(plan(sql).unwrap()).unwrap();
+        let after = optimise(&catalog, JoinReorder::new(&catalog), sql);
+        assert_eq!(before, after);
+    }
+}
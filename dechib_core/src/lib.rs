@@ -1,52 +1,84 @@
+use crate::functions::{AggregateUdf, ScalarFunction};
+use crate::lock_manager::LockManager;
 use crate::query_engine::QueryEngine;
+use crate::session::Session;
 use crate::storage_engine::StorageEngine;
-use crate::types::*;
+use std::sync::Arc;
 use std::{env, path::Path};
-use tracing::{debug, instrument};
+use tracing::instrument;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
+pub mod cost;
+pub mod evaluator;
+pub mod executor;
+pub mod expression;
+pub mod functions;
+pub mod join_order;
+pub mod lock_manager;
+pub mod logical_plan;
+pub mod optimiser;
+pub mod physical_plan;
 pub mod query_engine;
+pub mod session;
+pub mod statistics;
 pub mod storage_engine;
 pub mod types;
+pub mod vectorised;
 
+/// A handle to the database. Cloning it is cheap and all clones refer to the same database.
+#[derive(Clone)]
 pub struct Instance {
-    storage: StorageEngine,
+    storage: Arc<StorageEngine>,
+    locks: Arc<LockManager>,
     query: QueryEngine,
 }
 
+impl Default for Instance {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Instance {
     pub fn new_with_path(path: impl AsRef<Path>) -> Self {
         Self {
-            storage: StorageEngine::new_with_path(path),
-            query: QueryEngine::default(),
+            storage: Arc::new(StorageEngine::new_with_path(path)),
+            locks: Arc::default(),
+            query: QueryEngine,
         }
     }
 
     pub fn new() -> Self {
         Self {
-            storage: StorageEngine::new(),
-            query: QueryEngine::default(),
+            storage: Arc::new(StorageEngine::new()),
+            locks: Arc::default(),
+            query: QueryEngine,
         }
     }
 
+    /// Makes a function written in Rust callable from SQL, anywhere an expression can go
+    /// including column defaults and `CHECK` constraints. Functions only last as long as the
+    /// instance so they need registering again whenever the database is opened.
+    pub fn register_function(&self, func: ScalarFunction) -> anyhow::Result<()> {
+        self.storage.register_function(func)
+    }
+
+    /// Adds an aggregate function written in Rust, see [`functions::Aggregator`]. Like scalar
+    /// functions these need registering each time the database is opened.
+    pub fn register_aggregate(&self, func: AggregateUdf) -> anyhow::Result<()> {
+        self.storage.register_aggregate(func)
+    }
+
+    /// Starts a new session, each client connection should have its own session.
+    pub fn session(&self) -> Session {
+        Session::new(self.clone())
+    }
+
+    /// Runs the query in a new session. Any transaction left open by the query is rolled back so
+    /// use a [`Session`] to run transactions across calls.
     #[instrument(skip_all)]
     pub fn execute(&mut self, query: &str) -> anyhow::Result<()> {
-        let statements = self.query.process_sql(query)?;
-        for statement in &statements {
-            debug!("Running: {:?}", statement);
-            match statement {
-                Command::CreateTable(opts) => {
-                    self.storage.create_table(opts)?;
-                }
-                Command::Insert(opts) => {
-                    self.storage.insert_rows(opts)?;
-                }
-                Command::Select(_) => {
-                    anyhow::bail!("Currently don't support SELECT queries");
-                }
-            }
-        }
-        Ok(())
+        self.session().execute(query)
     }
 
     #[cfg(test)]
@@ -70,6 +102,7 @@ pub fn setup_logging() {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::*;
     use sqlparser::ast::DataType;
     use std::collections::BTreeMap;
     use tracing_test::traced_test;
@@ -131,4 +164,244 @@ mod tests {
 
         let _engine = StorageEngine::new_with_path(&handle.path);
     }
+
+    #[test]
+    #[traced_test]
+    fn user_defined_functions() {
+        use crate::functions::{ArgType, ReturnType, Signature, Volatility};
+        use std::sync::atomic::{AtomicI64, Ordering};
+
+        let handle = TableHandle::new();
+        let instance = Instance::new_with_path(&handle.path);
+
+        let checksum = ScalarFunction::new(
+            "checksum",
+            Signature::new(
+                vec![ArgType::Text],
+                ReturnType::Exact(DataType::Integer(None)),
+            ),
+            Volatility::Immutable,
+            |args| match &args[0] {
+                Value::Text(s) => Ok(Value::from(s.bytes().map(i64::from).sum::<i64>() % 256)),
+                _ => unreachable!(),
+            },
+        );
+        instance.register_function(checksum).unwrap();
+        let counter = Arc::new(AtomicI64::new(0));
+        let next = counter.clone();
+        let next_id = ScalarFunction::new(
+            "next_id",
+            Signature::new(vec![], ReturnType::Exact(DataType::Integer(None))),
+            Volatility::Volatile,
+            move |_| Ok(Value::from(next.fetch_add(1, Ordering::SeqCst) + 1)),
+        );
+        instance.register_function(next_id).unwrap();
+
+        // Builtins and existing functions can't be replaced
+        let upper = ScalarFunction::new(
+            "UPPER",
+            Signature::new(vec![ArgType::Text], ReturnType::SameAsArgument),
+            Volatility::Immutable,
+            |args| Ok(args[0].clone()),
+        );
+        assert!(instance.register_function(upper).is_err());
+        let again = ScalarFunction::new(
+            "checksum",
+            Signature::new(vec![], ReturnType::SameAsArgument),
+            Volatility::Immutable,
+            |_| Ok(Value::Null),
+        );
+        assert!(instance.register_function(again).is_err());
+
+        let mut session = instance.session();
+        // Volatile functions can't be used in checks
+        assert!(session
+            .execute("CREATE TABLE bad (id INTEGER PRIMARY KEY CHECK (id < next_id()))")
+            .is_err());
+        session
+            .execute(
+                "CREATE TABLE files (id INTEGER PRIMARY KEY DEFAULT next_id(), \
+                 name TEXT NOT NULL, sum INTEGER, CHECK (sum = checksum(name)))",
+            )
+            .unwrap();
+        session
+            .execute(
+                "INSERT INTO files (name, sum) VALUES ('a', checksum('a')), ('b', checksum('b'))",
+            )
+            .unwrap();
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+        assert!(session
+            .execute("INSERT INTO files (name, sum) VALUES ('c', 1)")
+            .is_err());
+        assert!(session
+            .execute("INSERT INTO files (id, name, sum) VALUES (next_id() + 10, 'd', 100)")
+            .is_ok());
+
+        let res = session
+            .query(
+                "SELECT id, checksum(upper(name)) FROM files WHERE checksum(name) > 97 ORDER BY id",
+            )
+            .unwrap();
+        assert_eq!(
+            res.rows,
+            vec![
+                vec![Value::from(2), Value::from(66)],
+                vec![Value::from(14), Value::from(68)],
+            ]
+        );
+        assert!(session.query("SELECT checksum(id) FROM files").is_err());
+        assert!(session.query("SELECT checksum() FROM files").is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn user_defined_aggregates() {
+        use crate::functions::{Aggregator, ArgType, ReturnType, Signature};
+        use bigdecimal::BigDecimal;
+
+        /// Not very approximate, keeps every value and picks the right one at the end
+        struct Percentile;
+
+        impl Aggregator for Percentile {
+            type State = (Vec<BigDecimal>, Option<BigDecimal>);
+
+            fn init(&self) -> Self::State {
+                (vec![], None)
+            }
+
+            fn accumulate(&self, state: &mut Self::State, args: &[Value]) -> anyhow::Result<()> {
+                match args {
+                    [Value::Number(n), Value::Number(p)] => {
+                        state.0.push(n.clone());
+                        state.1 = Some(p.clone());
+                        Ok(())
+                    }
+                    _ => anyhow::bail!("percentile takes numbers"),
+                }
+            }
+
+            fn merge(&self, state: &mut Self::State, other: Self::State) -> anyhow::Result<()> {
+                state.0.extend(other.0);
+                state.1 = state.1.take().or(other.1);
+                Ok(())
+            }
+
+            fn finalize(&self, (mut values, p): Self::State) -> anyhow::Result<Value> {
+                let Some(p) = p else {
+                    return Ok(Value::Null);
+                };
+                values.sort();
+                let i = (p * BigDecimal::from(values.len() as i64 - 1)).round(0);
+                Ok(Value::Number(
+                    values[i.to_string().parse::<usize>()?].clone(),
+                ))
+            }
+
+            fn save(&self, (values, p): Self::State) -> anyhow::Result<Value> {
+                let values = values.into_iter().map(Value::Number).collect();
+                let p = p.map(Value::Number).unwrap_or(Value::Null);
+                Ok(Value::Array(vec![Value::Array(values), p]))
+            }
+
+            fn load(&self, value: Value) -> anyhow::Result<Self::State> {
+                let number = |v| match v {
+                    Value::Number(n) => Ok(n),
+                    v => anyhow::bail!("Expected a number, got {}", v),
+                };
+                match value {
+                    Value::Array(state) => match <[Value; 2]>::try_from(state) {
+                        Ok([Value::Array(values), p]) => Ok((
+                            values
+                                .into_iter()
+                                .map(number)
+                                .collect::<anyhow::Result<_>>()?,
+                            (!p.is_null()).then(|| number(p)).transpose()?,
+                        )),
+                        _ => anyhow::bail!("Invalid percentile state"),
+                    },
+                    v => anyhow::bail!("Invalid percentile state {}", v),
+                }
+            }
+        }
+
+        let handle = TableHandle::new();
+        let instance = Instance::new_with_path(&handle.path);
+        let percentile = || {
+            AggregateUdf::new(
+                "percentile",
+                Signature::new(
+                    vec![ArgType::Number, ArgType::Number],
+                    ReturnType::SameAsArgument,
+                ),
+                Percentile,
+            )
+        };
+        instance.register_aggregate(percentile()).unwrap();
+        assert!(instance.register_aggregate(percentile()).is_err());
+        let count = AggregateUdf::new(
+            "COUNT",
+            Signature::new(vec![ArgType::Any], ReturnType::SameAsArgument),
+            Percentile,
+        );
+        assert!(instance.register_aggregate(count).is_err());
+        let lower = AggregateUdf::new(
+            "lower",
+            Signature::new(vec![ArgType::Any], ReturnType::SameAsArgument),
+            Percentile,
+        );
+        assert!(instance.register_aggregate(lower).is_err());
+
+        let mut session = instance.session();
+        session
+            .execute("CREATE TABLE readings (id INTEGER PRIMARY KEY, sensor TEXT NOT NULL, value NUMERIC)")
+            .unwrap();
+        let values = (1..=20)
+            .map(|i| format!("({}, '{}', {})", i, if i % 2 == 0 { "a" } else { "b" }, i))
+            .collect::<Vec<_>>()
+            .join(", ");
+        session
+            .execute(&format!(
+                "INSERT INTO readings (id, sensor, value) VALUES {}, (21, 'c', NULL)",
+                values
+            ))
+            .unwrap();
+        let res = session
+            .query(
+                "SELECT sensor, percentile(value, 0.5), PERCENTILE(value, 1) FROM readings \
+                 GROUP BY sensor HAVING percentile(value, 0) < 10 ORDER BY sensor",
+            )
+            .unwrap();
+        assert_eq!(
+            res.rows,
+            vec![
+                vec![Value::from("a"), Value::from(10), Value::from(20)],
+                vec![Value::from("b"), Value::from(9), Value::from(19)],
+            ]
+        );
+        // Partial states get written out to disk and merged when there are too many groups
+        let grouped = "SELECT id % 7, percentile(value, 0.5), percentile(value, 1) FROM readings \
+                       GROUP BY id % 7 ORDER BY id % 7";
+        let expected = session.query(grouped).unwrap().rows;
+        assert_eq!(expected.len(), 7);
+        session.execute("SET work_mem = '1kB'").unwrap();
+        assert_eq!(session.query(grouped).unwrap().rows, expected);
+        session.execute("SET work_mem = '4MB'").unwrap();
+
+        assert_eq!(
+            session
+                .query("SELECT percentile(value, 0.5) FROM readings WHERE sensor = 'c'")
+                .unwrap()
+                .rows,
+            vec![vec![Value::Null]]
+        );
+        assert!(session
+            .query("SELECT percentile(sensor, 0.5) FROM readings")
+            .is_err());
+        assert!(session
+            .query("SELECT percentile(value) FROM readings")
+            .is_err());
+        assert!(session
+            .query("SELECT id FROM readings WHERE percentile(value, 0.5) > 1")
+            .is_err());
+    }
 }
@@ -0,0 +1,591 @@
+//! Row level locks. Rows are identified by their table and the encoded primary key (the same key
+//! used to store them). Locks are owned by a transaction and held until it commits or rolls back,
+//! this is strict two-phase locking so a transaction never gives up a lock it might still need.
+//!
+//! Plain reads don't take any locks, they see whatever is committed when the row is read. Only
+//! `FOR UPDATE`/`FOR SHARE` reads, the rows an `UPDATE` or `DELETE` reads to modify and the rows
+//! being written are locked. Rows being modified are locked as they're read and read again under
+//! the lock, so two statements updating the same row can't both compute from the old value.
+//! `FOR UPDATE`/`FOR SHARE` rows are locked as they come out of the select, after sorting but
+//! before any `LIMIT`, so only the rows returned are locked and `SKIP LOCKED` moves on to the next.
+//! Writes also lock their values for each unique constraint before checking for clashes.
+//!
+//! Everything lives behind a single mutex which isn't going to win any benchmarks, but it makes it
+//! much easier to reason about what the lock table looks like at any point.
+//!
+//! Once transactions can wait on each other they can deadlock. Whenever a transaction has to wait
+//! we add it to the wait-for graph (an edge from each waiter to every transaction holding a
+//! conflicting lock) and look for a cycle through it. A cycle can only be created by a new edge so
+//! checking when we start waiting catches every deadlock. The youngest transaction in the cycle is
+//! picked as the victim since it has likely done the least work, it gets a
+//! [`LockError::Deadlock`] and should be rolled back to release its locks.
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tracing::{debug, trace, warn};
+
+pub type TransactionId = u64;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockMode {
+    /// Multiple transactions can hold a shared lock on the same row, `FOR SHARE`
+    Shared,
+    /// Only one transaction can hold a lock on the row, `FOR UPDATE` and any writes
+    Exclusive,
+}
+
+/// What to do when a lock is held by another transaction
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum WaitPolicy {
+    /// Wait until the lock is available (or we time out)
+    #[default]
+    Block,
+    /// Error immediately, `NOWAIT`
+    NoWait,
+    /// Carry on without the row, `SKIP LOCKED`
+    SkipLocked,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LockStatus {
+    Acquired,
+    /// The row was locked by someone else and the wait policy was `SkipLocked`
+    Skipped,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RowId {
+    pub table: String,
+    pub key: Vec<u8>,
+}
+
+impl RowId {
+    pub fn new(table: impl Into<String>, key: impl Into<Vec<u8>>) -> Self {
+        Self {
+            table: table.into(),
+            key: key.into(),
+        }
+    }
+
+    /// A value of one of the table's unique constraints. Whoever writes a row with the value locks
+    /// it, so two transactions can't both find it free.
+    pub fn unique_value(table: &str, columns: &[String], value: impl Into<Vec<u8>>) -> Self {
+        Self {
+            table: format!("{}({})", table, columns.join(", ")),
+            key: value.into(),
+        }
+    }
+}
+
+/// The locks a transaction takes on what it writes, always exclusive and waiting for them.
+#[derive(Copy, Clone)]
+pub struct WriteLocks<'a> {
+    pub locks: &'a LockManager,
+    pub transaction: TransactionId,
+    pub timeout: Option<Duration>,
+}
+
+impl WriteLocks<'_> {
+    pub fn lock(&self, row: &RowId) -> Result<(), LockError> {
+        self.locks
+            .lock(
+                self.transaction,
+                row,
+                LockMode::Exclusive,
+                WaitPolicy::Block,
+                self.timeout,
+            )
+            .map(|_| ())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LockError {
+    /// The row is locked and the wait policy was `NoWait`
+    NotAvailable { table: String },
+    /// We waited longer than the lock timeout
+    Timeout { table: String },
+    /// The transaction was part of a deadlock and chosen as the victim
+    Deadlock { transaction: TransactionId },
+}
+
+impl LockError {
+    /// Whether retrying the transaction from the start could succeed. For a deadlock the other
+    /// transactions should be able to make progress once the victim has rolled back.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Deadlock { .. })
+    }
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NotAvailable { table } => {
+                write!(f, "Could not obtain lock on row in relation {}", table)
+            }
+            Self::Timeout { table } => {
+                write!(f, "Timed out waiting for lock on row in relation {}", table)
+            }
+            Self::Deadlock { transaction } => write!(
+                f,
+                "Deadlock detected, transaction {} was aborted",
+                transaction
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LockError {}
+
+#[derive(Debug, Default)]
+struct LockTable {
+    /// Who holds each lock and in what mode
+    rows: BTreeMap<RowId, BTreeMap<TransactionId, LockMode>>,
+    /// The rows each transaction holds locks on so we can release them all when it finishes
+    held: BTreeMap<TransactionId, BTreeSet<RowId>>,
+    /// The lock each blocked transaction is waiting for
+    waiting: BTreeMap<TransactionId, (RowId, LockMode)>,
+    /// Waiting transactions that have been picked as deadlock victims but not woken up yet
+    victims: BTreeSet<TransactionId>,
+}
+
+impl LockTable {
+    /// A lock can be granted if it doesn't conflict with any lock held by another transaction.
+    fn can_grant(&self, transaction: TransactionId, row: &RowId, mode: LockMode) -> bool {
+        match self.rows.get(row) {
+            Some(holders) => holders
+                .iter()
+                .filter(|(id, _)| **id != transaction)
+                .all(|(_, held)| mode == LockMode::Shared && *held == LockMode::Shared),
+            None => true,
+        }
+    }
+
+    /// The transactions holding locks which stop `transaction` getting the lock it's waiting for.
+    fn blockers(&self, transaction: TransactionId) -> Vec<TransactionId> {
+        let Some((row, mode)) = self.waiting.get(&transaction) else {
+            return vec![];
+        };
+        self.rows
+            .get(row)
+            .map(|holders| {
+                holders
+                    .iter()
+                    .filter(|(id, held)| {
+                        **id != transaction
+                            && (*mode == LockMode::Exclusive || **held == LockMode::Exclusive)
+                    })
+                    .map(|(id, _)| *id)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Searches the wait-for graph for a cycle that goes through `start`, returning the
+    /// transactions in it.
+    fn find_cycle(&self, start: TransactionId) -> Option<Vec<TransactionId>> {
+        let mut visited = BTreeSet::new();
+        let mut stack = vec![(start, vec![start])];
+        while let Some((current, path)) = stack.pop() {
+            for next in self.blockers(current) {
+                if next == start {
+                    return Some(path);
+                }
+                if visited.insert(next) {
+                    let mut path = path.clone();
+                    path.push(next);
+                    stack.push((next, path));
+                }
+            }
+        }
+        None
+    }
+
+    fn grant(&mut self, transaction: TransactionId, row: &RowId, mode: LockMode) {
+        let held = self
+            .rows
+            .entry(row.clone())
+            .or_default()
+            .entry(transaction)
+            .or_insert(mode);
+        // Upgrading from shared to exclusive, but never downgrade
+        *held = (*held).max(mode);
+        self.held
+            .entry(transaction)
+            .or_default()
+            .insert(row.clone());
+    }
+}
+
+#[derive(Debug)]
+pub struct LockManager {
+    table: Mutex<LockTable>,
+    released: Condvar,
+    next_transaction: AtomicU64,
+}
+
+impl Default for LockManager {
+    fn default() -> Self {
+        Self {
+            table: Mutex::default(),
+            released: Condvar::new(),
+            next_transaction: AtomicU64::new(1),
+        }
+    }
+}
+
+impl LockManager {
+    /// Allocates an ID for a new transaction to own locks with.
+    pub fn begin(&self) -> TransactionId {
+        self.next_transaction.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Acquires a lock on the row, if the transaction already holds a lock at least as strong as
+    /// the one requested this returns immediately. A `timeout` of `None` waits forever.
+    pub fn lock(
+        &self,
+        transaction: TransactionId,
+        row: &RowId,
+        mode: LockMode,
+        wait: WaitPolicy,
+        timeout: Option<Duration>,
+    ) -> Result<LockStatus, LockError> {
+        let table = self.table.lock().unwrap();
+        let (mut table, res) = self.acquire(table, transaction, row, mode, wait, timeout);
+        // Whatever happened we're not waiting anymore
+        table.waiting.remove(&transaction);
+        table.victims.remove(&transaction);
+        res
+    }
+
+    fn acquire<'a>(
+        &'a self,
+        mut table: MutexGuard<'a, LockTable>,
+        transaction: TransactionId,
+        row: &RowId,
+        mode: LockMode,
+        wait: WaitPolicy,
+        timeout: Option<Duration>,
+    ) -> (MutexGuard<'a, LockTable>, Result<LockStatus, LockError>) {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            if table.victims.contains(&transaction) {
+                debug!(transaction, "aborting deadlock victim");
+                return (table, Err(LockError::Deadlock { transaction }));
+            }
+            if table.can_grant(transaction, row, mode) {
+                trace!(transaction, ?row, ?mode, "lock acquired");
+                table.grant(transaction, row, mode);
+                return (table, Ok(LockStatus::Acquired));
+            }
+            match wait {
+                WaitPolicy::NoWait => {
+                    let err = LockError::NotAvailable {
+                        table: row.table.clone(),
+                    };
+                    return (table, Err(err));
+                }
+                WaitPolicy::SkipLocked => return (table, Ok(LockStatus::Skipped)),
+                WaitPolicy::Block => {}
+            }
+            if let Entry::Vacant(entry) = table.waiting.entry(transaction) {
+                debug!(transaction, ?row, ?mode, "waiting for lock");
+                entry.insert((row.clone(), mode));
+                if let Some(cycle) = table.find_cycle(transaction) {
+                    // IDs are allocated in order so the biggest is the youngest
+                    let victim = cycle.iter().copied().max().unwrap_or(transaction);
+                    warn!(?cycle, victim, "deadlock detected");
+                    if victim == transaction {
+                        return (table, Err(LockError::Deadlock { transaction }));
+                    }
+                    table.victims.insert(victim);
+                    self.released.notify_all();
+                }
+            }
+            table = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        let err = LockError::Timeout {
+                            table: row.table.clone(),
+                        };
+                        return (table, Err(err));
+                    }
+                    self.released.wait_timeout(table, deadline - now).unwrap().0
+                }
+                None => self.released.wait(table).unwrap(),
+            };
+        }
+    }
+
+    /// Releases every lock held by the transaction, this should be called when it commits or
+    /// rolls back.
+    pub fn release_all(&self, transaction: TransactionId) {
+        let mut table = self.table.lock().unwrap();
+        if let Some(rows) = table.held.remove(&transaction) {
+            for row in &rows {
+                if let Some(holders) = table.rows.get_mut(row) {
+                    holders.remove(&transaction);
+                    if holders.is_empty() {
+                        table.rows.remove(row);
+                    }
+                }
+            }
+            debug!(transaction, count = rows.len(), "released locks");
+            self.released.notify_all();
+        }
+    }
+
+    /// The mode the transaction holds a lock on the row in, if any.
+    pub fn held_mode(&self, transaction: TransactionId, row: &RowId) -> Option<LockMode> {
+        let table = self.table.lock().unwrap();
+        table
+            .rows
+            .get(row)
+            .and_then(|holders| holders.get(&transaction))
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    fn row(key: u8) -> RowId {
+        RowId::new("jobs", vec![key])
+    }
+
+    #[test]
+    fn shared_locks_are_compatible() {
+        let locks = LockManager::default();
+        let (a, b) = (locks.begin(), locks.begin());
+        let nowait = WaitPolicy::NoWait;
+
+        assert_eq!(
+            locks.lock(a, &row(1), LockMode::Shared, nowait, None),
+            Ok(LockStatus::Acquired)
+        );
+        assert_eq!(
+            locks.lock(b, &row(1), LockMode::Shared, nowait, None),
+            Ok(LockStatus::Acquired)
+        );
+        assert!(locks
+            .lock(b, &row(1), LockMode::Exclusive, nowait, None)
+            .is_err());
+
+        locks.release_all(a);
+        // Now b is the only holder it can upgrade
+        assert_eq!(
+            locks.lock(b, &row(1), LockMode::Exclusive, nowait, None),
+            Ok(LockStatus::Acquired)
+        );
+        assert_eq!(locks.held_mode(b, &row(1)), Some(LockMode::Exclusive));
+        // and asking for a weaker lock doesn't downgrade it
+        locks
+            .lock(b, &row(1), LockMode::Shared, nowait, None)
+            .unwrap();
+        assert_eq!(locks.held_mode(b, &row(1)), Some(LockMode::Exclusive));
+    }
+
+    #[test]
+    fn wait_policies() {
+        let locks = LockManager::default();
+        let (a, b) = (locks.begin(), locks.begin());
+
+        locks
+            .lock(a, &row(1), LockMode::Exclusive, WaitPolicy::Block, None)
+            .unwrap();
+
+        assert_eq!(
+            locks.lock(b, &row(1), LockMode::Shared, WaitPolicy::NoWait, None),
+            Err(LockError::NotAvailable {
+                table: "jobs".to_string()
+            })
+        );
+        assert_eq!(
+            locks.lock(b, &row(1), LockMode::Shared, WaitPolicy::SkipLocked, None),
+            Ok(LockStatus::Skipped)
+        );
+        assert_eq!(
+            locks.lock(
+                b,
+                &row(1),
+                LockMode::Shared,
+                WaitPolicy::Block,
+                Some(Duration::from_millis(20))
+            ),
+            Err(LockError::Timeout {
+                table: "jobs".to_string()
+            })
+        );
+        // Other rows aren't affected
+        assert_eq!(
+            locks.lock(b, &row(2), LockMode::Exclusive, WaitPolicy::NoWait, None),
+            Ok(LockStatus::Acquired)
+        );
+    }
+
+    #[test]
+    fn waiters_wake_on_release() {
+        let locks = Arc::new(LockManager::default());
+        let (a, b) = (locks.begin(), locks.begin());
+
+        locks
+            .lock(a, &row(1), LockMode::Exclusive, WaitPolicy::Block, None)
+            .unwrap();
+
+        let waiter = {
+            let locks = locks.clone();
+            thread::spawn(move || {
+                locks.lock(b, &row(1), LockMode::Exclusive, WaitPolicy::Block, None)
+            })
+        };
+
+        thread::sleep(Duration::from_millis(20));
+        assert!(!waiter.is_finished());
+        locks.release_all(a);
+        assert_eq!(waiter.join().unwrap(), Ok(LockStatus::Acquired));
+        assert_eq!(locks.held_mode(a, &row(1)), None);
+        assert_eq!(locks.held_mode(b, &row(1)), Some(LockMode::Exclusive));
+    }
+
+    /// Spawns a thread which takes `first` and then once everyone has theirs tries to take
+    /// `second`.
+    fn lock_pair(
+        locks: &Arc<LockManager>,
+        barrier: &Arc<Barrier>,
+        transaction: TransactionId,
+        first: u8,
+        second: u8,
+    ) -> thread::JoinHandle<Result<LockStatus, LockError>> {
+        let locks = locks.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            locks
+                .lock(
+                    transaction,
+                    &row(first),
+                    LockMode::Exclusive,
+                    WaitPolicy::Block,
+                    None,
+                )
+                .unwrap();
+            barrier.wait();
+            let res = locks.lock(
+                transaction,
+                &row(second),
+                LockMode::Exclusive,
+                WaitPolicy::Block,
+                None,
+            );
+            // Finish the transaction so anyone waiting on us can carry on
+            locks.release_all(transaction);
+            res
+        })
+    }
+
+    #[test]
+    fn two_way_deadlock() {
+        let locks = Arc::new(LockManager::default());
+        let barrier = Arc::new(Barrier::new(2));
+        let (a, b) = (locks.begin(), locks.begin());
+
+        let t1 = lock_pair(&locks, &barrier, a, 1, 2);
+        let t2 = lock_pair(&locks, &barrier, b, 2, 1);
+
+        // The youngest transaction is the victim and the other one gets its lock
+        assert_eq!(t1.join().unwrap(), Ok(LockStatus::Acquired));
+        let err = t2.join().unwrap().unwrap_err();
+        assert_eq!(err, LockError::Deadlock { transaction: b });
+        assert!(err.is_retryable());
+    }
+
+    #[test]
+    fn three_way_deadlock() {
+        let locks = Arc::new(LockManager::default());
+        let barrier = Arc::new(Barrier::new(3));
+        let (a, b, c) = (locks.begin(), locks.begin(), locks.begin());
+
+        let t1 = lock_pair(&locks, &barrier, a, 1, 2);
+        let t2 = lock_pair(&locks, &barrier, b, 2, 3);
+        let t3 = lock_pair(&locks, &barrier, c, 3, 1);
+
+        assert_eq!(t1.join().unwrap(), Ok(LockStatus::Acquired));
+        assert_eq!(t2.join().unwrap(), Ok(LockStatus::Acquired));
+        assert_eq!(
+            t3.join().unwrap(),
+            Err(LockError::Deadlock { transaction: c })
+        );
+    }
+
+    #[test]
+    fn shared_upgrade_deadlock() {
+        // Two readers both trying to upgrade to a write is the classic way to deadlock yourself
+        let locks = Arc::new(LockManager::default());
+        let (a, b) = (locks.begin(), locks.begin());
+        for id in [a, b] {
+            locks
+                .lock(id, &row(1), LockMode::Shared, WaitPolicy::Block, None)
+                .unwrap();
+        }
+
+        let waiter = {
+            let locks = locks.clone();
+            thread::spawn(move || {
+                let res = locks.lock(a, &row(1), LockMode::Exclusive, WaitPolicy::Block, None);
+                locks.release_all(a);
+                res
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(
+            locks.lock(b, &row(1), LockMode::Exclusive, WaitPolicy::Block, None),
+            Err(LockError::Deadlock { transaction: b })
+        );
+        locks.release_all(b);
+        assert_eq!(waiter.join().unwrap(), Ok(LockStatus::Acquired));
+    }
+
+    #[test]
+    fn waiting_without_a_cycle_is_not_a_deadlock() {
+        let locks = Arc::new(LockManager::default());
+        let (a, b, c) = (locks.begin(), locks.begin(), locks.begin());
+        locks
+            .lock(a, &row(1), LockMode::Exclusive, WaitPolicy::Block, None)
+            .unwrap();
+        locks
+            .lock(b, &row(2), LockMode::Exclusive, WaitPolicy::Block, None)
+            .unwrap();
+
+        // c waits on a and b waits on c, but a isn't waiting on anything
+        let t1 = {
+            let locks = locks.clone();
+            thread::spawn(move || {
+                locks
+                    .lock(c, &row(3), LockMode::Exclusive, WaitPolicy::Block, None)
+                    .unwrap();
+                let res = locks.lock(c, &row(1), LockMode::Exclusive, WaitPolicy::Block, None);
+                locks.release_all(c);
+                res
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        let t2 = {
+            let locks = locks.clone();
+            thread::spawn(move || {
+                let res = locks.lock(b, &row(3), LockMode::Exclusive, WaitPolicy::Block, None);
+                locks.release_all(b);
+                res
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        locks.release_all(a);
+        assert_eq!(t1.join().unwrap(), Ok(LockStatus::Acquired));
+        assert_eq!(t2.join().unwrap(), Ok(LockStatus::Acquired));
+    }
+}
@@ -0,0 +1,3752 @@
+//! The logical plan is the tree of relational operators a statement is turned into before it's
+//! optimised and executed. Building it from the AST resolves every table and column name against
+//! the catalog so anything referring to something which doesn't exist is caught here.
+use crate::expression::{
+    AggregateExpr, AggregateFunction, Column, FrameBound, FrameUnits, ScalarExpr, WindowExpr,
+    WindowFrame, WindowFunction,
+};
+use crate::functions::{AggregateUdf, FunctionRegistry, ScalarFunction};
+use crate::lock_manager::{LockMode, WaitPolicy};
+use crate::statistics::TableStatistics;
+use crate::storage_engine::{column_constraints, same_columns, StorageEngine};
+use crate::types::{ColumnDescriptors, RowLocking, TypeClass, Value};
+use anyhow::Context;
+use bigdecimal::{BigDecimal, Zero};
+use sqlparser::ast::{
+    self, BinaryOperator, DataType, DateTimeField, Distinct, DuplicateTreatment, Expr, FromTable,
+    FunctionArg, FunctionArgExpr, FunctionArgumentClause, FunctionArguments, GroupByExpr, Insert,
+    JoinConstraint, JoinOperator, ObjectName, OnConflictAction, OnInsert, OrderByExpr, Query,
+    Select, SelectItem, SetExpr, Statement, TableAlias, TableFactor, TableWithJoins,
+    TrimWhereField, UnaryOperator,
+};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::ControlFlow;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Where the planner looks up tables.
+pub trait Catalog {
+    fn table_metadata(&self, name: &str) -> anyhow::Result<ColumnDescriptors>;
+
+    /// Statistics from the last time the table was analyzed, if it ever was.
+    fn table_statistics(&self, _name: &str) -> anyhow::Result<Option<TableStatistics>> {
+        Ok(None)
+    }
+
+    fn function(&self, name: &str) -> Option<Arc<ScalarFunction>> {
+        FunctionRegistry::builtin().get(name)
+    }
+
+    /// A user defined aggregate, the built in ones are always there.
+    fn aggregate(&self, _name: &str) -> Option<Arc<AggregateUdf>> {
+        None
+    }
+
+    /// The sets of columns which have to be unique, the primary key first if there is one
+    fn unique_constraints(&self, name: &str) -> anyhow::Result<Vec<Vec<String>>> {
+        Ok(column_constraints(&self.table_metadata(name)?))
+    }
+}
+
+impl Catalog for StorageEngine {
+    fn table_metadata(&self, name: &str) -> anyhow::Result<ColumnDescriptors> {
+        StorageEngine::table_metadata(self, name)
+    }
+
+    fn table_statistics(&self, name: &str) -> anyhow::Result<Option<TableStatistics>> {
+        StorageEngine::table_statistics(self, name)
+    }
+
+    fn function(&self, name: &str) -> Option<Arc<ScalarFunction>> {
+        StorageEngine::function(self, name)
+    }
+
+    fn unique_constraints(&self, name: &str) -> anyhow::Result<Vec<Vec<String>>> {
+        StorageEngine::unique_constraints(self, name)
+    }
+
+    fn aggregate(&self, name: &str) -> Option<Arc<AggregateUdf>> {
+        StorageEngine::aggregate(self, name)
+    }
+}
+
+/// Mainly so tests can plan queries without having to create a database
+impl Catalog for BTreeMap<String, ColumnDescriptors> {
+    fn table_metadata(&self, name: &str) -> anyhow::Result<ColumnDescriptors> {
+        self.get(name)
+            .cloned()
+            .with_context(|| format!("No table {} exists", name))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Field {
+    pub relation: Option<String>,
+    pub name: String,
+    pub datatype: DataType,
+    pub nullable: bool,
+    /// Only found by its qualified name and left out of `SELECT *`, for the columns a
+    /// `JOIN ... USING` has merged into one
+    pub hidden: bool,
+}
+
+impl Field {
+    pub fn new(relation: Option<String>, name: impl Into<String>, datatype: DataType) -> Self {
+        Self {
+            relation,
+            name: name.into(),
+            datatype,
+            nullable: true,
+            hidden: false,
+        }
+    }
+
+    pub fn column(&self) -> Column {
+        Column {
+            relation: self.relation.clone(),
+            name: self.name.clone(),
+        }
+    }
+
+    fn matches(&self, column: &Column, exact: bool) -> bool {
+        let eq = |a: &str, b: &str| {
+            if exact {
+                a == b
+            } else {
+                a.eq_ignore_ascii_case(b)
+            }
+        };
+        let relation_matches = match (&column.relation, &self.relation) {
+            (None, _) => !self.hidden,
+            (Some(a), Some(b)) => eq(a, b),
+            (Some(_), None) => false,
+        };
+        relation_matches && eq(&column.name, &self.name)
+    }
+}
+
+/// The columns produced by a plan node, in order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Schema {
+    pub fields: Vec<Field>,
+}
+
+static EMPTY_SCHEMA: Schema = Schema { fields: Vec::new() };
+
+impl Schema {
+    pub fn new(fields: Vec<Field>) -> Self {
+        Self { fields }
+    }
+
+    /// The columns of a table, in the order they're stored in
+    pub fn for_table(relation: &str, metadata: &ColumnDescriptors) -> Self {
+        let fields = metadata
+            .iter()
+            .map(|(name, desc)| Field {
+                relation: Some(relation.to_string()),
+                name: name.clone(),
+                datatype: desc.datatype.clone(),
+                nullable: !desc.not_null,
+                hidden: false,
+            })
+            .collect();
+        Self { fields }
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Looks for the column, `Ok(None)` means it's not there and an error means the name is
+    /// ambiguous. Identifiers are matched exactly first and if that fails case insensitively.
+    pub fn find(&self, column: &Column) -> anyhow::Result<Option<usize>> {
+        for exact in [true, false] {
+            let mut found = self
+                .fields
+                .iter()
+                .enumerate()
+                .filter(|(_, field)| field.matches(column, exact))
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>();
+            // A column merged by `USING` wins over the same name from a table joined later on,
+            // otherwise there'd be no way to refer to it in the next join's condition
+            let merged = |&i: &usize| self.fields[i].relation.is_none();
+            if found.len() > 1 && column.relation.is_none() && found.iter().any(merged) {
+                found.retain(merged);
+            }
+            match found[..] {
+                [] => {}
+                [index] => return Ok(Some(index)),
+                _ => anyhow::bail!("Column reference {} is ambiguous", column),
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn index_of(&self, column: &Column) -> anyhow::Result<usize> {
+        self.find(column)?
+            .with_context(|| format!("Column {} does not exist", column))
+    }
+
+    pub fn field(&self, column: &Column) -> anyhow::Result<&Field> {
+        Ok(&self.fields[self.index_of(column)?])
+    }
+
+    /// The schema of the two schemas' rows concatenated, as produced by a join.
+    pub fn join(&self, other: &Schema) -> Schema {
+        let mut fields = self.fields.clone();
+        fields.extend(other.fields.iter().cloned());
+        Schema { fields }
+    }
+
+    fn with_nullable(mut self) -> Self {
+        for field in &mut self.fields {
+            field.nullable = true;
+        }
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JoinKind {
+    Inner,
+    Left,
+    Right,
+    Full,
+    Cross,
+    /// Left rows which match at least one row on the right, only the left's columns come out
+    Semi,
+    /// Left rows which don't match anything on the right
+    Anti,
+}
+
+impl fmt::Display for JoinKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Self::Inner => "Inner",
+            Self::Left => "Left",
+            Self::Right => "Right",
+            Self::Full => "Full",
+            Self::Cross => "Cross",
+            Self::Semi => "Semi",
+            Self::Anti => "Anti",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SetOperator {
+    Union,
+    Intersect,
+    Except,
+}
+
+impl fmt::Display for SetOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Self::Union => "UNION",
+            Self::Intersect => "INTERSECT",
+            Self::Except => "EXCEPT",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// What an `Apply` works out from the rows its subquery returns for each row
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ApplyKind {
+    /// The value in the only row, an error if there's more than one
+    Scalar,
+    Exists,
+    /// Whether the expression is equal to any of the values, null if it isn't but might be
+    In(ScalarExpr),
+}
+
+impl fmt::Display for ApplyKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Scalar => write!(f, "Scalar"),
+            Self::Exists => write!(f, "EXISTS"),
+            Self::In(expr) => write!(f, "{} IN", expr),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SortKey {
+    pub expr: ScalarExpr,
+    pub asc: bool,
+    pub nulls_first: bool,
+}
+
+impl fmt::Display for SortKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.expr)?;
+        if !self.asc {
+            write!(f, " DESC")?;
+        }
+        // Nulls sort as if they're larger than everything else unless told otherwise
+        if self.nulls_first == self.asc {
+            if self.nulls_first {
+                write!(f, " NULLS FIRST")?;
+            } else {
+                write!(f, " NULLS LAST")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The hidden column a scan puts the key of each row in when the rows are going to be locked
+/// further up the plan.
+pub const ROW_KEY: &str = "__row_key";
+
+/// A table whose rows a `LockRows` locks, by the `ROW_KEY` column of `relation`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockedTable {
+    pub table: String,
+    pub relation: String,
+    pub lock: RowLocking,
+}
+
+impl LockedTable {
+    pub fn key(&self) -> Column {
+        Column {
+            relation: Some(self.relation.clone()),
+            name: ROW_KEY.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for LockedTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.table)?;
+        if self.relation != self.table {
+            write!(f, " AS {}", self.relation)?;
+        }
+        write!(f, " {}", self.lock)
+    }
+}
+
+/// `ON CONFLICT` or MySQL's `ON DUPLICATE KEY UPDATE` on an insert
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OnConflict {
+    /// The columns of the unique constraint it's for, `None` for any of them
+    pub target: Option<Vec<String>>,
+    /// `None` is `DO NOTHING`
+    pub update: Option<ConflictUpdate>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConflictUpdate {
+    /// The existing row followed by the row which was going to be inserted, which the
+    /// expressions are over
+    pub schema: Schema,
+    pub assignments: Vec<(String, ScalarExpr)>,
+    pub selection: Option<ScalarExpr>,
+    /// Whether the statement can update the same row more than once, as MySQL allows
+    pub repeatable: bool,
+}
+
+impl fmt::Display for OnConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ON CONFLICT")?;
+        if let Some(target) = &self.target {
+            write!(f, " ({})", target.join(", "))?;
+        }
+        let Some(update) = &self.update else {
+            return write!(f, " DO NOTHING");
+        };
+        let assignments = update
+            .assignments
+            .iter()
+            .map(|(col, expr)| format!("{} = {}", col, expr));
+        write!(f, " DO UPDATE SET {}", list(assignments))?;
+        if let Some(selection) = &update.selection {
+            write!(f, " WHERE {}", selection)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogicalPlan {
+    /// `projection` is set when only some of the table's columns are read, the schema only
+    /// contains those columns. Rows not matching all the `filters` are skipped.
+    Scan {
+        table: String,
+        alias: Option<String>,
+        schema: Schema,
+        projection: Option<Vec<String>>,
+        filters: Vec<ScalarExpr>,
+        lock: Option<RowLocking>,
+        /// Set when the filters pin down the whole primary key, the values are in the same order
+        /// as the key's columns and the row is looked up directly instead of scanning the table.
+        primary_key: Option<Vec<ScalarExpr>>,
+    },
+    Filter {
+        predicate: ScalarExpr,
+        input: Box<LogicalPlan>,
+    },
+    Project {
+        exprs: Vec<ScalarExpr>,
+        schema: Schema,
+        input: Box<LogicalPlan>,
+    },
+    Join {
+        left: Box<LogicalPlan>,
+        right: Box<LogicalPlan>,
+        kind: JoinKind,
+        condition: Option<ScalarExpr>,
+        schema: Schema,
+    },
+    /// Outputs the group by columns followed by the aggregates. With no aggregates this is how
+    /// `DISTINCT` is done.
+    Aggregate {
+        group_by: Vec<ScalarExpr>,
+        aggregates: Vec<AggregateExpr>,
+        schema: Schema,
+        input: Box<LogicalPlan>,
+    },
+    /// Adds a column to the input for each window function, the rows are otherwise unchanged
+    Window {
+        windows: Vec<WindowExpr>,
+        schema: Schema,
+        input: Box<LogicalPlan>,
+    },
+    Sort {
+        keys: Vec<SortKey>,
+        input: Box<LogicalPlan>,
+    },
+    /// A sort where only the first `offset + limit` rows are needed, plus any rows equal to the
+    /// last of them for `with_ties`
+    TopN {
+        keys: Vec<SortKey>,
+        limit: u64,
+        offset: u64,
+        with_ties: bool,
+        input: Box<LogicalPlan>,
+    },
+    /// `FOR UPDATE`/`FOR SHARE`, locks the rows of the tables as they're asked for so a limit
+    /// above only locks the rows it returns. Once locked the tables' columns are read again, rows
+    /// which have been deleted or no longer pass `recheck` are skipped.
+    LockRows {
+        tables: Vec<LockedTable>,
+        recheck: Vec<ScalarExpr>,
+        input: Box<LogicalPlan>,
+    },
+    /// `with_ties` is only valid straight over a sort, the optimiser turns the pair into a top-N
+    Limit {
+        limit: Option<u64>,
+        offset: u64,
+        with_ties: bool,
+        input: Box<LogicalPlan>,
+    },
+    Values {
+        rows: Vec<Vec<ScalarExpr>>,
+        schema: Schema,
+    },
+    /// Runs the subquery for every row of the input with its outer column references set to
+    /// the row's values, adding a column with the result. This is what happens to subqueries
+    /// which can't be turned into joins.
+    Apply {
+        kind: ApplyKind,
+        schema: Schema,
+        input: Box<LogicalPlan>,
+        subquery: Box<LogicalPlan>,
+    },
+    /// Reads the rows of a CTE worked out by a `With` further up, or the rows from the last
+    /// iteration of a recursive CTE. `id` tells apart CTEs with the same name.
+    CteScan {
+        name: String,
+        id: usize,
+        schema: Schema,
+    },
+    /// Works out the CTE once so its rows can be read by any number of `CteScan`s in the input
+    With {
+        name: String,
+        id: usize,
+        cte: Box<LogicalPlan>,
+        input: Box<LogicalPlan>,
+    },
+    /// `WITH RECURSIVE`, the recursive side is run again and again on the rows from the last go,
+    /// which it reads from the work table `id`, until it doesn't produce any new rows. With
+    /// `distinct` rows which have been seen before are dropped.
+    RecursiveCte {
+        name: String,
+        id: usize,
+        distinct: bool,
+        schema: Schema,
+        anchor: Box<LogicalPlan>,
+        recursive: Box<LogicalPlan>,
+    },
+    /// Rows are matched on all of their columns, with nulls equal to each other. Without `all`
+    /// the output has no duplicates.
+    SetOperation {
+        op: SetOperator,
+        all: bool,
+        schema: Schema,
+        left: Box<LogicalPlan>,
+        right: Box<LogicalPlan>,
+    },
+    /// The modifications output the rows they've stored, or deleted, when there's a `RETURNING`
+    /// clause to project them. Otherwise the schema's empty and nothing is output.
+    Insert {
+        table: String,
+        columns: Vec<String>,
+        on_conflict: Option<OnConflict>,
+        schema: Schema,
+        input: Box<LogicalPlan>,
+    },
+    /// The input's first columns are the table's, any after that are left over from planning the
+    /// `WHERE`
+    Update {
+        table: String,
+        assignments: Vec<(String, ScalarExpr)>,
+        schema: Schema,
+        input: Box<LogicalPlan>,
+    },
+    Delete {
+        table: String,
+        schema: Schema,
+        input: Box<LogicalPlan>,
+    },
+}
+
+impl LogicalPlan {
+    pub fn schema(&self) -> &Schema {
+        match self {
+            Self::Scan { schema, .. }
+            | Self::Project { schema, .. }
+            | Self::Join { schema, .. }
+            | Self::Aggregate { schema, .. }
+            | Self::Window { schema, .. }
+            | Self::Values { schema, .. }
+            | Self::Apply { schema, .. }
+            | Self::CteScan { schema, .. }
+            | Self::RecursiveCte { schema, .. }
+            | Self::SetOperation { schema, .. }
+            | Self::Insert { schema, .. }
+            | Self::Update { schema, .. }
+            | Self::Delete { schema, .. } => schema,
+            Self::With { input, .. } => input.schema(),
+            Self::Filter { input, .. }
+            | Self::Sort { input, .. }
+            | Self::TopN { input, .. }
+            | Self::LockRows { input, .. }
+            | Self::Limit { input, .. } => input.schema(),
+        }
+    }
+
+    /// Joins the two plans working out the output schema, outer joins make the columns from the
+    /// side which might not match nullable.
+    pub fn join(
+        left: LogicalPlan,
+        right: LogicalPlan,
+        kind: JoinKind,
+        condition: Option<ScalarExpr>,
+    ) -> LogicalPlan {
+        let (l, r) = (left.schema().clone(), right.schema().clone());
+        let schema = match kind {
+            JoinKind::Semi | JoinKind::Anti => l,
+            JoinKind::Inner | JoinKind::Cross => l.join(&r),
+            JoinKind::Left => l.join(&r.with_nullable()),
+            JoinKind::Right => l.with_nullable().join(&r),
+            JoinKind::Full => l.with_nullable().join(&r.with_nullable()),
+        };
+        LogicalPlan::Join {
+            left: Box::new(left),
+            right: Box::new(right),
+            kind,
+            condition,
+            schema,
+        }
+    }
+
+    /// Adds an `Apply` running the subquery for each row of the input, its result is the last
+    /// column.
+    pub fn apply(input: LogicalPlan, subquery: LogicalPlan, kind: ApplyKind) -> LogicalPlan {
+        let mut schema = input.schema().clone();
+        let (datatype, nullable) = match &kind {
+            ApplyKind::Scalar => (subquery.schema().fields[0].datatype.clone(), true),
+            ApplyKind::Exists => (DataType::Boolean, false),
+            ApplyKind::In(_) => (DataType::Boolean, true),
+        };
+        schema.fields.push(Field {
+            nullable,
+            ..Field::new(None, format!("__subquery_{}", schema.len()), datatype)
+        });
+        LogicalPlan::Apply {
+            kind,
+            schema,
+            input: Box::new(input),
+            subquery: Box::new(subquery),
+        }
+    }
+
+    /// Recomputes the schema of an `Apply` after its input's changed, keeping the name of the
+    /// result column so anything using it still works.
+    pub fn with_apply_schema(self) -> LogicalPlan {
+        match self {
+            Self::Apply {
+                kind,
+                mut schema,
+                input,
+                subquery,
+            } => {
+                let result = schema.fields.pop().unwrap();
+                schema = input.schema().clone();
+                schema.fields.push(result);
+                Self::Apply {
+                    kind,
+                    schema,
+                    input,
+                    subquery,
+                }
+            }
+            plan => plan,
+        }
+    }
+
+    /// A plan producing no rows with the same columns as this one.
+    pub fn empty(&self) -> LogicalPlan {
+        LogicalPlan::Values {
+            rows: vec![],
+            schema: self.schema().clone(),
+        }
+    }
+
+    pub fn inputs(&self) -> Vec<&LogicalPlan> {
+        match self {
+            Self::Scan { .. } | Self::Values { .. } | Self::CteScan { .. } => vec![],
+            Self::Join { left, right, .. } | Self::SetOperation { left, right, .. } => {
+                vec![left, right]
+            }
+            Self::With { cte, input, .. } => vec![cte, input],
+            Self::RecursiveCte {
+                anchor, recursive, ..
+            } => vec![anchor, recursive],
+            Self::Apply {
+                input, subquery, ..
+            } => vec![input, subquery],
+            Self::Filter { input, .. }
+            | Self::Project { input, .. }
+            | Self::Aggregate { input, .. }
+            | Self::Window { input, .. }
+            | Self::Sort { input, .. }
+            | Self::TopN { input, .. }
+            | Self::LockRows { input, .. }
+            | Self::Limit { input, .. }
+            | Self::Insert { input, .. }
+            | Self::Update { input, .. }
+            | Self::Delete { input, .. } => vec![input],
+        }
+    }
+
+    /// Replaces the inputs of this node with the result of `f`. Joins have their schema
+    /// recomputed as the inputs' schemas may have changed.
+    pub fn map_inputs(
+        self,
+        mut f: impl FnMut(LogicalPlan) -> anyhow::Result<LogicalPlan>,
+    ) -> anyhow::Result<LogicalPlan> {
+        let plan = match self {
+            Self::Scan { .. } | Self::Values { .. } | Self::CteScan { .. } => self,
+            Self::With {
+                name,
+                id,
+                cte,
+                input,
+            } => Self::With {
+                name,
+                id,
+                cte: Box::new(f(*cte)?),
+                input: Box::new(f(*input)?),
+            },
+            Self::RecursiveCte {
+                name,
+                id,
+                distinct,
+                schema,
+                anchor,
+                recursive,
+            } => Self::RecursiveCte {
+                name,
+                id,
+                distinct,
+                schema,
+                anchor: Box::new(f(*anchor)?),
+                recursive: Box::new(f(*recursive)?),
+            },
+            Self::SetOperation {
+                op,
+                all,
+                schema,
+                left,
+                right,
+            } => Self::SetOperation {
+                op,
+                all,
+                schema,
+                left: Box::new(f(*left)?),
+                right: Box::new(f(*right)?),
+            },
+            Self::Join {
+                left,
+                right,
+                kind,
+                condition,
+                ..
+            } => Self::join(f(*left)?, f(*right)?, kind, condition),
+            Self::Apply {
+                kind,
+                schema,
+                input,
+                subquery,
+            } => Self::Apply {
+                kind,
+                schema,
+                input: Box::new(f(*input)?),
+                subquery: Box::new(f(*subquery)?),
+            }
+            .with_apply_schema(),
+            Self::Filter { predicate, input } => Self::Filter {
+                predicate,
+                input: Box::new(f(*input)?),
+            },
+            Self::Project {
+                exprs,
+                schema,
+                input,
+            } => Self::Project {
+                exprs,
+                schema,
+                input: Box::new(f(*input)?),
+            },
+            Self::Aggregate {
+                group_by,
+                aggregates,
+                schema,
+                input,
+            } => Self::Aggregate {
+                group_by,
+                aggregates,
+                schema,
+                input: Box::new(f(*input)?),
+            },
+            Self::Window {
+                windows,
+                schema,
+                input,
+            } => Self::Window {
+                windows,
+                schema,
+                input: Box::new(f(*input)?),
+            },
+            Self::Sort { keys, input } => Self::Sort {
+                keys,
+                input: Box::new(f(*input)?),
+            },
+            Self::TopN {
+                keys,
+                limit,
+                offset,
+                with_ties,
+                input,
+            } => Self::TopN {
+                keys,
+                limit,
+                offset,
+                with_ties,
+                input: Box::new(f(*input)?),
+            },
+            Self::LockRows {
+                tables,
+                recheck,
+                input,
+            } => Self::LockRows {
+                tables,
+                recheck,
+                input: Box::new(f(*input)?),
+            },
+            Self::Limit {
+                limit,
+                offset,
+                with_ties,
+                input,
+            } => Self::Limit {
+                limit,
+                offset,
+                with_ties,
+                input: Box::new(f(*input)?),
+            },
+            Self::Insert {
+                table,
+                columns,
+                on_conflict,
+                schema,
+                input,
+            } => Self::Insert {
+                table,
+                columns,
+                on_conflict,
+                schema,
+                input: Box::new(f(*input)?),
+            },
+            Self::Update {
+                table,
+                assignments,
+                schema,
+                input,
+            } => Self::Update {
+                table,
+                assignments,
+                schema,
+                input: Box::new(f(*input)?),
+            },
+            Self::Delete {
+                table,
+                schema,
+                input,
+            } => Self::Delete {
+                table,
+                schema,
+                input: Box::new(f(*input)?),
+            },
+        };
+        Ok(plan)
+    }
+
+    /// Rewrites the plan bottom up, `f` is called on every node after its inputs have been
+    /// rewritten.
+    pub fn transform_up(
+        self,
+        f: &mut impl FnMut(LogicalPlan) -> anyhow::Result<LogicalPlan>,
+    ) -> anyhow::Result<LogicalPlan> {
+        let plan = self.map_inputs(|input| input.transform_up(f))?;
+        f(plan)
+    }
+
+    /// Applies `f` to every expression held directly by this node.
+    pub fn map_expressions(
+        self,
+        mut f: impl FnMut(ScalarExpr) -> anyhow::Result<ScalarExpr>,
+    ) -> anyhow::Result<LogicalPlan> {
+        let mut keys = |keys: Vec<SortKey>| {
+            keys.into_iter()
+                .map(|key| {
+                    Ok(SortKey {
+                        expr: f(key.expr)?,
+                        ..key
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+        let plan = match self {
+            Self::Scan {
+                table,
+                alias,
+                schema,
+                projection,
+                filters,
+                lock,
+                primary_key,
+            } => Self::Scan {
+                table,
+                alias,
+                schema,
+                projection,
+                filters: filters.into_iter().map(&mut f).collect::<Result<_, _>>()?,
+                lock,
+                primary_key: primary_key
+                    .map(|x| x.into_iter().map(&mut f).collect::<Result<_, _>>())
+                    .transpose()?,
+            },
+            Self::Filter { predicate, input } => Self::Filter {
+                predicate: f(predicate)?,
+                input,
+            },
+            Self::Project {
+                exprs,
+                schema,
+                input,
+            } => Self::Project {
+                exprs: exprs.into_iter().map(&mut f).collect::<Result<_, _>>()?,
+                schema,
+                input,
+            },
+            Self::Join {
+                left,
+                right,
+                kind,
+                condition,
+                schema,
+            } => Self::Join {
+                left,
+                right,
+                kind,
+                condition: condition.map(&mut f).transpose()?,
+                schema,
+            },
+            Self::Aggregate {
+                group_by,
+                aggregates,
+                schema,
+                input,
+            } => Self::Aggregate {
+                group_by: group_by.into_iter().map(&mut f).collect::<Result<_, _>>()?,
+                aggregates: aggregates
+                    .into_iter()
+                    .map(|agg| {
+                        Ok(AggregateExpr {
+                            args: agg.args.into_iter().map(&mut f).collect::<Result<_, _>>()?,
+                            order_by: agg
+                                .order_by
+                                .into_iter()
+                                .map(|key| {
+                                    Ok(SortKey {
+                                        expr: f(key.expr)?,
+                                        ..key
+                                    })
+                                })
+                                .collect::<anyhow::Result<_>>()?,
+                            ..agg
+                        })
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+                schema,
+                input,
+            },
+            Self::Window {
+                windows,
+                schema,
+                input,
+            } => Self::Window {
+                windows: windows
+                    .into_iter()
+                    .map(|w| w.map_exprs(&mut f))
+                    .collect::<anyhow::Result<_>>()?,
+                schema,
+                input,
+            },
+            Self::Sort { keys: k, input } => Self::Sort {
+                keys: keys(k)?,
+                input,
+            },
+            Self::TopN {
+                keys: k,
+                limit,
+                offset,
+                with_ties,
+                input,
+            } => Self::TopN {
+                keys: keys(k)?,
+                limit,
+                offset,
+                with_ties,
+                input,
+            },
+            Self::LockRows {
+                tables,
+                recheck,
+                input,
+            } => Self::LockRows {
+                tables,
+                recheck: recheck.into_iter().map(&mut f).collect::<Result<_, _>>()?,
+                input,
+            },
+            Self::Values { rows, schema } => Self::Values {
+                rows: rows
+                    .into_iter()
+                    .map(|row| row.into_iter().map(&mut f).collect::<Result<_, _>>())
+                    .collect::<anyhow::Result<_>>()?,
+                schema,
+            },
+            Self::Update {
+                table,
+                assignments,
+                schema,
+                input,
+            } => Self::Update {
+                table,
+                assignments: assignments
+                    .into_iter()
+                    .map(|(col, expr)| Ok((col, f(expr)?)))
+                    .collect::<anyhow::Result<_>>()?,
+                schema,
+                input,
+            },
+            Self::Apply {
+                kind: ApplyKind::In(expr),
+                schema,
+                input,
+                subquery,
+            } => Self::Apply {
+                kind: ApplyKind::In(f(expr)?),
+                schema,
+                input,
+                subquery,
+            },
+            plan @ (Self::Limit { .. }
+            | Self::Insert { .. }
+            | Self::Delete { .. }
+            | Self::Apply { .. }
+            | Self::CteScan { .. }
+            | Self::With { .. }
+            | Self::RecursiveCte { .. }
+            | Self::SetOperation { .. }) => plan,
+        };
+        Ok(plan)
+    }
+
+    /// The expressions held directly by this node
+    pub fn expressions(&self) -> Vec<&ScalarExpr> {
+        match self {
+            Self::Scan {
+                filters,
+                primary_key,
+                ..
+            } => filters.iter().chain(primary_key.iter().flatten()).collect(),
+            Self::Filter { predicate, .. } => vec![predicate],
+            Self::Project { exprs, .. } => exprs.iter().collect(),
+            Self::Join { condition, .. } => condition.iter().collect(),
+            Self::Aggregate {
+                group_by,
+                aggregates,
+                ..
+            } => group_by
+                .iter()
+                .chain(aggregates.iter().flat_map(|a| a.exprs()))
+                .collect(),
+            Self::Window { windows, .. } => windows.iter().flat_map(|w| w.exprs()).collect(),
+            Self::Sort { keys, .. } | Self::TopN { keys, .. } => {
+                keys.iter().map(|k| &k.expr).collect()
+            }
+            Self::LockRows { recheck, .. } => recheck.iter().collect(),
+            Self::Values { rows, .. } => rows.iter().flatten().collect(),
+            Self::Update { assignments, .. } => assignments.iter().map(|(_, e)| e).collect(),
+            Self::Apply {
+                kind: ApplyKind::In(expr),
+                ..
+            } => vec![expr],
+            Self::Limit { .. }
+            | Self::Insert { .. }
+            | Self::Delete { .. }
+            | Self::Apply { .. }
+            | Self::CteScan { .. }
+            | Self::With { .. }
+            | Self::RecursiveCte { .. }
+            | Self::SetOperation { .. } => vec![],
+        }
+    }
+
+    /// Whether anything in the plan refers to columns of an outer query. The subqueries of any
+    /// `Apply`s in the plan are left out, their outer query is the `Apply`'s input.
+    pub fn is_correlated(&self) -> bool {
+        self.expressions().iter().any(|e| e.is_correlated())
+            || match self {
+                Self::Apply { input, .. } => input.is_correlated(),
+                plan => plan.inputs().iter().any(|x| x.is_correlated()),
+            }
+    }
+
+    fn fmt_node(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Scan {
+                table,
+                alias,
+                projection,
+                filters,
+                lock,
+                primary_key,
+                ..
+            } => {
+                write!(f, "Scan: {}", table)?;
+                if let Some(alias) = alias {
+                    write!(f, " AS {}", alias)?;
+                }
+                if let Some(projection) = projection {
+                    write!(f, " projection=[{}]", projection.join(", "))?;
+                }
+                if !filters.is_empty() {
+                    write!(f, " filters=[{}]", list(filters))?;
+                }
+                if let Some(key) = primary_key {
+                    write!(f, " key=[{}]", list(key))?;
+                }
+                if let Some(lock) = lock {
+                    write!(f, " {}", lock)?;
+                }
+                Ok(())
+            }
+            Self::Filter { predicate, .. } => write!(f, "Filter: {}", predicate),
+            Self::Project { exprs, schema, .. } => {
+                let exprs = exprs
+                    .iter()
+                    .zip(&schema.fields)
+                    .map(|(expr, field)| match expr {
+                        ScalarExpr::Column(c) if *c == field.column() => expr.to_string(),
+                        _ => format!("{} AS {}", expr, field.name),
+                    });
+                write!(f, "Projection: {}", list(exprs))
+            }
+            Self::Join {
+                kind, condition, ..
+            } => {
+                write!(f, "Join: {}", kind)?;
+                if let Some(condition) = condition {
+                    write!(f, " ON {}", condition)?;
+                }
+                Ok(())
+            }
+            Self::Aggregate {
+                group_by,
+                aggregates,
+                ..
+            } => write!(
+                f,
+                "Aggregate: groupBy=[{}], aggr=[{}]",
+                list(group_by),
+                list(aggregates)
+            ),
+            Self::Window { windows, .. } => write!(f, "Window: {}", list(windows)),
+            Self::Sort { keys, .. } => write!(f, "Sort: {}", list(keys)),
+            Self::TopN {
+                keys,
+                limit,
+                offset,
+                with_ties,
+                ..
+            } => {
+                write!(f, "TopN: {} limit={}, offset={}", list(keys), limit, offset)?;
+                if *with_ties {
+                    write!(f, ", with ties")?;
+                }
+                Ok(())
+            }
+            Self::LockRows {
+                tables, recheck, ..
+            } => {
+                write!(f, "LockRows: {}", list(tables))?;
+                if !recheck.is_empty() {
+                    write!(f, " recheck=[{}]", list(recheck))?;
+                }
+                Ok(())
+            }
+            Self::Limit {
+                limit,
+                offset,
+                with_ties,
+                ..
+            } => {
+                match limit {
+                    Some(limit) => write!(f, "Limit: limit={}, offset={}", limit, offset)?,
+                    None => write!(f, "Limit: offset={}", offset)?,
+                }
+                if *with_ties {
+                    write!(f, ", with ties")?;
+                }
+                Ok(())
+            }
+            Self::Values { rows, .. } if rows.is_empty() => write!(f, "Empty"),
+            Self::Values { rows, .. } => {
+                let rows = rows.iter().map(|row| format!("({})", list(row)));
+                write!(f, "Values: {}", list(rows))
+            }
+            Self::Apply { kind, schema, .. } => {
+                write!(
+                    f,
+                    "Apply: {} AS {}",
+                    kind,
+                    schema.fields.last().unwrap().name
+                )
+            }
+            Self::CteScan { name, .. } => write!(f, "CteScan: {}", name),
+            Self::With { name, .. } => write!(f, "With: {}", name),
+            Self::RecursiveCte { name, distinct, .. } => {
+                let union = if *distinct { "UNION" } else { "UNION ALL" };
+                write!(f, "RecursiveCte: {} {}", name, union)
+            }
+            Self::SetOperation { op, all, .. } => {
+                write!(f, "SetOperation: {}{}", op, if *all { " ALL" } else { "" })
+            }
+            Self::Insert {
+                table,
+                columns,
+                on_conflict,
+                ..
+            } => {
+                write!(f, "Insert: {} ({})", table, columns.join(", "))?;
+                match on_conflict {
+                    Some(on_conflict) => write!(f, " {}", on_conflict),
+                    None => Ok(()),
+                }
+            }
+            Self::Update {
+                table, assignments, ..
+            } => {
+                let assignments = assignments
+                    .iter()
+                    .map(|(col, expr)| format!("{} = {}", col, expr));
+                write!(f, "Update: {} SET {}", table, list(assignments))
+            }
+            Self::Delete { table, .. } => write!(f, "Delete: {}", table),
+        }
+    }
+
+    fn fmt_indented(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        write!(f, "{:width$}", "", width = depth * 2)?;
+        self.fmt_node(f)?;
+        writeln!(f)?;
+        for input in self.inputs() {
+            input.fmt_indented(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn list<T: fmt::Display>(items: impl IntoIterator<Item = T>) -> String {
+    items
+        .into_iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Prints the plan as an indented tree, one node per line.
+impl fmt::Display for LogicalPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+/// What an `ORDER BY` key sorts on, either a column of the output or an expression which has to
+/// be computed alongside the output and then dropped.
+enum SortTarget {
+    Output(usize),
+    Hidden(ScalarExpr),
+}
+
+/// A CTE which can be referred to while planning. `references` counts how many times it's used
+/// so we know whether to work it out once or just put its plan in place of the reference.
+#[derive(Clone)]
+struct CteDefinition {
+    name: String,
+    id: usize,
+    plan: LogicalPlan,
+    references: Rc<Cell<usize>>,
+    /// From `AS [NOT] MATERIALIZED`
+    materialized: Option<bool>,
+}
+
+/// Turns statements into logical plans.
+#[derive(Clone)]
+pub struct PlanBuilder<'a> {
+    catalog: &'a dyn Catalog,
+    locking: Vec<RowLocking>,
+    /// When planning a subquery, the columns of the query it's in
+    outer: Option<Schema>,
+    /// The CTEs in scope, innermost last
+    ctes: Vec<CteDefinition>,
+    /// Gives each CTE in the statement its own ID
+    next_cte: Rc<Cell<usize>>,
+    /// The `WINDOW` clause of the select being planned
+    windows: BTreeMap<String, ast::WindowSpec>,
+}
+
+impl<'a> PlanBuilder<'a> {
+    pub fn new(catalog: &'a dyn Catalog) -> Self {
+        Self {
+            catalog,
+            locking: vec![],
+            outer: None,
+            ctes: vec![],
+            next_cte: Rc::new(Cell::new(0)),
+            windows: BTreeMap::new(),
+        }
+    }
+
+    /// Rows read from the tables will be locked as specified.
+    pub fn with_locking(mut self, locking: Vec<RowLocking>) -> Self {
+        self.locking = locking;
+        self
+    }
+
+    /// The tables whose rows the select over `plan` should lock, the scans of them have added a
+    /// row key column
+    fn locked_tables(&self, plan: &LogicalPlan) -> Vec<LockedTable> {
+        fn scans<'p>(plan: &'p LogicalPlan, found: &mut Vec<&'p LogicalPlan>) {
+            if let LogicalPlan::Scan { .. } = plan {
+                found.push(plan);
+            }
+            for input in plan.inputs() {
+                scans(input, found);
+            }
+        }
+        let mut found = vec![];
+        scans(plan, &mut found);
+        let mut tables = vec![];
+        for field in plan.schema().fields.iter().filter(|x| x.name == ROW_KEY) {
+            let relation = field.relation.clone().unwrap_or_default();
+            let scan = found.iter().find_map(|x| match x {
+                LogicalPlan::Scan { table, schema, .. } => {
+                    schema.fields.contains(field).then_some(table)
+                }
+                _ => None,
+            });
+            if let (Some(table), Some(lock)) = (scan, self.lock_for(&relation)) {
+                tables.push(LockedTable {
+                    table: table.clone(),
+                    relation,
+                    lock: lock.clone(),
+                });
+            }
+        }
+        tables
+    }
+
+    /// How the rows of the table or alias should be locked, if at all
+    fn lock_for(&self, relation: &str) -> Option<&RowLocking> {
+        self.locking.iter().rev().find(|x| match &x.table {
+            None => true,
+            Some(t) => t.eq_ignore_ascii_case(relation),
+        })
+    }
+
+    pub fn plan_statement(&self, statement: &Statement) -> anyhow::Result<LogicalPlan> {
+        match statement {
+            Statement::Query(query) => self.plan_query(query),
+            Statement::Insert(insert) => self.plan_insert(insert),
+            Statement::Update {
+                table,
+                assignments,
+                from,
+                selection,
+                returning,
+            } => {
+                if from.is_some() {
+                    anyhow::bail!("UPDATE ... FROM is not supported");
+                }
+                if !table.joins.is_empty() {
+                    anyhow::bail!("Can only UPDATE a single table");
+                }
+                let (name, plan) = self.plan_target_table(&table.relation, selection.as_ref())?;
+                let metadata = self.catalog.table_metadata(&name)?;
+                let assignments =
+                    self.lower_assignments(&name, &metadata, assignments, plan.schema())?;
+                let schema = returning_schema(&plan, metadata.len(), returning);
+                let plan = LogicalPlan::Update {
+                    table: name,
+                    assignments,
+                    schema,
+                    input: Box::new(plan),
+                };
+                self.plan_returning(plan, returning)
+            }
+            Statement::Delete(delete) => {
+                if delete.using.is_some() || !delete.tables.is_empty() {
+                    anyhow::bail!("Can only DELETE from a single table");
+                }
+                let from = match &delete.from {
+                    FromTable::WithFromKeyword(x) | FromTable::WithoutKeyword(x) => x,
+                };
+                let relation = match from.as_slice() {
+                    [table] if table.joins.is_empty() => &table.relation,
+                    _ => anyhow::bail!("Can only DELETE from a single table"),
+                };
+                let (name, plan) = self.plan_target_table(relation, delete.selection.as_ref())?;
+                let width = self.catalog.table_metadata(&name)?.len();
+                let schema = returning_schema(&plan, width, &delete.returning);
+                let plan = LogicalPlan::Delete {
+                    table: name,
+                    schema,
+                    input: Box::new(plan),
+                };
+                self.plan_returning(plan, &delete.returning)
+            }
+            s => anyhow::bail!("Can't create a plan for statement: {}", s),
+        }
+    }
+
+    pub fn plan_query(&self, query: &Query) -> anyhow::Result<LogicalPlan> {
+        let Some(with) = &query.with else {
+            return self.plan_query_body(query);
+        };
+        let mut builder = self.clone();
+        let mut defined: Vec<CteDefinition> = vec![];
+        for cte in &with.cte_tables {
+            let name = cte.alias.name.value.clone();
+            if defined.iter().any(|x| x.name == name) {
+                anyhow::bail!("WITH query name {} specified more than once", name);
+            }
+            let id = builder.cte_id();
+            let recursive = match with.recursive {
+                true => builder.plan_recursive_cte(cte)?,
+                false => None,
+            };
+            let plan = match recursive {
+                Some(plan) => plan,
+                None => rename(builder.plan_query(&cte.query)?, &cte.alias)?,
+            };
+            let definition = CteDefinition {
+                name,
+                id,
+                plan,
+                references: Rc::new(Cell::new(0)),
+                materialized: cte.materialized.as_ref().map(|x| match x {
+                    ast::CteAsMaterialized::Materialized => true,
+                    ast::CteAsMaterialized::NotMaterialized => false,
+                }),
+            };
+            builder.ctes.push(definition.clone());
+            defined.push(definition);
+        }
+        let mut plan = builder.plan_query_body(query)?;
+        // Going backwards so the CTEs used by later ones are worked out first
+        for cte in defined.into_iter().rev() {
+            let references = cte.references.get();
+            if references == 0 {
+                continue;
+            }
+            // Only worth working out up front if it's used more than once
+            if cte.materialized.unwrap_or(references > 1) {
+                plan = LogicalPlan::With {
+                    name: cte.name,
+                    id: cte.id,
+                    cte: Box::new(cte.plan),
+                    input: Box::new(plan),
+                };
+            } else {
+                plan = inline_cte(plan, cte.id, &cte.plan)?;
+            }
+        }
+        Ok(plan)
+    }
+
+    fn cte_id(&self) -> usize {
+        let id = self.next_cte.get();
+        self.next_cte.set(id + 1);
+        id
+    }
+
+    /// A CTE in `WITH RECURSIVE` is only recursive if it's a `UNION` whose right side refers to
+    /// the CTE, otherwise it's planned like any other.
+    fn plan_recursive_cte(&self, cte: &ast::Cte) -> anyhow::Result<Option<LogicalPlan>> {
+        let SetExpr::SetOperation {
+            op: ast::SetOperator::Union,
+            set_quantifier,
+            left,
+            right,
+        } = cte.query.body.as_ref()
+        else {
+            return Ok(None);
+        };
+        let name = cte.alias.name.value.clone();
+        let anchor = self.plan_set_expr(left)?;
+        let schema = rename(anchor.clone(), &cte.alias)?.schema().clone();
+        // The recursive side reads the rows from the last go through the work table
+        let work_table = self.cte_id();
+        let references = Rc::new(Cell::new(0));
+        let mut builder = self.clone();
+        builder.ctes.push(CteDefinition {
+            name: name.clone(),
+            id: work_table,
+            plan: LogicalPlan::CteScan {
+                name: name.clone(),
+                id: work_table,
+                schema: schema.clone(),
+            },
+            references: references.clone(),
+            materialized: Some(true),
+        });
+        let recursive = builder.plan_set_expr(right)?;
+        if references.get() == 0 {
+            return Ok(None);
+        }
+        let query = &cte.query;
+        if !query.order_by.is_empty() || query.limit.is_some() || query.offset.is_some() {
+            anyhow::bail!("ORDER BY, LIMIT and OFFSET can't be used in a recursive query");
+        }
+        if recursive.schema().len() != schema.len() {
+            anyhow::bail!(
+                "Both sides of the UNION in recursive query {} need the same number of columns",
+                name
+            );
+        }
+        let distinct = match set_quantifier {
+            ast::SetQuantifier::All => false,
+            ast::SetQuantifier::Distinct | ast::SetQuantifier::None => true,
+            q => anyhow::bail!("UNION {} isn't supported in a recursive query", q),
+        };
+        Ok(Some(LogicalPlan::RecursiveCte {
+            name,
+            id: work_table,
+            distinct,
+            schema,
+            anchor: Box::new(anchor),
+            recursive: Box::new(recursive),
+        }))
+    }
+
+    fn plan_query_body(&self, query: &Query) -> anyhow::Result<LogicalPlan> {
+        let mut plan = match query.body.as_ref() {
+            SetExpr::Select(select) => self.plan_select(select, &query.order_by)?,
+            body => {
+                let plan = self.plan_set_expr(body)?;
+                let mut keys = vec![];
+                for order_by in &query.order_by {
+                    let expr = match self.output_sort_target(&order_by.expr, plan.schema())? {
+                        Some(i) => ScalarExpr::Column(plan.schema().fields[i].column()),
+                        None => self.lower_expr(&order_by.expr, plan.schema(), false)?,
+                    };
+                    keys.push(sort_key(order_by, expr));
+                }
+                if keys.is_empty() {
+                    plan
+                } else {
+                    LogicalPlan::Sort {
+                        keys,
+                        input: Box::new(plan),
+                    }
+                }
+            }
+        };
+
+        let mut limit = query
+            .limit
+            .as_ref()
+            .map(|x| self.constant_u64(x, "LIMIT"))
+            .transpose()?;
+        let offset = query
+            .offset
+            .as_ref()
+            .map(|x| self.constant_u64(&x.value, "OFFSET"))
+            .transpose()?;
+        let mut with_ties = false;
+        if let Some(fetch) = &query.fetch {
+            if limit.is_some() {
+                anyhow::bail!("LIMIT and FETCH can't be used together");
+            }
+            if fetch.percent {
+                anyhow::bail!("FETCH ... PERCENT is not supported");
+            }
+            if fetch.with_ties && query.order_by.is_empty() {
+                anyhow::bail!("FETCH ... WITH TIES needs an ORDER BY");
+            }
+            // `FETCH FIRST ROW ONLY` is one row
+            limit = Some(match &fetch.quantity {
+                Some(quantity) => self.constant_u64(quantity, "FETCH")?,
+                None => 1,
+            });
+            with_ties = fetch.with_ties;
+        }
+        if limit.is_some() || offset.is_some() {
+            plan = LogicalPlan::Limit {
+                limit,
+                offset: offset.unwrap_or_default(),
+                with_ties,
+                input: Box::new(plan),
+            };
+        }
+        Ok(plan)
+    }
+
+    fn plan_set_expr(&self, body: &SetExpr) -> anyhow::Result<LogicalPlan> {
+        match body {
+            SetExpr::Select(select) => self.plan_select(select, &[]),
+            SetExpr::Query(query) => self.plan_query(query),
+            SetExpr::Values(values) => self.plan_values(&values.rows),
+            SetExpr::SetOperation {
+                op,
+                set_quantifier,
+                left,
+                right,
+            } => {
+                let op = match op {
+                    ast::SetOperator::Union => SetOperator::Union,
+                    ast::SetOperator::Intersect => SetOperator::Intersect,
+                    ast::SetOperator::Except => SetOperator::Except,
+                };
+                let all = match set_quantifier {
+                    ast::SetQuantifier::All => true,
+                    ast::SetQuantifier::Distinct | ast::SetQuantifier::None => false,
+                    q => anyhow::bail!("{} {} is not supported", op, q),
+                };
+                let left = self.plan_set_expr(left)?;
+                let right = self.plan_set_expr(right)?;
+                let schema = set_operation_schema(op, left.schema(), right.schema())?;
+                Ok(LogicalPlan::SetOperation {
+                    op,
+                    all,
+                    schema,
+                    left: Box::new(left),
+                    right: Box::new(right),
+                })
+            }
+            e => anyhow::bail!("Unsupported query: {}", e),
+        }
+    }
+
+    fn plan_values(&self, rows: &[Vec<Expr>]) -> anyhow::Result<LogicalPlan> {
+        let empty = Schema::default();
+        let mut res: Vec<Vec<ScalarExpr>> = Vec::with_capacity(rows.len());
+        for row in rows {
+            if !res.is_empty() && row.len() != res[0].len() {
+                anyhow::bail!("VALUES lists must all be the same length");
+            }
+            res.push(
+                row.iter()
+                    .map(|x| self.lower_expr(x, &empty, false))
+                    .collect::<anyhow::Result<Vec<_>>>()?,
+            );
+        }
+        let width = res.first().map(|x| x.len()).unwrap_or_default();
+        let fields = (0..width)
+            .map(|i| {
+                // Use the first non-null value to decide the type
+                let datatype = res
+                    .iter()
+                    .map(|row| row[i].data_type(&empty))
+                    .find(|x| *x != DataType::Unspecified)
+                    .unwrap_or(DataType::Unspecified);
+                Field::new(None, format!("column{}", i + 1), datatype)
+            })
+            .collect();
+        Ok(LogicalPlan::Values {
+            rows: res,
+            schema: Schema::new(fields),
+        })
+    }
+
+    fn plan_select(
+        &self,
+        select: &Select,
+        order_by: &[OrderByExpr],
+    ) -> anyhow::Result<LogicalPlan> {
+        let mut windows = BTreeMap::new();
+        for ast::NamedWindowDefinition(name, expr) in &select.named_window {
+            let spec = match expr {
+                ast::NamedWindowExpr::NamedWindow(base) => {
+                    resolve_window(&windows, &ast::WindowType::NamedWindow(base.clone()))?
+                }
+                ast::NamedWindowExpr::WindowSpec(spec) => {
+                    resolve_window(&windows, &ast::WindowType::WindowSpec(spec.clone()))?
+                }
+            };
+            if windows.insert(name.value.clone(), spec).is_some() {
+                anyhow::bail!("Window {} is already defined", name);
+            }
+        }
+        // Named windows can only be used by the select they're defined in
+        if windows != self.windows {
+            let builder = PlanBuilder {
+                windows,
+                ..self.clone()
+            };
+            return builder.plan_select(select, order_by);
+        }
+        let distinct = match &select.distinct {
+            None => false,
+            Some(Distinct::Distinct) => true,
+            Some(Distinct::On(_)) => anyhow::bail!("DISTINCT ON is not supported"),
+        };
+
+        let mut plan = self.plan_from(&select.from)?;
+        let locked = self.locked_tables(&plan);
+
+        let mut recheck = vec![];
+        if let Some(selection) = &select.selection {
+            let predicate = self.lower_expr(selection, plan.schema(), false)?;
+            if !locked.is_empty() {
+                recheck = predicate.clone().split_conjunction();
+            }
+            plan = LogicalPlan::Filter {
+                predicate,
+                input: Box::new(plan),
+            };
+        }
+
+        // Work out what the query outputs, these can still contain aggregates at this point
+        let (mut outputs, fields) =
+            self.lower_select_items(&select.projection, plan.schema(), true)?;
+        let output_schema = Schema::new(fields);
+
+        let mut sort_targets = vec![];
+        for key in order_by {
+            let target = match self.output_sort_target(&key.expr, &output_schema)? {
+                Some(i) => SortTarget::Output(i),
+                None => {
+                    let expr = self.lower_expr(&key.expr, plan.schema(), true)?;
+                    match outputs.iter().position(|x| *x == expr) {
+                        Some(i) => SortTarget::Output(i),
+                        None if distinct => anyhow::bail!(
+                            "For SELECT DISTINCT, ORDER BY expressions must appear in select list"
+                        ),
+                        None => SortTarget::Hidden(expr),
+                    }
+                }
+            };
+            sort_targets.push(target);
+        }
+
+        let having = select
+            .having
+            .as_ref()
+            .map(|x| self.lower_expr(x, plan.schema(), true))
+            .transpose()?;
+        if having.as_ref().is_some_and(|x| x.contains_window()) {
+            anyhow::bail!("Window functions are not allowed in HAVING");
+        }
+
+        let group_by = match &select.group_by {
+            GroupByExpr::All => anyhow::bail!("GROUP BY ALL is not supported"),
+            GroupByExpr::Expressions(exprs) => exprs
+                .iter()
+                .map(|x| self.lower_group_by(x, plan.schema(), &select.projection, &outputs))
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        };
+
+        let is_aggregate = !group_by.is_empty()
+            || having.is_some()
+            || outputs.iter().any(|x| x.contains_aggregate())
+            || sort_targets
+                .iter()
+                .any(|x| matches!(x, SortTarget::Hidden(e) if e.contains_aggregate()));
+
+        if !locked.is_empty() {
+            let lock = &locked[0].lock;
+            if is_aggregate {
+                anyhow::bail!(
+                    "{} is not allowed with GROUP BY or aggregate functions",
+                    lock
+                );
+            }
+            if distinct {
+                anyhow::bail!("{} is not allowed with DISTINCT", lock);
+            }
+            let windowed = outputs.iter().any(|x| x.contains_window())
+                || sort_targets
+                    .iter()
+                    .any(|x| matches!(x, SortTarget::Hidden(e) if e.contains_window()));
+            if windowed {
+                anyhow::bail!("{} is not allowed with window functions", lock);
+            }
+            // Subqueries can't be run again for a single row
+            recheck.retain(|x| !x.contains_subquery() && !x.is_correlated());
+            return plan_locked_select(
+                plan,
+                locked,
+                recheck,
+                (outputs, output_schema),
+                order_by,
+                sort_targets,
+            );
+        }
+
+        if is_aggregate {
+            let mut aggregates = vec![];
+            let exprs = outputs
+                .iter()
+                .chain(having.iter())
+                .chain(sort_targets.iter().filter_map(|x| match x {
+                    SortTarget::Hidden(e) => Some(e),
+                    SortTarget::Output(_) => None,
+                }));
+            for expr in exprs {
+                expr.walk(&mut |e| {
+                    if let ScalarExpr::Aggregate(agg) = e {
+                        if !aggregates.contains(agg) {
+                            aggregates.push(agg.clone());
+                        }
+                    }
+                });
+            }
+
+            let mut fields = vec![];
+            for expr in &group_by {
+                let field = match expr {
+                    ScalarExpr::Column(c) => plan.schema().field(c)?.clone(),
+                    e => Field::new(None, e.to_string(), e.data_type(plan.schema())),
+                };
+                fields.push(field);
+            }
+            for agg in &aggregates {
+                let mut field = Field::new(None, agg.to_string(), agg.data_type(plan.schema()));
+                field.nullable = agg.func != AggregateFunction::Count;
+                fields.push(field);
+            }
+            let schema = Schema::new(fields);
+
+            let rewrite = |expr: ScalarExpr| {
+                expr.transform_down(&mut |e| {
+                    if let Some(i) = group_by.iter().position(|x| x == e) {
+                        return Ok(Some(ScalarExpr::Column(schema.fields[i].column())));
+                    }
+                    match e {
+                        ScalarExpr::Aggregate(agg) => {
+                            let i = aggregates.iter().position(|x| x == agg).unwrap();
+                            Ok(Some(ScalarExpr::Column(
+                                schema.fields[group_by.len() + i].column(),
+                            )))
+                        }
+                        ScalarExpr::Column(c) => anyhow::bail!(
+                            "Column {} must appear in the GROUP BY clause or be used in an aggregate function",
+                            c
+                        ),
+                        _ => Ok(None),
+                    }
+                })
+            };
+            outputs = outputs
+                .into_iter()
+                .map(rewrite)
+                .collect::<anyhow::Result<_>>()?;
+            sort_targets = sort_targets
+                .into_iter()
+                .map(|x| match x {
+                    SortTarget::Hidden(e) => rewrite(e).map(SortTarget::Hidden),
+                    x => Ok(x),
+                })
+                .collect::<anyhow::Result<_>>()?;
+            let having = having.map(rewrite).transpose()?;
+
+            plan = LogicalPlan::Aggregate {
+                group_by,
+                aggregates,
+                schema,
+                input: Box::new(plan),
+            };
+            if let Some(predicate) = having {
+                plan = LogicalPlan::Filter {
+                    predicate,
+                    input: Box::new(plan),
+                };
+            }
+        }
+
+        // Window functions are worked out after any grouping, so they can use aggregates, and
+        // before the projection
+        let mut windows = vec![];
+        let exprs = outputs
+            .iter()
+            .chain(sort_targets.iter().filter_map(|x| match x {
+                SortTarget::Hidden(e) => Some(e),
+                SortTarget::Output(_) => None,
+            }));
+        for expr in exprs {
+            expr.walk(&mut |e| {
+                if let ScalarExpr::Window(window) = e {
+                    if !windows.contains(window.as_ref()) {
+                        windows.push(window.as_ref().clone());
+                    }
+                }
+            });
+        }
+        if !windows.is_empty() {
+            let mut fields = plan.schema().fields.clone();
+            for window in &windows {
+                fields.push(Field::new(
+                    None,
+                    window.to_string(),
+                    window.data_type(plan.schema()),
+                ));
+            }
+            let schema = Schema::new(fields);
+            let offset = plan.schema().len();
+            let rewrite = |expr: ScalarExpr| {
+                expr.transform_down(&mut |e| match e {
+                    ScalarExpr::Window(window) => {
+                        let i = windows.iter().position(|x| x == window.as_ref()).unwrap();
+                        Ok(Some(ScalarExpr::Column(schema.fields[offset + i].column())))
+                    }
+                    _ => Ok(None),
+                })
+            };
+            outputs = outputs
+                .into_iter()
+                .map(rewrite)
+                .collect::<anyhow::Result<_>>()?;
+            sort_targets = sort_targets
+                .into_iter()
+                .map(|x| match x {
+                    SortTarget::Hidden(e) => rewrite(e).map(SortTarget::Hidden),
+                    x => Ok(x),
+                })
+                .collect::<anyhow::Result<_>>()?;
+            plan = LogicalPlan::Window {
+                windows,
+                schema,
+                input: Box::new(plan),
+            };
+        }
+
+        // Anything we sort on which isn't in the output gets computed as an extra column which is
+        // removed again after sorting
+        let mut exprs = outputs;
+        let mut fields = output_schema.fields.clone();
+        let mut keys = vec![];
+        for (key, target) in order_by.iter().zip(sort_targets) {
+            let column = match target {
+                SortTarget::Output(i) => fields[i].column(),
+                SortTarget::Hidden(expr) => {
+                    let field = Field::new(
+                        None,
+                        format!("__sort_{}", fields.len()),
+                        expr.data_type(plan.schema()),
+                    );
+                    exprs.push(expr);
+                    fields.push(field);
+                    fields.last().unwrap().column()
+                }
+            };
+            keys.push(sort_key(key, ScalarExpr::Column(column)));
+        }
+        let has_hidden = fields.len() > output_schema.len();
+
+        plan = LogicalPlan::Project {
+            exprs,
+            schema: Schema::new(fields),
+            input: Box::new(plan),
+        };
+        if distinct {
+            plan = LogicalPlan::Aggregate {
+                group_by: output_schema
+                    .fields
+                    .iter()
+                    .map(|x| ScalarExpr::Column(x.column()))
+                    .collect(),
+                aggregates: vec![],
+                schema: output_schema.clone(),
+                input: Box::new(plan),
+            };
+        }
+        if !keys.is_empty() {
+            plan = LogicalPlan::Sort {
+                keys,
+                input: Box::new(plan),
+            };
+        }
+        if has_hidden {
+            plan = LogicalPlan::Project {
+                exprs: output_schema
+                    .fields
+                    .iter()
+                    .map(|x| ScalarExpr::Column(x.column()))
+                    .collect(),
+                schema: output_schema,
+                input: Box::new(plan),
+            };
+        }
+        Ok(plan)
+    }
+
+    /// `ORDER BY` can refer to output columns by name or position.
+    fn output_sort_target(&self, expr: &Expr, output: &Schema) -> anyhow::Result<Option<usize>> {
+        match expr {
+            Expr::Value(ast::Value::Number(n, _)) => {
+                let i = n
+                    .to_string()
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|i| (1..=output.len()).contains(i))
+                    .with_context(|| format!("ORDER BY position {} is not in select list", n))?;
+                Ok(Some(i - 1))
+            }
+            Expr::Identifier(_) | Expr::CompoundIdentifier(_) => {
+                output.find(&identifier_column(expr)?)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// `GROUP BY` takes input columns first, then output column names or positions.
+    fn lower_group_by(
+        &self,
+        expr: &Expr,
+        schema: &Schema,
+        projection: &[SelectItem],
+        outputs: &[ScalarExpr],
+    ) -> anyhow::Result<ScalarExpr> {
+        let output = match expr {
+            Expr::Value(ast::Value::Number(n, _)) => {
+                let i = n
+                    .to_string()
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|i| (1..=outputs.len()).contains(i))
+                    .with_context(|| format!("GROUP BY position {} is not in select list", n))?;
+                Some(outputs[i - 1].clone())
+            }
+            Expr::Identifier(ident)
+                if schema.find(&Column::unqualified(&ident.value))?.is_none() =>
+            {
+                projection
+                    .iter()
+                    .zip(outputs)
+                    .find_map(|(item, expr)| match item {
+                        SelectItem::ExprWithAlias { alias, .. } if alias.value == ident.value => {
+                            Some(expr.clone())
+                        }
+                        _ => None,
+                    })
+            }
+            _ => None,
+        };
+        let expr = match output {
+            Some(expr) => expr,
+            None => self.lower_expr(expr, schema, false)?,
+        };
+        if expr.contains_aggregate() {
+            anyhow::bail!("Aggregate functions are not allowed in GROUP BY");
+        }
+        Ok(expr)
+    }
+
+    fn plan_from(&self, from: &[TableWithJoins]) -> anyhow::Result<LogicalPlan> {
+        let mut res: Option<LogicalPlan> = None;
+        for table in from {
+            let plan = self.plan_table_with_joins(table)?;
+            res = Some(match res {
+                None => plan,
+                Some(left) => LogicalPlan::join(left, plan, JoinKind::Cross, None),
+            });
+        }
+        // No FROM gives a single row with no columns
+        Ok(res.unwrap_or_else(|| LogicalPlan::Values {
+            rows: vec![vec![]],
+            schema: Schema::default(),
+        }))
+    }
+
+    fn plan_table_with_joins(&self, table: &TableWithJoins) -> anyhow::Result<LogicalPlan> {
+        let mut plan = self.plan_relation(&table.relation)?;
+        for j in &table.joins {
+            let right = self.plan_relation(&j.relation)?;
+            let (kind, constraint) = match &j.join_operator {
+                JoinOperator::Inner(c) => (JoinKind::Inner, c),
+                JoinOperator::LeftOuter(c) => (JoinKind::Left, c),
+                JoinOperator::RightOuter(c) => (JoinKind::Right, c),
+                JoinOperator::FullOuter(c) => (JoinKind::Full, c),
+                JoinOperator::CrossJoin => (JoinKind::Cross, &JoinConstraint::None),
+                op => anyhow::bail!("Unsupported join: {:?}", op),
+            };
+            let using = match constraint {
+                JoinConstraint::Using(columns) => {
+                    columns.iter().map(|x| x.value.clone()).collect::<Vec<_>>()
+                }
+                // Every column name the two sides have in common
+                JoinConstraint::Natural => plan
+                    .schema()
+                    .fields
+                    .iter()
+                    .filter(|x| !x.hidden)
+                    .filter(|x| {
+                        let column = Column::unqualified(x.name.clone());
+                        matches!(right.schema().find(&column), Ok(Some(_)))
+                    })
+                    .map(|x| x.name.clone())
+                    .collect(),
+                _ => vec![],
+            };
+            if !using.is_empty() {
+                plan = self.plan_using_join(plan, right, kind, &using)?;
+                continue;
+            }
+            let condition = match constraint {
+                JoinConstraint::On(expr) => {
+                    let schema = plan.schema().join(right.schema());
+                    Some(self.lower_expr(expr, &schema, false)?)
+                }
+                JoinConstraint::None | JoinConstraint::Natural => None,
+                JoinConstraint::Using(_) => anyhow::bail!("JOIN ... USING needs some columns"),
+            };
+            let kind = if condition.is_none() {
+                JoinKind::Cross
+            } else {
+                kind
+            };
+            plan = LogicalPlan::join(plan, right, kind, condition);
+        }
+        Ok(plan)
+    }
+
+    /// `JOIN ... USING` and `NATURAL JOIN` join on the columns being equal and only output one
+    /// of each pair, which comes first. The originals can still be used by qualifying them.
+    fn plan_using_join(
+        &self,
+        left: LogicalPlan,
+        right: LogicalPlan,
+        kind: JoinKind,
+        using: &[String],
+    ) -> anyhow::Result<LogicalPlan> {
+        let mut pairs = vec![];
+        for name in using {
+            let column = Column::unqualified(name.clone());
+            let find = |plan: &LogicalPlan, side| {
+                plan.schema()
+                    .find(&column)?
+                    .map(|i| plan.schema().fields[i].clone())
+                    .with_context(|| {
+                        format!(
+                            "Column {} in USING doesn't exist in the {} table",
+                            name, side
+                        )
+                    })
+            };
+            pairs.push((find(&left, "left")?, find(&right, "right")?));
+        }
+        let condition = pairs.iter().map(|(l, r)| {
+            ScalarExpr::binary(
+                ScalarExpr::Column(l.column()),
+                BinaryOperator::Eq,
+                ScalarExpr::Column(r.column()),
+            )
+        });
+        let plan = LogicalPlan::join(left, right, kind, ScalarExpr::conjunction(condition));
+        let mut exprs = vec![];
+        let mut fields = vec![];
+        for (l, r) in &pairs {
+            let (l_expr, r_expr) = (
+                ScalarExpr::Column(l.column()),
+                ScalarExpr::Column(r.column()),
+            );
+            let (expr, nullable) = match kind {
+                JoinKind::Right => (r_expr, r.nullable),
+                JoinKind::Full => (
+                    ScalarExpr::Coalesce(vec![l_expr, r_expr]),
+                    l.nullable || r.nullable,
+                ),
+                _ => (l_expr, l.nullable),
+            };
+            exprs.push(expr);
+            fields.push(Field {
+                nullable,
+                ..Field::new(None, l.name.clone(), l.datatype.clone())
+            });
+        }
+        for field in &plan.schema().fields {
+            let merged = pairs
+                .iter()
+                .any(|(l, r)| field.column() == l.column() || field.column() == r.column());
+            exprs.push(ScalarExpr::Column(field.column()));
+            fields.push(Field {
+                hidden: field.hidden || merged,
+                ..field.clone()
+            });
+        }
+        Ok(LogicalPlan::Project {
+            exprs,
+            schema: Schema::new(fields),
+            input: Box::new(plan),
+        })
+    }
+
+    fn plan_relation(&self, relation: &TableFactor) -> anyhow::Result<LogicalPlan> {
+        match relation {
+            TableFactor::Table {
+                name, alias, args, ..
+            } => {
+                if args.is_some() {
+                    anyhow::bail!("Table functions are not supported");
+                }
+                let table = object_name(name);
+                let cte = self
+                    .ctes
+                    .iter()
+                    .rev()
+                    .find(|x| name.0.len() == 1 && x.name == table);
+                if let Some(cte) = cte {
+                    cte.references.set(cte.references.get() + 1);
+                    let plan = LogicalPlan::CteScan {
+                        name: cte.name.clone(),
+                        id: cte.id,
+                        schema: cte.plan.schema().clone(),
+                    };
+                    return match alias {
+                        Some(alias) => rename(plan, alias),
+                        None => Ok(plan),
+                    };
+                }
+                let metadata = self.catalog.table_metadata(&table)?;
+                let relation = alias
+                    .as_ref()
+                    .map(|x| x.name.value.clone())
+                    .unwrap_or_else(|| table.clone());
+
+                let mut schema = Schema::for_table(&relation, &metadata);
+                // Rows are locked after sorting by the select, the scan just says which rows
+                // they are
+                if self.lock_for(&relation).is_some() {
+                    let mut field = Field::new(Some(relation), ROW_KEY, DataType::Bytea);
+                    field.hidden = true;
+                    schema.fields.push(field);
+                }
+                let plan = LogicalPlan::Scan {
+                    table,
+                    alias: alias.as_ref().map(|x| x.name.value.clone()),
+                    schema,
+                    projection: None,
+                    filters: vec![],
+                    lock: None,
+                    primary_key: None,
+                };
+                match alias {
+                    Some(alias) if !alias.columns.is_empty() => rename(plan, alias),
+                    _ => Ok(plan),
+                }
+            }
+            TableFactor::Derived {
+                lateral,
+                subquery,
+                alias,
+            } => {
+                if *lateral {
+                    anyhow::bail!("LATERAL is not supported");
+                }
+                let plan = self.plan_query(subquery)?;
+                match alias {
+                    Some(alias) => rename(plan, alias),
+                    None => Ok(plan),
+                }
+            }
+            TableFactor::NestedJoin {
+                table_with_joins,
+                alias,
+            } => {
+                let plan = self.plan_table_with_joins(table_with_joins)?;
+                match alias {
+                    Some(alias) => rename(plan, alias),
+                    None => Ok(plan),
+                }
+            }
+            r => anyhow::bail!("Unsupported table reference: {}", r),
+        }
+    }
+
+    /// The table being modified by an `UPDATE` or `DELETE`, filtered down to the rows that are
+    /// affected.
+    /// The expressions and output fields for a SELECT list, or a RETURNING clause which works the
+    /// same way.
+    fn lower_select_items(
+        &self,
+        items: &[SelectItem],
+        schema: &Schema,
+        allow_aggregates: bool,
+    ) -> anyhow::Result<(Vec<ScalarExpr>, Vec<Field>)> {
+        let mut outputs = vec![];
+        let mut fields = vec![];
+        for item in items {
+            match item {
+                SelectItem::Wildcard(_) => {
+                    if schema.is_empty() {
+                        anyhow::bail!("SELECT * with no tables specified is not valid");
+                    }
+                    for field in schema.fields.iter().filter(|x| !x.hidden) {
+                        outputs.push(ScalarExpr::Column(field.column()));
+                        fields.push(field.clone());
+                    }
+                }
+                SelectItem::QualifiedWildcard(name, _) => {
+                    let relation = object_name(name);
+                    let matching = schema
+                        .fields
+                        .iter()
+                        .filter(|x| {
+                            x.relation
+                                .as_ref()
+                                .is_some_and(|r| r.eq_ignore_ascii_case(&relation))
+                                && x.name != ROW_KEY
+                        })
+                        .map(|x| Field {
+                            hidden: false,
+                            ..x.clone()
+                        })
+                        .collect::<Vec<_>>();
+                    if matching.is_empty() {
+                        anyhow::bail!("Missing FROM-clause entry for table {}", relation);
+                    }
+                    for field in matching {
+                        outputs.push(ScalarExpr::Column(field.column()));
+                        fields.push(field);
+                    }
+                }
+                SelectItem::UnnamedExpr(expr) => {
+                    let expr = self.lower_expr(expr, schema, allow_aggregates)?;
+                    let field = match &expr {
+                        ScalarExpr::Column(c) => schema.field(c)?.clone(),
+                        e => Field::new(None, e.to_string(), e.data_type(schema)),
+                    };
+                    outputs.push(expr);
+                    fields.push(field);
+                }
+                SelectItem::ExprWithAlias { expr, alias } => {
+                    let expr = self.lower_expr(expr, schema, allow_aggregates)?;
+                    let mut field = Field::new(None, &alias.value, expr.data_type(schema));
+                    if let ScalarExpr::Column(c) = &expr {
+                        field.nullable = schema.field(c)?.nullable;
+                    }
+                    outputs.push(expr);
+                    fields.push(field);
+                }
+            }
+        }
+        Ok((outputs, fields))
+    }
+
+    fn plan_target_table(
+        &self,
+        relation: &TableFactor,
+        selection: Option<&Expr>,
+    ) -> anyhow::Result<(String, LogicalPlan)> {
+        let name = match relation {
+            TableFactor::Table { name, .. } => object_name(name),
+            r => anyhow::bail!("Can't modify {}", r),
+        };
+        let mut plan = self.plan_relation(relation)?;
+        // The rows being modified are locked as they're read, and read again once locked, so
+        // concurrent modifications of a row happen one after the other rather than both starting
+        // from the same old value
+        if let LogicalPlan::Scan { lock, .. } = &mut plan {
+            *lock = Some(RowLocking {
+                mode: LockMode::Exclusive,
+                wait: WaitPolicy::Block,
+                table: None,
+            });
+        }
+        if let Some(selection) = selection {
+            let predicate = self.lower_expr(selection, plan.schema(), false)?;
+            plan = LogicalPlan::Filter {
+                predicate,
+                input: Box::new(plan),
+            };
+        }
+        Ok((name, plan))
+    }
+
+    /// `RETURNING` is a projection of the rows the modification outputs, it works the same as a
+    /// SELECT list without aggregates
+    fn plan_returning(
+        &self,
+        plan: LogicalPlan,
+        returning: &Option<Vec<SelectItem>>,
+    ) -> anyhow::Result<LogicalPlan> {
+        let Some(returning) = returning else {
+            return Ok(plan);
+        };
+        let (exprs, fields) = self.lower_select_items(returning, plan.schema(), false)?;
+        Ok(LogicalPlan::Project {
+            exprs,
+            schema: Schema::new(fields),
+            input: Box::new(plan),
+        })
+    }
+
+    fn lower_assignments(
+        &self,
+        table: &str,
+        metadata: &ColumnDescriptors,
+        assignments: &[ast::Assignment],
+        schema: &Schema,
+    ) -> anyhow::Result<Vec<(String, ScalarExpr)>> {
+        let mut res = vec![];
+        for assignment in assignments {
+            let column = match assignment.id.as_slice() {
+                [column] => &column.value,
+                [.., column] => &column.value,
+                [] => anyhow::bail!("Invalid assignment"),
+            };
+            let column = resolve_table_column(metadata, column)
+                .with_context(|| format!("Column {} of {} does not exist", column, table))?;
+            if res.iter().any(|(c, _)| *c == column) {
+                anyhow::bail!("Multiple assignments to the same column {}", column);
+            }
+            let value = self.lower_expr(&assignment.value, schema, false)?;
+            res.push((column, value));
+        }
+        Ok(res)
+    }
+
+    /// The update sees the existing row under the table's name or alias, followed by the row
+    /// which was going to be inserted as `excluded` (or MySQL's row alias). The new row's columns
+    /// have to be qualified so a bare column name is always the existing row.
+    fn plan_on_conflict(
+        &self,
+        insert: &Insert,
+        table: &str,
+        metadata: &ColumnDescriptors,
+    ) -> anyhow::Result<Option<OnConflict>> {
+        let Some(on) = &insert.on else {
+            return Ok(None);
+        };
+        let relation = insert.table_alias.as_ref().map_or(table, |x| &x.value);
+        let excluded = match &insert.insert_alias {
+            Some(alias) if alias.col_aliases.as_ref().is_some_and(|x| !x.is_empty()) => {
+                anyhow::bail!("Column aliases for the inserted row are not supported")
+            }
+            Some(alias) => object_name(&alias.row_alias),
+            None => "excluded".to_string(),
+        };
+        let mut new_row = Schema::for_table(&excluded, metadata);
+        for field in &mut new_row.fields {
+            field.hidden = true;
+        }
+        let schema = Schema::for_table(relation, metadata).join(&new_row);
+        let on_conflict = match on {
+            OnInsert::DuplicateKeyUpdate(assignments) => {
+                // `VALUES(col)` is the old way of getting at the new row
+                let mut assignments = assignments.clone();
+                for assignment in &mut assignments {
+                    let _ = ast::visit_expressions_mut(&mut assignment.value, |expr| {
+                        if let Some(column) = values_column(expr) {
+                            *expr =
+                                Expr::CompoundIdentifier(vec![ast::Ident::new(&excluded), column]);
+                        }
+                        ControlFlow::<()>::Continue(())
+                    });
+                }
+                OnConflict {
+                    target: None,
+                    update: Some(ConflictUpdate {
+                        assignments: self.lower_assignments(
+                            table,
+                            metadata,
+                            &assignments,
+                            &schema,
+                        )?,
+                        selection: None,
+                        repeatable: true,
+                        schema,
+                    }),
+                }
+            }
+            OnInsert::OnConflict(on_conflict) => {
+                let target = match &on_conflict.conflict_target {
+                    Some(ast::ConflictTarget::Columns(idents)) => {
+                        let mut columns = vec![];
+                        for ident in idents {
+                            columns.push(
+                                resolve_table_column(metadata, &ident.value).with_context(
+                                    || {
+                                        format!(
+                                            "Column {} of {} does not exist",
+                                            ident.value, table
+                                        )
+                                    },
+                                )?,
+                            );
+                        }
+                        let constraints = self.catalog.unique_constraints(table)?;
+                        if !constraints.iter().any(|x| same_columns(x, &columns)) {
+                            anyhow::bail!(
+                                "There is no unique constraint on {} ({})",
+                                table,
+                                columns.join(", ")
+                            );
+                        }
+                        Some(columns)
+                    }
+                    Some(ast::ConflictTarget::OnConstraint(name)) => {
+                        anyhow::bail!("ON CONFLICT ON CONSTRAINT {} is not supported", name)
+                    }
+                    None => None,
+                };
+                let update = match &on_conflict.action {
+                    OnConflictAction::DoNothing => None,
+                    OnConflictAction::DoUpdate(_) if target.is_none() => {
+                        anyhow::bail!("ON CONFLICT DO UPDATE needs the columns to check")
+                    }
+                    OnConflictAction::DoUpdate(update) => Some(ConflictUpdate {
+                        assignments: self.lower_assignments(
+                            table,
+                            metadata,
+                            &update.assignments,
+                            &schema,
+                        )?,
+                        selection: update
+                            .selection
+                            .as_ref()
+                            .map(|x| self.lower_expr(x, &schema, false))
+                            .transpose()?,
+                        repeatable: false,
+                        schema: schema.clone(),
+                    }),
+                };
+                OnConflict { target, update }
+            }
+            on => anyhow::bail!("{} is not supported", on),
+        };
+        Ok(Some(on_conflict))
+    }
+
+    fn plan_insert(&self, insert: &Insert) -> anyhow::Result<LogicalPlan> {
+        let table = object_name(&insert.table_name);
+        let metadata = self.catalog.table_metadata(&table)?;
+        let columns = if insert.columns.is_empty() {
+            metadata.keys().cloned().collect()
+        } else {
+            let mut columns: Vec<String> = vec![];
+            for ident in &insert.columns {
+                let column = resolve_table_column(&metadata, &ident.value).with_context(|| {
+                    format!("Column {} of {} does not exist", ident.value, table)
+                })?;
+                if columns.contains(&column) {
+                    anyhow::bail!(
+                        "Column '{}' is present multiple times in insert query",
+                        column
+                    );
+                }
+                columns.push(column);
+            }
+            columns
+        };
+        let input = match &insert.source {
+            Some(source) => self.plan_query(source)?,
+            None => LogicalPlan::Values {
+                rows: vec![vec![]],
+                schema: Schema::default(),
+            },
+        };
+        let width = input.schema().len();
+        if width > columns.len() {
+            anyhow::bail!("INSERT has more expressions than target columns");
+        }
+        if width < columns.len() && insert.source.is_some() {
+            anyhow::bail!("INSERT has more target columns than expressions");
+        }
+        // The values are checked again as they're inserted, this catches a query which can never
+        // produce the right type
+        for (column, field) in columns.iter().zip(&input.schema().fields) {
+            let expected = &metadata[column].datatype;
+            if let (Some(a), Some(b)) = (TypeClass::of(expected), TypeClass::of(&field.datatype)) {
+                if a != b {
+                    anyhow::bail!(
+                        "Column {} is of type {} but expression is of type {}",
+                        column,
+                        expected,
+                        field.datatype
+                    );
+                }
+            }
+        }
+        let schema = match &insert.returning {
+            Some(_) => {
+                let relation = insert.table_alias.as_ref().map_or(&table, |x| &x.value);
+                Schema::for_table(relation, &metadata)
+            }
+            None => Schema::default(),
+        };
+        let plan = LogicalPlan::Insert {
+            on_conflict: self.plan_on_conflict(insert, &table, &metadata)?,
+            table,
+            columns: if insert.source.is_some() {
+                columns
+            } else {
+                vec![]
+            },
+            schema,
+            input: Box::new(input),
+        };
+        self.plan_returning(plan, &insert.returning)
+    }
+
+    fn constant_u64(&self, expr: &Expr, clause: &str) -> anyhow::Result<u64> {
+        match self.lower_expr(expr, &EMPTY_SCHEMA, false)? {
+            ScalarExpr::Literal(Value::Number(n)) if n.is_integer() => n
+                .to_string()
+                .parse()
+                .with_context(|| format!("{} must not be negative", clause)),
+            e => anyhow::bail!("{} must be a non-negative integer, got {}", clause, e),
+        }
+    }
+
+    /// Converts an AST expression into a [`ScalarExpr`] resolving any columns against the schema.
+    pub fn lower_expr(
+        &self,
+        expr: &Expr,
+        schema: &Schema,
+        allow_aggregates: bool,
+    ) -> anyhow::Result<ScalarExpr> {
+        let lower = |e: &Expr| self.lower_expr(e, schema, allow_aggregates);
+        let res = match expr {
+            Expr::Identifier(_) | Expr::CompoundIdentifier(_) => {
+                let column = identifier_column(expr)?;
+                let outer = match &self.outer {
+                    Some(outer) if schema.find(&column)?.is_none() => outer.find(&column)?,
+                    _ => None,
+                };
+                match outer {
+                    Some(i) => {
+                        let field = &self.outer.as_ref().unwrap().fields[i];
+                        ScalarExpr::OuterColumn(field.column(), field.datatype.clone())
+                    }
+                    None => ScalarExpr::Column(schema.field(&column)?.column()),
+                }
+            }
+            Expr::Subquery(query) => {
+                ScalarExpr::Subquery(Box::new(self.plan_subquery(query, schema, true)?))
+            }
+            Expr::Exists { subquery, negated } => ScalarExpr::Exists {
+                subquery: Box::new(self.plan_subquery(subquery, schema, false)?),
+                negated: *negated,
+            },
+            Expr::InSubquery {
+                expr,
+                subquery,
+                negated,
+            } => ScalarExpr::InSubquery {
+                expr: Box::new(lower(expr)?),
+                subquery: Box::new(self.plan_subquery(subquery, schema, true)?),
+                negated: *negated,
+            },
+            Expr::Value(v) => ScalarExpr::Literal(Value::try_from(v.clone())?),
+            Expr::Nested(e) => lower(e)?,
+            Expr::BinaryOp { left, op, right } => ScalarExpr::BinaryOp {
+                left: Box::new(lower(left)?),
+                op: op.clone(),
+                right: Box::new(lower(right)?),
+            },
+            Expr::UnaryOp { op, expr } => match (op, lower(expr)?) {
+                (UnaryOperator::Minus, ScalarExpr::Literal(Value::Number(n))) => {
+                    ScalarExpr::Literal(Value::Number(-n))
+                }
+                (UnaryOperator::Plus, e @ ScalarExpr::Literal(Value::Number(_))) => e,
+                (op, expr) => ScalarExpr::UnaryOp {
+                    op: *op,
+                    expr: Box::new(expr),
+                },
+            },
+            Expr::IsNull(e) => ScalarExpr::IsNull(Box::new(lower(e)?)),
+            Expr::IsNotNull(e) => ScalarExpr::IsNotNull(Box::new(lower(e)?)),
+            Expr::Between {
+                expr,
+                negated,
+                low,
+                high,
+            } => ScalarExpr::Between {
+                expr: Box::new(lower(expr)?),
+                negated: *negated,
+                low: Box::new(lower(low)?),
+                high: Box::new(lower(high)?),
+            },
+            Expr::InList {
+                expr,
+                list,
+                negated,
+            } => ScalarExpr::InList {
+                expr: Box::new(lower(expr)?),
+                list: list.iter().map(lower).collect::<anyhow::Result<_>>()?,
+                negated: *negated,
+            },
+            Expr::Like {
+                negated,
+                expr: inner,
+                pattern,
+                escape_char,
+            }
+            | Expr::ILike {
+                negated,
+                expr: inner,
+                pattern,
+                escape_char,
+            } => {
+                let escape = match escape_char
+                    .as_deref()
+                    .map(|x| x.chars().collect::<Vec<_>>())
+                {
+                    None => None,
+                    Some(chars) if chars.len() == 1 => Some(chars[0]),
+                    Some(_) => anyhow::bail!("ESCAPE must be a single character"),
+                };
+                ScalarExpr::Like {
+                    expr: Box::new(lower(inner)?),
+                    pattern: Box::new(lower(pattern)?),
+                    negated: *negated,
+                    case_insensitive: matches!(expr, Expr::ILike { .. }),
+                    escape,
+                }
+            }
+            Expr::Case {
+                operand,
+                conditions,
+                results,
+                else_result,
+            } => ScalarExpr::Case {
+                operand: operand.as_deref().map(lower).transpose()?.map(Box::new),
+                when_then: conditions
+                    .iter()
+                    .zip(results)
+                    .map(|(when, then)| Ok((lower(when)?, lower(then)?)))
+                    .collect::<anyhow::Result<_>>()?,
+                else_result: else_result.as_deref().map(lower).transpose()?.map(Box::new),
+            },
+            Expr::Cast {
+                expr,
+                data_type,
+                format: None,
+                ..
+            } => ScalarExpr::Cast {
+                expr: Box::new(lower(expr)?),
+                data_type: data_type.clone(),
+            },
+            Expr::Function(function) if function.over.is_some() => {
+                self.lower_window(function, schema, allow_aggregates)?
+            }
+            Expr::Function(function)
+                if matches!(
+                    object_name(&function.name).to_lowercase().as_str(),
+                    "coalesce" | "nullif"
+                ) =>
+            {
+                let name = object_name(&function.name).to_lowercase();
+                let args = match &function.args {
+                    FunctionArguments::List(list) if list.duplicate_treatment.is_none() => {
+                        self.lower_function_args(&list.args, schema, allow_aggregates)?
+                    }
+                    _ => anyhow::bail!("Invalid arguments to {}", name),
+                };
+                match (name.as_str(), args.len()) {
+                    ("coalesce", 1..) => ScalarExpr::Coalesce(args),
+                    ("nullif", 2) => {
+                        let mut args = args.into_iter();
+                        ScalarExpr::NullIf(
+                            Box::new(args.next().unwrap()),
+                            Box::new(args.next().unwrap()),
+                        )
+                    }
+                    _ => anyhow::bail!("Wrong number of arguments to {}", name),
+                }
+            }
+            Expr::Ceil {
+                expr,
+                field: DateTimeField::NoDateTime,
+            } => self.call("ceil", vec![lower(expr)?], schema)?,
+            Expr::Floor {
+                expr,
+                field: DateTimeField::NoDateTime,
+            } => self.call("floor", vec![lower(expr)?], schema)?,
+            Expr::Position { expr, r#in } => {
+                self.call("position", vec![lower(expr)?, lower(r#in)?], schema)?
+            }
+            Expr::Substring {
+                expr,
+                substring_from,
+                substring_for,
+                ..
+            } => {
+                let mut args = vec![lower(expr)?];
+                args.push(match substring_from {
+                    Some(from) => lower(from)?,
+                    None => ScalarExpr::literal(1),
+                });
+                if let Some(len) = substring_for {
+                    args.push(lower(len)?);
+                }
+                self.call("substr", args, schema)?
+            }
+            Expr::Trim {
+                expr,
+                trim_where,
+                trim_what,
+                trim_characters,
+            } => {
+                let name = match trim_where {
+                    None | Some(TrimWhereField::Both) => "trim",
+                    Some(TrimWhereField::Leading) => "ltrim",
+                    Some(TrimWhereField::Trailing) => "rtrim",
+                };
+                let mut args = vec![lower(expr)?];
+                match (trim_what.as_deref(), trim_characters.as_deref()) {
+                    (Some(chars), _) | (None, Some([chars])) => args.push(lower(chars)?),
+                    (None, None) => {}
+                    (None, Some(_)) => anyhow::bail!("TRIM takes a single set of characters"),
+                }
+                self.call(name, args, schema)?
+            }
+            Expr::Function(function) => {
+                let name = object_name(&function.name);
+                let func = AggregateFunction::from_name(&name)
+                    .or_else(|| self.catalog.aggregate(&name).map(AggregateFunction::User));
+                let Some(func) = func else {
+                    if function.filter.is_some() {
+                        anyhow::bail!("{} is not an aggregate function", name);
+                    }
+                    let args = match &function.args {
+                        FunctionArguments::None => vec![],
+                        FunctionArguments::List(list) if list.duplicate_treatment.is_none() => {
+                            self.lower_function_args(&list.args, schema, allow_aggregates)?
+                        }
+                        _ => anyhow::bail!("Invalid arguments to {}", name),
+                    };
+                    return self.call(&name, args, schema);
+                };
+                if !allow_aggregates {
+                    anyhow::bail!("Aggregate functions are not allowed here");
+                }
+                if function.filter.is_some() {
+                    anyhow::bail!("FILTER is not supported");
+                }
+                let (args, distinct, order_by) = match &function.args {
+                    FunctionArguments::None => (vec![], false, vec![]),
+                    FunctionArguments::Subquery(_) => {
+                        anyhow::bail!("Subqueries are not supported")
+                    }
+                    FunctionArguments::List(list) => {
+                        let mut order_by = vec![];
+                        for clause in &list.clauses {
+                            match clause {
+                                FunctionArgumentClause::OrderBy(keys) => {
+                                    for key in keys {
+                                        let expr = self.lower_expr(&key.expr, schema, false)?;
+                                        order_by.push(sort_key(key, expr));
+                                    }
+                                }
+                                c => anyhow::bail!("{} is not supported in {}", c, func),
+                            }
+                        }
+                        (
+                            self.lower_function_args(&list.args, schema, false)?,
+                            list.duplicate_treatment == Some(DuplicateTreatment::Distinct),
+                            order_by,
+                        )
+                    }
+                };
+                let agg = AggregateExpr {
+                    func,
+                    args,
+                    distinct,
+                    order_by,
+                };
+                agg.check(schema)?;
+                ScalarExpr::Aggregate(agg)
+            }
+            e => anyhow::bail!("Unsupported expression: {}", e),
+        };
+        Ok(res)
+    }
+
+    /// A function call with `OVER`, which can be any aggregate as well as the window functions.
+    fn lower_window(
+        &self,
+        function: &ast::Function,
+        schema: &Schema,
+        allow_aggregates: bool,
+    ) -> anyhow::Result<ScalarExpr> {
+        let name = object_name(&function.name);
+        let func = WindowFunction::from_name(&name)
+            .or_else(|| AggregateFunction::from_name(&name).map(WindowFunction::Aggregate))
+            .or_else(|| {
+                let udf = self.catalog.aggregate(&name)?;
+                Some(WindowFunction::Aggregate(AggregateFunction::User(udf)))
+            })
+            .with_context(|| format!("{} is not a window function", name))?;
+        if !allow_aggregates {
+            anyhow::bail!("Window functions are not allowed here");
+        }
+        if function.filter.is_some() || function.null_treatment.is_some() {
+            anyhow::bail!("FILTER and IGNORE NULLS are not supported in window functions");
+        }
+        let args = match &function.args {
+            FunctionArguments::None => vec![],
+            FunctionArguments::List(list)
+                if list.duplicate_treatment.is_none() && list.clauses.is_empty() =>
+            {
+                self.lower_function_args(&list.args, schema, true)?
+            }
+            _ => anyhow::bail!("Invalid arguments to window function {}", name),
+        };
+
+        let spec = resolve_window(&self.windows, function.over.as_ref().unwrap())?;
+        let partition_by = spec
+            .partition_by
+            .iter()
+            .map(|x| self.lower_expr(x, schema, true))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut order_by = vec![];
+        for key in &spec.order_by {
+            order_by.push(sort_key(key, self.lower_expr(&key.expr, schema, true)?));
+        }
+        let frame = match &spec.window_frame {
+            None => WindowFrame::default(),
+            Some(frame) => {
+                let units = match frame.units {
+                    ast::WindowFrameUnits::Rows => FrameUnits::Rows,
+                    ast::WindowFrameUnits::Range => FrameUnits::Range,
+                    ast::WindowFrameUnits::Groups => anyhow::bail!("GROUPS is not supported"),
+                };
+                let start = self.lower_frame_bound(&frame.start_bound, units)?;
+                let end = match &frame.end_bound {
+                    Some(bound) => self.lower_frame_bound(bound, units)?,
+                    None => FrameBound::CurrentRow,
+                };
+                match (&start, &end) {
+                    (FrameBound::Following(None), _) => {
+                        anyhow::bail!("Frame start cannot be UNBOUNDED FOLLOWING")
+                    }
+                    (_, FrameBound::Preceding(None)) => {
+                        anyhow::bail!("Frame end cannot be UNBOUNDED PRECEDING")
+                    }
+                    (FrameBound::CurrentRow, FrameBound::Preceding(_))
+                    | (
+                        FrameBound::Following(_),
+                        FrameBound::Preceding(_) | FrameBound::CurrentRow,
+                    ) => {
+                        anyhow::bail!("Frame starting from {} cannot end at {}", start, end)
+                    }
+                    _ => {}
+                }
+                let has_offset = [&start, &end].iter().any(|x| {
+                    matches!(
+                        x,
+                        FrameBound::Preceding(Some(_)) | FrameBound::Following(Some(_))
+                    )
+                });
+                if units == FrameUnits::Range && has_offset {
+                    if order_by.len() != 1 {
+                        anyhow::bail!("RANGE with an offset needs exactly one ORDER BY column");
+                    }
+                    let data_type = order_by[0].expr.data_type(schema);
+                    if TypeClass::of(&data_type).is_some_and(|x| x != TypeClass::Number) {
+                        anyhow::bail!("RANGE with an offset can't be used on type {}", data_type);
+                    }
+                }
+                WindowFrame { units, start, end }
+            }
+        };
+
+        let window = WindowExpr {
+            func,
+            args,
+            partition_by,
+            order_by,
+            frame,
+        };
+        if window.exprs().any(|x| x.contains_window()) {
+            anyhow::bail!("Window function calls cannot be nested");
+        }
+        window.check(schema)?;
+        Ok(ScalarExpr::Window(Box::new(window)))
+    }
+
+    /// Offsets have to be constant, for `ROWS` they're a number of rows.
+    fn lower_frame_bound(
+        &self,
+        bound: &ast::WindowFrameBound,
+        units: FrameUnits,
+    ) -> anyhow::Result<FrameBound> {
+        let offset = |expr: &Option<Box<Expr>>| -> anyhow::Result<Option<Value>> {
+            let Some(expr) = expr else {
+                return Ok(None);
+            };
+            if units == FrameUnits::Rows {
+                return Ok(Some(Value::from(
+                    self.constant_u64(expr, "ROWS offset")? as i64
+                )));
+            }
+            match self.lower_expr(expr, &EMPTY_SCHEMA, false)? {
+                ScalarExpr::Literal(Value::Number(n)) if n >= BigDecimal::zero() => {
+                    Ok(Some(Value::Number(n)))
+                }
+                e => anyhow::bail!("RANGE offset must be a non-negative number, got {}", e),
+            }
+        };
+        let bound = match bound {
+            ast::WindowFrameBound::CurrentRow => FrameBound::CurrentRow,
+            ast::WindowFrameBound::Preceding(expr) => FrameBound::Preceding(offset(expr)?),
+            ast::WindowFrameBound::Following(expr) => FrameBound::Following(offset(expr)?),
+        };
+        Ok(bound)
+    }
+
+    /// Plans a subquery which can refer to the columns in `schema` as well as its own. Only
+    /// subqueries directly inside the query can see its columns.
+    fn plan_subquery(
+        &self,
+        query: &Query,
+        schema: &Schema,
+        single_column: bool,
+    ) -> anyhow::Result<LogicalPlan> {
+        let builder = PlanBuilder {
+            locking: vec![],
+            outer: Some(schema.clone()),
+            ..self.clone()
+        };
+        let plan = builder.plan_query(query)?;
+        if single_column && plan.schema().len() != 1 {
+            anyhow::bail!("Subquery must return only one column");
+        }
+        Ok(plan)
+    }
+
+    /// Resolves a call to a scalar function, checking the arguments against its signature.
+    fn call(
+        &self,
+        name: &str,
+        args: Vec<ScalarExpr>,
+        schema: &Schema,
+    ) -> anyhow::Result<ScalarExpr> {
+        let func = self
+            .catalog
+            .function(name)
+            .with_context(|| format!("Function {} does not exist", name))?;
+        func.check(&args.iter().map(|x| x.data_type(schema)).collect::<Vec<_>>())?;
+        Ok(ScalarExpr::Function { func, args })
+    }
+
+    /// Function arguments, a lone `*` means no arguments.
+    fn lower_function_args(
+        &self,
+        args: &[FunctionArg],
+        schema: &Schema,
+        allow_aggregates: bool,
+    ) -> anyhow::Result<Vec<ScalarExpr>> {
+        let mut res = vec![];
+        for arg in args {
+            match arg {
+                FunctionArg::Unnamed(FunctionArgExpr::Expr(e)) => {
+                    res.push(self.lower_expr(e, schema, allow_aggregates)?)
+                }
+                FunctionArg::Unnamed(FunctionArgExpr::Wildcard) if args.len() == 1 => {}
+                a => anyhow::bail!("Unsupported function argument: {}", a),
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// Fills in the window a window specification is based on. It takes the partitioning and
+/// ordering of that window, it can add an `ORDER BY` if the other window doesn't have one.
+fn resolve_window(
+    windows: &BTreeMap<String, ast::WindowSpec>,
+    over: &ast::WindowType,
+) -> anyhow::Result<ast::WindowSpec> {
+    let (name, spec) = match over {
+        ast::WindowType::WindowSpec(spec) => match &spec.window_name {
+            Some(name) => (name, spec),
+            None => return Ok(spec.clone()),
+        },
+        ast::WindowType::NamedWindow(name) => {
+            return windows
+                .get(&name.value)
+                .cloned()
+                .with_context(|| format!("Window {} does not exist", name))
+        }
+    };
+    let base = windows
+        .get(&name.value)
+        .with_context(|| format!("Window {} does not exist", name))?;
+    if !spec.partition_by.is_empty() {
+        anyhow::bail!("Can't override PARTITION BY of window {}", name);
+    }
+    if !spec.order_by.is_empty() && !base.order_by.is_empty() {
+        anyhow::bail!("Can't override ORDER BY of window {}", name);
+    }
+    if base.window_frame.is_some() {
+        anyhow::bail!("Can't copy window {} as it has a frame", name);
+    }
+    Ok(ast::WindowSpec {
+        window_name: None,
+        partition_by: base.partition_by.clone(),
+        order_by: if spec.order_by.is_empty() {
+            base.order_by.clone()
+        } else {
+            spec.order_by.clone()
+        },
+        window_frame: spec.window_frame.clone(),
+    })
+}
+
+fn sort_key(order_by: &OrderByExpr, expr: ScalarExpr) -> SortKey {
+    let asc = order_by.asc.unwrap_or(true);
+    SortKey {
+        expr,
+        asc,
+        nulls_first: order_by.nulls_first.unwrap_or(!asc),
+    }
+}
+
+/// Puts the CTE's plan in place of every scan of it
+fn inline_cte(plan: LogicalPlan, id: usize, cte: &LogicalPlan) -> anyhow::Result<LogicalPlan> {
+    plan.transform_up(&mut |plan| match plan {
+        LogicalPlan::CteScan { id: scanned, .. } if scanned == id => Ok(cte.clone()),
+        plan => plan.map_expressions(|e| e.map_subqueries(&mut |p| inline_cte(p, id, cte))),
+    })
+}
+
+/// The output takes its names from the left, the types have to be the same kind of thing on both
+/// sides and numbers of different types end up as `NUMERIC`.
+fn set_operation_schema(op: SetOperator, left: &Schema, right: &Schema) -> anyhow::Result<Schema> {
+    if left.len() != right.len() {
+        anyhow::bail!("Each {} query must have the same number of columns", op);
+    }
+    let mut fields = vec![];
+    for (l, r) in left.fields.iter().zip(&right.fields) {
+        let datatype = match (&l.datatype, &r.datatype) {
+            (DataType::Unspecified, ty) | (ty, DataType::Unspecified) => ty.clone(),
+            (l, r) if l == r => l.clone(),
+            (l, r) => match (TypeClass::of(l), TypeClass::of(r)) {
+                (Some(TypeClass::Number), Some(TypeClass::Number)) => {
+                    DataType::Numeric(ast::ExactNumberInfo::None)
+                }
+                (Some(TypeClass::Text), Some(TypeClass::Text)) => DataType::Text,
+                _ => anyhow::bail!("{} types {} and {} cannot be matched", op, l, r),
+            },
+        };
+        fields.push(Field {
+            nullable: l.nullable || r.nullable,
+            ..Field::new(None, l.name.clone(), datatype)
+        });
+    }
+    Ok(Schema::new(fields))
+}
+
+/// Gives the output of the plan a new relation name and optionally new column names.
+/// The rest of a select over rows being locked. Rows are sorted before they're locked, so a limit
+/// above only locks the rows it returns, and the outputs are worked out afterwards from the rows
+/// as they are once locked.
+fn plan_locked_select(
+    mut plan: LogicalPlan,
+    tables: Vec<LockedTable>,
+    recheck: Vec<ScalarExpr>,
+    (outputs, output_schema): (Vec<ScalarExpr>, Schema),
+    order_by: &[OrderByExpr],
+    sort_targets: Vec<SortTarget>,
+) -> anyhow::Result<LogicalPlan> {
+    if !order_by.is_empty() {
+        let mut fields = plan.schema().fields.clone();
+        let mut exprs = fields
+            .iter()
+            .map(|x| ScalarExpr::Column(x.column()))
+            .collect::<Vec<_>>();
+        let mut keys = vec![];
+        for (key, target) in order_by.iter().zip(sort_targets) {
+            let expr = match target {
+                SortTarget::Output(i) => outputs[i].clone(),
+                SortTarget::Hidden(expr) => expr,
+            };
+            let column = match expr {
+                ScalarExpr::Column(c) => c,
+                expr => {
+                    let field = Field::new(
+                        None,
+                        format!("__sort_{}", fields.len()),
+                        expr.data_type(plan.schema()),
+                    );
+                    exprs.push(expr);
+                    fields.push(field);
+                    fields.last().unwrap().column()
+                }
+            };
+            keys.push(sort_key(key, ScalarExpr::Column(column)));
+        }
+        if exprs.len() > plan.schema().len() {
+            plan = LogicalPlan::Project {
+                exprs,
+                schema: Schema::new(fields),
+                input: Box::new(plan),
+            };
+        }
+        plan = LogicalPlan::Sort {
+            keys,
+            input: Box::new(plan),
+        };
+    }
+    Ok(LogicalPlan::Project {
+        exprs: outputs,
+        schema: output_schema,
+        input: Box::new(LogicalPlan::LockRows {
+            tables,
+            recheck,
+            input: Box::new(plan),
+        }),
+    })
+}
+
+fn rename(plan: LogicalPlan, alias: &TableAlias) -> anyhow::Result<LogicalPlan> {
+    let schema = plan.schema();
+    if alias.columns.len() > schema.len() {
+        anyhow::bail!(
+            "{} has {} columns available but {} columns specified",
+            alias.name,
+            schema.len(),
+            alias.columns.len()
+        );
+    }
+    let exprs = schema
+        .fields
+        .iter()
+        .map(|x| ScalarExpr::Column(x.column()))
+        .collect();
+    let fields = schema
+        .fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| Field {
+            relation: Some(alias.name.value.clone()),
+            name: alias
+                .columns
+                .get(i)
+                .map(|x| x.value.clone())
+                .unwrap_or_else(|| field.name.clone()),
+            ..field.clone()
+        })
+        .collect();
+    Ok(LogicalPlan::Project {
+        exprs,
+        schema: Schema::new(fields),
+        input: Box::new(plan),
+    })
+}
+
+fn object_name(name: &ObjectName) -> String {
+    name.0
+        .iter()
+        .map(|x| x.value.clone())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// An update or delete outputs the table's columns from the rows it's read, if it outputs anything
+fn returning_schema(
+    input: &LogicalPlan,
+    width: usize,
+    returning: &Option<Vec<SelectItem>>,
+) -> Schema {
+    match returning {
+        Some(_) => Schema::new(input.schema().fields[..width].to_vec()),
+        None => Schema::default(),
+    }
+}
+
+/// The column in MySQL's `VALUES(col)`
+fn values_column(expr: &Expr) -> Option<ast::Ident> {
+    let Expr::Function(function) = expr else {
+        return None;
+    };
+    if !object_name(&function.name).eq_ignore_ascii_case("values") {
+        return None;
+    }
+    match &function.args {
+        FunctionArguments::List(list) => match list.args.as_slice() {
+            [FunctionArg::Unnamed(FunctionArgExpr::Expr(Expr::Identifier(ident)))] => {
+                Some(ident.clone())
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn identifier_column(expr: &Expr) -> anyhow::Result<Column> {
+    match expr {
+        Expr::Identifier(ident) => Ok(Column::unqualified(&ident.value)),
+        Expr::CompoundIdentifier(idents) => match idents.as_slice() {
+            [relation, name] => Ok(Column::new(Some(&relation.value), &name.value)),
+            _ => anyhow::bail!(
+                "Unsupported column reference: {}",
+                ObjectName(idents.to_vec())
+            ),
+        },
+        e => anyhow::bail!("Expected a column, got {}", e),
+    }
+}
+
+/// Table metadata is keyed by the column name as written in `CREATE TABLE`, find the column
+/// matching exactly or failing that ignoring case.
+fn resolve_table_column(metadata: &ColumnDescriptors, column: &str) -> Option<String> {
+    if metadata.contains_key(column) {
+        return Some(column.to_string());
+    }
+    metadata
+        .keys()
+        .find(|x| x.eq_ignore_ascii_case(column))
+        .cloned()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::types::{ColumnDescriptor, Command};
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+    use tracing_test::traced_test;
+
+    pub(crate) fn test_catalog() -> BTreeMap<String, ColumnDescriptors> {
+        let column = |datatype, not_null| ColumnDescriptor {
+            datatype,
+            not_null,
+            ..Default::default()
+        };
+        let key = ColumnDescriptor {
+            primary_key: true,
+            ..column(DataType::Integer(None), true)
+        };
+        let mut catalog = BTreeMap::new();
+        let mut users = BTreeMap::new();
+        users.insert("id".to_string(), key.clone());
+        users.insert("name".to_string(), column(DataType::Text, true));
+        users.insert("age".to_string(), column(DataType::Integer(None), false));
+        catalog.insert("users".to_string(), users);
+        let mut orders = BTreeMap::new();
+        orders.insert("id".to_string(), key);
+        orders.insert("user_id".to_string(), column(DataType::Integer(None), true));
+        orders.insert(
+            "total".to_string(),
+            column(DataType::Numeric(ast::ExactNumberInfo::None), false),
+        );
+        catalog.insert("orders".to_string(), orders);
+        catalog
+    }
+
+    pub(crate) fn plan(sql: &str) -> anyhow::Result<LogicalPlan> {
+        let catalog = test_catalog();
+        let statement = Parser::parse_sql(&GenericDialect {}, sql)?.remove(0);
+        PlanBuilder::new(&catalog).plan_statement(&statement)
+    }
+
+    pub(crate) fn assert_plan_eq(plan: &LogicalPlan, expected: &str) {
+        assert_eq!(plan.to_string().trim_end(), expected.trim());
+    }
+
+    fn assert_plan(sql: &str, expected: &str) {
+        assert_plan_eq(&plan(sql).unwrap(), expected);
+    }
+
+    #[test]
+    #[traced_test]
+    fn simple_select() {
+        assert_plan(
+            "SELECT name FROM users WHERE age > 30",
+            "
+Projection: users.name
+  Filter: users.age > 30
+    Scan: users",
+        );
+        assert_plan(
+            "SELECT * FROM users u",
+            "
+Projection: u.age, u.id, u.name
+  Scan: users AS u",
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn unknown_names() {
+        assert!(plan("SELECT email FROM users").is_err());
+        assert!(plan("SELECT name FROM people").is_err());
+        assert!(plan("SELECT o.id FROM users").is_err());
+        // Both tables have an id
+        let err = plan("SELECT id FROM users, orders").unwrap_err();
+        assert!(err.to_string().contains("ambiguous"), "{}", err);
+        assert!(plan("SELECT users.id, NAME FROM users, orders").is_ok());
+    }
+
+    #[test]
+    #[traced_test]
+    fn joins() {
+        assert_plan(
+            "SELECT u.name, o.total FROM users u LEFT JOIN orders o ON u.id = o.user_id",
+            "
+Projection: u.name, o.total
+  Join: Left ON u.id = o.user_id
+    Scan: users AS u
+    Scan: orders AS o",
+        );
+        let plan = plan("SELECT * FROM users u RIGHT JOIN orders o ON u.id = o.user_id").unwrap();
+        assert!(plan
+            .schema()
+            .fields
+            .iter()
+            .all(|x| x.relation.as_deref() == Some("o") || x.nullable));
+    }
+
+    #[test]
+    #[traced_test]
+    fn using_joins() {
+        let full = plan("SELECT * FROM users a FULL JOIN users b USING (id, name)").unwrap();
+        let names: Vec<_> = full
+            .schema()
+            .fields
+            .iter()
+            .map(|f| f.name.as_str())
+            .collect();
+        assert_eq!(names, ["id", "name", "age", "age"]);
+        assert!(!full.schema().fields[0].nullable);
+        assert!(full
+            .to_string()
+            .contains("Join: Full ON a.id = b.id AND a.name = b.name"));
+        // The merged column isn't ambiguous, the originals are still there when qualified
+        assert!(plan("SELECT id, a.id, b.id FROM users a JOIN users b USING (id)").is_ok());
+        assert!(plan("SELECT a.* FROM users a NATURAL JOIN users b").is_ok());
+        let err = plan("SELECT * FROM users JOIN orders USING (name)").unwrap_err();
+        assert!(
+            err.to_string().contains("in USING doesn't exist"),
+            "{}",
+            err
+        );
+        // Nothing in common so it's a cross join
+        let cross = plan("SELECT * FROM users NATURAL JOIN (SELECT 1 AS x) t").unwrap();
+        assert!(cross.to_string().contains("Join: Cross"), "{}", cross);
+    }
+
+    #[test]
+    #[traced_test]
+    fn aggregates() {
+        assert_plan(
+            "SELECT name, count(*), sum(age) AS total FROM users GROUP BY name HAVING count(*) > 1",
+            "
+Projection: users.name, count(*), sum(users.age) AS total
+  Filter: count(*) > 1
+    Aggregate: groupBy=[users.name], aggr=[count(*), sum(users.age)]
+      Scan: users",
+        );
+        assert!(plan("SELECT name, age FROM users GROUP BY name").is_err());
+        assert!(plan("SELECT name FROM users WHERE count(*) > 1").is_err());
+        assert!(plan("SELECT count(count(*)) FROM users").is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn order_by_and_limit() {
+        assert_plan(
+            "SELECT name AS n FROM users ORDER BY n DESC, 1 LIMIT 10 OFFSET 5",
+            "
+Limit: limit=10, offset=5
+  Sort: n DESC, n
+    Projection: users.name AS n
+      Scan: users",
+        );
+        // Sorting on something not selected computes it and drops it again
+        assert_plan(
+            "SELECT name FROM users ORDER BY age NULLS FIRST",
+            "
+Projection: users.name
+  Sort: __sort_1 NULLS FIRST
+    Projection: users.name, users.age AS __sort_1
+      Scan: users",
+        );
+        assert!(plan("SELECT DISTINCT name FROM users ORDER BY age").is_err());
+        assert!(plan("SELECT name FROM users ORDER BY 2").is_err());
+        assert_plan(
+            "SELECT name FROM users ORDER BY name OFFSET 2 ROWS FETCH FIRST 5 ROWS WITH TIES",
+            "
+Limit: limit=5, offset=2, with ties
+  Sort: users.name
+    Projection: users.name
+      Scan: users",
+        );
+        assert!(plan("SELECT name FROM users FETCH FIRST 5 ROWS WITH TIES").is_err());
+        assert!(plan("SELECT name FROM users LIMIT 1 FETCH FIRST 5 ROWS ONLY").is_err());
+        assert!(plan("SELECT name FROM users FETCH FIRST 50 PERCENT ROWS ONLY").is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn row_locking() {
+        let catalog = test_catalog();
+        let plan = |sql: &str| {
+            let statement = Parser::parse_sql(&GenericDialect {}, sql)?.remove(0);
+            let Command::Select(options) = Command::try_from(&statement)? else {
+                unreachable!()
+            };
+            PlanBuilder::new(&catalog)
+                .with_locking(options.locking)
+                .plan_query(&options.query)
+        };
+        // The rows are locked after they're sorted and before the limit, so only the rows which
+        // are returned get locked
+        assert_plan_eq(
+            &plan(
+                "SELECT name FROM users WHERE age > 30 ORDER BY id LIMIT 1 FOR UPDATE SKIP LOCKED",
+            )
+            .unwrap(),
+            "
+Limit: limit=1, offset=0
+  Projection: users.name
+    LockRows: users FOR UPDATE SKIP LOCKED recheck=[users.age > 30]
+      Sort: users.id
+        Filter: users.age > 30
+          Scan: users",
+        );
+        assert_plan_eq(
+            &plan("SELECT u.name, o.total FROM users u JOIN orders o ON u.id = o.user_id ORDER BY o.total * 2 FOR SHARE OF o NOWAIT")
+                .unwrap(),
+            "
+Projection: u.name, o.total
+  LockRows: orders AS o FOR SHARE NOWAIT
+    Sort: __sort_7
+      Projection: u.age, u.id, u.name, o.id, o.total, o.user_id, o.__row_key, o.total * 2 AS __sort_7
+        Join: Inner ON u.id = o.user_id
+          Scan: users AS u
+          Scan: orders AS o",
+        );
+        for sql in [
+            "SELECT count(*) FROM users FOR UPDATE",
+            "SELECT DISTINCT name FROM users FOR UPDATE",
+            "SELECT name, row_number() OVER () FROM users FOR UPDATE",
+        ] {
+            assert!(plan(sql).is_err(), "{}", sql);
+        }
+        // Not locked so nothing changes
+        assert_plan_eq(
+            &plan("SELECT count(*) FROM users FOR UPDATE OF orders").unwrap(),
+            "
+Projection: count(*)
+  Aggregate: groupBy=[], aggr=[count(*)]
+    Scan: users",
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn common_table_expressions() {
+        // Used once so it's inlined, unused ones are dropped
+        assert_plan(
+            "WITH old AS (SELECT id, name FROM users WHERE age > 60), unused AS (SELECT 1) SELECT name FROM old",
+            "
+Projection: old.name
+  Projection: users.id AS id, users.name AS name
+    Projection: users.id, users.name
+      Filter: users.age > 60
+        Scan: users",
+        );
+        assert_plan(
+            "WITH old AS (SELECT id FROM users WHERE age > 60) SELECT a.id FROM old a JOIN old b ON a.id = b.id",
+            "
+With: old
+  Projection: users.id AS id
+    Projection: users.id
+      Filter: users.age > 60
+        Scan: users
+  Projection: a.id
+    Join: Inner ON a.id = b.id
+      Projection: old.id AS id
+        CteScan: old
+      Projection: old.id AS id
+        CteScan: old",
+        );
+        assert_plan(
+            "WITH RECURSIVE n (x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n WHERE x < 10) SELECT x FROM n",
+            "
+Projection: n.x
+  RecursiveCte: n UNION ALL
+    Projection: 1 AS 1
+      Values: ()
+    Projection: n.x + 1 AS n.x + 1
+      Filter: n.x < 10
+        CteScan: n",
+        );
+        assert!(plan("WITH a AS (SELECT 1), a AS (SELECT 2) SELECT * FROM a").is_err());
+        assert!(plan(
+            "WITH RECURSIVE n (x) AS (SELECT 1 UNION SELECT 1, 2 FROM n) SELECT x FROM n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn set_operations() {
+        assert_plan(
+            "SELECT id, name FROM users UNION ALL SELECT user_id, 'order' FROM orders ORDER BY name",
+            "
+Sort: name
+  SetOperation: UNION ALL
+    Projection: users.id, users.name
+      Scan: users
+    Projection: orders.user_id, 'order' AS 'order'
+      Scan: orders",
+        );
+        // INTERSECT binds tighter than UNION
+        assert_plan(
+            "SELECT id FROM users UNION SELECT id FROM orders INTERSECT SELECT user_id FROM orders",
+            "
+SetOperation: UNION
+  Projection: users.id
+    Scan: users
+  SetOperation: INTERSECT
+    Projection: orders.id
+      Scan: orders
+    Projection: orders.user_id
+      Scan: orders",
+        );
+        let except = plan("SELECT age FROM users EXCEPT ALL SELECT total FROM orders").unwrap();
+        let field = &except.schema().fields[0];
+        assert_eq!(
+            (field.name.as_str(), &field.datatype),
+            ("age", &DataType::Numeric(ast::ExactNumberInfo::None))
+        );
+        let err = plan("SELECT id, name FROM users UNION SELECT id FROM orders").unwrap_err();
+        assert!(
+            err.to_string().contains("same number of columns"),
+            "{}",
+            err
+        );
+        let err = plan("SELECT name FROM users INTERSECT SELECT total FROM orders").unwrap_err();
+        assert!(err.to_string().contains("cannot be matched"), "{}", err);
+        assert_plan(
+            "INSERT INTO orders (id, user_id) SELECT id, id FROM users EXCEPT SELECT id, user_id FROM orders",
+            "
+Insert: orders (id, user_id)
+  SetOperation: EXCEPT
+    Projection: users.id, users.id
+      Scan: users
+    Projection: orders.id, orders.user_id
+      Scan: orders",
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn window_functions() {
+        assert_plan(
+            "SELECT name, rank() OVER (PARTITION BY age ORDER BY id DESC) AS r FROM users ORDER BY row_number() OVER ()",
+            "
+Projection: users.name, r
+  Sort: __sort_2
+    Projection: users.name, rank() OVER (PARTITION BY users.age ORDER BY users.id DESC) AS r, row_number() OVER () AS __sort_2
+      Window: rank() OVER (PARTITION BY users.age ORDER BY users.id DESC), row_number() OVER ()
+        Scan: users",
+        );
+        assert_plan(
+            "SELECT sum(total) OVER w, lag(total, 2, 0) OVER (w ORDER BY id) FROM orders WINDOW w AS (PARTITION BY user_id)",
+            "
+Projection: sum(orders.total) OVER (PARTITION BY orders.user_id), lag(orders.total, 2, 0) OVER (PARTITION BY orders.user_id ORDER BY orders.id)
+  Window: sum(orders.total) OVER (PARTITION BY orders.user_id), lag(orders.total, 2, 0) OVER (PARTITION BY orders.user_id ORDER BY orders.id)
+    Scan: orders",
+        );
+        assert_plan(
+            "SELECT count(*) OVER (), count(*) FROM users GROUP BY age",
+            "
+Projection: count(*) OVER (), count(*)
+  Window: count(*) OVER ()
+    Aggregate: groupBy=[users.age], aggr=[count(*)]
+      Scan: users",
+        );
+        // Windows go after grouping so they can use aggregates
+        assert_plan(
+            "SELECT user_id, sum(sum(total)) OVER (ORDER BY user_id ROWS BETWEEN 1 PRECEDING AND CURRENT ROW) FROM orders GROUP BY user_id",
+            "
+Projection: orders.user_id, sum(sum(orders.total)) OVER (ORDER BY orders.user_id ROWS BETWEEN 1 PRECEDING AND CURRENT ROW)
+  Window: sum(sum(orders.total)) OVER (ORDER BY orders.user_id ROWS BETWEEN 1 PRECEDING AND CURRENT ROW)
+    Aggregate: groupBy=[orders.user_id], aggr=[sum(orders.total)]
+      Scan: orders",
+        );
+        for (sql, message) in [
+            (
+                "SELECT id FROM users WHERE rank() OVER () > 1",
+                "not allowed here",
+            ),
+            (
+                "SELECT lower(name) OVER () FROM users",
+                "not a window function",
+            ),
+            (
+                "SELECT rank(id) OVER () FROM users",
+                "doesn't take any arguments",
+            ),
+            ("SELECT rank() OVER w FROM users", "Window w does not exist"),
+            (
+                "SELECT rank() OVER (w ORDER BY id) FROM users WINDOW w AS (ORDER BY age)",
+                "Can't override ORDER BY",
+            ),
+            (
+                "SELECT sum(id) OVER (ORDER BY id, age RANGE 1 PRECEDING) FROM users",
+                "exactly one ORDER BY",
+            ),
+            (
+                "SELECT sum(id) OVER (ROWS BETWEEN CURRENT ROW AND 1 PRECEDING) FROM users",
+                "cannot end at",
+            ),
+            (
+                "SELECT sum(rank() OVER ()) OVER () FROM users",
+                "cannot be nested",
+            ),
+            (
+                "SELECT age FROM users GROUP BY age HAVING rank() OVER () > 1",
+                "not allowed in HAVING",
+            ),
+        ] {
+            let err = plan(sql).unwrap_err();
+            assert!(err.to_string().contains(message), "{}: {}", sql, err);
+        }
+    }
+
+    #[test]
+    #[traced_test]
+    fn values_and_modifications() {
+        assert_plan(
+            "INSERT INTO users (id, name) VALUES (1, 'a'), (2, 'b')",
+            "
+Insert: users (id, name)
+  Values: (1, 'a'), (2, 'b')",
+        );
+        assert_plan(
+            "UPDATE users SET age = age + 1 WHERE name = 'a'",
+            "
+Update: users SET age = users.age + 1
+  Filter: users.name = 'a'
+    Scan: users FOR UPDATE",
+        );
+        assert_plan(
+            "DELETE FROM orders WHERE total IS NULL",
+            "
+Delete: orders
+  Filter: orders.total IS NULL
+    Scan: orders FOR UPDATE",
+        );
+        assert_plan(
+            "INSERT INTO users (id, name) VALUES (1, 'a') ON CONFLICT (id) DO UPDATE SET name = excluded.name WHERE users.age > 1",
+            "
+Insert: users (id, name) ON CONFLICT (id) DO UPDATE SET name = excluded.name WHERE users.age > 1
+  Values: (1, 'a')",
+        );
+        assert_plan(
+            "INSERT INTO users (id, name) VALUES (1, 'a') ON DUPLICATE KEY UPDATE name = VALUES(name), age = age + 1",
+            "
+Insert: users (id, name) ON CONFLICT DO UPDATE SET name = excluded.name, age = users.age + 1
+  Values: (1, 'a')",
+        );
+        for (sql, message) in [
+            (
+                "INSERT INTO users (id, name) VALUES (1, 'a') ON CONFLICT (name) DO NOTHING",
+                "no unique constraint",
+            ),
+            (
+                "INSERT INTO users (id, name) VALUES (1, 'a') ON CONFLICT DO UPDATE SET age = 1",
+                "needs the columns",
+            ),
+            (
+                "INSERT INTO users (id, name) VALUES (1, 'a') ON CONFLICT ON CONSTRAINT users_pkey DO NOTHING",
+                "not supported",
+            ),
+        ] {
+            let err = plan(sql).unwrap_err();
+            assert!(err.to_string().contains(message), "{}: {}", sql, err);
+        }
+        assert_plan(
+            "UPDATE users SET age = age + 1 WHERE name = 'a' RETURNING id, age",
+            "
+Projection: users.id, users.age
+  Update: users SET age = users.age + 1
+    Filter: users.name = 'a'
+      Scan: users FOR UPDATE",
+        );
+        assert_plan(
+            "DELETE FROM orders WHERE total IS NULL RETURNING *",
+            "
+Projection: orders.id, orders.total, orders.user_id
+  Delete: orders
+    Filter: orders.total IS NULL
+      Scan: orders FOR UPDATE",
+        );
+        assert!(plan("INSERT INTO users (id, name) VALUES (1)").is_err());
+        assert!(plan("INSERT INTO users (id, email) VALUES (1, 'a')").is_err());
+        assert!(plan("UPDATE users SET email = 'a'").is_err());
+    }
+}
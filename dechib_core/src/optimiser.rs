@@ -0,0 +1,1801 @@
+//! Rule based rewrites of the logical plan. Each rule is a self contained transformation that
+//! leaves the results of the plan unchanged, the optimiser just runs them all until they stop
+//! changing anything.
+use crate::cost::CostModel;
+use crate::evaluator::{binary_op, PhysicalExpr};
+use crate::expression::{Column, ScalarExpr};
+use crate::functions::Volatility;
+use crate::join_order::{JoinReorder, DEFAULT_DP_LIMIT};
+use crate::logical_plan::{ApplyKind, Catalog, Field, JoinKind, LogicalPlan, Schema, SortKey};
+use crate::types::Value;
+use sqlparser::ast::{BinaryOperator, UnaryOperator};
+use std::collections::HashSet;
+use tracing::trace;
+
+/// Stops us looping forever if a couple of rules keep undoing each other
+const MAX_PASSES: usize = 8;
+
+pub trait OptimiserRule {
+    fn name(&self) -> &'static str;
+
+    fn rewrite(&self, plan: LogicalPlan) -> anyhow::Result<LogicalPlan>;
+}
+
+pub struct Optimiser<'a> {
+    rules: Vec<Box<dyn OptimiserRule + 'a>>,
+}
+
+impl<'a> Optimiser<'a> {
+    /// The full set of rules, the cost based ones use the catalog to find statistics.
+    pub fn new(catalog: &'a dyn Catalog) -> Self {
+        Self::with_dp_limit(catalog, DEFAULT_DP_LIMIT)
+    }
+
+    /// The full set of rules, reordering joins of up to `dp_limit` relations with dynamic
+    /// programming and any bigger ones greedily.
+    pub fn with_dp_limit(catalog: &'a dyn Catalog, dp_limit: usize) -> Self {
+        Self {
+            rules: vec![
+                Box::new(SubqueryDecorrelation),
+                Box::new(ConstantFolding),
+                Box::new(FilterMerge),
+                Box::new(PredicatePushdown),
+                Box::new(RedundantSortElimination),
+                Box::new(TopN),
+                Box::new(ProjectionPruning),
+                Box::new(AccessPathSelection::new(catalog)),
+                Box::new(JoinReorder::new(catalog).with_dp_limit(dp_limit)),
+                Box::new(JoinCommute::new(catalog)),
+            ],
+        }
+    }
+
+    pub fn with_rules(rules: Vec<Box<dyn OptimiserRule + 'a>>) -> Self {
+        Self { rules }
+    }
+
+    pub fn optimise(&self, mut plan: LogicalPlan) -> anyhow::Result<LogicalPlan> {
+        for _ in 0..MAX_PASSES {
+            let before = plan.clone();
+            for rule in &self.rules {
+                plan = rule.rewrite(plan)?;
+                trace!(rule = rule.name(), plan = %plan, "applied rule");
+            }
+            if plan == before {
+                break;
+            }
+        }
+        Ok(plan)
+    }
+}
+
+/// Gets rid of subqueries in expressions. `EXISTS` and `IN` in a filter become semi joins, or
+/// anti joins when negated, as long as the predicates referring to the outer query can be pulled
+/// up out of the subquery to become the join condition. Anything else is run for each row by an
+/// `Apply`.
+pub struct SubqueryDecorrelation;
+
+impl OptimiserRule for SubqueryDecorrelation {
+    fn name(&self) -> &'static str {
+        "subquery_decorrelation"
+    }
+
+    fn rewrite(&self, plan: LogicalPlan) -> anyhow::Result<LogicalPlan> {
+        plan.transform_up(&mut |plan| {
+            if !plan.expressions().iter().any(|e| e.contains_subquery()) {
+                return Ok(plan);
+            }
+            let plan = plan.map_expressions(|e| self.rewrite_nested(e))?;
+            match plan {
+                LogicalPlan::Filter { predicate, input } => {
+                    let schema = input.schema().clone();
+                    let mut input = *input;
+                    let mut rest = vec![];
+                    for term in predicate.split_conjunction() {
+                        match semi_join(&input, &term)? {
+                            Some(joined) => input = joined,
+                            None => rest.push(term),
+                        }
+                    }
+                    let rest = rest
+                        .into_iter()
+                        .map(|e| apply_subqueries(e, &mut input))
+                        .collect::<anyhow::Result<Vec<_>>>()?;
+                    if input.schema() == &schema {
+                        return Ok(with_filter(input, rest));
+                    }
+                    // Put the columns back to what they were without the `Apply` results
+                    Ok(LogicalPlan::Project {
+                        exprs: schema
+                            .fields
+                            .iter()
+                            .map(|f| ScalarExpr::Column(f.column()))
+                            .collect(),
+                        schema,
+                        input: Box::new(with_filter(input, rest)),
+                    })
+                }
+                LogicalPlan::Project {
+                    exprs,
+                    schema,
+                    input,
+                } => {
+                    let mut input = *input;
+                    let exprs = exprs
+                        .into_iter()
+                        .map(|e| apply_subqueries(e, &mut input))
+                        .collect::<anyhow::Result<_>>()?;
+                    Ok(LogicalPlan::Project {
+                        exprs,
+                        schema,
+                        input: Box::new(input),
+                    })
+                }
+                // Anywhere else the executor will complain about them
+                plan => Ok(plan),
+            }
+        })
+    }
+}
+
+impl SubqueryDecorrelation {
+    /// Decorrelates any subqueries inside the subqueries in the expression
+    fn rewrite_nested(&self, expr: ScalarExpr) -> anyhow::Result<ScalarExpr> {
+        expr.map_subqueries(&mut |plan| self.rewrite(plan))
+    }
+}
+
+/// Joins the input with the subquery in a filter term if it's `[NOT] EXISTS` or `[NOT] IN` and
+/// can be decorrelated.
+fn semi_join(input: &LogicalPlan, term: &ScalarExpr) -> anyhow::Result<Option<LogicalPlan>> {
+    let (term, flipped) = match term {
+        ScalarExpr::UnaryOp {
+            op: UnaryOperator::Not,
+            expr,
+        } => (expr.as_ref(), true),
+        term => (term, false),
+    };
+    let (subquery, value, negated) = match term {
+        ScalarExpr::Exists { subquery, negated } => (subquery, None, *negated != flipped),
+        ScalarExpr::InSubquery {
+            expr,
+            subquery,
+            negated,
+        } => (subquery, Some(expr.as_ref()), *negated != flipped),
+        _ => return Ok(None),
+    };
+    let Some((mut subquery, mut pulled)) = pull_up(subquery.as_ref().clone())? else {
+        return Ok(None);
+    };
+    // Make sure the subquery's columns can't be mixed up with the outer query's
+    let clashes = subquery
+        .schema()
+        .fields
+        .iter()
+        .any(|f| !matches!(input.schema().find(&f.column()), Ok(None)));
+    if clashes {
+        let fields = &subquery.schema().fields;
+        let names = fields.iter().map(|f| &f.name).collect::<HashSet<_>>();
+        if names.len() < fields.len() {
+            return Ok(None);
+        }
+        let renamed = fields
+            .iter()
+            .map(|f| Column::new(Some(SUBQUERY_RELATION), f.name.clone()))
+            .collect::<Vec<_>>();
+        let columns = fields.iter().map(|f| f.column()).collect::<Vec<_>>();
+        let rename = |e: ScalarExpr| {
+            e.transform_down(&mut |e| match e {
+                ScalarExpr::Column(c) => Ok(columns
+                    .iter()
+                    .position(|x| x == c)
+                    .map(|i| ScalarExpr::Column(renamed[i].clone()))),
+                _ => Ok(None),
+            })
+        };
+        pulled = pulled
+            .into_iter()
+            .map(rename)
+            .collect::<anyhow::Result<_>>()?;
+        let schema = Schema::new(
+            fields
+                .iter()
+                .map(|f| Field {
+                    relation: Some(SUBQUERY_RELATION.to_string()),
+                    hidden: false,
+                    ..f.clone()
+                })
+                .collect(),
+        );
+        subquery = LogicalPlan::Project {
+            exprs: columns.into_iter().map(ScalarExpr::Column).collect(),
+            schema,
+            input: Box::new(subquery),
+        };
+    }
+    // The outer query's columns are just columns once they're in the join condition
+    let mut condition = pulled
+        .into_iter()
+        .map(|e| {
+            e.transform_down(&mut |e| match e {
+                ScalarExpr::OuterColumn(c, _) => Ok(Some(ScalarExpr::Column(c.clone()))),
+                _ => Ok(None),
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if let Some(value) = value {
+        let field = &subquery.schema().fields[0];
+        let found = ScalarExpr::Column(field.column());
+        let eq = ScalarExpr::binary(value.clone(), BinaryOperator::Eq, found.clone());
+        let value_nullable = match value {
+            ScalarExpr::Column(c) => input.schema().field(c)?.nullable,
+            _ => true,
+        };
+        // With NOT IN a null on either side means we don't know if it's in there, so the row
+        // doesn't make it through
+        if negated && (value_nullable || field.nullable) {
+            let or = |a, b| ScalarExpr::binary(a, BinaryOperator::Or, b);
+            condition.push(or(
+                or(eq, ScalarExpr::IsNull(Box::new(value.clone()))),
+                ScalarExpr::IsNull(Box::new(found)),
+            ));
+        } else {
+            condition.push(eq);
+        }
+    }
+    let kind = if negated {
+        JoinKind::Anti
+    } else {
+        JoinKind::Semi
+    };
+    Ok(Some(LogicalPlan::join(
+        input.clone(),
+        subquery,
+        kind,
+        ScalarExpr::conjunction(condition),
+    )))
+}
+
+/// What subqueries' columns are called if they'd clash with the outer query's
+const SUBQUERY_RELATION: &str = "__subquery";
+
+/// Takes the predicates referring to the outer query out of the subquery, `None` if there are
+/// correlated references which can't be moved.
+fn pull_up(plan: LogicalPlan) -> anyhow::Result<Option<(LogicalPlan, Vec<ScalarExpr>)>> {
+    let res = match plan {
+        LogicalPlan::Filter { predicate, input } => {
+            let Some((input, mut pulled)) = pull_up(*input)? else {
+                return Ok(None);
+            };
+            let (correlated, rest): (Vec<_>, Vec<_>) = predicate
+                .split_conjunction()
+                .into_iter()
+                .partition(|p| p.is_correlated());
+            pulled.extend(correlated);
+            (with_filter(input, rest), pulled)
+        }
+        LogicalPlan::Scan {
+            table,
+            alias,
+            schema,
+            projection,
+            filters,
+            lock,
+            primary_key,
+        } => {
+            if primary_key.iter().flatten().any(|e| e.is_correlated()) {
+                return Ok(None);
+            }
+            let (pulled, filters) = filters.into_iter().partition(|p| p.is_correlated());
+            let scan = LogicalPlan::Scan {
+                table,
+                alias,
+                schema,
+                projection,
+                filters,
+                lock,
+                primary_key,
+            };
+            (scan, pulled)
+        }
+        LogicalPlan::Project {
+            mut exprs,
+            mut schema,
+            input,
+        } => {
+            if exprs.iter().any(|e| e.is_correlated()) {
+                return Ok(None);
+            }
+            let Some((input, pulled)) = pull_up(*input)? else {
+                return Ok(None);
+            };
+            // The pulled up predicates still need the columns they use from below the projection
+            let mut needed = vec![];
+            add_columns(&mut needed, &pulled);
+            for column in needed {
+                let expr = ScalarExpr::Column(column.clone());
+                match schema.find(&column) {
+                    Ok(Some(i)) if exprs[i] == expr => {}
+                    Ok(None) => {
+                        exprs.push(expr);
+                        schema.fields.push(input.schema().field(&column)?.clone());
+                    }
+                    _ => return Ok(None),
+                }
+            }
+            let project = LogicalPlan::Project {
+                exprs,
+                schema,
+                input: Box::new(input),
+            };
+            (project, pulled)
+        }
+        // The order doesn't matter to a semi join
+        LogicalPlan::Sort { input, .. } => return pull_up(*input),
+        LogicalPlan::Join {
+            left,
+            right,
+            kind: kind @ (JoinKind::Inner | JoinKind::Cross),
+            condition,
+            ..
+        } => {
+            if condition.as_ref().is_some_and(|c| c.is_correlated()) {
+                return Ok(None);
+            }
+            let (Some((left, mut pulled)), Some((right, right_pulled))) =
+                (pull_up(*left)?, pull_up(*right)?)
+            else {
+                return Ok(None);
+            };
+            pulled.extend(right_pulled);
+            (LogicalPlan::join(left, right, kind, condition), pulled)
+        }
+        plan if plan.is_correlated() => return Ok(None),
+        plan => (plan, vec![]),
+    };
+    Ok(Some(res))
+}
+
+/// Replaces the subqueries in the expression with the results of `Apply`s added to the input
+fn apply_subqueries(expr: ScalarExpr, input: &mut LogicalPlan) -> anyhow::Result<ScalarExpr> {
+    expr.transform_down(&mut |e| {
+        let (subquery, kind, negated) = match e {
+            ScalarExpr::Subquery(subquery) => (subquery, ApplyKind::Scalar, false),
+            ScalarExpr::Exists { subquery, negated } => (subquery, ApplyKind::Exists, *negated),
+            ScalarExpr::InSubquery {
+                expr,
+                subquery,
+                negated,
+            } => {
+                let expr = apply_subqueries(expr.as_ref().clone(), input)?;
+                (subquery, ApplyKind::In(expr), *negated)
+            }
+            _ => return Ok(None),
+        };
+        let plan = std::mem::replace(input, LogicalPlan::empty(input));
+        *input = LogicalPlan::apply(plan, subquery.as_ref().clone(), kind);
+        let result = ScalarExpr::Column(input.schema().fields.last().unwrap().column());
+        Ok(Some(if negated {
+            ScalarExpr::UnaryOp {
+                op: UnaryOperator::Not,
+                expr: Box::new(result),
+            }
+        } else {
+            result
+        }))
+    })
+}
+
+/// Evaluates anything which doesn't depend on the row, and gets rid of filters which are always
+/// true or replaces them with an empty relation if they're never true.
+pub struct ConstantFolding;
+
+impl OptimiserRule for ConstantFolding {
+    fn name(&self) -> &'static str {
+        "constant_folding"
+    }
+
+    fn rewrite(&self, plan: LogicalPlan) -> anyhow::Result<LogicalPlan> {
+        plan.transform_up(&mut |plan| {
+            let plan = plan.map_expressions(|e| e.transform_up(&mut |e| Ok(fold(e))))?;
+            let plan = match plan {
+                LogicalPlan::Filter { predicate, input } => match as_bool(&predicate) {
+                    Some(true) => *input,
+                    Some(false) => input.empty(),
+                    None => LogicalPlan::Filter { predicate, input },
+                },
+                LogicalPlan::Scan {
+                    table,
+                    alias,
+                    schema,
+                    projection,
+                    filters,
+                    lock,
+                    primary_key,
+                } => {
+                    if filters.iter().any(|x| as_bool(x) == Some(false)) {
+                        return Ok(LogicalPlan::Values {
+                            rows: vec![],
+                            schema,
+                        });
+                    }
+                    LogicalPlan::Scan {
+                        table,
+                        alias,
+                        schema,
+                        projection,
+                        filters: filters
+                            .into_iter()
+                            .filter(|x| as_bool(x) != Some(true))
+                            .collect(),
+                        lock,
+                        primary_key,
+                    }
+                }
+                LogicalPlan::Join {
+                    left,
+                    right,
+                    kind: JoinKind::Inner,
+                    condition: Some(condition),
+                    ..
+                } if as_bool(&condition) == Some(true) => {
+                    LogicalPlan::join(*left, *right, JoinKind::Cross, None)
+                }
+                plan => plan,
+            };
+            Ok(plan)
+        })
+    }
+}
+
+/// `Some` if the expression is a constant which is definitely true or not true, null counts as
+/// false as far as filtering is concerned.
+fn as_bool(expr: &ScalarExpr) -> Option<bool> {
+    match expr {
+        ScalarExpr::Literal(Value::Boolean(b)) => Some(*b),
+        ScalarExpr::Literal(Value::Null) => Some(false),
+        _ => None,
+    }
+}
+
+/// Folds a single node whose children have already been folded.
+pub(crate) fn fold(expr: ScalarExpr) -> ScalarExpr {
+    use ScalarExpr::Literal;
+    match expr {
+        ScalarExpr::BinaryOp { left, op, right } => {
+            match (*left, op, *right) {
+                // Three valued logic, false AND null is still false
+                (Literal(Value::Boolean(false)), BinaryOperator::And, _)
+                | (_, BinaryOperator::And, Literal(Value::Boolean(false))) => {
+                    Literal(Value::Boolean(false))
+                }
+                (Literal(Value::Boolean(true)), BinaryOperator::Or, _)
+                | (_, BinaryOperator::Or, Literal(Value::Boolean(true))) => {
+                    Literal(Value::Boolean(true))
+                }
+                (Literal(Value::Boolean(true)), BinaryOperator::And, e)
+                | (e, BinaryOperator::And, Literal(Value::Boolean(true)))
+                | (Literal(Value::Boolean(false)), BinaryOperator::Or, e)
+                | (e, BinaryOperator::Or, Literal(Value::Boolean(false))) => e,
+                // If it fails leave it for the executor to report the error
+                (Literal(l), op, Literal(r)) => match binary_op(&l, &op, &r) {
+                    Ok(v) => Literal(v),
+                    Err(_) => ScalarExpr::binary(Literal(l), op, Literal(r)),
+                },
+                (l, op, r) => ScalarExpr::binary(l, op, r),
+            }
+        }
+        ScalarExpr::UnaryOp { op, expr } => match (op, *expr) {
+            (UnaryOperator::Not, Literal(Value::Boolean(b))) => Literal(Value::Boolean(!b)),
+            (UnaryOperator::Not | UnaryOperator::Minus, Literal(Value::Null)) => {
+                Literal(Value::Null)
+            }
+            (UnaryOperator::Minus, Literal(Value::Number(n))) => Literal(Value::Number(-n)),
+            (UnaryOperator::Plus, e @ Literal(Value::Number(_))) => e,
+            (op, expr) => ScalarExpr::UnaryOp {
+                op,
+                expr: Box::new(expr),
+            },
+        },
+        ScalarExpr::IsNull(e) => match *e {
+            Literal(v) => Literal(Value::Boolean(v.is_null())),
+            e => ScalarExpr::IsNull(Box::new(e)),
+        },
+        ScalarExpr::IsNotNull(e) => match *e {
+            Literal(v) => Literal(Value::Boolean(!v.is_null())),
+            e => ScalarExpr::IsNotNull(Box::new(e)),
+        },
+        e @ (ScalarExpr::Between { .. }
+        | ScalarExpr::InList { .. }
+        | ScalarExpr::Like { .. }
+        | ScalarExpr::Case { .. }
+        | ScalarExpr::Cast { .. }
+        | ScalarExpr::Coalesce(_)
+        | ScalarExpr::NullIf(..)
+        | ScalarExpr::Function { .. })
+            if e.children().iter().all(|x| matches!(x, Literal(_)))
+                && e.volatility() == Volatility::Immutable =>
+        {
+            let schema = Schema::default();
+            match PhysicalExpr::new(&e, &schema).and_then(|x| x.evaluate(&[])) {
+                Ok(v) => Literal(v),
+                Err(_) => e,
+            }
+        }
+        e => e,
+    }
+}
+
+/// Combines stacked filters into a single filter.
+pub struct FilterMerge;
+
+impl OptimiserRule for FilterMerge {
+    fn name(&self) -> &'static str {
+        "filter_merge"
+    }
+
+    fn rewrite(&self, plan: LogicalPlan) -> anyhow::Result<LogicalPlan> {
+        plan.transform_up(&mut |plan| match plan {
+            LogicalPlan::Filter {
+                predicate: outer,
+                input,
+            } => match *input {
+                LogicalPlan::Filter { predicate, input } => Ok(LogicalPlan::Filter {
+                    predicate: ScalarExpr::binary(predicate, BinaryOperator::And, outer),
+                    input,
+                }),
+                input => Ok(LogicalPlan::Filter {
+                    predicate: outer,
+                    input: Box::new(input),
+                }),
+            },
+            plan => Ok(plan),
+        })
+    }
+}
+
+/// Moves filters as close to the scans as possible so fewer rows flow through the rest of the
+/// plan, ending up in the scan itself where possible. Predicates above a join referring to both
+/// sides become part of the join condition.
+pub struct PredicatePushdown;
+
+impl OptimiserRule for PredicatePushdown {
+    fn name(&self) -> &'static str {
+        "predicate_pushdown"
+    }
+
+    fn rewrite(&self, plan: LogicalPlan) -> anyhow::Result<LogicalPlan> {
+        push_down(plan, vec![])
+    }
+}
+
+fn with_filter(plan: LogicalPlan, predicates: Vec<ScalarExpr>) -> LogicalPlan {
+    match ScalarExpr::conjunction(predicates) {
+        Some(predicate) => LogicalPlan::Filter {
+            predicate,
+            input: Box::new(plan),
+        },
+        None => plan,
+    }
+}
+
+/// Whether all the columns in the expression come from the plan's output
+fn references_only(expr: &ScalarExpr, plan: &LogicalPlan) -> bool {
+    expr.columns()
+        .iter()
+        .all(|c| matches!(plan.schema().find(c), Ok(Some(_))))
+}
+
+/// Replaces references to the output columns of a node with the expressions producing them.
+fn substitute(
+    expr: ScalarExpr,
+    columns: &[Column],
+    exprs: &[ScalarExpr],
+) -> anyhow::Result<ScalarExpr> {
+    expr.transform_down(&mut |e| match e {
+        ScalarExpr::Column(c) => Ok(columns
+            .iter()
+            .position(|x| x == c)
+            .map(|i| exprs[i].clone())),
+        _ => Ok(None),
+    })
+}
+
+fn push_down(plan: LogicalPlan, mut predicates: Vec<ScalarExpr>) -> anyhow::Result<LogicalPlan> {
+    let plan = match plan {
+        LogicalPlan::Filter { predicate, input } => {
+            predicates.extend(predicate.split_conjunction());
+            push_down(*input, predicates)?
+        }
+        LogicalPlan::Scan {
+            table,
+            alias,
+            schema,
+            projection,
+            mut filters,
+            lock,
+            primary_key,
+        } => {
+            for predicate in predicates {
+                if !filters.contains(&predicate) {
+                    filters.push(predicate);
+                }
+            }
+            LogicalPlan::Scan {
+                table,
+                alias,
+                schema,
+                projection,
+                filters,
+                lock,
+                primary_key,
+            }
+        }
+        LogicalPlan::Project {
+            exprs,
+            schema,
+            input,
+        } => {
+            let columns = schema.fields.iter().map(|x| x.column()).collect::<Vec<_>>();
+            let predicates = predicates
+                .into_iter()
+                .map(|p| substitute(p, &columns, &exprs))
+                .collect::<anyhow::Result<_>>()?;
+            LogicalPlan::Project {
+                exprs,
+                schema,
+                input: Box::new(push_down(*input, predicates)?),
+            }
+        }
+        LogicalPlan::Sort { keys, input } => LogicalPlan::Sort {
+            keys,
+            input: Box::new(push_down(*input, predicates)?),
+        },
+        LogicalPlan::Aggregate {
+            group_by,
+            aggregates,
+            schema,
+            input,
+        } => {
+            // Only predicates on the grouping columns can be applied before grouping
+            let columns = schema.fields[..group_by.len()]
+                .iter()
+                .map(|x| x.column())
+                .collect::<Vec<_>>();
+            let (below, above): (Vec<_>, Vec<_>) = predicates.into_iter().partition(|p| {
+                p.columns().iter().all(|c| columns.contains(c)) && !p.columns().is_empty()
+            });
+            let below = below
+                .into_iter()
+                .map(|p| substitute(p, &columns, &group_by))
+                .collect::<anyhow::Result<_>>()?;
+            let plan = LogicalPlan::Aggregate {
+                group_by,
+                aggregates,
+                schema,
+                input: Box::new(push_down(*input, below)?),
+            };
+            with_filter(plan, above)
+        }
+        LogicalPlan::Window {
+            windows,
+            schema,
+            input,
+        } => {
+            // Filtering out rows changes what the other rows in their partition see, unless all
+            // the rows of the partition go
+            let (below, above): (Vec<_>, Vec<_>) = predicates.into_iter().partition(|p| {
+                !p.columns().is_empty()
+                    && p.columns().iter().all(|c| {
+                        windows
+                            .iter()
+                            .all(|w| w.partition_by.contains(&ScalarExpr::Column((*c).clone())))
+                    })
+            });
+            let plan = LogicalPlan::Window {
+                windows,
+                schema,
+                input: Box::new(push_down(*input, below)?),
+            };
+            with_filter(plan, above)
+        }
+        LogicalPlan::Join {
+            left,
+            right,
+            kind,
+            condition,
+            ..
+        } => {
+            let mut left_preds = vec![];
+            let mut right_preds = vec![];
+            let mut join_preds = vec![];
+            let mut above = vec![];
+            // Filtering the side of an outer join which keeps all its rows changes the result,
+            // so only the preserved side can be filtered from above
+            let (left_above, right_above) = match kind {
+                JoinKind::Inner | JoinKind::Cross => (true, true),
+                JoinKind::Left | JoinKind::Semi | JoinKind::Anti => (true, false),
+                JoinKind::Right => (false, true),
+                JoinKind::Full => (false, false),
+            };
+            for p in predicates {
+                if left_above && references_only(&p, &left) {
+                    left_preds.push(p);
+                } else if right_above && references_only(&p, &right) {
+                    right_preds.push(p);
+                } else if left_above && right_above {
+                    join_preds.push(p);
+                } else {
+                    above.push(p);
+                }
+            }
+            // Conversely the join condition can only filter the side which isn't preserved, a
+            // semi join doesn't preserve anything but an anti join keeps left rows that don't match
+            let (left_on, right_on) = match kind {
+                JoinKind::Inner | JoinKind::Cross | JoinKind::Semi => (true, true),
+                JoinKind::Left | JoinKind::Anti => (false, true),
+                JoinKind::Right => (true, false),
+                JoinKind::Full => (false, false),
+            };
+            for p in condition.into_iter().flat_map(|x| x.split_conjunction()) {
+                if left_on && references_only(&p, &left) && !p.columns().is_empty() {
+                    left_preds.push(p);
+                } else if right_on && references_only(&p, &right) && !p.columns().is_empty() {
+                    right_preds.push(p);
+                } else {
+                    join_preds.push(p);
+                }
+            }
+            let condition = ScalarExpr::conjunction(join_preds);
+            let kind = match (kind, &condition) {
+                (JoinKind::Cross, Some(_)) => JoinKind::Inner,
+                (JoinKind::Inner, None) => JoinKind::Cross,
+                (kind, _) => kind,
+            };
+            let plan = LogicalPlan::join(
+                push_down(*left, left_preds)?,
+                push_down(*right, right_preds)?,
+                kind,
+                condition,
+            );
+            with_filter(plan, above)
+        }
+        LogicalPlan::Apply {
+            kind,
+            schema,
+            input,
+            subquery,
+        } => {
+            let (below, above) = predicates
+                .into_iter()
+                .partition(|p| references_only(p, &input));
+            let plan = LogicalPlan::Apply {
+                kind,
+                schema,
+                input: Box::new(push_down(*input, below)?),
+                subquery: Box::new(push_down(*subquery, vec![])?),
+            };
+            with_filter(plan, above)
+        }
+        LogicalPlan::With {
+            name,
+            id,
+            cte,
+            input,
+        } => LogicalPlan::With {
+            name,
+            id,
+            cte: Box::new(push_down(*cte, vec![])?),
+            input: Box::new(push_down(*input, predicates)?),
+        },
+        plan @ (LogicalPlan::Limit { .. }
+        | LogicalPlan::TopN { .. }
+        | LogicalPlan::LockRows { .. }
+        | LogicalPlan::Values { .. }
+        | LogicalPlan::CteScan { .. }
+        | LogicalPlan::RecursiveCte { .. }
+        | LogicalPlan::SetOperation { .. }
+        | LogicalPlan::Insert { .. }
+        | LogicalPlan::Update { .. }
+        | LogicalPlan::Delete { .. }) => {
+            let plan = plan.map_inputs(|input| push_down(input, vec![]))?;
+            with_filter(plan, predicates)
+        }
+    };
+    Ok(plan)
+}
+
+/// Removes any sort feeding into the plan, looking through nodes that keep rows in order.
+fn strip_sort(plan: LogicalPlan) -> anyhow::Result<LogicalPlan> {
+    match plan {
+        LogicalPlan::Sort { input, .. } => strip_sort(*input),
+        plan @ (LogicalPlan::Project { .. } | LogicalPlan::Filter { .. }) => {
+            plan.map_inputs(strip_sort)
+        }
+        plan => Ok(plan),
+    }
+}
+
+/// Drops sorts that can't affect the result, that's sorts which are immediately resorted or
+/// which feed into something that doesn't preserve order. Also removes duplicate and constant
+/// sort keys.
+pub struct RedundantSortElimination;
+
+impl OptimiserRule for RedundantSortElimination {
+    fn name(&self) -> &'static str {
+        "redundant_sort_elimination"
+    }
+
+    fn rewrite(&self, plan: LogicalPlan) -> anyhow::Result<LogicalPlan> {
+        plan.transform_up(&mut |plan| {
+            let plan = match plan {
+                LogicalPlan::Sort { keys, input } => {
+                    let mut deduped: Vec<SortKey> = vec![];
+                    for key in keys {
+                        let constant = matches!(key.expr, ScalarExpr::Literal(_));
+                        if !constant && !deduped.iter().any(|x| x.expr == key.expr) {
+                            deduped.push(key);
+                        }
+                    }
+                    let input = strip_sort(*input)?;
+                    if deduped.is_empty() {
+                        input
+                    } else {
+                        LogicalPlan::Sort {
+                            keys: deduped,
+                            input: Box::new(input),
+                        }
+                    }
+                }
+                LogicalPlan::TopN {
+                    keys,
+                    limit,
+                    offset,
+                    with_ties,
+                    input,
+                } => LogicalPlan::TopN {
+                    keys,
+                    limit,
+                    offset,
+                    with_ties,
+                    input: Box::new(strip_sort(*input)?),
+                },
+                plan @ (LogicalPlan::Aggregate { .. } | LogicalPlan::Join { .. }) => {
+                    plan.map_inputs(strip_sort)?
+                }
+                plan => plan,
+            };
+            Ok(plan)
+        })
+    }
+}
+
+/// A `LIMIT` over an `ORDER BY` only needs to keep track of the first few rows rather than sort
+/// everything.
+pub struct TopN;
+
+impl OptimiserRule for TopN {
+    fn name(&self) -> &'static str {
+        "top_n"
+    }
+
+    fn rewrite(&self, plan: LogicalPlan) -> anyhow::Result<LogicalPlan> {
+        plan.transform_up(&mut |plan| {
+            let (limit, offset, with_ties, input) = match plan {
+                LogicalPlan::Limit {
+                    limit: Some(limit),
+                    offset,
+                    with_ties,
+                    input,
+                } => (limit, offset, with_ties, input),
+                plan => return Ok(plan),
+            };
+            let plan = match *input {
+                LogicalPlan::Sort { keys, input } => LogicalPlan::TopN {
+                    keys,
+                    limit,
+                    offset,
+                    with_ties,
+                    input,
+                },
+                // Projections don't change the number of rows so the limit can go under them,
+                // this happens when sorting on a column which isn't selected
+                LogicalPlan::Project {
+                    exprs,
+                    schema,
+                    input,
+                } if matches!(*input, LogicalPlan::Sort { .. }) => {
+                    let LogicalPlan::Sort { keys, input } = *input else {
+                        unreachable!()
+                    };
+                    LogicalPlan::Project {
+                        exprs,
+                        schema,
+                        input: Box::new(LogicalPlan::TopN {
+                            keys,
+                            limit,
+                            offset,
+                            with_ties,
+                            input,
+                        }),
+                    }
+                }
+                input => LogicalPlan::Limit {
+                    limit: Some(limit),
+                    offset,
+                    with_ties,
+                    input: Box::new(input),
+                },
+            };
+            Ok(plan)
+        })
+    }
+}
+
+/// Only reads the columns which are actually used from each table, and removes projections that
+/// don't do anything.
+pub struct ProjectionPruning;
+
+impl OptimiserRule for ProjectionPruning {
+    fn name(&self) -> &'static str {
+        "projection_pruning"
+    }
+
+    fn rewrite(&self, plan: LogicalPlan) -> anyhow::Result<LogicalPlan> {
+        prune(plan, None)
+    }
+}
+
+fn add_columns<'a>(required: &mut Vec<Column>, exprs: impl IntoIterator<Item = &'a ScalarExpr>) {
+    for expr in exprs {
+        for column in expr.columns() {
+            if !required.contains(column) {
+                required.push(column.clone());
+            }
+        }
+    }
+}
+
+/// `required` is the columns of the plan's output which are used, `None` meaning all of them.
+fn prune(plan: LogicalPlan, required: Option<Vec<Column>>) -> anyhow::Result<LogicalPlan> {
+    let plan = match plan {
+        LogicalPlan::Scan {
+            table,
+            alias,
+            mut schema,
+            projection,
+            filters,
+            lock,
+            primary_key,
+        } => {
+            let projection = match required {
+                Some(mut required) => {
+                    add_columns(&mut required, &filters);
+                    let before = schema.len();
+                    schema.fields.retain(|f| required.contains(&f.column()));
+                    if schema.len() < before {
+                        Some(schema.fields.iter().map(|f| f.name.clone()).collect())
+                    } else {
+                        projection
+                    }
+                }
+                None => projection,
+            };
+            LogicalPlan::Scan {
+                table,
+                alias,
+                schema,
+                projection,
+                filters,
+                lock,
+                primary_key,
+            }
+        }
+        LogicalPlan::Project {
+            mut exprs,
+            mut schema,
+            input,
+        } => {
+            if let Some(required) = required {
+                let keep = schema
+                    .fields
+                    .iter()
+                    .map(|f| required.contains(&f.column()))
+                    .collect::<Vec<_>>();
+                let mut keep_iter = keep.iter();
+                exprs.retain(|_| *keep_iter.next().unwrap());
+                let mut keep_iter = keep.iter();
+                schema.fields.retain(|_| *keep_iter.next().unwrap());
+            }
+            let mut input_required = vec![];
+            add_columns(&mut input_required, &exprs);
+            let input = prune(*input, Some(input_required))?;
+            let identity = input.schema() == &schema
+                && exprs
+                    .iter()
+                    .zip(&input.schema().fields)
+                    .all(|(e, f)| *e == ScalarExpr::Column(f.column()));
+            if identity {
+                input
+            } else {
+                LogicalPlan::Project {
+                    exprs,
+                    schema,
+                    input: Box::new(input),
+                }
+            }
+        }
+        LogicalPlan::Filter { predicate, input } => {
+            let required = required.map(|mut r| {
+                add_columns(&mut r, [&predicate]);
+                r
+            });
+            LogicalPlan::Filter {
+                predicate,
+                input: Box::new(prune(*input, required)?),
+            }
+        }
+        LogicalPlan::Sort { keys, input } => {
+            let required = required.map(|mut r| {
+                add_columns(&mut r, keys.iter().map(|k| &k.expr));
+                r
+            });
+            LogicalPlan::Sort {
+                keys,
+                input: Box::new(prune(*input, required)?),
+            }
+        }
+        LogicalPlan::TopN {
+            keys,
+            limit,
+            offset,
+            with_ties,
+            input,
+        } => {
+            let required = required.map(|mut r| {
+                add_columns(&mut r, keys.iter().map(|k| &k.expr));
+                r
+            });
+            LogicalPlan::TopN {
+                keys,
+                limit,
+                offset,
+                with_ties,
+                input: Box::new(prune(*input, required)?),
+            }
+        }
+        LogicalPlan::Limit {
+            limit,
+            offset,
+            with_ties,
+            input,
+        } => LogicalPlan::Limit {
+            limit,
+            offset,
+            with_ties,
+            input: Box::new(prune(*input, required)?),
+        },
+        LogicalPlan::LockRows {
+            tables,
+            recheck,
+            input,
+        } => {
+            let required = required.map(|mut r| {
+                add_columns(&mut r, &recheck);
+                for table in &tables {
+                    if !r.contains(&table.key()) {
+                        r.push(table.key());
+                    }
+                }
+                r
+            });
+            LogicalPlan::LockRows {
+                tables,
+                recheck,
+                input: Box::new(prune(*input, required)?),
+            }
+        }
+        LogicalPlan::Join {
+            left,
+            right,
+            kind,
+            condition,
+            schema,
+        } => {
+            let mut required =
+                required.unwrap_or_else(|| schema.fields.iter().map(|f| f.column()).collect());
+            add_columns(&mut required, condition.iter());
+            let split = |plan: &LogicalPlan| {
+                required
+                    .iter()
+                    .filter(|c| matches!(plan.schema().find(c), Ok(Some(_))))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+            let (l, r) = (split(&left), split(&right));
+            LogicalPlan::join(
+                prune(*left, Some(l))?,
+                prune(*right, Some(r))?,
+                kind,
+                condition,
+            )
+        }
+        LogicalPlan::Aggregate {
+            group_by,
+            aggregates,
+            schema,
+            input,
+        } => {
+            let mut input_required = vec![];
+            add_columns(&mut input_required, &group_by);
+            add_columns(
+                &mut input_required,
+                aggregates.iter().flat_map(|a| a.exprs()),
+            );
+            LogicalPlan::Aggregate {
+                group_by,
+                aggregates,
+                schema,
+                input: Box::new(prune(*input, Some(input_required))?),
+            }
+        }
+        LogicalPlan::Window {
+            windows,
+            schema,
+            input,
+        } => {
+            let mut input_required = match required {
+                Some(required) => required
+                    .into_iter()
+                    .filter(|c| matches!(input.schema().find(c), Ok(Some(_))))
+                    .collect(),
+                None => input.schema().fields.iter().map(|f| f.column()).collect(),
+            };
+            add_columns(&mut input_required, windows.iter().flat_map(|w| w.exprs()));
+            let window_fields = schema.fields[input.schema().len()..].to_vec();
+            let input = prune(*input, Some(input_required))?;
+            let mut fields = input.schema().fields.clone();
+            fields.extend(window_fields);
+            LogicalPlan::Window {
+                windows,
+                schema: Schema::new(fields),
+                input: Box::new(input),
+            }
+        }
+        LogicalPlan::Apply {
+            kind,
+            schema,
+            input,
+            subquery,
+        } => {
+            let result = schema.fields.last().unwrap().column();
+            let mut input_required = match required {
+                Some(required) => required.into_iter().filter(|c| *c != result).collect(),
+                None => input.schema().fields.iter().map(|f| f.column()).collect(),
+            };
+            add_columns(&mut input_required, kind_exprs(&kind));
+            for column in outer_columns(&subquery) {
+                if !input_required.contains(&column) {
+                    input_required.push(column);
+                }
+            }
+            // Only the first column of the subquery is looked at, if any
+            let subquery_required = match kind {
+                ApplyKind::Exists => vec![],
+                _ => vec![subquery.schema().fields[0].column()],
+            };
+            let input = prune(*input, Some(input_required))?;
+            let subquery = prune(*subquery, Some(subquery_required))?;
+            LogicalPlan::Apply {
+                kind,
+                schema,
+                input: Box::new(input),
+                subquery: Box::new(subquery),
+            }
+            .with_apply_schema()
+        }
+        LogicalPlan::With {
+            name,
+            id,
+            cte,
+            input,
+        } => LogicalPlan::With {
+            name,
+            id,
+            // The scans of it can use different columns
+            cte: Box::new(prune(*cte, None)?),
+            input: Box::new(prune(*input, required)?),
+        },
+        // Every iteration has to produce the same columns, and set operations compare whole rows
+        plan @ (LogicalPlan::RecursiveCte { .. } | LogicalPlan::SetOperation { .. }) => {
+            plan.map_inputs(|input| prune(input, None))?
+        }
+        plan @ (LogicalPlan::Values { .. } | LogicalPlan::CteScan { .. }) => plan,
+        // Modifications need the whole row
+        plan @ (LogicalPlan::Insert { .. }
+        | LogicalPlan::Update { .. }
+        | LogicalPlan::Delete { .. }) => plan.map_inputs(|input| prune(input, None))?,
+    };
+    Ok(plan)
+}
+
+fn kind_exprs(kind: &ApplyKind) -> Vec<&ScalarExpr> {
+    match kind {
+        ApplyKind::In(expr) => vec![expr],
+        ApplyKind::Scalar | ApplyKind::Exists => vec![],
+    }
+}
+
+/// The columns of the outer query a subquery refers to, which have to be kept around for it.
+fn outer_columns(plan: &LogicalPlan) -> Vec<Column> {
+    let mut columns = vec![];
+    for expr in plan.expressions() {
+        expr.walk(&mut |e| {
+            if let ScalarExpr::OuterColumn(c, _) = e {
+                if !columns.contains(c) {
+                    columns.push(c.clone());
+                }
+            }
+        });
+    }
+    let inputs = match plan {
+        LogicalPlan::Apply { input, .. } => vec![input.as_ref()],
+        plan => plan.inputs(),
+    };
+    for input in inputs {
+        for c in outer_columns(input) {
+            if !columns.contains(&c) {
+                columns.push(c);
+            }
+        }
+    }
+    columns
+}
+
+/// Looks rows up by their primary key when the filters on a scan give a value for every key
+/// column, the filters are kept so they're still checked against the row that's found.
+pub struct AccessPathSelection<'a> {
+    catalog: &'a dyn Catalog,
+}
+
+impl<'a> AccessPathSelection<'a> {
+    pub fn new(catalog: &'a dyn Catalog) -> Self {
+        Self { catalog }
+    }
+
+    fn key_lookup(
+        &self,
+        table: &str,
+        schema: &Schema,
+        filters: &[ScalarExpr],
+    ) -> Option<Vec<ScalarExpr>> {
+        let metadata = self.catalog.table_metadata(table).ok()?;
+        let key_columns = metadata
+            .iter()
+            .filter(|(_, desc)| desc.primary_key)
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        if key_columns.is_empty() {
+            return None;
+        }
+        let is_key = |e: &ScalarExpr, key: &str| match e {
+            ScalarExpr::Column(c) => {
+                matches!(schema.find(c), Ok(Some(i)) if schema.fields[i].name == key)
+            }
+            _ => false,
+        };
+        let is_constant = |e: &ScalarExpr| e.columns().is_empty() && !e.contains_aggregate();
+        key_columns
+            .into_iter()
+            .map(|key| {
+                filters.iter().find_map(|f| match f {
+                    ScalarExpr::BinaryOp {
+                        left,
+                        op: BinaryOperator::Eq,
+                        right,
+                    } => {
+                        if is_key(left, key) && is_constant(right) {
+                            Some(right.as_ref().clone())
+                        } else if is_key(right, key) && is_constant(left) {
+                            Some(left.as_ref().clone())
+                        } else {
+                            None
+                        }
+                    }
+                    _ => None,
+                })
+            })
+            .collect()
+    }
+}
+
+impl OptimiserRule for AccessPathSelection<'_> {
+    fn name(&self) -> &'static str {
+        "access_path_selection"
+    }
+
+    fn rewrite(&self, plan: LogicalPlan) -> anyhow::Result<LogicalPlan> {
+        let model = CostModel::new(self.catalog);
+        plan.transform_up(&mut |plan| {
+            let LogicalPlan::Scan {
+                table,
+                schema,
+                filters,
+                primary_key: None,
+                ..
+            } = &plan
+            else {
+                return Ok(plan);
+            };
+            let Some(key) = self.key_lookup(table, schema, filters) else {
+                return Ok(plan);
+            };
+            let mut lookup = plan.clone();
+            if let LogicalPlan::Scan { primary_key, .. } = &mut lookup {
+                *primary_key = Some(key);
+            }
+            if model.estimate(&lookup).cost < model.estimate(&plan).cost {
+                Ok(lookup)
+            } else {
+                Ok(plan)
+            }
+        })
+    }
+}
+
+/// Swaps the inputs of a join when the other way round is cheaper, mostly so hash joins build
+/// their table from the smaller side. A projection puts the columns back in the original order.
+pub struct JoinCommute<'a> {
+    catalog: &'a dyn Catalog,
+}
+
+impl<'a> JoinCommute<'a> {
+    pub fn new(catalog: &'a dyn Catalog) -> Self {
+        Self { catalog }
+    }
+}
+
+impl OptimiserRule for JoinCommute<'_> {
+    fn name(&self) -> &'static str {
+        "join_commute"
+    }
+
+    fn rewrite(&self, plan: LogicalPlan) -> anyhow::Result<LogicalPlan> {
+        let model = CostModel::new(self.catalog);
+        plan.transform_up(&mut |plan| {
+            let LogicalPlan::Join {
+                left,
+                right,
+                kind,
+                condition,
+                schema,
+            } = &plan
+            else {
+                return Ok(plan);
+            };
+            let kind = match kind {
+                JoinKind::Inner | JoinKind::Cross | JoinKind::Full => *kind,
+                JoinKind::Left => JoinKind::Right,
+                JoinKind::Right => JoinKind::Left,
+                // Only the left side comes out of these so they can't be swapped
+                JoinKind::Semi | JoinKind::Anti => return Ok(plan),
+            };
+            let swapped = LogicalPlan::join(
+                right.as_ref().clone(),
+                left.as_ref().clone(),
+                kind,
+                condition.clone(),
+            );
+            // Only worth it if it's cheaper and we can still tell all the columns apart
+            let exprs = schema
+                .fields
+                .iter()
+                .map(|f| ScalarExpr::Column(f.column()))
+                .collect::<Vec<_>>();
+            let resolvable = exprs
+                .iter()
+                .flat_map(|e| e.columns())
+                .all(|c| matches!(swapped.schema().find(c), Ok(Some(_))));
+            if !resolvable || model.estimate(&swapped).cost >= model.estimate(&plan).cost {
+                return Ok(plan);
+            }
+            Ok(LogicalPlan::Project {
+                exprs,
+                schema: schema.clone(),
+                input: Box::new(swapped),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logical_plan::tests::{assert_plan_eq, plan, test_catalog};
+    use tracing_test::traced_test;
+
+    fn apply(rule: impl OptimiserRule, sql: &str) -> LogicalPlan {
+        rule.rewrite(plan(sql).unwrap()).unwrap()
+    }
+
+    #[test]
+    #[traced_test]
+    fn constant_folding() {
+        assert_plan_eq(
+            &apply(
+                ConstantFolding,
+                "SELECT 1 + 2 * 3 AS x, name FROM users WHERE age > 10 + 5 AND 1 = 1",
+            ),
+            "
+Projection: 7 AS x, users.name
+  Filter: users.age > 15
+    Scan: users",
+        );
+        assert_plan_eq(
+            &apply(
+                ConstantFolding,
+                "SELECT name FROM users WHERE NULL OR 1 > 2",
+            ),
+            "
+Projection: users.name
+  Empty",
+        );
+        assert_plan_eq(
+            &apply(ConstantFolding, "SELECT NOT NULL IS NULL, -(3 - 4)"),
+            "
+Projection: false AS NOT NULL IS NULL, 1 AS -(3 - 4)
+  Values: ()",
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn filter_merge() {
+        let merged = FilterMerge
+            .rewrite(LogicalPlan::Filter {
+                predicate: ScalarExpr::binary(
+                    ScalarExpr::column(Some("users"), "name"),
+                    BinaryOperator::Eq,
+                    ScalarExpr::literal("a"),
+                ),
+                input: Box::new(LogicalPlan::Filter {
+                    predicate: ScalarExpr::binary(
+                        ScalarExpr::column(Some("users"), "age"),
+                        BinaryOperator::Gt,
+                        ScalarExpr::literal(1),
+                    ),
+                    input: Box::new(plan("SELECT * FROM users").unwrap()),
+                }),
+            })
+            .unwrap();
+        assert_plan_eq(
+            &merged,
+            "
+Filter: users.age > 1 AND users.name = 'a'
+  Projection: users.age, users.id, users.name
+    Scan: users",
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn predicate_pushdown() {
+        assert_plan_eq(
+            &apply(
+                PredicatePushdown,
+                "SELECT u.name FROM users u JOIN orders o ON u.id = o.user_id AND o.total > 10 WHERE u.age > 3",
+            ),
+            "
+Projection: u.name
+  Join: Inner ON u.id = o.user_id
+    Scan: users AS u filters=[u.age > 3]
+    Scan: orders AS o filters=[o.total > 10]",
+        );
+        // The null extended side of an outer join can't be filtered from above
+        assert_plan_eq(
+            &apply(
+                PredicatePushdown,
+                "SELECT u.name FROM users u LEFT JOIN orders o ON u.id = o.user_id WHERE o.total > 10 AND u.age > 3",
+            ),
+            "
+Projection: u.name
+  Filter: o.total > 10
+    Join: Left ON u.id = o.user_id
+      Scan: users AS u filters=[u.age > 3]
+      Scan: orders AS o",
+        );
+        // Through projections and onto grouping columns but not aggregates
+        assert_plan_eq(
+            &apply(
+                PredicatePushdown,
+                "SELECT * FROM (SELECT name AS n, count(*) AS c FROM users GROUP BY name) t WHERE n = 'a' AND c > 1",
+            ),
+            "
+Projection: t.n, t.c
+  Projection: n AS n, c AS c
+    Projection: users.name AS n, count(*) AS c
+      Filter: count(*) > 1
+        Aggregate: groupBy=[users.name], aggr=[count(*)]
+          Scan: users filters=[users.name = 'a']",
+        );
+        // Only predicates on columns every window partitions by go below windows
+        assert_plan_eq(
+            &apply(
+                PredicatePushdown,
+                "SELECT * FROM (SELECT user_id, rank() OVER (PARTITION BY user_id ORDER BY total) AS r FROM orders) t WHERE user_id = 1 AND r = 1",
+            ),
+            "
+Projection: t.user_id, t.r
+  Projection: orders.user_id AS user_id, r AS r
+    Projection: orders.user_id, rank() OVER (PARTITION BY orders.user_id ORDER BY orders.total) AS r
+      Filter: rank() OVER (PARTITION BY orders.user_id ORDER BY orders.total) = 1
+        Window: rank() OVER (PARTITION BY orders.user_id ORDER BY orders.total)
+          Scan: orders filters=[orders.user_id = 1]",
+        );
+        // Cross joins with a condition become inner joins
+        assert_plan_eq(
+            &apply(
+                PredicatePushdown,
+                "SELECT u.name FROM users u, orders o WHERE u.id = o.user_id",
+            ),
+            "
+Projection: u.name
+  Join: Inner ON u.id = o.user_id
+    Scan: users AS u
+    Scan: orders AS o",
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn redundant_sorts() {
+        assert_plan_eq(
+            &apply(
+                RedundantSortElimination,
+                "SELECT count(*) FROM (SELECT * FROM users ORDER BY name) u",
+            ),
+            "
+Projection: count(*)
+  Aggregate: groupBy=[], aggr=[count(*)]
+    Projection: users.age AS age, users.id AS id, users.name AS name
+      Projection: users.age, users.id, users.name
+        Scan: users",
+        );
+        assert_plan_eq(
+            &apply(
+                RedundantSortElimination,
+                "SELECT name FROM (SELECT * FROM users ORDER BY age) u ORDER BY name, name",
+            ),
+            "
+Sort: u.name
+  Projection: u.name
+    Projection: users.age AS age, users.id AS id, users.name AS name
+      Projection: users.age, users.id, users.name
+        Scan: users",
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn top_n() {
+        assert_plan_eq(
+            &apply(
+                TopN,
+                "SELECT name FROM users ORDER BY name LIMIT 3 OFFSET 1",
+            ),
+            "
+TopN: users.name limit=3, offset=1
+  Projection: users.name
+    Scan: users",
+        );
+        assert_plan_eq(
+            &apply(TopN, "SELECT name FROM users ORDER BY age DESC LIMIT 3"),
+            "
+Projection: users.name
+  TopN: __sort_1 DESC limit=3, offset=0
+    Projection: users.name, users.age AS __sort_1
+      Scan: users",
+        );
+        assert_plan_eq(
+            &apply(
+                TopN,
+                "SELECT name FROM users ORDER BY age FETCH FIRST 3 ROWS WITH TIES",
+            ),
+            "
+Projection: users.name
+  TopN: __sort_1 limit=3, offset=0, with ties
+    Projection: users.name, users.age AS __sort_1
+      Scan: users",
+        );
+        assert_plan_eq(
+            &apply(TopN, "SELECT name FROM users LIMIT 3"),
+            "
+Limit: limit=3, offset=0
+  Projection: users.name
+    Scan: users",
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn projection_pruning() {
+        assert_plan_eq(
+            &apply(ProjectionPruning, "SELECT * FROM users"),
+            "Scan: users",
+        );
+        assert_plan_eq(
+            &apply(
+                ProjectionPruning,
+                "SELECT u.name FROM users u JOIN orders o ON u.id = o.user_id",
+            ),
+            "
+Projection: u.name
+  Join: Inner ON u.id = o.user_id
+    Scan: users AS u projection=[id, name]
+    Scan: orders AS o projection=[user_id]",
+        );
+        assert_plan_eq(
+            &apply(
+                ProjectionPruning,
+                "SELECT name FROM (SELECT * FROM users) u",
+            ),
+            "
+Projection: users.name AS name
+  Scan: users projection=[name]",
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn access_paths() {
+        let catalog = test_catalog();
+        let optimise = |sql| {
+            Optimiser::with_rules(vec![
+                Box::new(PredicatePushdown),
+                Box::new(AccessPathSelection::new(&catalog)),
+            ])
+            .optimise(plan(sql).unwrap())
+            .unwrap()
+        };
+        assert_plan_eq(
+            &optimise("SELECT name FROM users WHERE 1 + 2 = id AND age > 3"),
+            "
+Projection: users.name
+  Scan: users filters=[1 + 2 = users.id, users.age > 3] key=[1 + 2]",
+        );
+        // Not the whole key or not a constant
+        assert_plan_eq(
+            &optimise("SELECT name FROM users WHERE id > 3 OR id = 1"),
+            "
+Projection: users.name
+  Scan: users filters=[users.id > 3 OR users.id = 1]",
+        );
+        assert_plan_eq(
+            &optimise("SELECT * FROM users u JOIN orders o ON u.id = o.user_id WHERE o.id = 4"),
+            "
+Projection: u.age, u.id, u.name, o.id, o.total, o.user_id
+  Join: Inner ON u.id = o.user_id
+    Scan: users AS u
+    Scan: orders AS o filters=[o.id = 4] key=[4]",
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn join_commute() {
+        let catalog = test_catalog();
+        let optimiser = Optimiser::with_rules(vec![
+            Box::new(PredicatePushdown),
+            Box::new(JoinCommute::new(&catalog)),
+        ]);
+        // The filtered side is smaller so that should be the one we build the hash table from
+        assert_plan_eq(
+            &optimiser
+                .optimise(
+                    plan("SELECT * FROM users u JOIN orders o ON u.id = o.user_id WHERE u.age = 3")
+                        .unwrap(),
+                )
+                .unwrap(),
+            "
+Projection: u.age, u.id, u.name, o.id, o.total, o.user_id
+  Projection: u.age, u.id, u.name, o.id, o.total, o.user_id
+    Join: Inner ON u.id = o.user_id
+      Scan: orders AS o
+      Scan: users AS u filters=[u.age = 3]",
+        );
+        // Unless the few rows there are can look up the other side by its primary key
+        assert_plan_eq(
+            &optimiser
+                .optimise(
+                    plan("SELECT * FROM orders o LEFT JOIN users u ON u.id = o.user_id WHERE o.total = 1")
+                        .unwrap(),
+                )
+                .unwrap(),
+            "
+Projection: o.id, o.total, o.user_id, u.age, u.id, u.name
+  Join: Left ON u.id = o.user_id
+    Scan: orders AS o filters=[o.total = 1]
+    Scan: users AS u",
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn subquery_decorrelation() {
+        assert_plan_eq(
+            &apply(
+                SubqueryDecorrelation,
+                "SELECT name FROM users u WHERE EXISTS (SELECT 1 FROM orders o WHERE o.user_id = u.id AND o.total > 5)",
+            ),
+            "
+Projection: u.name
+  Join: Semi ON o.user_id = u.id
+    Scan: users AS u
+    Projection: 1 AS 1, o.user_id
+      Filter: o.total > 5
+        Scan: orders AS o",
+        );
+        assert_plan_eq(
+            &apply(
+                SubqueryDecorrelation,
+                "SELECT name FROM users WHERE id NOT IN (SELECT user_id FROM orders)",
+            ),
+            "
+Projection: users.name
+  Join: Anti ON users.id = orders.user_id
+    Scan: users
+    Projection: orders.user_id
+      Scan: orders",
+        );
+        assert_plan_eq(
+            &apply(
+                SubqueryDecorrelation,
+                "SELECT name FROM users WHERE age NOT IN (SELECT total FROM orders)",
+            ),
+            "
+Projection: users.name
+  Join: Anti ON users.age = orders.total OR users.age IS NULL OR orders.total IS NULL
+    Scan: users
+    Projection: orders.total
+      Scan: orders",
+        );
+        // Correlated predicates under an aggregate can't be moved so this has to be run per row
+        assert_plan_eq(
+            &apply(
+                SubqueryDecorrelation,
+                "SELECT name, (SELECT count(*) FROM orders o WHERE o.user_id = u.id) FROM users u WHERE u.age > 3",
+            ),
+            "
+Projection: u.name, __subquery_3 AS (<subquery>)
+  Apply: Scalar AS __subquery_3
+    Filter: u.age > 3
+      Scan: users AS u
+    Projection: count(*)
+      Aggregate: groupBy=[], aggr=[count(*)]
+        Filter: o.user_id = outer(u.id)
+          Scan: orders AS o",
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn all_rules() {
+        let catalog = test_catalog();
+        let plan = Optimiser::new(&catalog)
+            .optimise(
+                plan("SELECT u.name FROM users u, orders o WHERE u.id = o.user_id AND o.total > 1 + 1 ORDER BY u.age LIMIT 5")
+                    .unwrap(),
+            )
+            .unwrap();
+        assert_plan_eq(
+            &plan,
+            "
+Projection: u.name
+  TopN: __sort_1 limit=5, offset=0
+    Projection: u.name, u.age AS __sort_1
+      Join: Inner ON u.id = o.user_id
+        Scan: users AS u
+        Scan: orders AS o projection=[total, user_id] filters=[o.total > 2]",
+        );
+    }
+}
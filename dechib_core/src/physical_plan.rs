@@ -0,0 +1,892 @@
+//! The physical plan is the optimised logical plan with each node turned into the operator which
+//! will actually run it, so a join becomes a hash, merge or nested loop join depending on its
+//! condition and how big the inputs are. Every node carries the cost model's estimates so
+//! `EXPLAIN` can show them.
+use crate::cost::{is_equi_join, CostModel, Estimate, JoinMethod};
+use crate::expression::{AggregateExpr, ScalarExpr, WindowExpr};
+use crate::logical_plan::{
+    list, ApplyKind, JoinKind, LockedTable, LogicalPlan, OnConflict, Schema, SetOperator, SortKey,
+};
+use crate::types::RowLocking;
+use crate::types::Value;
+use sqlparser::ast::BinaryOperator;
+use std::fmt::{self, Write};
+use std::time::Duration;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operator {
+    TableScan {
+        table: String,
+        alias: Option<String>,
+        projection: Option<Vec<String>>,
+        filters: Vec<ScalarExpr>,
+        lock: Option<RowLocking>,
+    },
+    /// Looks the row up by its primary key rather than reading the whole table
+    IndexScan {
+        table: String,
+        alias: Option<String>,
+        projection: Option<Vec<String>>,
+        key: Vec<ScalarExpr>,
+        filters: Vec<ScalarExpr>,
+        lock: Option<RowLocking>,
+    },
+    Filter {
+        predicate: ScalarExpr,
+    },
+    Projection {
+        exprs: Vec<ScalarExpr>,
+    },
+    NestedLoopJoin {
+        kind: JoinKind,
+        condition: Option<ScalarExpr>,
+    },
+    /// Builds a hash table from the right input and probes it with the left. Each key is a pair
+    /// of expressions over the left and right inputs which have to be equal, anything else in the
+    /// join condition is checked afterwards by `filter`.
+    HashJoin {
+        kind: JoinKind,
+        keys: Vec<(ScalarExpr, ScalarExpr)>,
+        filter: Option<ScalarExpr>,
+    },
+    /// Sorts both inputs on the keys and walks through them together, used instead of a hash
+    /// join when the hash table would be too big.
+    MergeJoin {
+        kind: JoinKind,
+        keys: Vec<(ScalarExpr, ScalarExpr)>,
+        filter: Option<ScalarExpr>,
+    },
+    /// Looks up the row on the right by its primary key for each row on the left, so it only has
+    /// the left input. `key` is over the left input's columns, `filters` are the right table's
+    /// own and `filter` is the rest of the join condition.
+    IndexJoin {
+        kind: JoinKind,
+        table: String,
+        alias: Option<String>,
+        projection: Option<Vec<String>>,
+        key: Vec<ScalarExpr>,
+        filters: Vec<ScalarExpr>,
+        lock: Option<RowLocking>,
+        filter: Option<ScalarExpr>,
+        /// The columns read from the right table
+        schema: Schema,
+    },
+    HashAggregate {
+        group_by: Vec<ScalarExpr>,
+        aggregates: Vec<AggregateExpr>,
+    },
+    /// Sorts the input by each window's partition and order keys, adding a column per window
+    /// to the rows in the order they came in
+    Window {
+        windows: Vec<WindowExpr>,
+    },
+    Sort {
+        keys: Vec<SortKey>,
+    },
+    TopN {
+        keys: Vec<SortKey>,
+        limit: u64,
+        offset: u64,
+        with_ties: bool,
+    },
+    /// Locks the rows each row came from as it's asked for, reading them again once locked
+    LockRows {
+        tables: Vec<LockedTable>,
+        recheck: Vec<ScalarExpr>,
+    },
+    Limit {
+        limit: Option<u64>,
+        offset: u64,
+    },
+    Values {
+        rows: Vec<Vec<ScalarExpr>>,
+    },
+    /// Runs the second input for every row of the first, adding a column with the result. Any
+    /// references to the outer row in the subquery are filled in with the row's values first.
+    Apply {
+        kind: ApplyKind,
+    },
+    /// Reads the rows of a CTE or a recursive query's work table
+    CteScan {
+        name: String,
+        id: usize,
+    },
+    /// Works out the first input and saves its rows for the `CteScan`s in the second
+    With {
+        name: String,
+        id: usize,
+    },
+    /// Runs the second input over and over on the rows it produced last time, starting with the
+    /// rows from the first
+    RecursiveCte {
+        name: String,
+        id: usize,
+        distinct: bool,
+    },
+    /// Reads all of the second input into a hash table, then goes through the first. `UNION`
+    /// just passes both inputs through.
+    SetOperation {
+        op: SetOperator,
+        all: bool,
+    },
+    Insert {
+        table: String,
+        columns: Vec<String>,
+        on_conflict: Option<OnConflict>,
+    },
+    Update {
+        table: String,
+        assignments: Vec<(String, ScalarExpr)>,
+    },
+    Delete {
+        table: String,
+    },
+}
+
+/// What actually happened when the operator was run, for `EXPLAIN ANALYZE`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Metrics {
+    /// Rows produced over all the loops
+    pub rows: u64,
+    /// How many times the operator was started, the inner side of a nested loop join is started
+    /// once per row on the outer side.
+    pub loops: u64,
+    /// Time spent in the operator including its inputs
+    pub elapsed: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhysicalPlan {
+    pub operator: Operator,
+    pub schema: Schema,
+    pub estimate: Estimate,
+    pub inputs: Vec<PhysicalPlan>,
+    /// Filled in when the plan has been run by `EXPLAIN ANALYZE`
+    pub metrics: Option<Metrics>,
+}
+
+impl PhysicalPlan {
+    pub fn new(plan: &LogicalPlan, model: &CostModel) -> anyhow::Result<Self> {
+        let mut inputs = plan
+            .inputs()
+            .into_iter()
+            .map(|input| Self::new(input, model))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let operator = match plan.clone() {
+            LogicalPlan::Scan {
+                table,
+                alias,
+                projection,
+                filters,
+                lock,
+                primary_key,
+                ..
+            } => match primary_key {
+                Some(key) => Operator::IndexScan {
+                    table,
+                    alias,
+                    projection,
+                    key,
+                    filters,
+                    lock,
+                },
+                None => Operator::TableScan {
+                    table,
+                    alias,
+                    projection,
+                    filters,
+                    lock,
+                },
+            },
+            LogicalPlan::Filter { predicate, .. } => Operator::Filter { predicate },
+            LogicalPlan::Project { exprs, .. } => Operator::Projection { exprs },
+            LogicalPlan::Join {
+                left,
+                right,
+                kind,
+                condition,
+                ..
+            } => {
+                let equi = condition
+                    .as_ref()
+                    .is_some_and(|c| is_equi_join(c, &left, &right));
+                let rows = model.estimate(plan).rows;
+                let (l, r) = (model.estimate(&left), model.estimate(&right));
+                let (method, cost) = model.join_method(l, r, rows, equi);
+                let lookup = model.index_lookup(&left, &right, kind, condition.as_ref());
+                match (lookup, right.as_ref()) {
+                    (
+                        Some((key, filter)),
+                        LogicalPlan::Scan {
+                            table,
+                            alias,
+                            schema,
+                            projection,
+                            filters,
+                            lock,
+                            ..
+                        },
+                    ) if model.index_join_cost(l, rows) <= cost => {
+                        // The right side is looked up by the join itself
+                        inputs.truncate(1);
+                        Operator::IndexJoin {
+                            kind,
+                            table: table.clone(),
+                            alias: alias.clone(),
+                            projection: projection.clone(),
+                            key,
+                            filters: filters.clone(),
+                            lock: lock.clone(),
+                            filter,
+                            schema: schema.clone(),
+                        }
+                    }
+                    _ => match (method, condition) {
+                        (JoinMethod::Hash, Some(condition)) => {
+                            let (keys, filter) = hash_keys(condition, &left, &right);
+                            Operator::HashJoin { kind, keys, filter }
+                        }
+                        (JoinMethod::Merge, Some(condition)) => {
+                            let (keys, filter) = hash_keys(condition, &left, &right);
+                            Operator::MergeJoin { kind, keys, filter }
+                        }
+                        (_, condition) => Operator::NestedLoopJoin { kind, condition },
+                    },
+                }
+            }
+            LogicalPlan::Aggregate {
+                group_by,
+                aggregates,
+                ..
+            } => Operator::HashAggregate {
+                group_by,
+                aggregates,
+            },
+            LogicalPlan::Window { windows, .. } => Operator::Window { windows },
+            LogicalPlan::Sort { keys, .. } => Operator::Sort { keys },
+            LogicalPlan::TopN {
+                keys,
+                limit,
+                offset,
+                with_ties,
+                ..
+            } => Operator::TopN {
+                keys,
+                limit,
+                offset,
+                with_ties,
+            },
+            LogicalPlan::LockRows {
+                tables, recheck, ..
+            } => Operator::LockRows { tables, recheck },
+            LogicalPlan::Limit {
+                with_ties: true, ..
+            } => anyhow::bail!("WITH TIES can only be used on sorted rows"),
+            LogicalPlan::Limit { limit, offset, .. } => Operator::Limit { limit, offset },
+            LogicalPlan::Values { rows, .. } => Operator::Values { rows },
+            LogicalPlan::Apply { kind, .. } => Operator::Apply { kind },
+            LogicalPlan::CteScan { name, id, .. } => Operator::CteScan { name, id },
+            LogicalPlan::With { name, id, .. } => Operator::With { name, id },
+            LogicalPlan::RecursiveCte {
+                name, id, distinct, ..
+            } => Operator::RecursiveCte { name, id, distinct },
+            LogicalPlan::SetOperation { op, all, .. } => Operator::SetOperation { op, all },
+            LogicalPlan::Insert {
+                table,
+                columns,
+                on_conflict,
+                ..
+            } => Operator::Insert {
+                table,
+                columns,
+                on_conflict,
+            },
+            LogicalPlan::Update {
+                table, assignments, ..
+            } => Operator::Update { table, assignments },
+            LogicalPlan::Delete { table, .. } => Operator::Delete { table },
+        };
+        Ok(Self {
+            operator,
+            schema: plan.schema().clone(),
+            estimate: model.estimate(plan),
+            inputs,
+            metrics: None,
+        })
+    }
+
+    /// Fills in the values of the outer query's columns, which are in `schema`, from `row`. The
+    /// subqueries of any `Apply`s are left alone as they refer to the `Apply`'s input instead.
+    pub fn bind_outer(&self, schema: &Schema, row: &[Value]) -> anyhow::Result<PhysicalPlan> {
+        let bind = |e: ScalarExpr| {
+            e.transform_down(&mut |e| match e {
+                ScalarExpr::OuterColumn(c, _) => match schema.find(c)? {
+                    Some(i) => Ok(Some(ScalarExpr::Literal(row[i].clone()))),
+                    None => anyhow::bail!("Couldn't find column {} of the outer query", c),
+                },
+                _ => Ok(None),
+            })
+        };
+        let inputs = match self.operator {
+            Operator::Apply { .. } => vec![
+                self.inputs[0].bind_outer(schema, row)?,
+                self.inputs[1].clone(),
+            ],
+            _ => self
+                .inputs
+                .iter()
+                .map(|input| input.bind_outer(schema, row))
+                .collect::<anyhow::Result<_>>()?,
+        };
+        Ok(PhysicalPlan {
+            operator: self.operator.clone().map_expressions(bind)?,
+            schema: self.schema.clone(),
+            estimate: self.estimate,
+            inputs,
+            metrics: None,
+        })
+    }
+
+    /// Whether anything in the plan locks rows for `FOR UPDATE` or `FOR SHARE`
+    pub fn locks_rows(&self) -> bool {
+        matches!(self.operator, Operator::LockRows { .. })
+            || self.inputs.iter().any(|x| x.locks_rows())
+    }
+
+    /// Whether the plan refers to the columns of an outer query, see `LogicalPlan::is_correlated`
+    pub fn is_correlated(&self) -> bool {
+        let mut found = false;
+        let _ = self.operator.clone().map_expressions(|e| {
+            found |= e.is_correlated();
+            Ok(e)
+        });
+        let inputs = match self.operator {
+            Operator::Apply { .. } => &self.inputs[..1],
+            _ => &self.inputs[..],
+        };
+        found || inputs.iter().any(|x| x.is_correlated())
+    }
+
+    fn fmt_indented(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        write!(
+            f,
+            "{:width$}{} (rows={:.0} cost={:.2})",
+            "",
+            self.operator,
+            self.estimate.rows,
+            self.estimate.cost,
+            width = depth * 2
+        )?;
+        if let Some(metrics) = &self.metrics {
+            write!(
+                f,
+                " (actual rows={} loops={} time={:.3}ms)",
+                metrics.rows,
+                metrics.loops,
+                metrics.elapsed.as_secs_f64() * 1000.0
+            )?;
+        }
+        writeln!(f)?;
+        for input in &self.inputs {
+            input.fmt_indented(f, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    /// The plan as a JSON object, for tools which want to do something with the plan.
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out);
+        out
+    }
+
+    fn write_json(&self, out: &mut String) {
+        let (name, details) = match self.operator.to_string().split_once(": ") {
+            Some((name, details)) => (name.to_string(), details.to_string()),
+            None => (self.operator.to_string(), String::new()),
+        };
+        // Writing to a string can't fail
+        let _ = write!(
+            out,
+            "{{\"operator\": {}, \"details\": {}, \"estimated_rows\": {:.0}, \"estimated_cost\": {:.2}",
+            json_string(&name),
+            json_string(&details),
+            self.estimate.rows,
+            self.estimate.cost
+        );
+        if let Some(metrics) = &self.metrics {
+            let _ = write!(
+                out,
+                ", \"actual_rows\": {}, \"loops\": {}, \"time_ms\": {:.3}",
+                metrics.rows,
+                metrics.loops,
+                metrics.elapsed.as_secs_f64() * 1000.0
+            );
+        }
+        out.push_str(", \"inputs\": [");
+        for (i, input) in self.inputs.iter().enumerate() {
+            if i > 0 {
+                out.push_str(", ");
+            }
+            input.write_json(out);
+        }
+        out.push_str("]}");
+    }
+}
+
+impl Operator {
+    /// Replaces every expression in the operator with the result of `f`.
+    pub fn map_expressions(
+        self,
+        mut f: impl FnMut(ScalarExpr) -> anyhow::Result<ScalarExpr>,
+    ) -> anyhow::Result<Operator> {
+        let mut all = |exprs: Vec<ScalarExpr>| {
+            exprs
+                .into_iter()
+                .map(&mut f)
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+        let hash = matches!(self, Self::HashJoin { .. });
+        let operator = match self {
+            Self::TableScan {
+                table,
+                alias,
+                projection,
+                filters,
+                lock,
+            } => Self::TableScan {
+                table,
+                alias,
+                projection,
+                filters: all(filters)?,
+                lock,
+            },
+            Self::IndexScan {
+                table,
+                alias,
+                projection,
+                key,
+                filters,
+                lock,
+            } => Self::IndexScan {
+                table,
+                alias,
+                projection,
+                key: all(key)?,
+                filters: all(filters)?,
+                lock,
+            },
+            Self::Filter { predicate } => Self::Filter {
+                predicate: all(vec![predicate])?.remove(0),
+            },
+            Self::Projection { exprs } => Self::Projection { exprs: all(exprs)? },
+            Self::NestedLoopJoin { kind, condition } => Self::NestedLoopJoin {
+                kind,
+                condition: all(condition.into_iter().collect())?.pop(),
+            },
+            Self::HashJoin { kind, keys, filter } | Self::MergeJoin { kind, keys, filter } => {
+                let (left, right): (Vec<_>, Vec<_>) = keys.into_iter().unzip();
+                let keys = all(left)?.into_iter().zip(all(right)?).collect();
+                let filter = all(filter.into_iter().collect())?.pop();
+                if hash {
+                    Self::HashJoin { kind, keys, filter }
+                } else {
+                    Self::MergeJoin { kind, keys, filter }
+                }
+            }
+            Self::IndexJoin {
+                kind,
+                table,
+                alias,
+                projection,
+                key,
+                filters,
+                lock,
+                filter,
+                schema,
+            } => Self::IndexJoin {
+                kind,
+                table,
+                alias,
+                projection,
+                key: all(key)?,
+                filters: all(filters)?,
+                lock,
+                filter: all(filter.into_iter().collect())?.pop(),
+                schema,
+            },
+            Self::HashAggregate {
+                group_by,
+                aggregates,
+            } => Self::HashAggregate {
+                group_by: all(group_by)?,
+                aggregates: aggregates
+                    .into_iter()
+                    .map(|agg| {
+                        Ok(AggregateExpr {
+                            args: all(agg.args)?,
+                            order_by: sort_keys(agg.order_by, &mut all)?,
+                            ..agg
+                        })
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+            },
+            Self::Window { windows } => Self::Window {
+                windows: windows
+                    .into_iter()
+                    .map(|w| {
+                        Ok(WindowExpr {
+                            args: all(w.args)?,
+                            partition_by: all(w.partition_by)?,
+                            order_by: sort_keys(w.order_by, &mut all)?,
+                            ..w
+                        })
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+            },
+            Self::Sort { keys } => Self::Sort {
+                keys: sort_keys(keys, &mut all)?,
+            },
+            Self::TopN {
+                keys,
+                limit,
+                offset,
+                with_ties,
+            } => Self::TopN {
+                keys: sort_keys(keys, &mut all)?,
+                limit,
+                offset,
+                with_ties,
+            },
+            Self::LockRows { tables, recheck } => Self::LockRows {
+                tables,
+                recheck: all(recheck)?,
+            },
+            Self::Values { rows } => Self::Values {
+                rows: rows
+                    .into_iter()
+                    .map(&mut all)
+                    .collect::<anyhow::Result<_>>()?,
+            },
+            Self::Apply {
+                kind: ApplyKind::In(expr),
+            } => Self::Apply {
+                kind: ApplyKind::In(all(vec![expr])?.remove(0)),
+            },
+            Self::Update { table, assignments } => {
+                let (columns, exprs): (Vec<_>, Vec<_>) = assignments.into_iter().unzip();
+                Self::Update {
+                    table,
+                    assignments: columns.into_iter().zip(all(exprs)?).collect(),
+                }
+            }
+            operator @ (Self::Limit { .. }
+            | Self::Apply { .. }
+            | Self::CteScan { .. }
+            | Self::With { .. }
+            | Self::RecursiveCte { .. }
+            | Self::SetOperation { .. }
+            | Self::Insert { .. }
+            | Self::Delete { .. }) => operator,
+        };
+        Ok(operator)
+    }
+}
+
+fn sort_keys(
+    keys: Vec<SortKey>,
+    all: &mut impl FnMut(Vec<ScalarExpr>) -> anyhow::Result<Vec<ScalarExpr>>,
+) -> anyhow::Result<Vec<SortKey>> {
+    let (exprs, keys): (Vec<_>, Vec<_>) = keys.into_iter().map(|k| (k.expr.clone(), k)).unzip();
+    Ok(all(exprs)?
+        .into_iter()
+        .zip(keys)
+        .map(|(expr, key)| SortKey { expr, ..key })
+        .collect())
+}
+
+/// Prints the plan as an indented tree with the estimates for each node.
+impl fmt::Display for PhysicalPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scan = |f: &mut fmt::Formatter,
+                    table: &str,
+                    alias: &Option<String>,
+                    projection: &Option<Vec<String>>,
+                    filters: &[ScalarExpr],
+                    lock: &Option<RowLocking>| {
+            write!(f, "{}", table)?;
+            if let Some(alias) = alias {
+                write!(f, " AS {}", alias)?;
+            }
+            if let Some(projection) = projection {
+                write!(f, " projection=[{}]", projection.join(", "))?;
+            }
+            if !filters.is_empty() {
+                write!(f, " filters=[{}]", list(filters))?;
+            }
+            if let Some(lock) = lock {
+                write!(f, " {}", lock)?;
+            }
+            Ok(())
+        };
+        match self {
+            Self::TableScan {
+                table,
+                alias,
+                projection,
+                filters,
+                lock,
+            } => {
+                write!(f, "TableScan: ")?;
+                scan(f, table, alias, projection, filters, lock)
+            }
+            Self::IndexScan {
+                table,
+                alias,
+                projection,
+                key,
+                filters,
+                lock,
+            } => {
+                write!(f, "IndexScan: ")?;
+                scan(f, table, alias, projection, filters, lock)?;
+                write!(f, " key=[{}]", list(key))
+            }
+            Self::Filter { predicate } => write!(f, "Filter: {}", predicate),
+            Self::Projection { exprs } => write!(f, "Projection: {}", list(exprs)),
+            Self::NestedLoopJoin { kind, condition } => {
+                write!(f, "NestedLoopJoin: {}", kind)?;
+                if let Some(condition) = condition {
+                    write!(f, " ON {}", condition)?;
+                }
+                Ok(())
+            }
+            Self::HashJoin { kind, keys, filter } | Self::MergeJoin { kind, keys, filter } => {
+                let name = match self {
+                    Self::HashJoin { .. } => "HashJoin",
+                    _ => "MergeJoin",
+                };
+                let keys = keys.iter().map(|(l, r)| format!("{} = {}", l, r));
+                write!(f, "{}: {} keys=[{}]", name, kind, list(keys))?;
+                if let Some(filter) = filter {
+                    write!(f, " filter={}", filter)?;
+                }
+                Ok(())
+            }
+            Self::IndexJoin {
+                kind,
+                table,
+                alias,
+                projection,
+                key,
+                filters,
+                lock,
+                filter,
+                ..
+            } => {
+                write!(f, "IndexJoin: {} ", kind)?;
+                scan(f, table, alias, projection, filters, lock)?;
+                write!(f, " key=[{}]", list(key))?;
+                if let Some(filter) = filter {
+                    write!(f, " filter={}", filter)?;
+                }
+                Ok(())
+            }
+            Self::HashAggregate {
+                group_by,
+                aggregates,
+            } => write!(
+                f,
+                "HashAggregate: groupBy=[{}], aggr=[{}]",
+                list(group_by),
+                list(aggregates)
+            ),
+            Self::Window { windows } => write!(f, "Window: {}", list(windows)),
+            Self::Sort { keys } => write!(f, "Sort: {}", list(keys)),
+            Self::TopN {
+                keys,
+                limit,
+                offset,
+                with_ties,
+            } => {
+                write!(f, "TopN: {} limit={}, offset={}", list(keys), limit, offset)?;
+                if *with_ties {
+                    write!(f, ", with ties")?;
+                }
+                Ok(())
+            }
+            Self::LockRows { tables, recheck } => {
+                write!(f, "LockRows: {}", list(tables))?;
+                if !recheck.is_empty() {
+                    write!(f, " recheck=[{}]", list(recheck))?;
+                }
+                Ok(())
+            }
+            Self::Limit { limit, offset } => match limit {
+                Some(limit) => write!(f, "Limit: limit={}, offset={}", limit, offset),
+                None => write!(f, "Limit: offset={}", offset),
+            },
+            Self::Values { rows } if rows.is_empty() => write!(f, "Empty"),
+            Self::Values { rows } => {
+                let rows = rows.iter().map(|row| format!("({})", list(row)));
+                write!(f, "Values: {}", list(rows))
+            }
+            Self::Apply { kind } => write!(f, "Apply: {}", kind),
+            Self::CteScan { name, .. } => write!(f, "CteScan: {}", name),
+            Self::With { name, .. } => write!(f, "With: {}", name),
+            Self::RecursiveCte { name, distinct, .. } => {
+                let union = if *distinct { "UNION" } else { "UNION ALL" };
+                write!(f, "RecursiveCte: {} {}", name, union)
+            }
+            Self::SetOperation { op, all } => {
+                write!(f, "SetOperation: {}{}", op, if *all { " ALL" } else { "" })
+            }
+            Self::Insert {
+                table,
+                columns,
+                on_conflict,
+            } => {
+                write!(f, "Insert: {} ({})", table, columns.join(", "))?;
+                match on_conflict {
+                    Some(on_conflict) => write!(f, " {}", on_conflict),
+                    None => Ok(()),
+                }
+            }
+            Self::Update { table, assignments } => {
+                let assignments = assignments
+                    .iter()
+                    .map(|(col, expr)| format!("{} = {}", col, expr));
+                write!(f, "Update: {} SET {}", table, list(assignments))
+            }
+            Self::Delete { table } => write!(f, "Delete: {}", table),
+        }
+    }
+}
+
+/// Splits a join condition into the pairs of expressions to hash on and whatever is left over.
+fn hash_keys(
+    condition: ScalarExpr,
+    left: &LogicalPlan,
+    right: &LogicalPlan,
+) -> (Vec<(ScalarExpr, ScalarExpr)>, Option<ScalarExpr>) {
+    let from = |e: &ScalarExpr, plan: &LogicalPlan| {
+        let columns = e.columns();
+        !columns.is_empty()
+            && columns
+                .iter()
+                .all(|c| matches!(plan.schema().find(c), Ok(Some(_))))
+    };
+    let mut keys = vec![];
+    let mut rest = vec![];
+    for term in condition.split_conjunction() {
+        match term {
+            ScalarExpr::BinaryOp {
+                left: a,
+                op: BinaryOperator::Eq,
+                right: b,
+            } if from(&a, left) && from(&b, right) => keys.push((*a, *b)),
+            ScalarExpr::BinaryOp {
+                left: a,
+                op: BinaryOperator::Eq,
+                right: b,
+            } if from(&a, right) && from(&b, left) => keys.push((*b, *a)),
+            term => rest.push(term),
+        }
+    }
+    (keys, ScalarExpr::conjunction(rest))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logical_plan::tests::{plan, test_catalog};
+    use crate::optimiser::Optimiser;
+    use tracing_test::traced_test;
+
+    fn physical(sql: &str) -> PhysicalPlan {
+        let catalog = test_catalog();
+        let plan = Optimiser::new(&catalog)
+            .optimise(plan(sql).unwrap())
+            .unwrap();
+        PhysicalPlan::new(&plan, &CostModel::new(&catalog)).unwrap()
+    }
+
+    #[test]
+    #[traced_test]
+    fn choose_operators() {
+        let plan = physical(
+            "SELECT u.name, o.total FROM users u JOIN orders o ON o.user_id = u.id AND o.total > u.age WHERE u.id = 4",
+        );
+        assert_eq!(
+            plan.to_string().trim_end(),
+            "
+Projection: u.name, o.total (rows=2 cost=1014.08)
+  HashJoin: Inner keys=[o.user_id = u.id] filter=o.total > u.age (rows=2 cost=1014.07)
+    TableScan: orders AS o projection=[total, user_id] (rows=1000 cost=1000.00)
+    IndexScan: users AS u filters=[u.id = 4] key=[4] (rows=1 cost=4.00)"
+                .trim()
+        );
+
+        let plan = physical("SELECT * FROM users u JOIN orders o ON u.id < o.user_id");
+        assert!(plan
+            .to_string()
+            .contains("NestedLoopJoin: Inner ON u.id < o.user_id"));
+
+        // Only a handful of orders so it's cheaper to look each user up
+        let plan = physical(
+            "SELECT o.id, u.name FROM orders o LEFT JOIN users u ON u.id = o.user_id AND u.age > o.total WHERE o.total = 5",
+        );
+        assert!(
+            plan.to_string()
+                .contains("IndexJoin: Left users AS u key=[o.user_id] filter=u.age > o.total"),
+            "{}",
+            plan
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn json() {
+        let mut plan = physical("SELECT name FROM users WHERE name = 'a \"b\"' LIMIT 1");
+        assert_eq!(
+            plan.to_json(),
+            r#"{"operator": "Limit", "details": "limit=1, offset=0", "estimated_rows": 1, "estimated_cost": 1010.00, "inputs": [{"operator": "TableScan", "details": "users projection=[name] filters=[users.name = 'a \"b\"']", "estimated_rows": 5, "estimated_cost": 1010.00, "inputs": []}]}"#
+        );
+        plan.metrics = Some(Metrics {
+            rows: 1,
+            loops: 1,
+            elapsed: Duration::from_micros(1500),
+        });
+        assert!(plan
+            .to_json()
+            .contains(r#""actual_rows": 1, "loops": 1, "time_ms": 1.500"#));
+        assert!(plan.to_string().starts_with(
+            "Limit: limit=1, offset=0 (rows=1 cost=1010.00) (actual rows=1 loops=1 time=1.500ms)"
+        ));
+    }
+}
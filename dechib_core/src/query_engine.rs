@@ -1,8 +1,11 @@
+use crate::logical_plan::{Catalog, LogicalPlan, PlanBuilder};
+use crate::optimiser::Optimiser;
 use crate::types::*;
-use sqlparser::ast::Statement;
+use sqlparser::ast::{Delete, DescribeAlias, Insert, Statement};
 use sqlparser::dialect::GenericDialect;
+use sqlparser::keywords::Keyword;
 use sqlparser::parser::Parser;
-use std::collections::BTreeMap;
+use sqlparser::tokenizer::Token;
 use tracing::debug;
 
 #[derive(Copy, Clone, Debug, Default)]
@@ -11,22 +14,210 @@ pub struct QueryEngine;
 impl QueryEngine {
     pub fn process_sql(&self, sql: &str) -> anyhow::Result<Vec<Command>> {
         let dialect = GenericDialect {};
-        let parsed = Parser::parse_sql(&dialect, sql)?;
-        debug!(ast=?parsed, "parsed sql query");
-        let mut res = Vec::with_capacity(parsed.len());
-
-        for statement in &parsed {
-            res.push(Command::try_from(statement)?);
+        let mut parser = Parser::new(&dialect).try_with_sql(sql)?;
+        let mut res = vec![];
+        // Same as `Parser::parse_statements` but sqlparser only knows the hive style `ANALYZE
+        // TABLE t` so we handle the postgres style `ANALYZE [t]` ourselves
+        let mut expecting_delimiter = false;
+        loop {
+            while parser.consume_token(&Token::SemiColon) {
+                expecting_delimiter = false;
+            }
+            if parser.peek_token().token == Token::EOF {
+                break;
+            }
+            if expecting_delimiter {
+                parser.expected::<()>("end of statement", parser.peek_token())?;
+            }
+            let command = if parser.parse_keyword(Keyword::EXPLAIN) {
+                if parser.peek_token().token == Token::LParen {
+                    parse_explain_options(&mut parser)?
+                } else {
+                    parse_explain(&mut parser)?
+                }
+            } else if parser.parse_keyword(Keyword::ANALYZE) {
+                match parser.peek_token().token {
+                    Token::EOF | Token::SemiColon => Command::Analyze(None),
+                    Token::Word(w) if w.keyword == Keyword::TABLE => {
+                        Command::try_from(&parser.parse_analyze()?)?
+                    }
+                    _ => Command::Analyze(Some(parser.parse_object_name(false)?.to_string())),
+                }
+            } else {
+                let statement = parse_statement(&mut parser)?;
+                debug!(ast=?statement, "parsed sql query");
+                Command::try_from(&statement)?
+            };
+            res.push(command);
+            expecting_delimiter = true;
         }
         Ok(res)
     }
 
-    pub fn create_execution_plan(&self, query: &str) -> anyhow::Result<()> {
+    /// Parses the SQL and builds a logical plan for each statement in it.
+    pub fn create_execution_plan(
+        &self,
+        query: &str,
+        catalog: &dyn Catalog,
+    ) -> anyhow::Result<Vec<LogicalPlan>> {
         let dialect = GenericDialect {};
         let parsed = Parser::parse_sql(&dialect, query)?;
         debug!(ast=?parsed, "parsed sql query");
-        todo!()
+        let builder = PlanBuilder::new(catalog);
+        let optimiser = Optimiser::new(catalog);
+        parsed
+            .iter()
+            .map(|statement| optimiser.optimise(builder.plan_statement(statement)?))
+            .collect()
+    }
+
+    /// Plans any statement that has a plan, `SELECT`s keep their row locking. Joins of more than
+    /// `dp_limit` tables are ordered greedily.
+    pub fn plan_statement(
+        &self,
+        statement: &Statement,
+        catalog: &dyn Catalog,
+        dp_limit: usize,
+    ) -> anyhow::Result<LogicalPlan> {
+        if let Statement::Query(query) = statement {
+            if let Command::Select(options) = Command::try_from(statement)? {
+                return self.plan_query(&options, catalog, dp_limit);
+            }
+            anyhow::bail!("Unexpected query: {}", query);
+        }
+        let plan = PlanBuilder::new(catalog).plan_statement(statement)?;
+        debug!(plan=%plan, "created logical plan");
+        let plan = Optimiser::with_dp_limit(catalog, dp_limit).optimise(plan)?;
+        debug!(plan=%plan, "optimised plan");
+        Ok(plan)
+    }
+
+    pub fn plan_query(
+        &self,
+        options: &QueryOptions,
+        catalog: &dyn Catalog,
+        dp_limit: usize,
+    ) -> anyhow::Result<LogicalPlan> {
+        let plan = PlanBuilder::new(catalog)
+            .with_locking(options.locking.clone())
+            .plan_query(&options.query)?;
+        debug!(plan=%plan, "created logical plan");
+        let plan = Optimiser::with_dp_limit(catalog, dp_limit).optimise(plan)?;
+        debug!(plan=%plan, "optimised plan");
+        Ok(plan)
+    }
+}
+
+/// `EXPLAIN [ANALYZE] [VERBOSE] [FORMAT fmt] ...` the same as sqlparser, except the statement is
+/// parsed by [`parse_statement`] so it can have `RETURNING`.
+fn parse_explain(parser: &mut Parser) -> anyhow::Result<Command> {
+    let analyze = parser.parse_keyword(Keyword::ANALYZE);
+    let verbose = parser.parse_keyword(Keyword::VERBOSE);
+    let format = match parser.parse_keyword(Keyword::FORMAT) {
+        true => Some(parser.parse_analyze_format()?),
+        false => None,
+    };
+    let statement = parse_statement(parser)?;
+    if matches!(statement, Statement::Explain { .. }) {
+        anyhow::bail!("EXPLAIN must be the root of the plan");
+    }
+    Command::try_from(&Statement::Explain {
+        describe_alias: DescribeAlias::Explain,
+        analyze,
+        verbose,
+        statement: Box::new(statement),
+        format,
+    })
+}
+
+/// Postgres style `EXPLAIN (ANALYZE, FORMAT JSON) ...` which sqlparser doesn't handle.
+fn parse_explain_options(parser: &mut Parser) -> anyhow::Result<Command> {
+    let mut analyze = false;
+    let mut format = ExplainFormat::Text;
+    parser.expect_token(&Token::LParen)?;
+    loop {
+        let option = parser.parse_identifier(false)?;
+        match option.value.to_uppercase().as_str() {
+            "ANALYZE" => {
+                analyze = match parser.peek_token().token {
+                    Token::Word(w) if ["TRUE", "ON"].contains(&w.value.to_uppercase().as_str()) => {
+                        parser.next_token();
+                        true
+                    }
+                    Token::Word(w)
+                        if ["FALSE", "OFF"].contains(&w.value.to_uppercase().as_str()) =>
+                    {
+                        parser.next_token();
+                        false
+                    }
+                    _ => true,
+                }
+            }
+            "FORMAT" => {
+                let value = parser.parse_identifier(false)?;
+                format = match value.value.to_uppercase().as_str() {
+                    "TEXT" => ExplainFormat::Text,
+                    "JSON" => ExplainFormat::Json,
+                    _ => anyhow::bail!("Unsupported EXPLAIN format: {}", value),
+                };
+            }
+            _ => anyhow::bail!("Unrecognised EXPLAIN option: {}", option),
+        }
+        if !parser.consume_token(&Token::Comma) {
+            break;
+        }
+    }
+    parser.expect_token(&Token::RParen)?;
+    Ok(Command::Explain(ExplainOptions {
+        analyze,
+        format,
+        statement: Box::new(parse_statement(parser)?),
+    }))
+}
+
+/// sqlparser doesn't reserve `RETURNING` as a table alias so in `DELETE FROM t RETURNING id` or
+/// `INSERT INTO t SELECT * FROM u RETURNING *` it's taken as the alias of the last table. For
+/// modifications we parse everything before a top level `RETURNING` on its own and then add the
+/// clause ourselves.
+fn parse_statement(parser: &mut Parser) -> anyhow::Result<Statement> {
+    let modification = matches!(
+        parser.peek_token().token,
+        Token::Word(w) if matches!(w.keyword, Keyword::INSERT | Keyword::UPDATE | Keyword::DELETE)
+    );
+    if !modification {
+        return Ok(parser.parse_statement()?);
+    }
+    let mut tokens = vec![];
+    let mut depth = 0usize;
+    loop {
+        let token = parser.peek_nth_token(tokens.len()).token;
+        match &token {
+            Token::EOF | Token::SemiColon => return Ok(parser.parse_statement()?),
+            Token::LParen => depth += 1,
+            Token::RParen => depth = depth.saturating_sub(1),
+            Token::Word(w) if depth == 0 && w.keyword == Keyword::RETURNING => break,
+            _ => {}
+        }
+        tokens.push(token);
     }
+    let skip = tokens.len();
+    let mut inner = Parser::new(&GenericDialect {}).with_tokens(tokens);
+    let mut statement = inner.parse_statement()?;
+    if inner.peek_token().token != Token::EOF {
+        inner.expected::<()>("RETURNING", inner.peek_token())?;
+    }
+    for _ in 0..skip {
+        parser.next_token();
+    }
+    parser.expect_keyword(Keyword::RETURNING)?;
+    let items = parser.parse_comma_separated(Parser::parse_select_item)?;
+    match &mut statement {
+        Statement::Insert(Insert { returning, .. })
+        | Statement::Update { returning, .. }
+        | Statement::Delete(Delete { returning, .. }) => *returning = Some(items),
+        x => anyhow::bail!("RETURNING isn't supported for: {}", x),
+    }
+    Ok(statement)
 }
 
 #[cfg(test)]
@@ -37,9 +228,118 @@ mod tests {
     #[test]
     #[traced_test]
     fn duplicate_column_in_insert() {
-        let engine = QueryEngine::default();
+        let engine = QueryEngine;
         let res = engine
             .process_sql("INSERT INTO Persons (FirstName, FirstName) VALUES ('Daniel', 'Daniel');");
         assert!(res.is_err(), "{:?} should be error", res);
     }
+
+    #[test]
+    #[traced_test]
+    fn analyze() {
+        let engine = QueryEngine;
+        let res = engine
+            .process_sql("ANALYZE; ANALYZE users; ANALYZE TABLE orders; SELECT 1")
+            .unwrap();
+        assert!(matches!(res[0], Command::Analyze(None)));
+        assert!(matches!(&res[1], Command::Analyze(Some(t)) if t == "users"));
+        assert!(matches!(&res[2], Command::Analyze(Some(t)) if t == "orders"));
+        assert!(matches!(res[3], Command::Select(_)));
+        assert!(engine.process_sql("ANALYZE users orders").is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn explain() {
+        let engine = QueryEngine;
+        let res = engine
+            .process_sql(
+                "EXPLAIN SELECT 1; EXPLAIN ANALYZE FORMAT JSON SELECT 1; EXPLAIN (FORMAT JSON, ANALYZE false) SELECT 1; EXPLAIN (analyze) SELECT 1",
+            )
+            .unwrap();
+        let options = res
+            .iter()
+            .map(|x| match x {
+                Command::Explain(options) => (options.analyze, options.format),
+                x => panic!("Expected explain: {:?}", x),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            options,
+            vec![
+                (false, ExplainFormat::Text),
+                (true, ExplainFormat::Json),
+                (false, ExplainFormat::Json),
+                (true, ExplainFormat::Text)
+            ]
+        );
+        assert!(engine.process_sql("EXPLAIN (FORMAT XML) SELECT 1").is_err());
+        assert!(engine.process_sql("EXPLAIN (COSTS) SELECT 1").is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn returning() {
+        let engine = QueryEngine;
+        let res = engine
+            .process_sql(
+                "DELETE FROM t RETURNING id;
+                 DELETE FROM t AS x RETURNING x.id, 1;
+                 INSERT INTO t (name) SELECT name FROM t RETURNING *;
+                 INSERT INTO t (name) SELECT name FROM (SELECT name FROM u) AS v RETURNING id;
+                 UPDATE t SET n = 1 FROM u RETURNING t.n",
+            )
+            .unwrap();
+        let returning = res
+            .iter()
+            .map(|x| match x {
+                Command::Modify(statement) => match statement.as_ref() {
+                    Statement::Insert(Insert { returning, .. })
+                    | Statement::Update { returning, .. }
+                    | Statement::Delete(Delete { returning, .. }) => returning
+                        .iter()
+                        .flatten()
+                        .map(|x| x.to_string())
+                        .collect::<Vec<_>>(),
+                    x => panic!("Expected modification: {:?}", x),
+                },
+                x => panic!("Expected modification: {:?}", x),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            returning,
+            vec![
+                vec!["id"],
+                vec!["x.id", "1"],
+                vec!["*"],
+                vec!["id"],
+                vec!["t.n"]
+            ]
+        );
+        // The table alias isn't lost
+        assert!(
+            matches!(&res[1], Command::Modify(x) if x.to_string() == "DELETE FROM t AS x RETURNING x.id, 1"),
+            "{:?}",
+            res[1]
+        );
+        assert!(engine.process_sql("DELETE FROM t RETURNING").is_err());
+
+        // Including under EXPLAIN
+        for sql in [
+            "EXPLAIN DELETE FROM t RETURNING id",
+            "EXPLAIN ANALYZE FORMAT JSON DELETE FROM t RETURNING id",
+            "EXPLAIN (ANALYZE) DELETE FROM t RETURNING id",
+        ] {
+            let res = engine.process_sql(sql).unwrap();
+            assert!(
+                matches!(&res[0], Command::Explain(options) if options.statement.to_string() == "DELETE FROM t RETURNING id"),
+                "{:?}",
+                res[0]
+            );
+        }
+        assert!(engine.process_sql("EXPLAIN EXPLAIN SELECT 1").is_err());
+        assert!(engine
+            .process_sql("DELETE FROM t x y RETURNING id")
+            .is_err());
+    }
 }
@@ -0,0 +1,1791 @@
+//! A session is the state tied to a single client connection. Statements are executed within a
+//! session so it's where we keep track of the current transaction, any variables the client has
+//! set, prepared statements and who the client has authenticated as.
+use crate::cost::CostModel;
+use crate::executor::{
+    self, BoxedOperator, ExecutionContext, MetricsHandle, Row, DEFAULT_MAX_RECURSION,
+    DEFAULT_WORK_MEM,
+};
+use crate::join_order::DEFAULT_DP_LIMIT;
+use crate::lock_manager::{LockError, RowId, TransactionId, WriteLocks};
+use crate::physical_plan::PhysicalPlan;
+use crate::storage_engine::{FlushedWrites, WriteSet};
+use crate::types::*;
+use crate::vectorised::{self, BoxedBatchOperator};
+use crate::Instance;
+use bigdecimal::ToPrimitive;
+use sqlparser::ast::{self, visit_expressions_mut, Expr, Statement};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ops::ControlFlow;
+use std::rc::Rc;
+use std::time::Duration;
+use tracing::{debug, instrument, warn};
+
+/// Who the client connected to the session is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    pub user: String,
+}
+
+impl Identity {
+    pub fn new(user: impl Into<String>) -> Self {
+        Self { user: user.into() }
+    }
+}
+
+/// An explicit transaction started with `BEGIN`. Writes are kept here until we commit, and any
+/// locks taken are owned by `id`.
+#[derive(Debug)]
+struct Transaction {
+    id: TransactionId,
+    writes: WriteSet,
+}
+
+pub struct Session {
+    instance: Instance,
+    transaction: Option<Transaction>,
+    variables: BTreeMap<String, Value>,
+    prepared: BTreeMap<String, Box<Statement>>,
+    identity: Option<Identity>,
+}
+
+impl Session {
+    pub(crate) fn new(instance: Instance) -> Self {
+        Self {
+            instance,
+            transaction: None,
+            variables: BTreeMap::new(),
+            prepared: BTreeMap::new(),
+            identity: None,
+        }
+    }
+
+    pub fn identity(&self) -> Option<&Identity> {
+        self.identity.as_ref()
+    }
+
+    pub fn set_identity(&mut self, identity: Identity) {
+        debug!(user = identity.user, "session authenticated");
+        self.identity = Some(identity);
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    /// The ID of the current transaction if there is one.
+    pub fn transaction_id(&self) -> Option<TransactionId> {
+        self.transaction.as_ref().map(|x| x.id)
+    }
+
+    /// Gets the value of a variable set via `SET`
+    pub fn variable(&self, name: &str) -> Option<&Value> {
+        self.variables.get(&name.to_lowercase())
+    }
+
+    /// Runs the statements discarding any results.
+    pub fn execute(&mut self, query: &str) -> anyhow::Result<()> {
+        self.query(query).map(|_| ())
+    }
+
+    /// Runs the statements returning the results of the last one.
+    #[instrument(skip(self))]
+    pub fn query(&mut self, query: &str) -> anyhow::Result<ResultSet> {
+        let mut cursor = self.cursor(query)?;
+        let rows = cursor.by_ref().collect::<anyhow::Result<_>>()?;
+        Ok(ResultSet {
+            columns: cursor.columns().to_vec(),
+            rows,
+        })
+    }
+
+    /// Runs the statements giving back a cursor over the results of the last one, see [`Cursor`].
+    #[instrument(skip(self))]
+    pub fn cursor(&mut self, query: &str) -> anyhow::Result<Cursor<'_>> {
+        let mut statements = self.instance.query.process_sql(query)?;
+        let last = statements.pop();
+        for statement in statements {
+            let output = self.run(statement);
+            for row in self.open(output)? {
+                row?;
+            }
+        }
+        let output = match last {
+            Some(statement) => self.run(statement),
+            None => Ok(Output::Rows(ResultSet::default())),
+        };
+        self.open(output)
+    }
+
+    fn open(&mut self, output: anyhow::Result<Output>) -> anyhow::Result<Cursor<'_>> {
+        match output {
+            Ok(Output::Rows(result)) => Ok(Cursor {
+                session: self,
+                columns: result.columns,
+                state: CursorState::Ready(result.rows.into_iter()),
+            }),
+            Ok(Output::Plan(plan)) => Cursor::open(self, &plan, &mut None),
+            Err(e) => {
+                self.rollback_if_victim(&e);
+                Err(e)
+            }
+        }
+    }
+
+    /// If we were picked as a deadlock victim we need to give up our locks for the other
+    /// transactions to progress
+    fn rollback_if_victim(&mut self, e: &anyhow::Error) {
+        if e.downcast_ref::<LockError>()
+            .is_some_and(|e| e.is_retryable())
+        {
+            warn!("Rolling back transaction: {}", e);
+            self.rollback();
+        }
+    }
+
+    fn rollback(&mut self) {
+        if let Some(transaction) = self.transaction.take() {
+            self.instance.locks.release_all(transaction.id);
+        }
+    }
+
+    /// Queries and statements with `RETURNING` are only planned, they're run as their rows are
+    /// read
+    fn run(&mut self, command: Command) -> anyhow::Result<Output> {
+        debug!("Running: {:?}", command);
+        match command {
+            Command::CreateTable(opts) => {
+                self.instance.storage.create_table(&opts)?;
+            }
+            Command::Insert(insert) => {
+                let opts = insert.evaluate(self.instance.storage.as_ref())?;
+                self.with_transaction(|session, id| {
+                    let mut writes = WriteSet::default();
+                    let pending = session.transaction.as_ref().map(|x| &x.writes);
+                    let locks = WriteLocks {
+                        locks: &session.instance.locks,
+                        transaction: id,
+                        timeout: session.lock_timeout()?,
+                    };
+                    session.instance.storage.stage_insert(
+                        &opts,
+                        None,
+                        pending,
+                        Some(locks),
+                        &mut writes,
+                    )?;
+                    session.apply_writes(id, writes, None)
+                })?;
+            }
+            Command::Modify(statement) => {
+                let storage = self.instance.storage.as_ref();
+                let plan =
+                    self.instance
+                        .query
+                        .plan_statement(&statement, storage, self.dp_limit()?)?;
+                // Only outputs anything with RETURNING
+                let plan = PhysicalPlan::new(&plan, &CostModel::new(storage))?;
+                return Ok(Output::Plan(Box::new(plan)));
+            }
+            Command::Select(opts) => {
+                let storage = self.instance.storage.as_ref();
+                let plan = self
+                    .instance
+                    .query
+                    .plan_query(&opts, storage, self.dp_limit()?)?;
+                let plan = PhysicalPlan::new(&plan, &CostModel::new(storage))?;
+                return Ok(Output::Plan(Box::new(plan)));
+            }
+            Command::Begin => {
+                if self.transaction.is_some() {
+                    anyhow::bail!("There is already a transaction in progress");
+                }
+                self.transaction = Some(Transaction {
+                    id: self.instance.locks.begin(),
+                    writes: WriteSet::default(),
+                });
+            }
+            Command::Commit => match self.transaction.take() {
+                Some(transaction) => {
+                    let res = self.instance.storage.commit(transaction.writes);
+                    self.instance.locks.release_all(transaction.id);
+                    res?;
+                }
+                None => warn!("There is no transaction in progress"),
+            },
+            Command::Rollback => {
+                if self.transaction.is_none() {
+                    warn!("There is no transaction in progress");
+                }
+                self.rollback();
+            }
+            Command::Set { name, value } => {
+                self.variables.insert(name, value);
+            }
+            Command::Show(name) => return self.show(&name).map(Output::Rows),
+            Command::Prepare { name, statement } => {
+                if self.prepared.contains_key(&name) {
+                    anyhow::bail!("Prepared statement {} already exists", name);
+                }
+                self.prepared.insert(name, statement);
+            }
+            Command::Execute { name, parameters } => {
+                let statement = match self.prepared.get(&name) {
+                    Some(statement) => bind_parameters(statement, &parameters)?,
+                    None => anyhow::bail!("Prepared statement {} does not exist", name),
+                };
+                return self.run(Command::try_from(&statement)?);
+            }
+            Command::Deallocate(name) => {
+                if self.prepared.remove(&name).is_none() {
+                    anyhow::bail!("Prepared statement {} does not exist", name);
+                }
+            }
+            Command::Explain(options) => return self.explain(&options).map(Output::Rows),
+            Command::Analyze(table) => {
+                let tables = match table {
+                    Some(table) => vec![table],
+                    None => self.instance.storage.tables()?,
+                };
+                for table in tables {
+                    self.instance.storage.analyze(&table)?;
+                }
+            }
+        }
+        Ok(Output::Rows(ResultSet::default()))
+    }
+
+    /// Runs a statement as part of the current transaction. Outside of a transaction the statement
+    /// gets one to itself, so any rows it locks while reading stay locked until its writes are
+    /// committed and are released as soon as it's done.
+    fn with_transaction<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self, TransactionId) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        if let Some(transaction) = &self.transaction {
+            return f(self, transaction.id);
+        }
+        let id = self.instance.locks.begin();
+        let res = f(self, id);
+        self.instance.locks.release_all(id);
+        res
+    }
+
+    /// Writes made outside of a transaction are committed straight away along with any the
+    /// statement flushed, otherwise they're committed with the rest of the transaction. Either way
+    /// we need an exclusive lock on every row written, usually we'll already have it from reading
+    /// the row.
+    fn apply_writes(
+        &mut self,
+        id: TransactionId,
+        writes: WriteSet,
+        flushed: Option<FlushedWrites>,
+    ) -> anyhow::Result<()> {
+        if writes.is_empty() && flushed.as_ref().map_or(true, |x| x.is_empty()) {
+            return Ok(());
+        }
+        let locks = WriteLocks {
+            locks: &self.instance.locks,
+            transaction: id,
+            timeout: self.lock_timeout()?,
+        };
+        for (table, key) in writes.rows() {
+            locks.lock(&RowId::new(table, key))?;
+        }
+        match self.transaction.as_mut() {
+            Some(transaction) => {
+                transaction.writes.extend(writes);
+                Ok(())
+            }
+            None => {
+                let storage = &self.instance.storage;
+                let mut flushed = flushed.unwrap_or_default();
+                storage.flush(writes, &mut flushed)?;
+                storage.commit_flushed(flushed)
+            }
+        }
+    }
+
+    /// How long to wait for a row lock, set via the `lock_timeout` variable in milliseconds. Zero
+    /// or unset means we wait forever.
+    fn lock_timeout(&self) -> anyhow::Result<Option<Duration>> {
+        let millis = match self.variables.get("lock_timeout") {
+            None => return Ok(None),
+            Some(Value::Number(n)) => n.to_u64(),
+            Some(Value::Text(s)) => s.trim().trim_end_matches("ms").trim().parse().ok(),
+            Some(_) => None,
+        };
+        match millis {
+            Some(0) => Ok(None),
+            Some(ms) => Ok(Some(Duration::from_millis(ms))),
+            None => anyhow::bail!("Invalid value for lock_timeout, expected milliseconds"),
+        }
+    }
+
+    /// How many iterations a recursive CTE can do, set via the `max_recursion` variable.
+    fn max_recursion(&self) -> anyhow::Result<u64> {
+        let max = match self.variables.get("max_recursion") {
+            None => return Ok(DEFAULT_MAX_RECURSION),
+            Some(Value::Number(n)) => n.to_u64(),
+            Some(Value::Text(s)) => s.trim().parse().ok(),
+            Some(_) => None,
+        };
+        max.ok_or_else(|| anyhow::anyhow!("Invalid value for max_recursion, expected a number"))
+    }
+
+    /// How much memory a sort can use before spilling to disk, set via `work_mem`. Like Postgres
+    /// a plain number is in kilobytes, otherwise it can have a kB, MB or GB unit.
+    fn work_mem(&self) -> anyhow::Result<usize> {
+        let invalid = || anyhow::anyhow!("Invalid value for work_mem, expected a size like 4MB");
+        let kilobytes = match self.variables.get("work_mem") {
+            None => return Ok(DEFAULT_WORK_MEM),
+            Some(Value::Number(n)) => n.to_usize().ok_or_else(invalid)?,
+            Some(Value::Text(s)) => {
+                let s = s.trim();
+                let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+                let (number, unit) = s.split_at(split);
+                let scale = match unit.trim().to_lowercase().as_str() {
+                    "" | "kb" => 1,
+                    "mb" => 1 << 10,
+                    "gb" => 1 << 20,
+                    _ => return Err(invalid()),
+                };
+                number.parse::<usize>().map_err(|_| invalid())? * scale
+            }
+            Some(_) => return Err(invalid()),
+        };
+        Ok(kilobytes << 10)
+    }
+
+    /// The most tables in a join which get ordered by dynamic programming, bigger joins are ordered
+    /// greedily. Set via `join_dp_limit`.
+    fn dp_limit(&self) -> anyhow::Result<usize> {
+        let limit = match self.variables.get("join_dp_limit") {
+            None => return Ok(DEFAULT_DP_LIMIT),
+            Some(Value::Number(n)) => n.to_usize(),
+            Some(Value::Text(s)) => s.trim().parse().ok(),
+            Some(_) => None,
+        };
+        limit.ok_or_else(|| anyhow::anyhow!("Invalid value for join_dp_limit, expected a number"))
+    }
+
+    /// Whether queries should be run a batch at a time, set with `SET execution_mode`.
+    fn vectorised(&self) -> anyhow::Result<bool> {
+        match self.variables.get("execution_mode") {
+            None => Ok(false),
+            Some(Value::Text(s)) if s.eq_ignore_ascii_case("row") => Ok(false),
+            Some(Value::Text(s))
+                if s.eq_ignore_ascii_case("vectorised") || s.eq_ignore_ascii_case("vectorized") =>
+            {
+                Ok(true)
+            }
+            Some(_) => {
+                anyhow::bail!("Invalid value for execution_mode, expected row or vectorised")
+            }
+        }
+    }
+
+    /// The state a statement's [`ExecutionContext`] starts with
+    fn statement_state(&self) -> anyhow::Result<StatementState> {
+        Ok(StatementState {
+            lock_timeout: self.lock_timeout()?,
+            max_recursion: self.max_recursion()?,
+            work_mem: self.work_mem()?,
+            ctes: HashMap::new(),
+            staged: WriteSet::default(),
+            flushed: self.transaction.is_none().then(FlushedWrites::default),
+        })
+    }
+
+    /// Like Postgres `EXPLAIN ANALYZE` really runs the statement, so its writes are applied
+    fn explain(&mut self, options: &ExplainOptions) -> anyhow::Result<ResultSet> {
+        let storage = self.instance.storage.as_ref();
+        let plan =
+            self.instance
+                .query
+                .plan_statement(&options.statement, storage, self.dp_limit()?)?;
+        let mut plan = PhysicalPlan::new(&plan, &CostModel::new(storage))?;
+        if options.analyze {
+            let mut metrics = Some(vec![]);
+            for row in Cursor::open(self, &plan, &mut metrics)? {
+                row?;
+            }
+            executor::assign_metrics(&mut plan, metrics.unwrap_or_default());
+        }
+        let rows = match options.format {
+            ExplainFormat::Text => plan
+                .to_string()
+                .lines()
+                .map(|line| vec![Value::Text(line.to_string())])
+                .collect(),
+            ExplainFormat::Json => vec![vec![Value::Text(plan.to_json())]],
+        };
+        Ok(ResultSet {
+            columns: vec!["QUERY PLAN".to_string()],
+            rows,
+        })
+    }
+
+    fn show(&self, name: &str) -> anyhow::Result<ResultSet> {
+        if name == "all" {
+            Ok(ResultSet {
+                columns: vec!["name".to_string(), "setting".to_string()],
+                rows: self
+                    .variables
+                    .iter()
+                    .map(|(k, v)| vec![Value::Text(k.clone()), v.clone()])
+                    .collect(),
+            })
+        } else {
+            let value = self
+                .variables
+                .get(name)
+                .ok_or_else(|| anyhow::anyhow!("Unrecognised configuration parameter {}", name))?;
+            Ok(ResultSet {
+                columns: vec![name.to_string()],
+                rows: vec![vec![value.clone()]],
+            })
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if self.transaction.is_some() {
+            debug!("Rolling back unfinished transaction");
+            self.rollback();
+        }
+    }
+}
+
+/// What running a statement gives back
+enum Output {
+    Rows(ResultSet),
+    /// A plan which hasn't been run yet
+    Plan(Box<PhysicalPlan>),
+}
+
+/// The rows of a statement, pulled through its plan as they're read. Until the cursor's read to
+/// the end or closed the statement is still running, so it keeps hold of any locks it's taken
+/// (outside of a transaction it has one to itself). Once it's finished anything the statement
+/// wrote is applied. Dropping the cursor part way through throws the writes away.
+pub struct Cursor<'s> {
+    session: &'s mut Session,
+    columns: Vec<String>,
+    state: CursorState,
+}
+
+enum CursorState {
+    /// Statements which don't go through the executor have their rows straight away
+    Ready(std::vec::IntoIter<Row>),
+    Running(Box<RunningStatement>),
+    Finished,
+}
+
+struct RunningStatement {
+    operator: RootOperator,
+    transaction: TransactionId,
+    /// Whether the transaction is the statement's own, rather than the session's
+    own_transaction: bool,
+    state: StatementState,
+}
+
+/// What's kept of the [`ExecutionContext`] between reading each row
+struct StatementState {
+    lock_timeout: Option<Duration>,
+    max_recursion: u64,
+    work_mem: usize,
+    ctes: HashMap<usize, Rc<Vec<Row>>>,
+    staged: WriteSet,
+    flushed: Option<FlushedWrites>,
+}
+
+/// The top of the plan with whichever executor the session is set to use
+enum RootOperator {
+    Rows(BoxedOperator),
+    /// The rows left from the last batch
+    Batches(BoxedBatchOperator, VecDeque<Row>),
+}
+
+impl RootOperator {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        match self {
+            Self::Rows(operator) => operator.open(ctx),
+            Self::Batches(operator, _) => operator.open(ctx),
+        }
+    }
+
+    fn next(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        match self {
+            Self::Rows(operator) => operator.next(ctx),
+            Self::Batches(operator, rows) => {
+                if rows.is_empty() {
+                    if let Some(batch) = operator.next_batch(ctx)? {
+                        rows.extend(batch.rows());
+                    }
+                }
+                Ok(rows.pop_front())
+            }
+        }
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        match self {
+            Self::Rows(operator) => operator.close(),
+            Self::Batches(operator, _) => operator.close(),
+        }
+    }
+}
+
+impl RunningStatement {
+    /// Operators only get the context for each call, so it's put together again each time from
+    /// what's kept of it
+    fn with_context<T>(
+        &mut self,
+        session: &Session,
+        f: impl FnOnce(&mut RootOperator, &ExecutionContext) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let state = &mut self.state;
+        let ctx = ExecutionContext {
+            storage: session.instance.storage.as_ref(),
+            locks: &session.instance.locks,
+            transaction: self.transaction,
+            writes: session.transaction.as_ref().map(|x| &x.writes),
+            lock_timeout: state.lock_timeout,
+            max_recursion: state.max_recursion,
+            work_mem: state.work_mem,
+            ctes: RefCell::new(std::mem::take(&mut state.ctes)),
+            staged: RefCell::new(std::mem::take(&mut state.staged)),
+            flushed: RefCell::new(state.flushed.take()),
+        };
+        let res = f(&mut self.operator, &ctx);
+        state.ctes = ctx.ctes.into_inner();
+        state.staged = ctx.staged.into_inner();
+        state.flushed = ctx.flushed.into_inner();
+        res
+    }
+}
+
+impl<'s> Cursor<'s> {
+    /// Starts running the plan, recording what each operator does in `metrics` if given some
+    fn open(
+        session: &'s mut Session,
+        plan: &PhysicalPlan,
+        metrics: &mut Option<Vec<MetricsHandle>>,
+    ) -> anyhow::Result<Self> {
+        let operator = match session.vectorised()? {
+            true => {
+                RootOperator::Batches(vectorised::build_operator(plan, metrics)?, VecDeque::new())
+            }
+            false => RootOperator::Rows(executor::build_operator(plan, metrics)?),
+        };
+        let state = session.statement_state()?;
+        let (transaction, own_transaction) = match &session.transaction {
+            Some(transaction) => (transaction.id, false),
+            None => (session.instance.locks.begin(), true),
+        };
+        let mut cursor = Self {
+            session,
+            columns: plan.schema.fields.iter().map(|f| f.name.clone()).collect(),
+            state: CursorState::Running(Box::new(RunningStatement {
+                operator,
+                transaction,
+                own_transaction,
+                state,
+            })),
+        };
+        if let CursorState::Running(statement) = &mut cursor.state {
+            let res = statement.with_context(cursor.session, |operator, ctx| operator.open(ctx));
+            if let Err(e) = res {
+                return Err(cursor.fail(e));
+            }
+        }
+        Ok(cursor)
+    }
+
+    pub fn columns(&self) -> &[String] {
+        self.columns.as_slice()
+    }
+
+    /// Stops reading rows and finishes the statement, applying anything it's written
+    pub fn close(mut self) -> anyhow::Result<()> {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        let CursorState::Running(mut statement) =
+            std::mem::replace(&mut self.state, CursorState::Finished)
+        else {
+            return Ok(());
+        };
+        let StatementState {
+            staged, flushed, ..
+        } = statement.state;
+        let res = statement.operator.close().and_then(|()| {
+            self.session
+                .apply_writes(statement.transaction, staged, flushed)
+        });
+        if statement.own_transaction {
+            self.session
+                .instance
+                .locks
+                .release_all(statement.transaction);
+        }
+        res.map_err(|e| {
+            self.session.rollback_if_victim(&e);
+            e
+        })
+    }
+
+    /// Gives up on the statement, throwing away its writes
+    fn abandon(&mut self) {
+        if let CursorState::Running(mut statement) =
+            std::mem::replace(&mut self.state, CursorState::Finished)
+        {
+            if let Err(e) = statement.operator.close() {
+                warn!("Failed to close abandoned statement: {}", e);
+            }
+            if statement.own_transaction {
+                self.session
+                    .instance
+                    .locks
+                    .release_all(statement.transaction);
+            }
+        }
+    }
+
+    fn fail(&mut self, e: anyhow::Error) -> anyhow::Error {
+        self.abandon();
+        self.session.rollback_if_victim(&e);
+        e
+    }
+}
+
+impl Iterator for Cursor<'_> {
+    type Item = anyhow::Result<Row>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let statement = match &mut self.state {
+            CursorState::Ready(rows) => return rows.next().map(Ok),
+            CursorState::Running(statement) => statement,
+            CursorState::Finished => return None,
+        };
+        match statement.with_context(self.session, |operator, ctx| operator.next(ctx)) {
+            Ok(Some(row)) => Some(Ok(row)),
+            Ok(None) => self.finish().err().map(Err),
+            Err(e) => Some(Err(self.fail(e))),
+        }
+    }
+}
+
+impl Drop for Cursor<'_> {
+    fn drop(&mut self) {
+        self.abandon();
+    }
+}
+
+/// Replaces the placeholders in a prepared statement with the provided parameters. We accept both
+/// numbered `$1` placeholders and positional `?` ones.
+fn bind_parameters(statement: &Statement, parameters: &[Expr]) -> anyhow::Result<Statement> {
+    let mut statement = statement.clone();
+    let mut position = 0;
+    let res = visit_expressions_mut(&mut statement, |expr| {
+        if let Expr::Value(ast::Value::Placeholder(placeholder)) = expr {
+            let index = if placeholder == "?" {
+                position += 1;
+                position
+            } else {
+                match placeholder.trim_start_matches('$').parse::<usize>() {
+                    Ok(i) => i,
+                    Err(_) => return ControlFlow::Break(placeholder.clone()),
+                }
+            };
+            match index.checked_sub(1).and_then(|i| parameters.get(i)) {
+                Some(param) => *expr = param.clone(),
+                None => return ControlFlow::Break(placeholder.clone()),
+            }
+        }
+        ControlFlow::Continue(())
+    });
+    if let ControlFlow::Break(placeholder) = res {
+        anyhow::bail!("No parameter provided for {}", placeholder);
+    }
+    Ok(statement)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock_manager::LockMode;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+    use tempfile::tempdir;
+    use tracing_test::traced_test;
+
+    fn fixture(instance: &Instance) {
+        instance
+            .session()
+            .execute(
+                "CREATE TABLE users (id INTEGER AUTO_INCREMENT PRIMARY KEY, name TEXT NOT NULL)",
+            )
+            .unwrap();
+    }
+
+    fn row_count(instance: &Instance, table: &str) -> usize {
+        let storage = instance.storage();
+        let handle = storage.handle().cf_handle(table).unwrap();
+        storage
+            .handle()
+            .iterator_cf(&handle, rocksdb::IteratorMode::Start)
+            .filter(|x| x.as_ref().unwrap().0.starts_with(b"row/"))
+            .count()
+    }
+
+    #[test]
+    #[traced_test]
+    fn set_and_show_variables() {
+        let dir = tempdir().unwrap();
+        let instance = Instance::new_with_path(dir.path());
+        let mut session = instance.session();
+
+        session.execute("SET search_path TO public").unwrap();
+        session.execute("SET statement_timeout = 100").unwrap();
+
+        let res = session.query("SHOW search_path").unwrap();
+        assert_eq!(res.columns, vec!["search_path".to_string()]);
+        assert_eq!(res.rows, vec![vec![Value::Text("public".to_string())]]);
+
+        let res = session.query("SHOW ALL").unwrap();
+        assert_eq!(res.rows.len(), 2);
+
+        assert!(session.query("SHOW doesnt_exist").is_err());
+
+        // Variables don't leak between sessions
+        let mut other = instance.session();
+        assert!(other.query("SHOW search_path").is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn analyze() {
+        let dir = tempdir().unwrap();
+        let instance = Instance::new_with_path(dir.path());
+        fixture(&instance);
+        let mut session = instance.session();
+        session
+            .execute("INSERT INTO users (name) VALUES ('Daniel'), ('Guido'), ('Daniel')")
+            .unwrap();
+        assert!(instance
+            .storage()
+            .table_statistics("users")
+            .unwrap()
+            .is_none());
+
+        session.execute("ANALYZE users").unwrap();
+        let stats = instance
+            .storage()
+            .table_statistics("users")
+            .unwrap()
+            .unwrap();
+        assert_eq!(stats.row_count, 3);
+        assert_eq!(stats.columns["name"].distinct_count, 2);
+
+        session
+            .execute("INSERT INTO users (name) VALUES ('Alan')")
+            .unwrap();
+        session.execute("ANALYZE").unwrap();
+        let stats = instance
+            .storage()
+            .table_statistics("users")
+            .unwrap()
+            .unwrap();
+        assert_eq!(stats.row_count, 4);
+
+        assert!(session.execute("ANALYZE doesnt_exist").is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn explain() {
+        let dir = tempdir().unwrap();
+        let instance = Instance::new_with_path(dir.path());
+        fixture(&instance);
+        let mut session = instance.session();
+
+        let res = session
+            .query("EXPLAIN SELECT name FROM users WHERE id = 1")
+            .unwrap();
+        assert_eq!(res.columns, vec!["QUERY PLAN".to_string()]);
+        assert_eq!(
+            res.rows,
+            vec![
+                vec![Value::from("Projection: users.name (rows=1 cost=4.01)")],
+                vec![Value::from(
+                    "  IndexScan: users filters=[users.id = 1] key=[1] (rows=1 cost=4.00)"
+                )],
+            ]
+        );
+
+        let res = session
+            .query("EXPLAIN (FORMAT JSON) INSERT INTO users (name) VALUES ('Daniel')")
+            .unwrap();
+        assert_eq!(res.rows.len(), 1);
+        let Value::Text(json) = &res.rows[0][0] else {
+            panic!("Expected text: {:?}", res.rows);
+        };
+        assert!(json.starts_with(r#"{"operator": "Insert""#), "{}", json);
+
+        assert!(session.query("EXPLAIN SELECT * FROM doesnt_exist").is_err());
+
+        session
+            .execute("INSERT INTO users (name) VALUES ('Daniel'), ('Guido')")
+            .unwrap();
+        let res = session
+            .query("EXPLAIN ANALYZE SELECT name FROM users WHERE name = 'Guido'")
+            .unwrap();
+        let Value::Text(line) = &res.rows[0][0] else {
+            panic!("Expected text: {:?}", res.rows);
+        };
+        assert!(line.contains("(actual rows=1 loops=1 time="), "{}", line);
+    }
+
+    #[test]
+    #[traced_test]
+    fn select() {
+        let dir = tempdir().unwrap();
+        let instance = Instance::new_with_path(dir.path());
+        fixture(&instance);
+        let mut session = instance.session();
+        session
+            .execute("INSERT INTO users (name) VALUES ('Daniel'), ('Guido')")
+            .unwrap();
+
+        let res = session
+            .query("SELECT id, name FROM users ORDER BY name DESC")
+            .unwrap();
+        assert_eq!(res.columns, vec!["id".to_string(), "name".to_string()]);
+        assert_eq!(
+            res.rows,
+            vec![
+                vec![Value::from(2), Value::from("Guido")],
+                vec![Value::from(1), Value::from("Daniel")],
+            ]
+        );
+
+        // Transactions see their own writes before anyone else does
+        session.execute("BEGIN").unwrap();
+        session
+            .execute("INSERT INTO users (name) VALUES ('Alan')")
+            .unwrap();
+        let count =
+            |session: &mut Session| session.query("SELECT count(*) FROM users").unwrap().rows;
+        assert_eq!(count(&mut session), vec![vec![Value::from(3)]]);
+        let mut other = instance.session();
+        assert_eq!(count(&mut other), vec![vec![Value::from(2)]]);
+        session.execute("COMMIT").unwrap();
+        assert_eq!(count(&mut other), vec![vec![Value::from(3)]]);
+
+        assert!(session.query("SELECT toshi FROM users").is_err());
+
+        session
+            .execute("SET execution_mode = 'vectorised'")
+            .unwrap();
+        assert_eq!(count(&mut session), vec![vec![Value::from(3)]]);
+        let counter =
+            "WITH RECURSIVE n (x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n WHERE x < 20) \
+                       SELECT count(*) FROM n";
+        assert_eq!(
+            session.query(counter).unwrap().rows,
+            vec![vec![Value::from(20)]]
+        );
+        session.execute("SET max_recursion = 5").unwrap();
+        assert!(session.query(counter).is_err());
+        session.execute("SET work_mem = '1kB'").unwrap();
+        let sorted = session
+            .query("SELECT name FROM users ORDER BY name")
+            .unwrap();
+        assert_eq!(sorted.rows[0], vec![Value::from("Alan")]);
+        session.execute("SET work_mem = 'lots'").unwrap();
+        assert!(session.query("SELECT name FROM users").is_err());
+        session.execute("SET work_mem = 4096").unwrap();
+
+        let join = "SELECT count(*) FROM users a JOIN users b ON a.id = b.id \
+                    JOIN users c ON b.id = c.id";
+        assert_eq!(
+            session.query(join).unwrap().rows,
+            vec![vec![Value::from(3)]]
+        );
+        assert!(!logs_contain("using greedy join ordering"));
+        session.execute("SET join_dp_limit = 2").unwrap();
+        assert_eq!(
+            session.query(join).unwrap().rows,
+            vec![vec![Value::from(3)]]
+        );
+        assert!(logs_contain("using greedy join ordering"));
+        session.execute("SET join_dp_limit = 'many'").unwrap();
+        assert!(session.query(join).is_err());
+        session.execute("SET join_dp_limit = 10").unwrap();
+
+        session.execute("SET execution_mode = 'sideways'").unwrap();
+        assert!(session.query("SELECT name FROM users").is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn check_constraints() {
+        let dir = tempdir().unwrap();
+        {
+            let instance = Instance::new_with_path(dir.path());
+            let mut session = instance.session();
+            assert!(session
+                .execute("CREATE TABLE bad (id INTEGER PRIMARY KEY CHECK (id + 1))")
+                .is_err());
+            assert!(session
+                .execute("CREATE TABLE bad (id INTEGER PRIMARY KEY CHECK (toshi > 1))")
+                .is_err());
+            session
+                .execute(
+                    "CREATE TABLE stock (id INTEGER PRIMARY KEY, \
+                     count INTEGER CHECK (count >= 0), low INTEGER, high INTEGER, \
+                     CHECK (low <= high))",
+                )
+                .unwrap();
+            session
+                .execute("INSERT INTO stock (id, count, low, high) VALUES (1, 0, 1, 2)")
+                .unwrap();
+            // Null isn't a failure
+            session
+                .execute("INSERT INTO stock (id, count, low) VALUES (2, NULL, 5)")
+                .unwrap();
+            assert!(session
+                .execute("INSERT INTO stock (id, count) VALUES (3, -1)")
+                .is_err());
+        }
+        let instance = Instance::new_with_path(dir.path());
+        let mut session = instance.session();
+        assert!(session
+            .execute("INSERT INTO stock (id, low, high) VALUES (4, 2, 1)")
+            .is_err());
+        assert_eq!(
+            session.query("SELECT count(*) FROM stock").unwrap().rows,
+            vec![vec![Value::from(2)]]
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn expressions() {
+        let dir = tempdir().unwrap();
+        let instance = Instance::new_with_path(dir.path());
+        let mut session = instance.session();
+        session
+            .execute(
+                "CREATE TABLE items (id INTEGER PRIMARY KEY, name TEXT, \
+                 price NUMERIC, stock INTEGER DEFAULT 1 + 1)",
+            )
+            .unwrap();
+        // Values can't refer to columns
+        assert!(session
+            .execute("INSERT INTO items (id, name) VALUES (1, price)")
+            .is_err());
+        session
+            .execute(
+                "INSERT INTO items (id, name, price) VALUES (1, 'apple', 1 / 4), \
+                 (2, 'Avocado', CAST('1.5' AS NUMERIC)), (1 + 2, NULLIF('x', 'x'), NULL)",
+            )
+            .unwrap();
+        session
+            .execute("INSERT INTO items (id, name, stock) VALUES (4, 'banana', -3 * 2)")
+            .unwrap();
+
+        for mode in ["row", "vectorised"] {
+            session
+                .execute(&format!("SET execution_mode = '{}'", mode))
+                .unwrap();
+            let res = session
+                .query(
+                    "SELECT id, CASE WHEN price > 1 THEN 'dear' WHEN price IS NULL THEN 'free' \
+                     ELSE 'cheap' END, COALESCE(name, '?'), stock * 2 FROM items \
+                     WHERE name ILIKE 'a%' OR id IN (3, 5) ORDER BY id",
+                )
+                .unwrap();
+            assert_eq!(
+                res.rows,
+                vec![
+                    vec![Value::from(1), "cheap".into(), "apple".into(), 4.into()],
+                    vec![Value::from(2), "dear".into(), "Avocado".into(), 4.into()],
+                    vec![Value::from(3), "free".into(), "?".into(), 4.into()],
+                ]
+            );
+            let res = session
+                .query("SELECT name FROM items WHERE stock NOT BETWEEN 0 AND 10")
+                .unwrap();
+            assert_eq!(res.rows, vec![vec![Value::from("banana")]]);
+
+            let res = session
+                .query(
+                    "SELECT upper(name), round(price, 1) FROM items \
+                     WHERE length(name) > 5 OR substr(name, 1, 1) = 'b' ORDER BY id",
+                )
+                .unwrap();
+            assert_eq!(
+                res.rows,
+                vec![
+                    vec![
+                        Value::from("AVOCADO"),
+                        Value::Number("1.5".parse().unwrap())
+                    ],
+                    vec![Value::from("BANANA"), Value::Null],
+                ]
+            );
+        }
+        // Argument types are checked against the column types when planning
+        assert!(session.query("SELECT upper(price) FROM items").is_err());
+        assert!(session.query("SELECT abs(name) FROM items").is_err());
+        assert!(session
+            .query("SELECT id FROM items WHERE length(upper(stock)) > 1")
+            .is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn commit_and_rollback() {
+        let dir = tempdir().unwrap();
+        let instance = Instance::new_with_path(dir.path());
+        fixture(&instance);
+        let mut session = instance.session();
+
+        session.execute("BEGIN").unwrap();
+        assert!(session.in_transaction());
+        assert!(session.execute("BEGIN").is_err());
+        session
+            .execute("INSERT INTO users (name) VALUES ('Daniel')")
+            .unwrap();
+        assert_eq!(row_count(&instance, "users"), 0);
+        session.execute("ROLLBACK").unwrap();
+        assert!(!session.in_transaction());
+        assert_eq!(row_count(&instance, "users"), 0);
+
+        session.execute("BEGIN").unwrap();
+        session
+            .execute("INSERT INTO users (name) VALUES ('Daniel'), ('Guido')")
+            .unwrap();
+        // A failing statement doesn't take the rest of the transaction with it
+        assert!(session
+            .execute("INSERT INTO users (toshi) VALUES ('Daniel')")
+            .is_err());
+        session.execute("COMMIT").unwrap();
+        assert_eq!(row_count(&instance, "users"), 2);
+
+        // Outside of a transaction writes are applied immediately
+        session
+            .execute("INSERT INTO users (name) VALUES ('Alan')")
+            .unwrap();
+        assert_eq!(row_count(&instance, "users"), 3);
+    }
+
+    #[test]
+    #[traced_test]
+    fn insert_from_query() {
+        let dir = tempdir().unwrap();
+        let instance = Instance::new_with_path(dir.path());
+        fixture(&instance);
+        let mut session = instance.session();
+        session
+            .execute("CREATE TABLE names (name TEXT PRIMARY KEY, length INTEGER DEFAULT 0)")
+            .unwrap();
+        session
+            .execute("INSERT INTO names (name) VALUES ('Daniel'), ('Guido')")
+            .unwrap();
+
+        // Generated ids and defaults get filled in, and it doesn't see the rows it's inserting
+        session
+            .execute("INSERT INTO users (name) SELECT name FROM names ORDER BY name")
+            .unwrap();
+        session
+            .execute("INSERT INTO users (name) SELECT name || '!' FROM users")
+            .unwrap();
+        let res = session
+            .query("SELECT id, name FROM users ORDER BY id")
+            .unwrap();
+        assert_eq!(
+            res.rows,
+            vec![
+                vec![Value::from(1), Value::from("Daniel")],
+                vec![Value::from(2), Value::from("Guido")],
+                vec![Value::from(3), Value::from("Daniel!")],
+                vec![Value::from(4), Value::from("Guido!")],
+            ]
+        );
+
+        // Goes in with the rest of the transaction
+        session.execute("BEGIN").unwrap();
+        session
+            .execute("INSERT INTO names (name, length) SELECT name, length(name) FROM users WHERE id > 2")
+            .unwrap();
+        assert_eq!(row_count(&instance, "names"), 2);
+        session.execute("COMMIT").unwrap();
+        assert_eq!(
+            session
+                .query("SELECT name, length FROM names ORDER BY name")
+                .unwrap()
+                .rows,
+            vec![
+                vec![Value::from("Daniel"), Value::from(0)],
+                vec![Value::from("Daniel!"), Value::from(7)],
+                vec![Value::from("Guido"), Value::from(0)],
+                vec![Value::from("Guido!"), Value::from(6)],
+            ]
+        );
+
+        // Big enough to take a few batches, unique values are tracked across all of them
+        session
+            .execute(
+                "CREATE TABLE codes (id INTEGER PRIMARY KEY AUTO_INCREMENT, code INTEGER UNIQUE)",
+            )
+            .unwrap();
+        session
+            .execute(
+                "INSERT INTO codes (code) \
+                 WITH RECURSIVE n (x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n WHERE x < 50) \
+                 SELECT a.x * 100 + b.x FROM n AS a, n AS b",
+            )
+            .unwrap();
+        assert_eq!(row_count(&instance, "codes"), 2500);
+        let err = session
+            .execute(
+                "INSERT INTO codes (code) SELECT code + 10000 FROM codes UNION ALL SELECT 10101",
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("UNIQUE"), "{}", err);
+        assert_eq!(row_count(&instance, "codes"), 2500);
+        // Earlier batches are flushed by the time the clash is found, they still count
+        let err = session
+            .execute("INSERT INTO codes (id, code) SELECT id + 5000, NULL FROM codes UNION ALL SELECT 5001, NULL")
+            .unwrap_err();
+        assert!(err.to_string().contains("UNIQUE"), "{}", err);
+        assert_eq!(row_count(&instance, "codes"), 2500);
+        session
+            .execute(
+                "INSERT INTO codes (code) SELECT code + 20000 FROM codes \
+                 UNION ALL SELECT code + 20000 FROM codes ON CONFLICT (code) DO NOTHING",
+            )
+            .unwrap();
+        assert_eq!(row_count(&instance, "codes"), 5000);
+    }
+
+    #[test]
+    #[traced_test]
+    fn upsert() {
+        let dir = tempdir().unwrap();
+        let instance = Instance::new_with_path(dir.path());
+        let mut session = instance.session();
+        session
+            .execute("CREATE TABLE counters (name TEXT PRIMARY KEY, email TEXT UNIQUE, hits INTEGER NOT NULL DEFAULT 1)")
+            .unwrap();
+        session
+            .execute("INSERT INTO counters (name, email) VALUES ('a', 'a@x'), ('b', 'b@x')")
+            .unwrap();
+
+        // Clashes are an error whether the row's in the table or the same statement
+        for sql in [
+            "INSERT INTO counters (name) VALUES ('a')",
+            "INSERT INTO counters (name, email) VALUES ('c', 'a@x')",
+            "INSERT INTO counters (name) VALUES ('c'), ('c')",
+            "INSERT INTO counters (name) SELECT 'c' UNION ALL SELECT 'c'",
+        ] {
+            let err = session.execute(sql).unwrap_err();
+            assert!(err.to_string().contains("UNIQUE"), "{}: {}", sql, err);
+        }
+        assert_eq!(row_count(&instance, "counters"), 2);
+
+        session
+            .execute(
+                "INSERT INTO counters (name) VALUES ('a'), ('c') ON CONFLICT (name) DO NOTHING",
+            )
+            .unwrap();
+        // Without a target any constraint counts, with one the others are still errors
+        session
+            .execute(
+                "INSERT INTO counters (name, email) VALUES ('d', 'b@x') ON CONFLICT DO NOTHING",
+            )
+            .unwrap();
+        assert!(session
+            .execute("INSERT INTO counters (name, email) VALUES ('d', 'b@x') ON CONFLICT (name) DO NOTHING")
+            .is_err());
+        assert!(session
+            .execute("INSERT INTO counters (name) VALUES ('d') ON CONFLICT (hits) DO NOTHING")
+            .is_err());
+        assert_eq!(row_count(&instance, "counters"), 3);
+
+        session
+            .execute("INSERT INTO counters (name, hits) VALUES ('a', 5), ('b', 2) ON CONFLICT (name) DO UPDATE SET hits = counters.hits + excluded.hits")
+            .unwrap();
+        session
+            .execute("INSERT INTO counters (name) VALUES ('a'), ('b') ON CONFLICT (name) DO UPDATE SET hits = hits * 10 WHERE counters.hits > 5")
+            .unwrap();
+        // Postgres won't update a row twice, or let the update clash with another row
+        assert!(session
+            .execute("INSERT INTO counters (name) VALUES ('c'), ('c') ON CONFLICT (name) DO UPDATE SET hits = 0")
+            .is_err());
+        assert!(session
+            .execute("INSERT INTO counters (name) VALUES ('c') ON CONFLICT (name) DO UPDATE SET email = 'a@x'")
+            .is_err());
+        let rows = |session: &mut Session| {
+            session
+                .query("SELECT name, email, hits FROM counters ORDER BY name")
+                .unwrap()
+                .rows
+        };
+        assert_eq!(
+            rows(&mut session),
+            vec![
+                vec![Value::from("a"), Value::from("a@x"), Value::from(60)],
+                vec![Value::from("b"), Value::from("b@x"), Value::from(3)],
+                vec![Value::from("c"), Value::Null, Value::from(1)],
+            ]
+        );
+
+        // MySQL's version checks every constraint and can hit the same row again
+        session
+            .execute("INSERT INTO counters (name, hits) VALUES ('b', 1), ('b', 2) ON DUPLICATE KEY UPDATE hits = hits + VALUES(hits)")
+            .unwrap();
+        session
+            .execute("INSERT INTO counters (name, hits) VALUES ('c', 4) AS new ON DUPLICATE KEY UPDATE hits = new.hits")
+            .unwrap();
+        session
+            .execute("INSERT INTO counters (name, email) VALUES ('e', 'a@x') ON DUPLICATE KEY UPDATE hits = 0")
+            .unwrap();
+        assert_eq!(
+            rows(&mut session),
+            vec![
+                vec![Value::from("a"), Value::from("a@x"), Value::from(0)],
+                vec![Value::from("b"), Value::from("b@x"), Value::from(6)],
+                vec![Value::from("c"), Value::Null, Value::from(4)],
+            ]
+        );
+
+        // Sees the rest of the transaction's writes
+        session.execute("BEGIN").unwrap();
+        session
+            .execute("INSERT INTO counters (name) VALUES ('t')")
+            .unwrap();
+        session
+            .execute("INSERT INTO counters (name) VALUES ('t') ON CONFLICT (name) DO UPDATE SET hits = counters.hits + 1")
+            .unwrap();
+        session.execute("COMMIT").unwrap();
+        assert_eq!(
+            session
+                .query("SELECT hits FROM counters WHERE name = 't'")
+                .unwrap()
+                .rows,
+            vec![vec![Value::from(2)]]
+        );
+
+        // Table level constraints work the same way
+        session
+            .execute("CREATE TABLE visits (page TEXT NOT NULL, day INTEGER NOT NULL, hits INTEGER DEFAULT 1, UNIQUE (page, day))")
+            .unwrap();
+        session
+            .execute("INSERT INTO visits (page, day) VALUES ('/', 1), ('/', 2)")
+            .unwrap();
+        assert!(session
+            .execute("INSERT INTO visits (page, day) VALUES ('/', 2)")
+            .is_err());
+        session
+            .execute("INSERT INTO visits (page, day) VALUES ('/', 1), ('/', 3) ON CONFLICT (day, page) DO UPDATE SET hits = visits.hits + 1")
+            .unwrap();
+        assert_eq!(
+            session
+                .query("SELECT day, hits FROM visits ORDER BY day")
+                .unwrap()
+                .rows,
+            vec![
+                vec![Value::from(1), Value::from(2)],
+                vec![Value::from(2), Value::from(1)],
+                vec![Value::from(3), Value::from(1)],
+            ]
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn returning() {
+        let dir = tempdir().unwrap();
+        let instance = Instance::new_with_path(dir.path());
+        fixture(&instance);
+        let mut session = instance.session();
+
+        // Generated values are filled in
+        let res = session
+            .query("INSERT INTO users (name) VALUES ('Daniel'), ('Guido') RETURNING id")
+            .unwrap();
+        assert_eq!(res.columns, vec!["id"]);
+        assert_eq!(res.rows, vec![vec![Value::from(1)], vec![Value::from(2)]]);
+        let res = session
+            .query("INSERT INTO users (name) SELECT name || '!' FROM users WHERE id = 1 RETURNING *, length(name) AS len")
+            .unwrap();
+        assert_eq!(res.columns, vec!["id", "name", "len"]);
+        assert_eq!(
+            res.rows,
+            vec![vec![Value::from(3), Value::from("Daniel!"), Value::from(7)]]
+        );
+
+        // Without RETURNING nothing comes back
+        let res = session
+            .query("UPDATE users SET name = 'Ada' WHERE id = 2")
+            .unwrap();
+        assert!(res.columns.is_empty() && res.rows.is_empty());
+        let res = session
+            .query("UPDATE users SET name = name || '?' WHERE id > 2 RETURNING id, name")
+            .unwrap();
+        assert_eq!(
+            res.rows,
+            vec![vec![Value::from(3), Value::from("Daniel!?")]]
+        );
+        // Changing the primary key moves the row, as long as nothing's in the way
+        let res = session
+            .query("UPDATE users SET id = 10 WHERE name = 'Ada' RETURNING *")
+            .unwrap();
+        assert_eq!(res.rows, vec![vec![Value::from(10), Value::from("Ada")]]);
+        assert!(session
+            .execute("UPDATE users SET id = 3 WHERE id = 10")
+            .is_err());
+        assert!(session.execute("UPDATE users SET name = NULL").is_err());
+        let err = session
+            .execute("DELETE FROM users WHERE id > 0 RETURNING count(*)")
+            .unwrap_err();
+        assert!(err.to_string().contains("not allowed"), "{}", err);
+
+        let res = session
+            .query("DELETE FROM users WHERE id < 3 RETURNING name")
+            .unwrap();
+        assert_eq!(res.rows, vec![vec![Value::from("Daniel")]]);
+        assert_eq!(
+            session
+                .query("SELECT id, name FROM users ORDER BY id")
+                .unwrap()
+                .rows,
+            vec![
+                vec![Value::from(3), Value::from("Daniel!?")],
+                vec![Value::from(10), Value::from("Ada")],
+            ]
+        );
+
+        // Only rows which were inserted or updated come back from an upsert
+        let res = session
+            .query("INSERT INTO users (id, name) VALUES (3, 'Alan'), (11, 'Grace') ON CONFLICT (id) DO NOTHING RETURNING id")
+            .unwrap();
+        assert_eq!(res.rows, vec![vec![Value::from(11)]]);
+        let res = session
+            .query("INSERT INTO users (id, name) VALUES (3, 'Alan') ON CONFLICT (id) DO UPDATE SET name = excluded.name RETURNING name")
+            .unwrap();
+        assert_eq!(res.rows, vec![vec![Value::from("Alan")]]);
+
+        // Rows in a table without a primary key are found by their values
+        session.execute("CREATE TABLE logs (msg TEXT)").unwrap();
+        session
+            .execute("INSERT INTO logs (msg) VALUES ('a'), ('a'), ('b')")
+            .unwrap();
+        let res = session
+            .query("UPDATE logs SET msg = 'c' WHERE msg = 'a' RETURNING msg")
+            .unwrap();
+        assert_eq!(res.rows, vec![vec![Value::from("c")]; 2]);
+        session
+            .execute("SET execution_mode = 'vectorised'")
+            .unwrap();
+        let res = session
+            .query("DELETE FROM logs WHERE msg = 'c' RETURNING upper(msg)")
+            .unwrap();
+        assert_eq!(res.rows, vec![vec![Value::from("C")]; 2]);
+        assert_eq!(row_count(&instance, "logs"), 1);
+        // RETURNING straight after the table isn't taken as its alias
+        let res = session
+            .query("INSERT INTO logs (msg) SELECT msg || '!' FROM logs RETURNING *")
+            .unwrap();
+        assert_eq!(res.rows, vec![vec![Value::from("b!")]]);
+        let res = session.query("DELETE FROM logs RETURNING msg").unwrap();
+        assert_eq!(res.rows.len(), 2);
+        assert_eq!(row_count(&instance, "logs"), 0);
+        session
+            .execute("INSERT INTO logs (msg) VALUES ('b')")
+            .unwrap();
+
+        // Works on the transaction's own rows too
+        session.execute("BEGIN").unwrap();
+        let res = session
+            .query("INSERT INTO logs (msg) VALUES ('d') RETURNING msg")
+            .unwrap();
+        assert_eq!(res.rows, vec![vec![Value::from("d")]]);
+        let res = session
+            .query("DELETE FROM logs WHERE msg IS NOT NULL RETURNING msg")
+            .unwrap();
+        assert_eq!(res.rows.len(), 2);
+        assert_eq!(row_count(&instance, "logs"), 1);
+        session.execute("COMMIT").unwrap();
+        assert_eq!(row_count(&instance, "logs"), 0);
+    }
+
+    #[test]
+    #[traced_test]
+    fn prepared_statements() {
+        let dir = tempdir().unwrap();
+        let instance = Instance::new_with_path(dir.path());
+        fixture(&instance);
+        let mut session = instance.session();
+
+        session
+            .execute("PREPARE add_user AS INSERT INTO users (name) VALUES ($1)")
+            .unwrap();
+        assert!(session
+            .execute("PREPARE add_user AS INSERT INTO users (name) VALUES ($1)")
+            .is_err());
+        session.execute("EXECUTE add_user('Daniel')").unwrap();
+        session.execute("EXECUTE add_user('Guido')").unwrap();
+        assert_eq!(row_count(&instance, "users"), 2);
+
+        assert!(session.execute("EXECUTE add_user").is_err());
+        assert!(session.execute("EXECUTE add_user(true)").is_err());
+
+        session.execute("DEALLOCATE add_user").unwrap();
+        assert!(session.execute("EXECUTE add_user('Alan')").is_err());
+        assert!(session.execute("DEALLOCATE add_user").is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn writes_lock_rows_until_transaction_ends() {
+        let dir = tempdir().unwrap();
+        let instance = Instance::new_with_path(dir.path());
+        instance
+            .session()
+            .execute("CREATE TABLE jobs (id INTEGER PRIMARY KEY, name TEXT NOT NULL)")
+            .unwrap();
+
+        let mut a = instance.session();
+        let mut b = instance.session();
+        b.execute("SET lock_timeout = 20").unwrap();
+
+        a.execute("BEGIN").unwrap();
+        a.execute("INSERT INTO jobs (id, name) VALUES (1, 'build')")
+            .unwrap();
+
+        let key = crate::storage_engine::encode_key(&[Value::Number(1.into())]).unwrap();
+        let row = RowId::new("jobs", key);
+        assert_eq!(
+            instance.locks.held_mode(a.transaction_id().unwrap(), &row),
+            Some(LockMode::Exclusive)
+        );
+
+        let err = b
+            .execute("INSERT INTO jobs (id, name) VALUES (1, 'test')")
+            .unwrap_err();
+        assert!(err.downcast_ref::<LockError>().is_some(), "{}", err);
+        // Different rows are fine
+        b.execute("INSERT INTO jobs (id, name) VALUES (2, 'test')")
+            .unwrap();
+
+        a.execute("COMMIT").unwrap();
+        // Once the lock's gone it's the row itself that's in the way
+        let err = b
+            .execute("INSERT INTO jobs (id, name) VALUES (1, 'test')")
+            .unwrap_err();
+        assert!(err.downcast_ref::<LockError>().is_none(), "{}", err);
+        assert!(err.to_string().contains("UNIQUE"), "{}", err);
+
+        // Dropping a session with an open transaction releases its locks
+        a.execute("BEGIN").unwrap();
+        a.execute("INSERT INTO jobs (id, name) VALUES (3, 'deploy')")
+            .unwrap();
+        assert!(b
+            .execute("INSERT INTO jobs (id, name) VALUES (3, 'deploy')")
+            .is_err());
+        std::mem::drop(a);
+        b.execute("INSERT INTO jobs (id, name) VALUES (3, 'deploy')")
+            .unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn select_for_update_locks_returned_rows() {
+        let dir = tempdir().unwrap();
+        let instance = Instance::new_with_path(dir.path());
+        let mut session = instance.session();
+        session
+            .execute("CREATE TABLE jobs (id INTEGER PRIMARY KEY, state TEXT NOT NULL)")
+            .unwrap();
+        session
+            .execute("INSERT INTO jobs (id, state) VALUES (1, 'new'), (2, 'new'), (3, 'new')")
+            .unwrap();
+        let next_job = "SELECT id FROM jobs WHERE state = 'new' ORDER BY id LIMIT 1";
+        let ids = |session: &mut Session, sql: &str| {
+            session
+                .query(sql)
+                .unwrap()
+                .rows
+                .into_iter()
+                .map(|row| row[0].clone())
+                .collect::<Vec<_>>()
+        };
+        let id = |n: i32| Value::Number(n.into());
+
+        let mut a = instance.session();
+        let mut b = instance.session();
+        let mut c = instance.session();
+        c.execute("SET lock_timeout = 20").unwrap();
+
+        a.execute("BEGIN").unwrap();
+        let sql = format!("{} FOR UPDATE SKIP LOCKED", next_job);
+        assert_eq!(ids(&mut a, &sql), vec![id(1)]);
+        // Only the row that was returned is locked
+        let row = |n: i32| {
+            let key = crate::storage_engine::encode_key(&[id(n)]).unwrap();
+            RowId::new("jobs", key)
+        };
+        let a_id = a.transaction_id().unwrap();
+        assert_eq!(
+            instance.locks.held_mode(a_id, &row(1)),
+            Some(LockMode::Exclusive)
+        );
+        assert_eq!(instance.locks.held_mode(a_id, &row(2)), None);
+
+        b.execute("BEGIN").unwrap();
+        assert_eq!(ids(&mut b, &sql), vec![id(2)]);
+        let sql = format!("{} FOR SHARE SKIP LOCKED", next_job);
+        assert_eq!(ids(&mut c, &sql), vec![id(3)]);
+        c.execute("SET execution_mode = 'vectorised'").unwrap();
+        assert_eq!(ids(&mut c, &sql), vec![id(3)]);
+
+        for sql in [
+            format!("{} FOR UPDATE NOWAIT", next_job),
+            "SELECT id FROM jobs WHERE id = 2 FOR SHARE NOWAIT".to_string(),
+        ] {
+            let err = c.query(&sql).unwrap_err();
+            assert!(err.downcast_ref::<LockError>().is_some(), "{}", err);
+        }
+        b.execute("COMMIT").unwrap();
+
+        // Waits for the first job, which is done by the time it gets the lock so it takes the
+        // next one
+        let waiter = thread::spawn(move || {
+            c.execute("SET lock_timeout = 5000").unwrap();
+            ids(&mut c, &format!("{} FOR UPDATE", next_job))
+        });
+        thread::sleep(Duration::from_millis(100));
+        a.execute("UPDATE jobs SET state = 'done' WHERE id = 1")
+            .unwrap();
+        a.execute("COMMIT").unwrap();
+        assert_eq!(waiter.join().unwrap(), vec![id(2)]);
+    }
+
+    #[test]
+    #[traced_test]
+    fn deadlock_victim_is_rolled_back() {
+        let dir = tempdir().unwrap();
+        let instance = Instance::new_with_path(dir.path());
+        instance
+            .session()
+            .execute("CREATE TABLE jobs (id INTEGER PRIMARY KEY, name TEXT NOT NULL)")
+            .unwrap();
+        let barrier = Arc::new(Barrier::new(2));
+
+        let spawn = |first: usize, second: usize| {
+            let mut session = instance.session();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                session.execute("BEGIN").unwrap();
+                session
+                    .execute(&format!(
+                        "INSERT INTO jobs (id, name) VALUES ({first}, 'first')"
+                    ))
+                    .unwrap();
+                barrier.wait();
+                let res = session.execute(&format!(
+                    "INSERT INTO jobs (id, name) VALUES ({second}, 'second')"
+                ));
+                match res {
+                    Ok(()) => session.execute("COMMIT").map(|_| true),
+                    Err(e) => {
+                        let lock_err = e.downcast_ref::<LockError>().unwrap();
+                        assert!(lock_err.is_retryable());
+                        assert!(!session.in_transaction());
+                        Ok(false)
+                    }
+                }
+            })
+        };
+
+        let t1 = spawn(1, 2);
+        let t2 = spawn(2, 1);
+        let committed = [t1.join().unwrap().unwrap(), t2.join().unwrap().unwrap()];
+        // Exactly one of them should have been picked as the victim
+        assert_eq!(committed.iter().filter(|x| **x).count(), 1);
+    }
+
+    #[test]
+    #[traced_test]
+    fn concurrent_updates_are_not_lost() {
+        let dir = tempdir().unwrap();
+        let instance = Instance::new_with_path(dir.path());
+        let mut session = instance.session();
+        session
+            .execute("CREATE TABLE counter (id INTEGER PRIMARY KEY, n INTEGER NOT NULL)")
+            .unwrap();
+        session
+            .execute("INSERT INTO counter (id, n) VALUES (1, 0)")
+            .unwrap();
+
+        let threads = (0..8)
+            .map(|i| {
+                let mut session = instance.session();
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        // Mix autocommit statements with explicit transactions
+                        if i % 2 == 0 {
+                            session
+                                .execute("UPDATE counter SET n = n + 1 WHERE id = 1")
+                                .unwrap();
+                        } else {
+                            session.execute("BEGIN").unwrap();
+                            session.execute("UPDATE counter SET n = n + 1").unwrap();
+                            session.execute("COMMIT").unwrap();
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let res = session.query("SELECT n FROM counter").unwrap();
+        assert_eq!(res.rows, vec![vec![Value::Number(400.into())]]);
+    }
+
+    #[test]
+    #[traced_test]
+    fn concurrent_inserts_keep_values_unique() {
+        let dir = tempdir().unwrap();
+        let instance = Instance::new_with_path(dir.path());
+        let mut session = instance.session();
+        session
+            .execute("CREATE TABLE keys (id INTEGER PRIMARY KEY)")
+            .unwrap();
+        session
+            .execute(
+                "CREATE TABLE emails (id INTEGER PRIMARY KEY AUTO_INCREMENT, email TEXT UNIQUE)",
+            )
+            .unwrap();
+
+        let threads = (0..8)
+            .map(|_| {
+                let mut session = instance.session();
+                thread::spawn(move || {
+                    let mut inserted = 0;
+                    for i in 0..200 {
+                        if session
+                            .execute(&format!("INSERT INTO keys (id) VALUES ({i})"))
+                            .is_ok()
+                        {
+                            inserted += 1;
+                        }
+                        let _ = session
+                            .execute(&format!("INSERT INTO emails (email) VALUES ('{i}@x')"));
+                    }
+                    inserted
+                })
+            })
+            .collect::<Vec<_>>();
+        let inserted = threads
+            .into_iter()
+            .map(|x| x.join().unwrap())
+            .sum::<usize>();
+
+        assert_eq!(inserted, 200);
+        assert_eq!(row_count(&instance, "keys"), 200);
+        assert_eq!(row_count(&instance, "emails"), 200);
+    }
+
+    #[test]
+    fn concurrent_upserts_do_nothing_on_conflict() {
+        let dir = tempdir().unwrap();
+        let instance = Instance::new_with_path(dir.path());
+        let mut session = instance.session();
+        session
+            .execute("CREATE TABLE u (id INTEGER PRIMARY KEY AUTO_INCREMENT, email TEXT UNIQUE)")
+            .unwrap();
+
+        let threads = (0..8)
+            .map(|_| {
+                let mut session = instance.session();
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        session
+                            .execute(&format!(
+                                "INSERT INTO u (email) VALUES ('{i}@x') ON CONFLICT (email) DO NOTHING"
+                            ))
+                            .unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(row_count(&instance, "u"), 100);
+    }
+
+    #[test]
+    #[traced_test]
+    fn cursor_runs_the_statement_as_rows_are_read() {
+        let dir = tempdir().unwrap();
+        let instance = Instance::new_with_path(dir.path());
+        let mut a = instance.session();
+        a.execute("CREATE TABLE jobs (id INTEGER PRIMARY KEY, state TEXT NOT NULL)")
+            .unwrap();
+        a.execute("INSERT INTO jobs (id, state) VALUES (1, 'new'), (2, 'new'), (3, 'new')")
+            .unwrap();
+        let mut b = instance.session();
+        b.execute("SET lock_timeout = 20").unwrap();
+        let id = |n: i32| vec![Value::Number(n.into())];
+
+        // Rows are only locked as they're read, and stay locked until the cursor's done
+        let mut cursor = a
+            .cursor("SELECT id FROM jobs ORDER BY id FOR UPDATE")
+            .unwrap();
+        assert_eq!(cursor.columns(), ["id"]);
+        assert_eq!(cursor.next().unwrap().unwrap(), id(1));
+        assert!(b
+            .execute("UPDATE jobs SET state = 'done' WHERE id = 1")
+            .is_err());
+        b.execute("UPDATE jobs SET state = 'done' WHERE id = 3")
+            .unwrap();
+        let rest = cursor.collect::<anyhow::Result<Vec<_>>>().unwrap();
+        assert_eq!(rest, vec![id(2), id(3)]);
+        b.execute("UPDATE jobs SET state = 'done' WHERE id = 1")
+            .unwrap();
+
+        // Writes are applied once the statement's finished, not if it's given up on
+        let insert = "INSERT INTO jobs (id, state) VALUES (4, 'new'), (5, 'new') RETURNING id";
+        let mut cursor = a.cursor(insert).unwrap();
+        assert_eq!(cursor.next().unwrap().unwrap(), id(4));
+        std::mem::drop(cursor);
+        assert_eq!(row_count(&instance, "jobs"), 3);
+        let mut cursor = a.cursor(insert).unwrap();
+        assert_eq!(cursor.next().unwrap().unwrap(), id(4));
+        cursor.close().unwrap();
+        assert_eq!(row_count(&instance, "jobs"), 5);
+    }
+
+    #[test]
+    fn identity() {
+        let dir = tempdir().unwrap();
+        let instance = Instance::new_with_path(dir.path());
+        let mut session = instance.session();
+        assert!(session.identity().is_none());
+        session.set_identity(Identity::new("daniel"));
+        assert_eq!(session.identity(), Some(&Identity::new("daniel")));
+    }
+}
@@ -0,0 +1,362 @@
+//! Statistics about the data in a table, gathered by `ANALYZE` and used by the optimiser to
+//! estimate how many rows each part of a plan will produce.
+use crate::types::{ColumnDescriptors, Record, Value};
+use bigdecimal::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+/// How many of the most common values we keep for each column
+pub const MAX_MOST_COMMON: usize = 10;
+/// How many buckets the histograms are split into
+pub const HISTOGRAM_BUCKETS: usize = 20;
+/// How many rows `ANALYZE` samples from each table
+pub const SAMPLE_ROWS: usize = 30_000;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TableStatistics {
+    pub row_count: u64,
+    pub columns: BTreeMap<String, ColumnStatistics>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ColumnStatistics {
+    /// Fraction of the rows where the column is null
+    pub null_fraction: f64,
+    /// Number of distinct non-null values
+    pub distinct_count: u64,
+    /// The most common values along with the fraction of rows they appear in, most common first.
+    /// Values which only appear once aren't included.
+    pub most_common: Vec<(Value, f64)>,
+    /// The bounds of an equi-depth histogram over the values which aren't in `most_common`. Each
+    /// bucket contains roughly the same number of rows.
+    pub histogram: Vec<Value>,
+}
+
+impl TableStatistics {
+    pub fn compute<'a>(
+        rows: impl IntoIterator<Item = &'a Record>,
+        columns: &ColumnDescriptors,
+    ) -> Self {
+        let mut sampler = RowSampler::new(columns);
+        for row in rows {
+            sampler.add(row);
+        }
+        sampler.finish()
+    }
+}
+
+/// Gathers table statistics from a uniform sample of at most `SAMPLE_ROWS` rows so `ANALYZE` uses
+/// bounded memory however big the table is. Rows are fed in one at a time and the sample is
+/// maintained with reservoir sampling.
+pub struct RowSampler<'a> {
+    columns: Vec<&'a str>,
+    sample: Vec<Vec<Value>>,
+    row_count: u64,
+    rng: u64,
+}
+
+impl<'a> RowSampler<'a> {
+    pub fn new(columns: &'a ColumnDescriptors) -> Self {
+        Self {
+            columns: columns.keys().map(|x| x.as_str()).collect(),
+            sample: vec![],
+            row_count: 0,
+            // Fixed seed so analyzing the same data always gives the same statistics
+            rng: 0x9e37_79b9_7f4a_7c15,
+        }
+    }
+
+    pub fn add(&mut self, row: &Record) {
+        self.row_count += 1;
+        let slot = if self.sample.len() < SAMPLE_ROWS {
+            self.sample.len()
+        } else {
+            let i = (self.next_random() % self.row_count) as usize;
+            if i >= SAMPLE_ROWS {
+                return;
+            }
+            i
+        };
+        let values = self
+            .columns
+            .iter()
+            .map(|name| {
+                row.columns
+                    .get(*name)
+                    .map(|x| x.as_ref().clone())
+                    .unwrap_or(Value::Null)
+            })
+            .collect();
+        if slot == self.sample.len() {
+            self.sample.push(values);
+        } else {
+            self.sample[slot] = values;
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64, plenty good enough for picking sample rows
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    pub fn finish(mut self) -> TableStatistics {
+        let mut columns = BTreeMap::new();
+        // Take one column at a time out of the sample so we only hold one extra copy of it
+        for (i, name) in self.columns.iter().enumerate() {
+            let values = self
+                .sample
+                .iter_mut()
+                .map(|row| std::mem::replace(&mut row[i], Value::Null))
+                .collect();
+            let stats = ColumnStatistics::from_sample(values, self.row_count);
+            columns.insert(name.to_string(), stats);
+        }
+        TableStatistics {
+            row_count: self.row_count,
+            columns,
+        }
+    }
+}
+
+impl ColumnStatistics {
+    pub fn compute(values: Vec<Value>) -> Self {
+        let rows = values.len() as u64;
+        Self::from_sample(values, rows)
+    }
+
+    /// Statistics for a column from a uniform sample of the values in a table of `rows` rows.
+    pub fn from_sample(mut values: Vec<Value>, rows: u64) -> Self {
+        let total = values.len();
+        if total == 0 {
+            return Self::default();
+        }
+        values.retain(|x| !x.is_null());
+        let null_fraction = (total - values.len()) as f64 / total as f64;
+        values.sort_by(|a, b| a.compare(b).unwrap_or(Ordering::Equal));
+
+        // Values are sorted so equal values are next to each other
+        let mut counts: Vec<(Value, usize)> = vec![];
+        for value in values {
+            match counts.last_mut() {
+                Some((last, count)) if *last == value => *count += 1,
+                _ => counts.push((value, 1)),
+            }
+        }
+        let mut distinct_count = counts.len() as u64;
+        let non_null = total as f64 * (1.0 - null_fraction);
+        if rows > total as u64 && non_null > 0.0 {
+            // Scale up the distinct values seen in the sample with the Haas-Stokes estimator
+            let n = non_null;
+            let population = rows as f64 * (1.0 - null_fraction);
+            let once = counts.iter().filter(|(_, count)| *count == 1).count() as f64;
+            let d = distinct_count as f64;
+            let estimate = n * d / (n - once + once * n / population);
+            distinct_count = estimate.clamp(d, population).round() as u64;
+        }
+
+        let mut by_frequency = counts
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, count))| *count > 1)
+            .map(|(i, (_, count))| (i, *count))
+            .collect::<Vec<_>>();
+        by_frequency.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        by_frequency.truncate(MAX_MOST_COMMON);
+        let most_common = by_frequency
+            .iter()
+            .map(|(i, count)| (counts[*i].0.clone(), *count as f64 / total as f64))
+            .collect();
+
+        let rest = counts
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !by_frequency.iter().any(|(x, _)| x == i))
+            .flat_map(|(_, (value, count))| (0..*count).map(move |_| value))
+            .collect::<Vec<_>>();
+        let histogram = if rest.len() < 2 {
+            vec![]
+        } else {
+            let buckets = HISTOGRAM_BUCKETS.min(rest.len() - 1);
+            let mut bounds: Vec<Value> = (0..=buckets)
+                .map(|i| rest[i * (rest.len() - 1) / buckets].clone())
+                .collect();
+            bounds.dedup();
+            bounds
+        };
+
+        Self {
+            null_fraction,
+            distinct_count,
+            most_common,
+            histogram,
+        }
+    }
+
+    fn most_common_fraction(&self) -> f64 {
+        self.most_common.iter().map(|(_, f)| f).sum()
+    }
+
+    /// Fraction of rows which are equal to the value.
+    pub fn eq_selectivity(&self, value: &Value) -> f64 {
+        if value.is_null() {
+            return 0.0;
+        }
+        if let Some((_, fraction)) = self.most_common.iter().find(|(v, _)| v == value) {
+            return *fraction;
+        }
+        // Otherwise assume the remaining values are evenly spread
+        let others = self
+            .distinct_count
+            .saturating_sub(self.most_common.len() as u64);
+        if others == 0 {
+            return 0.0;
+        }
+        (1.0 - self.null_fraction - self.most_common_fraction()).max(0.0) / others as f64
+    }
+
+    /// Fraction of rows which are less than the value.
+    pub fn lt_selectivity(&self, value: &Value) -> f64 {
+        let common = self
+            .most_common
+            .iter()
+            .filter(|(v, _)| v.compare(value) == Some(Ordering::Less))
+            .map(|(_, f)| f)
+            .sum::<f64>();
+        let rest = (1.0 - self.null_fraction - self.most_common_fraction()).max(0.0);
+        common + rest * self.histogram_fraction(value)
+    }
+
+    /// Where the value falls in the histogram, from 0 if it's below everything to 1 if it's above
+    /// everything.
+    fn histogram_fraction(&self, value: &Value) -> f64 {
+        let bounds = &self.histogram;
+        match bounds.len() {
+            0 => return 0.5,
+            1 => {
+                return match value.compare(&bounds[0]) {
+                    Some(Ordering::Greater) => 1.0,
+                    Some(Ordering::Less) => 0.0,
+                    _ => 0.5,
+                }
+            }
+            _ => {}
+        }
+        let buckets = (bounds.len() - 1) as f64;
+        let Some(i) = bounds
+            .iter()
+            .position(|b| value.compare(b) != Some(Ordering::Greater))
+        else {
+            return 1.0;
+        };
+        if i == 0 {
+            return 0.0;
+        }
+        // Interpolate within the bucket if we can, otherwise assume it's in the middle
+        let within = match (&bounds[i - 1], &bounds[i], value) {
+            (Value::Number(lo), Value::Number(hi), Value::Number(v)) => {
+                let (lo, hi, v) = (lo.to_f64(), hi.to_f64(), v.to_f64());
+                match (lo, hi, v) {
+                    (Some(lo), Some(hi), Some(v)) if hi > lo => (v - lo) / (hi - lo),
+                    _ => 0.5,
+                }
+            }
+            _ => 0.5,
+        };
+        ((i - 1) as f64 + within) / buckets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ColumnDescriptor;
+    use std::sync::Arc;
+
+    fn record(id: i64, city: Option<&str>) -> Record {
+        let mut columns = BTreeMap::new();
+        columns.insert("id".to_string(), Arc::new(Value::from(id)));
+        let city = city.map(Value::from).unwrap_or(Value::Null);
+        columns.insert("city".to_string(), Arc::new(city));
+        Record { columns }
+    }
+
+    #[test]
+    fn compute_statistics() {
+        let mut rows = vec![];
+        for i in 0..100 {
+            let city = match i % 10 {
+                0..=4 => Some("london"),
+                5 | 6 => Some("paris"),
+                7 => None,
+                _ => Some(["a", "b", "c", "d", "e", "f", "g", "h"][i as usize % 8]),
+            };
+            rows.push(record(i, city));
+        }
+        let mut columns = BTreeMap::new();
+        columns.insert("id".to_string(), ColumnDescriptor::default());
+        columns.insert("city".to_string(), ColumnDescriptor::default());
+        let stats = TableStatistics::compute(&rows, &columns);
+        assert_eq!(stats.row_count, 100);
+
+        let id = &stats.columns["id"];
+        assert_eq!(id.distinct_count, 100);
+        assert_eq!(id.null_fraction, 0.0);
+        assert!(id.most_common.is_empty());
+        assert_eq!(id.histogram.len(), HISTOGRAM_BUCKETS + 1);
+        assert_eq!(id.histogram[0], Value::from(0));
+        assert_eq!(id.histogram[HISTOGRAM_BUCKETS], Value::from(99));
+        let lt = id.lt_selectivity(&Value::from(25));
+        assert!((lt - 0.25).abs() < 0.02, "{}", lt);
+        assert_eq!(id.lt_selectivity(&Value::from(-5)), 0.0);
+        assert_eq!(id.lt_selectivity(&Value::from(500)), 1.0);
+        assert!((id.eq_selectivity(&Value::from(3)) - 0.01).abs() < 1e-9);
+
+        let city = &stats.columns["city"];
+        assert_eq!(city.null_fraction, 0.1);
+        assert_eq!(city.most_common[0], (Value::from("london"), 0.5));
+        assert_eq!(city.most_common[1], (Value::from("paris"), 0.2));
+        assert_eq!(city.eq_selectivity(&Value::from("london")), 0.5);
+        assert_eq!(city.eq_selectivity(&Value::Null), 0.0);
+    }
+
+    #[test]
+    fn sample_large_table() {
+        let rows = (0..100_000)
+            .map(|i| record(i, Some(["a", "b", "c", "d"][i as usize % 4])))
+            .collect::<Vec<_>>();
+        let mut columns = BTreeMap::new();
+        columns.insert("id".to_string(), ColumnDescriptor::default());
+        columns.insert("city".to_string(), ColumnDescriptor::default());
+        let mut sampler = RowSampler::new(&columns);
+        for row in &rows {
+            sampler.add(row);
+        }
+        assert_eq!(sampler.sample.len(), SAMPLE_ROWS);
+        let stats = sampler.finish();
+        assert_eq!(stats.row_count, 100_000);
+
+        let id = &stats.columns["id"];
+        assert_eq!(id.distinct_count, 100_000);
+        let lt = id.lt_selectivity(&Value::from(25_000));
+        assert!((lt - 0.25).abs() < 0.02, "{}", lt);
+
+        let city = &stats.columns["city"];
+        assert_eq!(city.distinct_count, 4);
+        for (_, fraction) in &city.most_common {
+            assert!((fraction - 0.25).abs() < 0.02, "{}", fraction);
+        }
+    }
+
+    #[test]
+    fn empty_table() {
+        let stats = TableStatistics::compute(&[], &BTreeMap::new());
+        assert_eq!(stats, TableStatistics::default());
+        let column = ColumnStatistics::compute(vec![]);
+        assert_eq!(column.eq_selectivity(&Value::from(1)), 0.0);
+        assert_eq!(column.lt_selectivity(&Value::from(1)), 0.5);
+    }
+}
@@ -1,39 +1,687 @@
+use crate::evaluator::{compile_constant, PhysicalExpr};
+use crate::expression::AggregateFunction;
+use crate::functions::{AggregateUdf, FunctionRegistry, ScalarFunction, Volatility};
+use crate::lock_manager::{RowId, WriteLocks};
+use crate::logical_plan::{PlanBuilder, Schema};
+use crate::statistics::{RowSampler, TableStatistics};
 use crate::types::*;
 use anyhow::Context;
 use bigdecimal::{BigDecimal, FromPrimitive};
 use postcard::{from_bytes, to_allocvec};
-use rocksdb::{Options, WriteBatch, DB};
+use rocksdb::{DBWithThreadMode, Direction, IteratorMode, MultiThreaded, Options, WriteBatch};
 use sqlparser::ast::Expr;
-use std::collections::BTreeMap;
+use sqlparser::dialect::GenericDialect;
+use sqlparser::parser::Parser;
+use std::collections::{BTreeMap, HashMap};
 use std::path::Path;
-use std::rc::Rc;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use tracing::debug;
-
-const TABLE_METADATA_KEY: &'static str = "__metadata__";
+use std::sync::{Arc, RwLock};
+use uuid::Uuid;
+
+const TABLE_METADATA_KEY: &str = "__metadata__";
+const TABLE_STATISTICS_KEY: &str = "__statistics__";
+const TABLE_CHECKS_KEY: &str = "__checks__";
+const TABLE_UNIQUE_KEY: &str = "__unique__";
+/// Set once the table's unique index is kept up to date, tables from before there was one have it
+/// built when the database is opened
+const TABLE_UNIQUE_INDEX_KEY: &str = "__unique_index__";
+/// All rows are stored under this prefix so they don't collide with the table metadata
+const ROW_KEY_PREFIX: &[u8] = b"row/";
+/// The values of the unique constraints other than the primary key are stored under this prefix,
+/// pointing to the key of the row which has them
+const UNIQUE_KEY_PREFIX: &[u8] = b"unique/";
+
+type DB = DBWithThreadMode<MultiThreaded>;
 
 pub struct StorageEngine {
     db: DB,
-    auto_incs: BTreeMap<Entry, AtomicUsize>,
+    auto_incs: RwLock<BTreeMap<Entry, Arc<AtomicUsize>>>,
+    /// Functions registered by whoever's embedding us, these aren't persisted so they need
+    /// registering again each time the database is opened.
+    functions: RwLock<FunctionRegistry>,
+}
+
+pub enum Action {
+    Increment(Arc<AtomicUsize>),
+    ApplyConstant(Arc<Value>),
+    /// Volatile defaults are worked out again for each row
+    Evaluate(PhysicalExpr),
 }
 
-pub enum Action<'a> {
-    Increment(&'a AtomicUsize),
-    ApplyConstant(Rc<Value>),
+/// What an insert does with a row which has the same values as an existing one for one of the
+/// table's unique constraints.
+pub struct OnConflict {
+    /// The columns of the constraint the action is for, clashes on any other constraint are still
+    /// an error. `None` covers all of them.
+    pub target: Option<Vec<String>>,
+    pub action: ConflictAction,
 }
 
-fn generate_pk_name(record: &Record, metadata: &ColumnDescriptors) -> String {
-    let mut name = String::new();
-    for key in metadata
+pub enum ConflictAction {
+    DoNothing,
+    /// The expressions are given the existing row followed by the row which was going to be
+    /// inserted. Rows not matching `selection` are left alone.
+    Update {
+        assignments: Vec<(String, PhysicalExpr)>,
+        selection: Option<PhysicalExpr>,
+        /// MySQL lets one statement update the same row more than once, Postgres doesn't
+        repeatable: bool,
+    },
+}
+
+/// Writes which have been staged but not yet applied to the database. Each table maps the row
+/// keys to the new serialized row, or `None` if the row is deleted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WriteSet {
+    tables: BTreeMap<String, BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
+}
+
+impl WriteSet {
+    pub fn put(&mut self, table: &str, key: Vec<u8>, value: Vec<u8>) {
+        self.tables
+            .entry(table.to_string())
+            .or_default()
+            .insert(key, Some(value));
+    }
+
+    pub fn delete(&mut self, table: &str, key: Vec<u8>) {
+        self.tables
+            .entry(table.to_string())
+            .or_default()
+            .insert(key, None);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tables.is_empty()
+    }
+
+    /// The table and key of every row written
+    pub fn rows(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.tables
+            .iter()
+            .flat_map(|(table, rows)| rows.keys().map(move |key| (table.as_str(), key.as_slice())))
+    }
+
+    /// The staged version of a row, `Some(None)` if it's been deleted and `None` if it hasn't
+    /// been written.
+    pub fn get(&self, table: &str, key: &[u8]) -> Option<Option<&[u8]>> {
+        self.tables
+            .get(table)?
+            .get(key)
+            .map(|value| value.as_deref())
+    }
+
+    /// Every row written to the table in key order
+    pub fn table(&self, table: &str) -> impl Iterator<Item = (&[u8], Option<&[u8]>)> {
+        self.tables
+            .get(table)
+            .into_iter()
+            .flatten()
+            .map(|(key, value)| (key.as_slice(), value.as_deref()))
+    }
+
+    /// Merge in the writes from another set, when both write the same row the writes from
+    /// `other` win.
+    pub fn extend(&mut self, other: WriteSet) {
+        for (table, rows) in other.tables {
+            self.tables.entry(table).or_default().extend(rows);
+        }
+    }
+}
+
+/// Encodes the primary key values into the key the row is stored under. Numbers are normalised so
+/// `1` and `1.0` refer to the same row.
+/// Writes which have been checked and added to a batch for the database, so a statement writing a
+/// lot of rows doesn't have to keep them all in a [`WriteSet`] until it's done. Nothing can read
+/// them back before they're committed with [`StorageEngine::commit_flushed`].
+#[derive(Default)]
+pub struct FlushedWrites {
+    batch: WriteBatch,
+}
+
+impl FlushedWrites {
+    pub fn is_empty(&self) -> bool {
+        self.batch.is_empty()
+    }
+}
+
+pub fn encode_key(values: &[Value]) -> anyhow::Result<Vec<u8>> {
+    let values = values
+        .iter()
+        .map(|x| match x {
+            Value::Number(n) => Value::Number(n.normalized()),
+            v => v.clone(),
+        })
+        .collect::<Vec<_>>();
+    let mut key = ROW_KEY_PREFIX.to_vec();
+    key.extend(to_allocvec(&values)?);
+    Ok(key)
+}
+
+/// Whether the name is taken by a built in scalar or aggregate function
+fn is_builtin(name: &str) -> bool {
+    FunctionRegistry::builtin().get(name).is_some() || AggregateFunction::from_name(name).is_some()
+}
+
+fn generate_row_key(record: &Record, metadata: &ColumnDescriptors) -> anyhow::Result<Vec<u8>> {
+    let mut values = vec![];
+    for column in metadata
         .iter()
-        .filter(|(_, desc)| !desc.primary_key)
+        .filter(|(_, desc)| desc.primary_key)
         .map(|(k, _)| k)
     {
-        name.push_str(&format!("{}/", key));
+        match record.columns.get(column) {
+            Some(v) if **v != Value::Null => values.push(v.as_ref().clone()),
+            _ => anyhow::bail!("Primary key column {} has no value", column),
+        }
+    }
+    if values.is_empty() {
+        // No primary key so every row is unique
+        let mut key = ROW_KEY_PREFIX.to_vec();
+        key.extend(Uuid::new_v4().as_bytes());
+        Ok(key)
+    } else {
+        encode_key(&values)
+    }
+}
+
+/// The sets of columns which have to be unique going by the column descriptors, the primary key
+/// first if there is one
+pub fn column_constraints(metadata: &ColumnDescriptors) -> Vec<Vec<String>> {
+    let primary_key = metadata
+        .iter()
+        .filter(|(_, desc)| desc.primary_key)
+        .map(|(k, _)| k.clone())
+        .collect::<Vec<_>>();
+    let unique = metadata
+        .iter()
+        .filter(|(_, desc)| desc.unique && !desc.primary_key)
+        .map(|(k, _)| vec![k.clone()]);
+    Some(primary_key)
+        .filter(|x| !x.is_empty())
+        .into_iter()
+        .chain(unique)
+        .collect()
+}
+
+/// Whether the two lists have the same columns, ignoring the order
+pub fn same_columns(a: &[String], b: &[String]) -> bool {
+    a.len() == b.len() && a.iter().all(|x| b.contains(x))
+}
+
+/// The values of the row in the order of the table's schema
+fn record_values(record: &Record, metadata: &ColumnDescriptors) -> Vec<Value> {
+    metadata
+        .keys()
+        .map(|column| {
+            record
+                .columns
+                .get(column)
+                .map(|x| x.as_ref().clone())
+                .unwrap_or(Value::Null)
+        })
+        .collect()
+}
+
+/// Where the row with the value for the unique constraint is kept in the table's unique index
+fn unique_index_key(columns: &[String], value: &[u8]) -> Vec<u8> {
+    let mut key = UNIQUE_KEY_PREFIX.to_vec();
+    key.extend(format!("({})/", columns.join(", ")).as_bytes());
+    key.extend(value);
+    key
+}
+
+/// The encoded values of a unique constraint mapped to the key of the row which has them
+type ConstraintValues = HashMap<Vec<u8>, Vec<u8>>;
+
+/// A table's rows as an insert sees them, its own writes over the transaction's over the committed
+/// rows. Only the unique values of the staged rows are kept here, the committed rows holding a
+/// value are looked up in the table's unique index.
+///
+/// With `locks` each row's key and unique values are locked before looking for clashes. Nobody
+/// else can commit a row with a value we hold the lock for, so once we have it what we look up
+/// stays true until we commit.
+struct UniqueIndex<'a> {
+    storage: &'a StorageEngine,
+    table: &'a str,
+    pending: Option<&'a WriteSet>,
+    locks: Option<WriteLocks<'a>>,
+    primary_key: Option<Vec<String>>,
+    /// The other constraints, with the key of the staged row which has each set of values
+    secondary: Vec<(Vec<String>, ConstraintValues)>,
+    /// Keys of the rows which have been flushed, and whether there's a row there
+    flushed: HashMap<Vec<u8>, bool>,
+}
+
+impl<'a> UniqueIndex<'a> {
+    fn new(
+        storage: &'a StorageEngine,
+        table: &'a str,
+        metadata: &ColumnDescriptors,
+        pending: Option<&'a WriteSet>,
+        locks: Option<WriteLocks<'a>>,
+        writes: &WriteSet,
+    ) -> anyhow::Result<Self> {
+        let mut constraints = storage.unique_constraints(table)?;
+        let primary_key = metadata
+            .values()
+            .any(|desc| desc.primary_key)
+            .then(|| constraints.remove(0));
+        let mut index = Self {
+            storage,
+            table,
+            pending,
+            locks,
+            primary_key,
+            secondary: constraints
+                .into_iter()
+                .map(|x| (x, HashMap::new()))
+                .collect(),
+            flushed: HashMap::new(),
+        };
+        if !index.secondary.is_empty() {
+            let mut rows = BTreeMap::new();
+            for (key, value) in pending.into_iter().flat_map(|x| x.table(table)) {
+                rows.insert(key, value);
+            }
+            for (key, value) in writes.table(table) {
+                rows.insert(key, value);
+            }
+            for (key, value) in rows {
+                if let Some(value) = value {
+                    index.add(key, &from_bytes(value)?)?;
+                }
+            }
+        }
+        Ok(index)
+    }
+
+    fn get(&self, key: &[u8], writes: &WriteSet) -> anyhow::Result<Option<Record>> {
+        match self.staged(key, writes) {
+            Some(Some(bytes)) => Ok(Some(from_bytes(bytes)?)),
+            Some(None) => Ok(None),
+            None => self.storage.get_row(self.table, key),
+        }
+    }
+
+    fn exists(&self, key: &[u8], writes: &WriteSet) -> anyhow::Result<bool> {
+        if let Some(row) = writes.get(self.table, key) {
+            return Ok(row.is_some());
+        }
+        if let Some(&row) = self.flushed.get(key) {
+            return Ok(row);
+        }
+        Ok(self.get(key, writes)?.is_some())
+    }
+
+    fn staged<'b>(&'b self, key: &[u8], writes: &'b WriteSet) -> Option<Option<&'b [u8]>> {
+        writes
+            .get(self.table, key)
+            .or_else(|| self.pending.and_then(|x| x.get(self.table, key)))
+    }
+
+    /// Rows with a null in the constraint never clash
+    fn constraint_key(columns: &[String], record: &Record) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut values = vec![];
+        for column in columns {
+            match record.columns.get(column) {
+                Some(v) if **v != Value::Null => values.push(v.as_ref().clone()),
+                _ => return Ok(None),
+            }
+        }
+        encode_key(&values).map(Some)
+    }
+
+    fn lock_key(&self, key: &[u8]) -> anyhow::Result<()> {
+        if let Some(locks) = &self.locks {
+            locks.lock(&RowId::new(self.table, key))?;
+        }
+        Ok(())
+    }
+
+    /// Locks the key a row is being written to and its values for the unique constraints, has to
+    /// happen before looking for clashes.
+    fn lock(&self, key: &[u8], record: &Record) -> anyhow::Result<()> {
+        let Some(locks) = self.locks else {
+            return Ok(());
+        };
+        locks.lock(&RowId::new(self.table, key))?;
+        for (columns, _) in &self.secondary {
+            if let Some(value) = Self::constraint_key(columns, record)? {
+                locks.lock(&RowId::unique_value(self.table, columns, value))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The key of the row which has the value for one of the secondary constraints
+    fn find(&self, i: usize, value: &[u8], writes: &WriteSet) -> anyhow::Result<Option<Vec<u8>>> {
+        let (columns, rows) = &self.secondary[i];
+        if let Some(key) = rows.get(value) {
+            return Ok(Some(key.clone()));
+        }
+        let Some(key) = self.storage.unique_row(self.table, columns, value)? else {
+            return Ok(None);
+        };
+        // Committed rows we've written over only have the values they're staged with
+        let written = self.staged(&key, writes).is_some() || self.flushed.contains_key(&key);
+        Ok((!written).then_some(key))
+    }
+
+    fn add(&mut self, key: &[u8], record: &Record) -> anyhow::Result<()> {
+        for (columns, rows) in &mut self.secondary {
+            if let Some(value) = Self::constraint_key(columns, record)? {
+                rows.insert(value, key.to_vec());
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, record: &Record) -> anyhow::Result<()> {
+        for (columns, rows) in &mut self.secondary {
+            if let Some(value) = Self::constraint_key(columns, record)? {
+                rows.remove(&value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds a row other than `own` which clashes with the record stored under `key`, on one of
+    /// the constraints picked by `filter`. Gives the constraint's columns and the row's key.
+    fn clash(
+        &self,
+        record: &Record,
+        key: &[u8],
+        own: Option<&[u8]>,
+        writes: &WriteSet,
+        filter: impl Fn(&[String]) -> bool,
+    ) -> anyhow::Result<Option<(&[String], Vec<u8>)>> {
+        if let Some(columns) = self.primary_key.as_ref().filter(|x| filter(x)) {
+            if Some(key) != own && self.exists(key, writes)? {
+                return Ok(Some((columns, key.to_vec())));
+            }
+        }
+        for (i, (columns, _)) in self.secondary.iter().enumerate() {
+            if !filter(columns) {
+                continue;
+            }
+            let Some(value) = Self::constraint_key(columns, record)? else {
+                continue;
+            };
+            match self.find(i, &value, writes)? {
+                Some(other) if Some(other.as_slice()) != own => return Ok(Some((columns, other))),
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Checks rows against a table's constraints as they're staged
+struct TableWriter<'a> {
+    table: &'a str,
+    metadata: ColumnDescriptors,
+    checks: Vec<(Expr, PhysicalExpr)>,
+    has_primary_key: bool,
+    index: UniqueIndex<'a>,
+}
+
+impl<'a> TableWriter<'a> {
+    fn new(
+        storage: &'a StorageEngine,
+        table: &'a str,
+        pending: Option<&'a WriteSet>,
+        locks: Option<WriteLocks<'a>>,
+        writes: &WriteSet,
+    ) -> anyhow::Result<Self> {
+        let metadata = storage.table_metadata(table)?;
+        let checks = storage
+            .table_checks(table)?
+            .into_iter()
+            .map(|check| {
+                let compiled = storage.compile_check(table, &metadata, &check)?;
+                Ok((check, compiled))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self {
+            table,
+            has_primary_key: metadata.values().any(|desc| desc.primary_key),
+            index: UniqueIndex::new(storage, table, &metadata, pending, locks, writes)?,
+            metadata,
+            checks,
+        })
+    }
+
+    fn check_row(&self, record: &Record) -> anyhow::Result<()> {
+        if self.checks.is_empty() {
+            return Ok(());
+        }
+        let row = record_values(record, &self.metadata);
+        // Like WHERE except null counts as passing
+        for (check, expr) in &self.checks {
+            match expr.evaluate(&row)? {
+                Value::Boolean(false) => {
+                    anyhow::bail!("Row in {} violates CHECK constraint {}", self.table, check)
+                }
+                Value::Boolean(true) | Value::Null => {}
+                v => anyhow::bail!("CHECK constraint {} gave {}", check, v),
+            }
+        }
+        Ok(())
+    }
+
+    fn violation(&self, columns: &[String]) -> anyhow::Error {
+        anyhow::anyhow!(
+            "Row in {} violates UNIQUE constraint ({})",
+            self.table,
+            columns.join(", ")
+        )
+    }
+
+    fn existing(&self, key: &[u8], writes: &WriteSet) -> anyhow::Result<Record> {
+        self.index
+            .get(key, writes)?
+            .ok_or_else(|| anyhow::anyhow!("Row in {} has gone", self.table))
+    }
+
+    /// Stages a new row which has already been checked, as long as it doesn't clash with another
+    fn insert(
+        &mut self,
+        key: Vec<u8>,
+        record: &Record,
+        writes: &mut WriteSet,
+    ) -> anyhow::Result<()> {
+        if let Some((columns, _)) = self.index.clash(record, &key, None, writes, |_| true)? {
+            return Err(self.violation(columns));
+        }
+        self.index.add(&key, record)?;
+        writes.put(self.table, key, to_allocvec(record)?);
+        Ok(())
+    }
+
+    fn update(
+        &mut self,
+        key: Vec<u8>,
+        existing: &Record,
+        updated: Record,
+        writes: &mut WriteSet,
+    ) -> anyhow::Result<Record> {
+        for (name, value) in updated.columns.iter() {
+            if !self.metadata[name].value_matches_type(value) {
+                anyhow::bail!("Value for {} doesn't match column type", name);
+            }
+        }
+        self.check_row(&updated)?;
+        // Without a primary key the row stays where it is, otherwise it can move
+        let new_key = match self.has_primary_key {
+            true => generate_row_key(&updated, &self.metadata)?,
+            false => key.clone(),
+        };
+        self.index.lock(&new_key, &updated)?;
+        let own = Some(key.as_slice());
+        if let Some((columns, _)) = self
+            .index
+            .clash(&updated, &new_key, own, writes, |_| true)?
+        {
+            return Err(self.violation(columns));
+        }
+        self.index.remove(existing)?;
+        self.index.add(&new_key, &updated)?;
+        if new_key != key {
+            writes.delete(self.table, key);
+        }
+        writes.put(self.table, new_key, to_allocvec(&updated)?);
+        Ok(updated)
+    }
+}
+
+/// Stages the rows of an insert, see [`StorageEngine::insert_writer`]
+pub struct InsertWriter<'a> {
+    writer: TableWriter<'a>,
+    /// How to fill in each column the insert doesn't give a value for
+    value_actions: BTreeMap<String, Action>,
+    on_conflict: Option<&'a OnConflict>,
+}
+
+impl InsertWriter<'_> {
+    /// Checks and stages the rows, giving them back as they're stored. `writes` should be the
+    /// same set each time.
+    pub fn stage(
+        &mut self,
+        records: impl IntoIterator<Item = Record>,
+        writes: &mut WriteSet,
+    ) -> anyhow::Result<Vec<Record>> {
+        let mut stored = vec![];
+
+        for mut record in records {
+            // validate record
+            for (name, value) in record.columns.iter() {
+                if !self.writer.metadata[name].value_matches_type(value) {
+                    anyhow::bail!("Value for {} doesn't match column type", name);
+                }
+            }
+
+            // Add things like missing default fields
+            for (column, action) in &self.value_actions {
+                let value = match action {
+                    Action::Increment(val) => {
+                        let value = val.fetch_add(1, Ordering::SeqCst);
+                        Arc::new(Value::Number(BigDecimal::from_usize(value).unwrap()))
+                    }
+                    Action::ApplyConstant(con) => con.clone(),
+                    Action::Evaluate(expr) => Arc::new(expr.evaluate(&[])?),
+                };
+                record.columns.insert(column.clone(), value);
+            }
+
+            self.writer.check_row(&record)?;
+
+            let key = generate_row_key(&record, &self.writer.metadata)?;
+            self.writer.index.lock(&key, &record)?;
+
+            let conflict = match self.on_conflict {
+                Some(on_conflict) => self
+                    .conflict(on_conflict, &record, &key, writes)?
+                    .map(|x| (on_conflict, x)),
+                None => None,
+            };
+            let Some((on_conflict, existing_key)) = conflict else {
+                self.writer.insert(key, &record, writes)?;
+                stored.push(record);
+                continue;
+            };
+            let ConflictAction::Update {
+                assignments,
+                selection,
+                repeatable,
+            } = &on_conflict.action
+            else {
+                continue;
+            };
+            if !repeatable && writes.get(self.writer.table, &existing_key).is_some() {
+                anyhow::bail!(
+                    "ON CONFLICT DO UPDATE can't change the same row of {} twice",
+                    self.writer.table
+                );
+            }
+            let existing = self.writer.existing(&existing_key, writes)?;
+            let mut row = record_values(&existing, &self.writer.metadata);
+            row.extend(record_values(&record, &self.writer.metadata));
+            if let Some(selection) = selection {
+                if selection.evaluate(&row)? != Value::Boolean(true) {
+                    continue;
+                }
+            }
+            let mut updated = existing.clone();
+            for (column, expr) in assignments {
+                updated
+                    .columns
+                    .insert(column.clone(), Arc::new(expr.evaluate(&row)?));
+            }
+            stored.push(
+                self.writer
+                    .update(existing_key, &existing, updated, writes)?,
+            );
+        }
+        Ok(stored)
+    }
+
+    /// Moves what's been staged to `flushed`, only the keys of the table's rows are kept. Rows an
+    /// `ON CONFLICT DO UPDATE` could need again are left staged.
+    pub fn flush(
+        &mut self,
+        writes: &mut WriteSet,
+        flushed: &mut FlushedWrites,
+    ) -> anyhow::Result<()> {
+        if let Some(OnConflict {
+            action: ConflictAction::Update { .. },
+            ..
+        }) = self.on_conflict
+        {
+            return Ok(());
+        }
+        let writes = std::mem::take(writes);
+        let index = &mut self.writer.index;
+        for (key, row) in writes.table(self.writer.table) {
+            index.flushed.insert(key.to_vec(), row.is_some());
+        }
+        index.storage.flush(writes, flushed)
+    }
+
+    /// The key of the row the record clashes with on the constraints `on_conflict` is for. Once
+    /// we hold the locks on its values nobody can add another row with them, but whoever has the
+    /// row we've found locked could still be changing it. So before it's updated it's looked up
+    /// again once we have its lock too.
+    fn conflict(
+        &self,
+        on_conflict: &OnConflict,
+        record: &Record,
+        key: &[u8],
+        writes: &WriteSet,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        // Only the target constraint is handled, the others are checked as normal on insert
+        let is_target = |columns: &[String]| match &on_conflict.target {
+            Some(target) => same_columns(target, columns),
+            None => true,
+        };
+        let index = &self.writer.index;
+        let mut found = index
+            .clash(record, key, None, writes, is_target)?
+            .map(|(_, x)| x);
+        if let ConflictAction::DoNothing = on_conflict.action {
+            return Ok(found);
+        }
+        while let Some(existing_key) = found {
+            index.lock_key(&existing_key)?;
+            found = index
+                .clash(record, key, None, writes, is_target)?
+                .map(|(_, x)| x);
+            if found.as_ref() == Some(&existing_key) {
+                break;
+            }
+        }
+        Ok(found)
     }
-    name.pop();
-    assert!(!name.is_empty());
-    name
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, Ord, PartialOrd)]
@@ -42,6 +690,12 @@ pub struct Entry {
     column: String,
 }
 
+impl Default for StorageEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl StorageEngine {
     pub fn new() -> Self {
         Self::new_with_path("_dechib_db")
@@ -54,10 +708,66 @@ impl StorageEngine {
             Ok(cf) => DB::open_cf(&opts, path, &cf).expect("Failed to load storage"),
             Err(_) => DB::open(&opts, path).expect("Failed to create storage"),
         };
-        Self {
+        let storage = Self {
             db,
-            auto_incs: BTreeMap::new(),
+            auto_incs: RwLock::new(BTreeMap::new()),
+            functions: RwLock::default(),
+        };
+        storage
+            .build_unique_indexes()
+            .expect("Failed to build unique indexes");
+        storage
+    }
+
+    /// Indexes the rows of any table which doesn't have its unique index yet
+    fn build_unique_indexes(&self) -> anyhow::Result<()> {
+        for table in self.tables()? {
+            let handle = self.db.cf_handle(&table).unwrap();
+            if self
+                .db
+                .get_pinned_cf(&handle, TABLE_UNIQUE_INDEX_KEY)?
+                .is_some()
+            {
+                continue;
+            }
+            let constraints = self.secondary_constraints(&table)?;
+            let mut batch = WriteBatch::default();
+            for (key, record) in self.scan_rows(&table)? {
+                for columns in &constraints {
+                    if let Some(value) = UniqueIndex::constraint_key(columns, &record)? {
+                        batch.put_cf(&handle, unique_index_key(columns, &value), &key);
+                    }
+                }
+            }
+            batch.put_cf(&handle, TABLE_UNIQUE_INDEX_KEY, []);
+            self.db.write(batch)?;
         }
+        Ok(())
+    }
+
+    /// Makes the function callable from SQL. Built in functions can't be replaced.
+    pub fn register_function(&self, func: ScalarFunction) -> anyhow::Result<()> {
+        if is_builtin(&func.name) {
+            anyhow::bail!("Function {} already exists", func.name);
+        }
+        self.functions.write().unwrap().register(func)
+    }
+
+    pub fn register_aggregate(&self, func: AggregateUdf) -> anyhow::Result<()> {
+        if is_builtin(&func.name) {
+            anyhow::bail!("Function {} already exists", func.name);
+        }
+        self.functions.write().unwrap().register_aggregate(func)
+    }
+
+    pub fn function(&self, name: &str) -> Option<Arc<ScalarFunction>> {
+        FunctionRegistry::builtin()
+            .get(name)
+            .or_else(|| self.functions.read().unwrap().get(name))
+    }
+
+    pub fn aggregate(&self, name: &str) -> Option<Arc<AggregateUdf>> {
+        self.functions.read().unwrap().aggregate(name)
     }
 
     pub fn handle(&self) -> &DB {
@@ -69,13 +779,13 @@ impl StorageEngine {
     }
 
     fn validate_table_options(&self, create_table: &CreateTableOptions) -> anyhow::Result<()> {
-        for (column, props) in create_table
+        for props in create_table
             .columns
-            .iter()
-            .filter(|(_, x)| x.foreign_key.is_some())
+            .values()
+            .filter(|x| x.foreign_key.is_some())
         {
             if let Some((table, col)) = props.foreign_key.as_ref() {
-                let table_metadata = self.table_metadata(&table)?;
+                let table_metadata = self.table_metadata(table)?;
                 if let Some(desc) = table_metadata.get(col) {
                     if !desc.primary_key {
                         anyhow::bail!("Foreign key {}.{} must refer to a primary key", table, col);
@@ -85,10 +795,38 @@ impl StorageEngine {
                 }
             }
         }
+        for check in &create_table.checks {
+            self.compile_check(&create_table.name, &create_table.columns, check)?;
+        }
         Ok(())
     }
 
-    pub fn create_table(&mut self, create_table: &CreateTableOptions) -> anyhow::Result<()> {
+    /// Checks have to give the same answer every time for a row or we couldn't trust the rows
+    /// already in the table, so they can only call immutable functions.
+    fn compile_check(
+        &self,
+        table: &str,
+        metadata: &ColumnDescriptors,
+        check: &Expr,
+    ) -> anyhow::Result<PhysicalExpr> {
+        let schema = Schema::for_table(table, metadata);
+        let expr = PlanBuilder::new(self).lower_expr(check, &schema, false)?;
+        if !matches!(
+            TypeClass::of(&expr.data_type(&schema)),
+            None | Some(TypeClass::Boolean)
+        ) {
+            anyhow::bail!("CHECK constraint {} must be a boolean", check);
+        }
+        if expr.volatility() != Volatility::Immutable {
+            anyhow::bail!(
+                "CHECK constraint {} can only call immutable functions",
+                check
+            );
+        }
+        PhysicalExpr::new(&expr, &schema)
+    }
+
+    pub fn create_table(&self, create_table: &CreateTableOptions) -> anyhow::Result<()> {
         self.validate_table_options(create_table)?;
         // So each table should be a column family so operations that operate on different tables
         // can happen concurrently (my current understanding)
@@ -104,18 +842,34 @@ impl StorageEngine {
             TABLE_METADATA_KEY,
             to_allocvec(&create_table.columns)?,
         )?;
+        // Stored as SQL for the same reason as column defaults
+        let checks = create_table
+            .checks
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        self.db
+            .put_cf(&handle, TABLE_CHECKS_KEY, to_allocvec(&checks)?)?;
+        self.db.put_cf(
+            &handle,
+            TABLE_UNIQUE_KEY,
+            to_allocvec(&create_table.unique)?,
+        )?;
+        self.db.put_cf(&handle, TABLE_UNIQUE_INDEX_KEY, [])?;
 
-        for (column, props) in create_table
+        let mut auto_incs = self.auto_incs.write().unwrap();
+        for column in create_table
             .columns
             .iter()
             .filter(|(_, v)| v.auto_increment)
+            .map(|(k, _)| k)
         {
-            let initial = AtomicUsize::new(1);
+            let initial = Arc::new(AtomicUsize::new(1));
             let entry = Entry {
                 table: name.to_string(),
                 column: column.to_string(),
             };
-            self.auto_incs.insert(entry, initial);
+            auto_incs.insert(entry, initial);
         }
 
         Ok(())
@@ -134,82 +888,335 @@ impl StorageEngine {
         Ok(res)
     }
 
-    pub fn insert_rows(&mut self, insert_op: &InsertOptions) -> anyhow::Result<()> {
+    /// The table's `CHECK` constraints
+    pub fn table_checks(&self, name: &str) -> anyhow::Result<Vec<Expr>> {
+        let handle = self
+            .db
+            .cf_handle(name)
+            .with_context(|| format!("No table {} exists", name))?;
+        let checks: Vec<String> = match self.db.get_pinned_cf(&handle, TABLE_CHECKS_KEY)? {
+            Some(bytes) => from_bytes(&bytes)?,
+            None => vec![],
+        };
+        checks
+            .iter()
+            .map(|x| {
+                Ok(Parser::new(&GenericDialect {})
+                    .try_with_sql(x)?
+                    .parse_expr()?)
+            })
+            .collect()
+    }
+
+    /// The sets of columns which have to be unique, the primary key first if there is one
+    pub fn unique_constraints(&self, name: &str) -> anyhow::Result<Vec<Vec<String>>> {
+        let mut constraints = column_constraints(&self.table_metadata(name)?);
+        let handle = self.db.cf_handle(name).unwrap();
+        let unique: Vec<Vec<String>> = match self.db.get_pinned_cf(&handle, TABLE_UNIQUE_KEY)? {
+            Some(bytes) => from_bytes(&bytes)?,
+            None => vec![],
+        };
+        for columns in unique {
+            if !constraints.iter().any(|x| same_columns(x, &columns)) {
+                constraints.push(columns);
+            }
+        }
+        Ok(constraints)
+    }
+
+    /// The unique constraints which have an index, rows are already stored by the primary key
+    fn secondary_constraints(&self, name: &str) -> anyhow::Result<Vec<Vec<String>>> {
+        let mut constraints = self.unique_constraints(name)?;
+        if self.table_metadata(name)?.values().any(|x| x.primary_key) {
+            constraints.remove(0);
+        }
+        Ok(constraints)
+    }
+
+    /// The key of the committed row with the value for the unique constraint, if there is one
+    fn unique_row(
+        &self,
+        table: &str,
+        columns: &[String],
+        value: &[u8],
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let handle = self
+            .db
+            .cf_handle(table)
+            .with_context(|| format!("No table {} exists", table))?;
+        Ok(self.db.get_cf(&handle, unique_index_key(columns, value))?)
+    }
+
+    /// The names of all the tables in the database
+    pub fn tables(&self) -> anyhow::Result<Vec<String>> {
+        let mut tables = DB::list_cf(&Options::default(), self.db.path())?;
+        tables.retain(|x| x != "default");
+        Ok(tables)
+    }
+
+    /// Every committed row in the table along with the key it's stored under.
+    pub fn scan_rows(&self, table: &str) -> anyhow::Result<Vec<(Vec<u8>, Record)>> {
+        let handle = self
+            .db
+            .cf_handle(table)
+            .with_context(|| format!("No table {} exists", table))?;
+        let mut rows = vec![];
+        for entry in self.db.prefix_iterator_cf(&handle, ROW_KEY_PREFIX) {
+            let (key, value) = entry?;
+            if !key.starts_with(ROW_KEY_PREFIX) {
+                break;
+            }
+            rows.push((key.to_vec(), from_bytes(&value)?));
+        }
+        Ok(rows)
+    }
+
+    /// Up to `limit` committed rows in key order, starting after the key `after` or at the start of
+    /// the table. Lets a scan read through a table a chunk at a time.
+    pub fn scan_batch(
+        &self,
+        table: &str,
+        after: Option<&[u8]>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(Vec<u8>, Record)>> {
+        let handle = self
+            .db
+            .cf_handle(table)
+            .with_context(|| format!("No table {} exists", table))?;
+        let start = after.unwrap_or(ROW_KEY_PREFIX);
+        let mut rows = vec![];
+        for entry in self
+            .db
+            .iterator_cf(&handle, IteratorMode::From(start, Direction::Forward))
+        {
+            let (key, value) = entry?;
+            if !key.starts_with(ROW_KEY_PREFIX) || rows.len() == limit {
+                break;
+            }
+            if Some(key.as_ref()) == after {
+                continue;
+            }
+            rows.push((key.to_vec(), from_bytes(&value)?));
+        }
+        Ok(rows)
+    }
+
+    /// Looks up a single committed row by its key.
+    pub fn get_row(&self, table: &str, key: &[u8]) -> anyhow::Result<Option<Record>> {
+        let handle = self
+            .db
+            .cf_handle(table)
+            .with_context(|| format!("No table {} exists", table))?;
+        match self.db.get_pinned_cf(&handle, key)? {
+            Some(bytes) => Ok(Some(from_bytes(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The statistics from the last time the table was analyzed, if it ever has been.
+    pub fn table_statistics(&self, name: &str) -> anyhow::Result<Option<TableStatistics>> {
+        let handle = self
+            .db
+            .cf_handle(name)
+            .with_context(|| format!("No table {} exists", name))?;
+        match self.db.get_pinned_cf(&handle, TABLE_STATISTICS_KEY)? {
+            Some(bytes) => Ok(Some(from_bytes(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Gathers statistics on the table's current contents and stores them alongside the table
+    /// metadata.
+    pub fn analyze(&self, name: &str) -> anyhow::Result<TableStatistics> {
+        let metadata = self.table_metadata(name)?;
+        let handle = self
+            .db
+            .cf_handle(name)
+            .with_context(|| format!("No table {} exists", name))?;
+        // Stream the rows through the sampler rather than loading the whole table
+        let mut sampler = RowSampler::new(&metadata);
+        for entry in self.db.prefix_iterator_cf(&handle, ROW_KEY_PREFIX) {
+            let (key, value) = entry?;
+            if !key.starts_with(ROW_KEY_PREFIX) {
+                break;
+            }
+            sampler.add(&from_bytes(&value)?);
+        }
+        let stats = sampler.finish();
+        self.db
+            .put_cf(&handle, TABLE_STATISTICS_KEY, to_allocvec(&stats)?)?;
+        Ok(stats)
+    }
+
+    /// Inserts the rows and immediately commits them.
+    pub fn insert_rows(&self, insert_op: &InsertOptions) -> anyhow::Result<()> {
+        let mut writes = WriteSet::default();
+        self.stage_insert(insert_op, None, None, None, &mut writes)?;
+        self.commit(writes)
+    }
+
+    /// Validates the rows being inserted and fills in any generated values, adding the resulting
+    /// rows to `writes` and giving them back. Nothing is written to the database until the write
+    /// set is committed.
+    ///
+    /// Rows clashing on a unique constraint with a committed row, one in `pending` or one already
+    /// staged in `writes` are an error unless `on_conflict` says otherwise. `writes` should only
+    /// hold the statement's own writes and `pending` the rest of the transaction's.
+    ///
+    /// With `locks` the rows and their unique values are locked before they're checked, so two
+    /// transactions can't both insert the same value.
+    pub fn stage_insert(
+        &self,
+        insert_op: &InsertOptions,
+        on_conflict: Option<&OnConflict>,
+        pending: Option<&WriteSet>,
+        locks: Option<WriteLocks>,
+        writes: &mut WriteSet,
+    ) -> anyhow::Result<Vec<Record>> {
+        let mut writer = self.insert_writer(
+            &insert_op.table,
+            &insert_op.columns,
+            on_conflict,
+            pending,
+            locks,
+            writes,
+        )?;
+        writer.stage(insert_op.records(), writes)
+    }
+
+    /// Like [`StorageEngine::stage_insert`] but the rows can be staged a batch at a time.
+    pub fn insert_writer<'a>(
+        &'a self,
+        table: &'a str,
+        columns: &[String],
+        on_conflict: Option<&'a OnConflict>,
+        pending: Option<&'a WriteSet>,
+        locks: Option<WriteLocks<'a>>,
+        writes: &WriteSet,
+    ) -> anyhow::Result<InsertWriter<'a>> {
         // We should validate our metadata against our column data types!
-        let metadata = self.table_metadata(&insert_op.table)?;
+        let metadata = self.table_metadata(table)?;
 
         // First lets just go over and make sure column names match etc
-        if let Some(bad_column) = insert_op
-            .columns
-            .iter()
-            .find(|x| !metadata.contains_key(x.as_str()))
-        {
+        if let Some(bad_column) = columns.iter().find(|x| !metadata.contains_key(x.as_str())) {
             anyhow::bail!("Column {} not present in table", bad_column);
         }
 
+        let auto_incs = self.auto_incs.read().unwrap();
         let mut value_actions = BTreeMap::new();
 
         for (column, desc) in metadata.iter() {
             if desc.needs_value() {
                 // Now find missing columns that we need!
-                if !insert_op.columns.contains(column) {
+                if !columns.contains(column) {
                     anyhow::bail!("Required column {} is missing", column)
                 }
-            } else if !insert_op.columns.contains(column) && desc.should_generate() {
-                if let Some(Expr::Value(val)) = &desc.default {
-                    value_actions.insert(
-                        column,
-                        Action::ApplyConstant(Rc::new(Value::try_from(val.clone())?)),
-                    );
-                } else if desc.default.is_some() {
-                    anyhow::bail!("Unsupported default expression: {:?}", desc.default);
+            } else if !columns.contains(column) && desc.should_generate() {
+                if let Some(default) = &desc.default {
+                    let (expr, volatility) = compile_constant(default, self)?;
+                    let action = if volatility == Volatility::Volatile {
+                        Action::Evaluate(expr)
+                    } else {
+                        Action::ApplyConstant(Arc::new(expr.evaluate(&[])?))
+                    };
+                    value_actions.insert(column.clone(), action);
                 } else if desc.auto_increment {
                     let entry = Entry {
-                        table: insert_op.table.to_string(),
+                        table: table.to_string(),
                         column: column.to_string(),
                     };
-                    let auto_inc = self
-                        .auto_incs
+                    let auto_inc = auto_incs
                         .get(&entry)
                         .with_context(|| format!("No auto increment support for {}", column))?;
-                    value_actions.insert(column, Action::Increment(auto_inc));
+                    value_actions.insert(column.clone(), Action::Increment(auto_inc.clone()));
                 } else {
                     anyhow::bail!("Unsure how to generate value for {}", column);
                 }
             }
         }
 
-        // handle must exist if we got metadata
-        let mut transaction = WriteBatch::default();
-        let handle = self.db.cf_handle(&insert_op.table).unwrap();
+        Ok(InsertWriter {
+            writer: TableWriter::new(self, table, pending, locks, writes)?,
+            value_actions,
+            on_conflict,
+        })
+    }
 
-        for mut record in insert_op.records() {
-            // validate record
-            for (name, value) in record.columns.iter() {
-                if !metadata[name].value_matches_type(value) {
-                    anyhow::bail!("Value for {} doesn't match column type", name);
-                }
-            }
+    /// Replaces each row stored under the key with the new version, checking them the same as
+    /// inserted rows. A row moves to a new key if its primary key's changed. Gives back the rows
+    /// as they're stored.
+    pub fn stage_update(
+        &self,
+        table: &str,
+        rows: Vec<(Vec<u8>, Record)>,
+        pending: Option<&WriteSet>,
+        locks: Option<WriteLocks>,
+        writes: &mut WriteSet,
+    ) -> anyhow::Result<Vec<Record>> {
+        let mut writer = TableWriter::new(self, table, pending, locks, writes)?;
+        let mut stored = vec![];
+        for (key, updated) in rows {
+            let existing = writer.existing(&key, writes)?;
+            stored.push(writer.update(key, &existing, updated, writes)?);
+        }
+        Ok(stored)
+    }
 
-            // Add things like missing default fields
-            for (column, action) in &value_actions {
-                let value = match action {
-                    Action::Increment(val) => {
-                        let value = val.fetch_add(1, Ordering::SeqCst);
-                        Rc::new(Value::Number(BigDecimal::from_usize(value).unwrap()))
-                    }
-                    Action::ApplyConstant(con) => con.clone(),
-                };
-                record.columns.insert(column.to_string(), value);
-            }
+    /// Atomically applies all the writes in the set to the database, keeping the unique indexes
+    /// up to date. The rows written should be locked so they can't change under us.
+    pub fn commit(&self, writes: WriteSet) -> anyhow::Result<()> {
+        let mut flushed = FlushedWrites::default();
+        self.flush(writes, &mut flushed)?;
+        self.commit_flushed(flushed)
+    }
 
-            let pk = generate_pk_name(&record, &metadata);
+    /// Atomically applies everything that's been flushed to the database.
+    pub fn commit_flushed(&self, flushed: FlushedWrites) -> anyhow::Result<()> {
+        self.db.write(flushed.batch)?;
+        Ok(())
+    }
 
-            // If valid insert
-            let record = to_allocvec(&record)?;
-            transaction.put_cf(&handle, &pk, &record);
+    /// Adds the writes to the batch along with the changes to the unique indexes, later writes
+    /// replacing earlier ones. Like [`StorageEngine::commit`] the rows should be locked.
+    pub fn flush(&self, writes: WriteSet, flushed: &mut FlushedWrites) -> anyhow::Result<()> {
+        let batch = &mut flushed.batch;
+        for (table, rows) in &writes.tables {
+            let handle = self
+                .db
+                .cf_handle(table)
+                .with_context(|| format!("No table {} exists", table))?;
+            let constraints = self.secondary_constraints(table)?;
+            if !constraints.is_empty() {
+                // The old values all go first, a row could be taking a value another's giving up
+                for key in rows.keys() {
+                    let Some(record) = self.get_row(table, key)? else {
+                        continue;
+                    };
+                    for columns in &constraints {
+                        if let Some(value) = UniqueIndex::constraint_key(columns, &record)? {
+                            batch.delete_cf(&handle, unique_index_key(columns, &value));
+                        }
+                    }
+                }
+                for (key, value) in rows {
+                    let Some(value) = value else {
+                        continue;
+                    };
+                    let record = from_bytes(value)?;
+                    for columns in &constraints {
+                        if let Some(value) = UniqueIndex::constraint_key(columns, &record)? {
+                            batch.put_cf(&handle, unique_index_key(columns, &value), key);
+                        }
+                    }
+                }
+            }
+            for (key, value) in rows {
+                match value {
+                    Some(value) => batch.put_cf(&handle, key, value),
+                    None => batch.delete_cf(&handle, key),
+                }
+            }
         }
-        self.db.write(transaction)?;
         Ok(())
     }
 }
@@ -277,6 +1284,8 @@ mod tests {
         CreateTableOptions {
             name: "users".to_string(),
             columns,
+            checks: vec![],
+            unique: vec![],
         }
     }
 
@@ -284,7 +1293,7 @@ mod tests {
     #[traced_test]
     fn create_table() {
         let handle = TableHandle::new();
-        let mut engine = StorageEngine::new_with_path(&handle.path);
+        let engine = StorageEngine::new_with_path(&handle.path);
 
         let opt = default_fixture();
 
@@ -305,7 +1314,7 @@ mod tests {
     #[traced_test]
     fn error_if_table_already_exists() {
         let handle = TableHandle::new();
-        let mut engine = StorageEngine::new_with_path(&handle.path);
+        let engine = StorageEngine::new_with_path(&handle.path);
 
         let opt = default_fixture();
 
@@ -317,10 +1326,7 @@ mod tests {
     #[traced_test]
     fn metadata_error_on_nonexistant_table() {
         let handle = TableHandle::new();
-        let mut engine = StorageEngine::new_with_path(&handle.path);
-
-        let path = format!("./target/{}", Uuid::new_v4());
-        let mut engine = StorageEngine::new_with_path(&path);
+        let engine = StorageEngine::new_with_path(&handle.path);
 
         assert!(engine.table_metadata("users").is_err());
     }
@@ -329,7 +1335,7 @@ mod tests {
     #[traced_test]
     fn invalid_insert_ops() {
         let handle = TableHandle::new();
-        let mut engine = StorageEngine::new_with_path(&handle.path);
+        let engine = StorageEngine::new_with_path(&handle.path);
 
         let opt = default_fixture();
 
@@ -377,7 +1383,7 @@ mod tests {
     #[traced_test]
     fn primary_key_increments() {
         let handle = TableHandle::new();
-        let mut engine = StorageEngine::new_with_path(&handle.path);
+        let engine = StorageEngine::new_with_path(&handle.path);
 
         let opt = default_fixture();
 
@@ -394,9 +1400,88 @@ mod tests {
             table: "users".to_string(),
             column: "id".to_string(),
         };
-        assert_eq!(engine.auto_incs[&pk].load(Ordering::Relaxed), 2);
+        assert_eq!(
+            engine.auto_incs.read().unwrap()[&pk].load(Ordering::Relaxed),
+            2
+        );
+
+        engine.insert_rows(&insert).unwrap();
+        assert_eq!(
+            engine.auto_incs.read().unwrap()[&pk].load(Ordering::Relaxed),
+            3
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn analyze_table() {
+        let handle = TableHandle::new();
+        let engine = StorageEngine::new_with_path(&handle.path);
+        engine.create_table(&default_fixture()).unwrap();
+        assert_eq!(engine.tables().unwrap(), vec!["users".to_string()]);
+        assert_eq!(engine.table_statistics("users").unwrap(), None);
 
+        let insert = InsertOptions {
+            table: "users".to_string(),
+            columns: vec!["name".to_string()],
+            values: ["a", "b", "b"]
+                .into_iter()
+                .map(|x| vec![Value::from(x).into()])
+                .collect(),
+        };
         engine.insert_rows(&insert).unwrap();
-        assert_eq!(engine.auto_incs[&pk].load(Ordering::Relaxed), 3);
+        assert_eq!(engine.scan_rows("users").unwrap().len(), 3);
+
+        let stats = engine.analyze("users").unwrap();
+        assert_eq!(stats.row_count, 3);
+        assert_eq!(stats.columns["name"].distinct_count, 2);
+        assert_eq!(stats.columns["city"].distinct_count, 1);
+        // Statistics aren't rows
+        assert_eq!(engine.scan_rows("users").unwrap().len(), 3);
+
+        std::mem::drop(engine);
+        let engine = StorageEngine::new_with_path(&handle.path);
+        assert_eq!(engine.table_statistics("users").unwrap(), Some(stats));
+    }
+
+    #[test]
+    #[traced_test]
+    fn unique_index_built_on_open() {
+        let handle = TableHandle::new();
+        let engine = StorageEngine::new_with_path(&handle.path);
+        let mut opt = default_fixture();
+        opt.unique.push(vec!["name".to_string()]);
+        engine.create_table(&opt).unwrap();
+        let insert = |id: u32, name: &str| InsertOptions {
+            table: "users".to_string(),
+            columns: vec!["id".to_string(), "name".to_string()],
+            values: vec![vec![
+                Value::Number(id.into()).into(),
+                Value::from(name).into(),
+            ]],
+        };
+        engine.insert_rows(&insert(1, "a")).unwrap();
+        assert!(engine.insert_rows(&insert(2, "a")).is_err());
+
+        // Like a database from before tables had an index
+        let cf = engine.db.cf_handle("users").unwrap();
+        let index = engine
+            .db
+            .prefix_iterator_cf(&cf, UNIQUE_KEY_PREFIX)
+            .map(|x| x.unwrap().0)
+            .take_while(|x| x.starts_with(UNIQUE_KEY_PREFIX))
+            .collect::<Vec<_>>();
+        assert_eq!(index.len(), 1);
+        for key in index {
+            engine.db.delete_cf(&cf, key).unwrap();
+        }
+        engine.db.delete_cf(&cf, TABLE_UNIQUE_INDEX_KEY).unwrap();
+        std::mem::drop(cf);
+        std::mem::drop(engine);
+
+        let engine = StorageEngine::new_with_path(&handle.path);
+        assert!(engine.insert_rows(&insert(2, "a")).is_err());
+        engine.insert_rows(&insert(2, "b")).unwrap();
+        assert_eq!(engine.scan_rows("users").unwrap().len(), 2);
     }
 }
@@ -1,25 +1,73 @@
+use crate::evaluator::evaluate_constant;
+use crate::lock_manager::{LockMode, WaitPolicy};
+use crate::logical_plan::Catalog;
 use anyhow::Context;
 use bigdecimal::BigDecimal;
 use serde::{Deserialize, Serialize};
 use sqlparser::ast::{
-    self, ColumnOption, DataType, Expr, Insert, Query, SetExpr, Statement, TableConstraint,
+    self, AnalyzeFormat, ColumnOption, DataType, Expr, Insert, LockType, NonBlock, Query, SetExpr,
+    Statement, TableConstraint,
 };
+use std::cmp::Ordering;
 use std::collections::{BTreeMap, HashSet};
 use std::convert::TryFrom;
-use std::rc::Rc;
+use std::fmt;
+use std::sync::Arc;
 use tracing::{debug, error, warn};
 
 pub type ColumnDescriptors = BTreeMap<String, ColumnDescriptor>;
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Value {
     Text(String),
     Boolean(bool),
-    Number(BigDecimal),
+    Number(#[serde(with = "decimal_string")] BigDecimal),
     Bytes(Vec<u8>),
+    /// Only produced by `array_agg` for now, there's no array columns
+    Array(Vec<Value>),
     Null,
 }
 
+/// BigDecimal's own deserialize impl relies on `deserialize_any` which postcard doesn't support,
+/// so numbers are stored as strings instead.
+mod decimal_string {
+    use bigdecimal::BigDecimal;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(n: &BigDecimal, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(n)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<BigDecimal, D::Error> {
+        let s = String::deserialize(d)?;
+        s.parse().map_err(D::Error::custom)
+    }
+}
+
+/// Same problem as `decimal_string` for any numbers inside an expression, so defaults are stored
+/// as SQL and parsed again when they're loaded.
+mod expr_string {
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+    use sqlparser::ast::Expr;
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+
+    pub fn serialize<S: Serializer>(expr: &Option<Expr>, s: S) -> Result<S::Ok, S::Error> {
+        expr.as_ref().map(|x| x.to_string()).serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Expr>, D::Error> {
+        Option::<String>::deserialize(d)?
+            .map(|s| {
+                Parser::new(&GenericDialect {})
+                    .try_with_sql(&s)
+                    .and_then(|mut x| x.parse_expr())
+                    .map_err(D::Error::custom)
+            })
+            .transpose()
+    }
+}
+
 impl TryFrom<ast::Value> for Value {
     type Error = anyhow::Error;
 
@@ -32,7 +80,7 @@ impl TryFrom<ast::Value> for Value {
             | ast::Value::NationalStringLiteral(s) => Value::Text(s),
             ast::Value::Boolean(b) => Value::Boolean(b),
             ast::Value::Null => Value::Null,
-            ast::Value::Number(n, b) => {
+            ast::Value::Number(n, _) => {
                 // I don't think I care about longs...
                 Value::Number(n)
             }
@@ -44,15 +92,132 @@ impl TryFrom<ast::Value> for Value {
                 // TODO is this right?
                 Value::Bytes(s.into_bytes())
             }
-            e => anyhow::bail!("Unsupported ast Value"),
+            _ => anyhow::bail!("Unsupported ast Value"),
         };
         Ok(v)
     }
 }
 
+impl Value {
+    /// The type a literal of this value would be given, nulls are left unspecified.
+    pub fn data_type(&self) -> DataType {
+        match self {
+            Value::Text(_) => DataType::Text,
+            Value::Boolean(_) => DataType::Boolean,
+            Value::Number(_) => DataType::Numeric(ast::ExactNumberInfo::None),
+            Value::Bytes(_) => DataType::Bytea,
+            Value::Array(values) => {
+                // Go off the first non-null element, an array of nulls has no better type
+                let element = values
+                    .iter()
+                    .find(|x| !x.is_null())
+                    .map(|x| x.data_type())
+                    .unwrap_or(DataType::Unspecified);
+                DataType::Array(ast::ArrayElemTypeDef::SquareBracket(
+                    Box::new(element),
+                    None,
+                ))
+            }
+            Value::Null => DataType::Unspecified,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    /// SQL comparison, nothing compares with null and values of different types can't be
+    /// compared.
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Text(a), Value::Text(b)) => Some(a.cmp(b)),
+            (Value::Boolean(a), Value::Boolean(b)) => Some(a.cmp(b)),
+            (Value::Number(a), Value::Number(b)) => Some(a.cmp(b)),
+            (Value::Bytes(a), Value::Bytes(b)) => Some(a.cmp(b)),
+            // Element by element like postgres, nulls sort after everything else
+            (Value::Array(a), Value::Array(b)) => {
+                for (a, b) in a.iter().zip(b) {
+                    let ord = match (a.is_null(), b.is_null()) {
+                        (true, true) => Ordering::Equal,
+                        (true, false) => Ordering::Greater,
+                        (false, true) => Ordering::Less,
+                        (false, false) => a.compare(b)?,
+                    };
+                    if ord.is_ne() {
+                        return Some(ord);
+                    }
+                }
+                Some(a.len().cmp(&b.len()))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Boolean(b)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Number(n.into())
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Text(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Text(s)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Text(s) => write!(f, "{}", s),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Bytes(b) => write!(f, "\\x{}", hex::encode(b)),
+            Value::Array(values) => {
+                // Postgres style, elements are quoted when they'd be ambiguous otherwise
+                write!(f, "{{")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    let s = value.to_string();
+                    let quote = match value {
+                        Value::Null => false,
+                        Value::Text(_) => {
+                            s.is_empty()
+                                || s.eq_ignore_ascii_case("null")
+                                || s.contains(|c: char| "{},\"\\".contains(c) || c.is_whitespace())
+                        }
+                        _ => matches!(value, Value::Bytes(_)),
+                    };
+                    if quote {
+                        write!(f, "\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))?;
+                    } else {
+                        write!(f, "{}", s)?;
+                    }
+                }
+                write!(f, "}}")
+            }
+            Value::Null => write!(f, "NULL"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Record {
-    pub columns: BTreeMap<String, Rc<Value>>,
+    pub columns: BTreeMap<String, Arc<Value>>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -63,6 +228,7 @@ pub struct ColumnDescriptor {
     pub primary_key: bool,
     pub auto_increment: bool,
     pub foreign_key: Option<(String, String)>,
+    #[serde(with = "expr_string")]
     pub default: Option<Expr>,
     // skipping check and create index as things I shalln't support (yet)
 }
@@ -77,40 +243,90 @@ impl ColumnDescriptor {
     }
 
     pub fn value_matches_type(&self, value: &Value) -> bool {
-        match (value, &self.datatype) {
-            (
-                Value::Text(_),
-                DataType::Text
-                | DataType::Character(_)
-                | DataType::Char(_)
-                | DataType::CharacterVarying(_)
-                | DataType::Varchar(_)
-                | DataType::Nvarchar(_),
-            ) => true,
-            (Value::Boolean(_), DataType::Bool | DataType::Boolean) => true,
-            (
-                Value::Number(_),
-                DataType::Numeric(_)
-                | DataType::Decimal(_)
-                | DataType::Dec(_)
-                | DataType::Float(_)
-                | DataType::Int(_)
-                | DataType::UnsignedInt(_)
-                | DataType::Integer(_)
-                | DataType::UnsignedInteger(_)
-                | DataType::Real
-                | DataType::Double,
-            ) => true,
-            (Value::Bytes(_), DataType::Bytea | DataType::Blob(_) | DataType::Bytes(_)) => true,
+        match (value, TypeClass::of(&self.datatype)) {
+            (Value::Text(_), Some(TypeClass::Text))
+            | (Value::Boolean(_), Some(TypeClass::Boolean))
+            | (Value::Number(_), Some(TypeClass::Number))
+            | (Value::Bytes(_), Some(TypeClass::Bytes)) => true,
             (Value::Null, _) if !self.not_null => true,
-            (val, ty) => {
-                error!("{:?} does not match type {}", val, ty);
+            (val, _) => {
+                error!("{:?} does not match type {}", val, self.datatype);
                 false
             }
         }
     }
 }
 
+/// The kind of value a SQL type holds. We only have one representation for each of these so it's
+/// all that matters when checking whether a value fits a type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TypeClass {
+    Text,
+    Boolean,
+    Number,
+    Bytes,
+}
+
+impl TypeClass {
+    /// `None` for types we don't support
+    pub fn of(data_type: &DataType) -> Option<Self> {
+        match data_type {
+            DataType::Text
+            | DataType::Character(_)
+            | DataType::Char(_)
+            | DataType::CharacterVarying(_)
+            | DataType::CharVarying(_)
+            | DataType::Varchar(_)
+            | DataType::Nvarchar(_)
+            | DataType::String(_) => Some(Self::Text),
+            DataType::Bool | DataType::Boolean => Some(Self::Boolean),
+            DataType::Numeric(_)
+            | DataType::Decimal(_)
+            | DataType::Dec(_)
+            | DataType::Float(_)
+            | DataType::Real
+            | DataType::Double
+            | DataType::DoublePrecision
+            | DataType::Float4
+            | DataType::Float8
+            | DataType::Float64
+            | DataType::TinyInt(_)
+            | DataType::SmallInt(_)
+            | DataType::Int(_)
+            | DataType::Int2(_)
+            | DataType::Int4(_)
+            | DataType::Int8(_)
+            | DataType::Int64
+            | DataType::Integer(_)
+            | DataType::BigInt(_)
+            | DataType::UnsignedInt(_)
+            | DataType::UnsignedInteger(_)
+            | DataType::UnsignedBigInt(_) => Some(Self::Number),
+            DataType::Bytea | DataType::Blob(_) | DataType::Bytes(_) => Some(Self::Bytes),
+            _ => None,
+        }
+    }
+}
+
+/// Whether the type only holds whole numbers, dividing two of these truncates like Postgres.
+pub fn is_integer_type(data_type: &DataType) -> bool {
+    matches!(
+        data_type,
+        DataType::TinyInt(_)
+            | DataType::SmallInt(_)
+            | DataType::Int(_)
+            | DataType::Int2(_)
+            | DataType::Int4(_)
+            | DataType::Int8(_)
+            | DataType::Int64
+            | DataType::Integer(_)
+            | DataType::BigInt(_)
+            | DataType::UnsignedInt(_)
+            | DataType::UnsignedInteger(_)
+            | DataType::UnsignedBigInt(_)
+    )
+}
+
 impl Default for ColumnDescriptor {
     fn default() -> Self {
         Self {
@@ -128,26 +344,171 @@ impl Default for ColumnDescriptor {
 #[derive(Clone, Debug)]
 pub enum Command {
     CreateTable(CreateTableOptions),
-    Insert(InsertOptions),
+    Insert(InsertStatement),
+    /// Statements which change rows and have to go through the planner, like an `INSERT` from a
+    /// query
+    Modify(Box<Statement>),
     Select(QueryOptions),
+    Begin,
+    Commit,
+    Rollback,
+    Set {
+        name: String,
+        value: Value,
+    },
+    Show(String),
+    Prepare {
+        name: String,
+        statement: Box<Statement>,
+    },
+    Execute {
+        name: String,
+        parameters: Vec<Expr>,
+    },
+    Deallocate(String),
+    /// Gathers statistics for the table, or every table if there isn't one
+    Analyze(Option<String>),
+    Explain(ExplainOptions),
+}
+
+/// The rows produced by running a statement. Statements which don't produce any rows give an
+/// empty result set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResultSet {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl fmt::Display for ResultSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rows = self
+            .rows
+            .iter()
+            .map(|row| row.iter().map(|x| x.to_string()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let mut widths = self.columns.iter().map(|x| x.len()).collect::<Vec<_>>();
+        for row in &rows {
+            for (width, value) in widths.iter_mut().zip(row) {
+                *width = (*width).max(value.len());
+            }
+        }
+        let header = self
+            .columns
+            .iter()
+            .zip(&widths)
+            .map(|(col, width)| format!(" {:width$} ", col, width = width))
+            .collect::<Vec<_>>();
+        writeln!(f, "{}", header.join("|"))?;
+        let separator = widths
+            .iter()
+            .map(|width| "-".repeat(width + 2))
+            .collect::<Vec<_>>();
+        writeln!(f, "{}", separator.join("+"))?;
+        for row in &rows {
+            let row = row
+                .iter()
+                .zip(&widths)
+                .map(|(val, width)| format!(" {:width$} ", val, width = width))
+                .collect::<Vec<_>>();
+            writeln!(f, "{}", row.join("|"))?;
+        }
+        write!(
+            f,
+            "({} row{})",
+            rows.len(),
+            if rows.len() == 1 { "" } else { "s" }
+        )
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct CreateTableOptions {
     pub name: String,
     pub columns: ColumnDescriptors,
+    /// `CHECK` constraints, including any given on a single column
+    pub checks: Vec<Expr>,
+    /// Table level `UNIQUE` constraints, a column marked `UNIQUE` is in its descriptor instead
+    pub unique: Vec<Vec<String>>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct InsertOptions {
     pub table: String,
     pub columns: Vec<String>,
-    pub values: Vec<Vec<Rc<Value>>>,
+    pub values: Vec<Vec<Arc<Value>>>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// An `INSERT` before its values have been evaluated, which has to wait until we've got a
+/// catalog to look up any functions in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsertStatement {
+    pub table: String,
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Expr>>,
+}
+
+impl InsertStatement {
+    pub fn evaluate(&self, catalog: &dyn Catalog) -> anyhow::Result<InsertOptions> {
+        let values = self
+            .rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|x| evaluate_constant(x, catalog).map(Arc::new))
+                    .collect()
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(InsertOptions {
+            table: self.table.clone(),
+            columns: self.columns.clone(),
+            values,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct QueryOptions {
-    //TODO maybe I just want to keep the query type
+    pub query: Box<Query>,
+    pub locking: Vec<RowLocking>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExplainFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExplainOptions {
+    /// Run the statement and show what actually happened alongside the estimates
+    pub analyze: bool,
+    pub format: ExplainFormat,
+    pub statement: Box<Statement>,
+}
+
+/// A `FOR UPDATE`/`FOR SHARE` clause, rows read from the table (or all tables if `table` isn't
+/// set) are locked until the end of the transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RowLocking {
+    pub mode: LockMode,
+    pub wait: WaitPolicy,
+    pub table: Option<String>,
+}
+
+/// Without the table, as it's shown after the table in plans
+impl fmt::Display for RowLocking {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.mode {
+            LockMode::Shared => write!(f, "FOR SHARE")?,
+            LockMode::Exclusive => write!(f, "FOR UPDATE")?,
+        }
+        match self.wait {
+            WaitPolicy::Block => Ok(()),
+            WaitPolicy::NoWait => write!(f, " NOWAIT"),
+            WaitPolicy::SkipLocked => write!(f, " SKIP LOCKED"),
+        }
+    }
 }
 
 impl InsertOptions {
@@ -161,10 +522,6 @@ impl InsertOptions {
                 .collect(),
         })
     }
-
-    fn is_empty(&self) -> bool {
-        self.columns.is_empty() || self.values.is_empty()
-    }
 }
 
 impl TryFrom<&Statement> for Command {
@@ -180,6 +537,8 @@ impl TryFrom<&Statement> for Command {
                 ..
             } => {
                 let mut descriptor = BTreeMap::new();
+                let mut checks = vec![];
+                let mut unique = vec![];
                 for col in columns {
                     let entry = descriptor.entry(col.name.to_string()).or_insert_with(|| {
                         ColumnDescriptor {
@@ -220,13 +579,22 @@ impl TryFrom<&Statement> for Command {
                                     referred_columns[0].to_string(),
                                 ));
                             }
-                            ColumnOption::Check(_) => anyhow::bail!("CHECK not yet supported"),
+                            ColumnOption::Check(e) => checks.push(e.clone()),
                             ColumnOption::OnUpdate(_) => {
                                 anyhow::bail!("ON UPDATE not yet supported")
                             }
                             ColumnOption::Generated { .. } => {
                                 anyhow::bail!("GENERATED not yet supported")
                             }
+                            ColumnOption::DialectSpecific(tokens)
+                                if tokens.iter().any(|t| {
+                                    let t = t.to_string();
+                                    t.eq_ignore_ascii_case("AUTO_INCREMENT")
+                                        || t.eq_ignore_ascii_case("AUTOINCREMENT")
+                                }) =>
+                            {
+                                entry.auto_increment = true;
+                            }
                             ColumnOption::Null
                             | ColumnOption::DialectSpecific(_)
                             | ColumnOption::CharacterSet(_)
@@ -239,7 +607,6 @@ impl TryFrom<&Statement> for Command {
                 for constraint in constraints {
                     match constraint {
                         TableConstraint::ForeignKey {
-                            name,
                             columns,
                             foreign_table,
                             referred_columns,
@@ -265,9 +632,7 @@ impl TryFrom<&Statement> for Command {
                                 anyhow::bail!("Specified foreign key column does not exist");
                             }
                         }
-                        TableConstraint::Check { .. } => {
-                            anyhow::bail!("Check constraints not supported")
-                        }
+                        TableConstraint::Check { expr, .. } => checks.push(*expr.clone()),
                         TableConstraint::PrimaryKey { columns, .. } => {
                             for col in columns {
                                 if let Some(entry) = descriptor.get_mut(&col.to_string()) {
@@ -280,6 +645,19 @@ impl TryFrom<&Statement> for Command {
                                 }
                             }
                         }
+                        TableConstraint::Unique { columns, .. } => {
+                            let mut names = vec![];
+                            for col in columns {
+                                if !descriptor.contains_key(&col.to_string()) {
+                                    anyhow::bail!(
+                                        "Unique constraint applied to not existing column: {}",
+                                        col
+                                    );
+                                }
+                                names.push(col.to_string());
+                            }
+                            unique.push(names);
+                        }
                         e => anyhow::bail!("MySQL constraint: {} is not supported", e),
                     }
                 }
@@ -287,10 +665,78 @@ impl TryFrom<&Statement> for Command {
                 Ok(Command::CreateTable(CreateTableOptions {
                     name: name.to_string(),
                     columns: descriptor,
+                    checks,
+                    unique,
                 }))
             }
             Statement::Insert(insert) => process_insert(insert),
+            Statement::Update { .. } | Statement::Delete(_) => {
+                Ok(Command::Modify(Box::new(statement.clone())))
+            }
             Statement::Query(query) => process_query(query),
+            Statement::StartTransaction { .. } => Ok(Command::Begin),
+            Statement::Commit { chain: false } => Ok(Command::Commit),
+            Statement::Rollback {
+                chain: false,
+                savepoint: None,
+            } => Ok(Command::Rollback),
+            Statement::SetVariable {
+                variable, value, ..
+            } => {
+                if value.len() != 1 {
+                    anyhow::bail!("SET {} expects exactly one value", variable);
+                }
+                let value = match &value[0] {
+                    Expr::Value(v) => Value::try_from(v.clone())?,
+                    // Allows things like `SET search_path TO public`
+                    Expr::Identifier(ident) => Value::Text(ident.value.clone()),
+                    e => anyhow::bail!("Unhandled expression type: {}", e),
+                };
+                Ok(Command::Set {
+                    name: variable.to_string().to_lowercase(),
+                    value,
+                })
+            }
+            Statement::ShowVariable { variable } => Ok(Command::Show(
+                variable
+                    .iter()
+                    .map(|x| x.value.to_lowercase())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            )),
+            Statement::Prepare {
+                name, statement, ..
+            } => Ok(Command::Prepare {
+                name: name.value.clone(),
+                statement: statement.clone(),
+            }),
+            Statement::Execute {
+                name, parameters, ..
+            } => Ok(Command::Execute {
+                name: name.value.clone(),
+                parameters: parameters.clone(),
+            }),
+            Statement::Deallocate { name, .. } => Ok(Command::Deallocate(name.value.clone())),
+            Statement::Explain {
+                analyze,
+                statement,
+                format,
+                ..
+            } => {
+                let format = match format {
+                    None | Some(AnalyzeFormat::TEXT) => ExplainFormat::Text,
+                    Some(AnalyzeFormat::JSON) => ExplainFormat::Json,
+                    Some(format) => anyhow::bail!("Unsupported EXPLAIN format: {}", format),
+                };
+                Ok(Command::Explain(ExplainOptions {
+                    analyze: *analyze,
+                    format,
+                    statement: statement.clone(),
+                }))
+            }
+            Statement::Analyze { table_name, .. } => {
+                Ok(Command::Analyze(Some(table_name.to_string())))
+            }
             e => {
                 anyhow::bail!("Unsupported Statement: {}", e);
             }
@@ -299,7 +745,26 @@ impl TryFrom<&Statement> for Command {
 }
 
 fn process_query(query: &Query) -> anyhow::Result<Command> {
-    todo!()
+    let locking = query
+        .locks
+        .iter()
+        .map(|lock| RowLocking {
+            mode: match lock.lock_type {
+                LockType::Share => LockMode::Shared,
+                LockType::Update => LockMode::Exclusive,
+            },
+            wait: match lock.nonblock {
+                None => WaitPolicy::Block,
+                Some(NonBlock::Nowait) => WaitPolicy::NoWait,
+                Some(NonBlock::SkipLocked) => WaitPolicy::SkipLocked,
+            },
+            table: lock.of.as_ref().map(|x| x.to_string()),
+        })
+        .collect();
+    Ok(Command::Select(QueryOptions {
+        query: Box::new(query.clone()),
+        locking,
+    }))
 }
 
 fn process_insert(insert: &Insert) -> anyhow::Result<Command> {
@@ -310,30 +775,20 @@ fn process_insert(insert: &Insert) -> anyhow::Result<Command> {
             anyhow::bail!("Column '{}' is present multiple times in insert query", col);
         }
     }
-    let mut values = vec![];
+    let mut rows = vec![];
     if let Some(source) = &insert.source {
         match source.body.as_ref() {
-            SetExpr::Values(v) => {
-                for row in &v.rows {
-                    let mut my_row = vec![];
-                    for val in row {
-                        match val {
-                            Expr::Value(v) => {
-                                my_row.push(Value::try_from(v.clone())?.into());
-                            }
-                            e => anyhow::bail!("Unhandled expression type: {}", e),
-                        }
-                    }
-                    values.push(my_row);
-                }
-            }
-            e => anyhow::bail!("Unhandled set expression: {}", e),
+            SetExpr::Values(v) => rows.clone_from(&v.rows),
+            _ => return Ok(Command::Modify(Box::new(Statement::Insert(insert.clone())))),
         }
     }
+    if insert.on.is_some() || insert.returning.is_some() {
+        return Ok(Command::Modify(Box::new(Statement::Insert(insert.clone()))));
+    }
 
-    Ok(Command::Insert(InsertOptions {
+    Ok(Command::Insert(InsertStatement {
         table: insert.table_name.to_string(),
         columns,
-        values,
+        rows,
     }))
 }
@@ -0,0 +1,984 @@
+//! Vectorised execution. Instead of passing single rows between operators we pass batches of
+//! rows stored a column at a time, and expressions are evaluated over a whole column in one go.
+//! This cuts down the overhead per row a lot for queries which read through big tables.
+//!
+//! Filters don't copy anything, they just shrink the batch's selection vector which lists the
+//! rows that are still live. Operators which don't have a vectorised version yet are run with
+//! the row executor with their input and output converted to and from batches.
+use crate::evaluator::{binary_op, unary_op, PhysicalExpr};
+use crate::executor::{
+    self, BoxedOperator, ExecutionContext, Groups, MetricsHandle, PhysicalOperator, Row, TableScan,
+    MAX_HASH_GROUPS,
+};
+use crate::expression::AggregateExpr;
+use crate::physical_plan::{Operator, PhysicalPlan};
+use crate::types::Value;
+use bigdecimal::BigDecimal;
+use sqlparser::ast::{BinaryOperator, UnaryOperator};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// The most rows we put in a batch
+pub const BATCH_SIZE: usize = 1024;
+
+/// A bit for every row in a batch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bitmap {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl Bitmap {
+    pub fn new(len: usize, value: bool) -> Self {
+        let word = if value { u64::MAX } else { 0 };
+        Self {
+            words: vec![word; (len + 63) / 64],
+            len,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, i: usize) -> bool {
+        self.words[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    pub fn set(&mut self, i: usize, value: bool) {
+        if value {
+            self.words[i / 64] |= 1 << (i % 64);
+        } else {
+            self.words[i / 64] &= !(1 << (i % 64));
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ColumnData {
+    Boolean(Vec<bool>),
+    Number(Vec<BigDecimal>),
+    Text(Vec<String>),
+    /// Bytes, columns with a mix of types and columns which are all null
+    Values(Vec<Value>),
+}
+
+/// A column of values, null values have a placeholder in `data` and are marked in the validity
+/// bitmap.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Column {
+    pub data: ColumnData,
+    /// Set for every row that isn't null
+    pub validity: Bitmap,
+}
+
+impl Column {
+    pub fn from_values(values: Vec<Value>) -> Self {
+        let mut validity = Bitmap::new(values.len(), true);
+        for (i, value) in values.iter().enumerate() {
+            if value.is_null() {
+                validity.set(i, false);
+            }
+        }
+        let all = |f: fn(&Value) -> bool| values.iter().all(|v| v.is_null() || f(v));
+        let data = match values.iter().find(|v| !v.is_null()) {
+            Some(Value::Boolean(_)) if all(|v| matches!(v, Value::Boolean(_))) => {
+                ColumnData::Boolean(
+                    values
+                        .into_iter()
+                        .map(|v| matches!(v, Value::Boolean(true)))
+                        .collect(),
+                )
+            }
+            Some(Value::Number(_)) if all(|v| matches!(v, Value::Number(_))) => ColumnData::Number(
+                values
+                    .into_iter()
+                    .map(|v| match v {
+                        Value::Number(n) => n,
+                        _ => BigDecimal::default(),
+                    })
+                    .collect(),
+            ),
+            Some(Value::Text(_)) if all(|v| matches!(v, Value::Text(_))) => ColumnData::Text(
+                values
+                    .into_iter()
+                    .map(|v| match v {
+                        Value::Text(s) => s,
+                        _ => String::new(),
+                    })
+                    .collect(),
+            ),
+            _ => ColumnData::Values(values),
+        };
+        Self { data, validity }
+    }
+
+    /// The same value repeated for every row
+    pub fn constant(value: &Value, len: usize) -> Self {
+        Self::from_values(vec![value.clone(); len])
+    }
+
+    pub fn len(&self) -> usize {
+        self.validity.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.validity.is_empty()
+    }
+
+    pub fn is_null(&self, i: usize) -> bool {
+        !self.validity.get(i)
+    }
+
+    pub fn value(&self, i: usize) -> Value {
+        if self.is_null(i) {
+            return Value::Null;
+        }
+        match &self.data {
+            ColumnData::Boolean(v) => Value::Boolean(v[i]),
+            ColumnData::Number(v) => Value::Number(v[i].clone()),
+            ColumnData::Text(v) => Value::Text(v[i].clone()),
+            ColumnData::Values(v) => v[i].clone(),
+        }
+    }
+
+    /// The value as a boolean, `None` if it's null or not a boolean
+    fn truth(&self, i: usize) -> Option<bool> {
+        match &self.data {
+            ColumnData::Boolean(v) if !self.is_null(i) => Some(v[i]),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Batch {
+    pub columns: Vec<Column>,
+    /// The rows in the batch which haven't been filtered out, in order
+    pub selection: Vec<usize>,
+    len: usize,
+}
+
+impl Batch {
+    pub fn from_rows(rows: Vec<Row>, width: usize) -> Self {
+        let len = rows.len();
+        let mut columns = vec![Vec::with_capacity(len); width];
+        for row in rows {
+            for (column, value) in columns.iter_mut().zip(row) {
+                column.push(value);
+            }
+        }
+        Self {
+            columns: columns.into_iter().map(Column::from_values).collect(),
+            selection: (0..len).collect(),
+            len,
+        }
+    }
+
+    /// How many rows are stored in the batch, including ones which aren't selected
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// How many rows are selected
+    pub fn num_rows(&self) -> usize {
+        self.selection.len()
+    }
+
+    pub fn row(&self, i: usize) -> Row {
+        self.columns.iter().map(|c| c.value(i)).collect()
+    }
+
+    /// The selected rows
+    pub fn rows(&self) -> impl Iterator<Item = Row> + '_ {
+        self.selection.iter().map(|&i| self.row(i))
+    }
+}
+
+/// Evaluates the expression for the selected rows of the batch. What's in the result for the
+/// rows which aren't selected is unspecified.
+pub fn evaluate<'a>(
+    expr: &PhysicalExpr,
+    batch: &'a Batch,
+    selection: &[usize],
+) -> anyhow::Result<Cow<'a, Column>> {
+    let len = batch.len();
+    let column = match expr {
+        PhysicalExpr::Column(i) => return Ok(Cow::Borrowed(&batch.columns[*i])),
+        PhysicalExpr::Literal(v) => Column::constant(v, len),
+        PhysicalExpr::BinaryOp {
+            left,
+            op: op @ (BinaryOperator::And | BinaryOperator::Or),
+            right,
+        } => {
+            let left = evaluate(left, batch, selection)?;
+            // Like the row executor we only evaluate the right side where the left doesn't
+            // already give the answer
+            let decided = matches!(op, BinaryOperator::Or);
+            let remaining = selection
+                .iter()
+                .copied()
+                .filter(|&i| left.value(i) != Value::Boolean(decided))
+                .collect::<Vec<_>>();
+            let right = evaluate(right, batch, &remaining)?;
+            let mut res = vec![Value::Null; len];
+            for &i in selection {
+                res[i] = Value::Boolean(decided);
+            }
+            for &i in &remaining {
+                res[i] = match (left.truth(i), right.truth(i), decided) {
+                    (_, Some(r), _) if r == decided => Value::Boolean(decided),
+                    (Some(_), Some(r), _) => Value::Boolean(r),
+                    _ => binary_op(&left.value(i), op, &right.value(i))?,
+                };
+            }
+            Column::from_values(res)
+        }
+        PhysicalExpr::BinaryOp { left, op, right } => {
+            let left = evaluate(left, batch, selection)?;
+            let right = evaluate(right, batch, selection)?;
+            binary(&left, op, &right, selection)?
+        }
+        PhysicalExpr::UnaryOp { op, expr } => {
+            let input = evaluate(expr, batch, selection)?;
+            match (op, &input.data) {
+                (UnaryOperator::Not, ColumnData::Boolean(v)) => Column {
+                    data: ColumnData::Boolean(v.iter().map(|x| !x).collect()),
+                    validity: input.validity.clone(),
+                },
+                _ => {
+                    let mut res = vec![Value::Null; len];
+                    for &i in selection {
+                        res[i] = unary_op(*op, &input.value(i))?;
+                    }
+                    Column::from_values(res)
+                }
+            }
+        }
+        PhysicalExpr::IsNull(e) | PhysicalExpr::IsNotNull(e) => {
+            let input = evaluate(e, batch, selection)?;
+            let is_null = matches!(expr, PhysicalExpr::IsNull(_));
+            Column {
+                data: ColumnData::Boolean((0..len).map(|i| input.is_null(i) == is_null).collect()),
+                validity: Bitmap::new(len, true),
+            }
+        }
+        // No kernels for these yet, so go a row at a time
+        _ => {
+            let mut res = vec![Value::Null; len];
+            for &i in selection {
+                res[i] = expr.evaluate(&batch.row(i))?;
+            }
+            Column::from_values(res)
+        }
+    };
+    Ok(Cow::Owned(column))
+}
+
+fn binary(
+    left: &Column,
+    op: &BinaryOperator,
+    right: &Column,
+    selection: &[usize],
+) -> anyhow::Result<Column> {
+    let comparison: Option<fn(Ordering) -> bool> = match op {
+        BinaryOperator::Eq => Some(Ordering::is_eq),
+        BinaryOperator::NotEq => Some(Ordering::is_ne),
+        BinaryOperator::Lt => Some(Ordering::is_lt),
+        BinaryOperator::LtEq => Some(Ordering::is_le),
+        BinaryOperator::Gt => Some(Ordering::is_gt),
+        BinaryOperator::GtEq => Some(Ordering::is_ge),
+        _ => None,
+    };
+    match (&left.data, &right.data, comparison) {
+        (ColumnData::Number(l), ColumnData::Number(r), Some(f)) => {
+            return Ok(compare(l, r, f, left, right, selection))
+        }
+        (ColumnData::Text(l), ColumnData::Text(r), Some(f)) => {
+            return Ok(compare(l, r, f, left, right, selection))
+        }
+        (ColumnData::Boolean(l), ColumnData::Boolean(r), Some(f)) => {
+            return Ok(compare(l, r, f, left, right, selection))
+        }
+        (ColumnData::Number(l), ColumnData::Number(r), None)
+            if matches!(
+                op,
+                BinaryOperator::Plus | BinaryOperator::Minus | BinaryOperator::Multiply
+            ) =>
+        {
+            let mut res = vec![BigDecimal::default(); left.len()];
+            let mut validity = Bitmap::new(left.len(), false);
+            for &i in selection {
+                if left.is_null(i) || right.is_null(i) {
+                    continue;
+                }
+                res[i] = match op {
+                    BinaryOperator::Plus => &l[i] + &r[i],
+                    BinaryOperator::Minus => &l[i] - &r[i],
+                    _ => &l[i] * &r[i],
+                };
+                validity.set(i, true);
+            }
+            return Ok(Column {
+                data: ColumnData::Number(res),
+                validity,
+            });
+        }
+        _ => {}
+    }
+    // Anything else is done a value at a time the same way as the row executor
+    let mut res = vec![Value::Null; left.len()];
+    for &i in selection {
+        res[i] = binary_op(&left.value(i), op, &right.value(i))?;
+    }
+    Ok(Column::from_values(res))
+}
+
+fn compare<T: Ord>(
+    l: &[T],
+    r: &[T],
+    f: fn(Ordering) -> bool,
+    left: &Column,
+    right: &Column,
+    selection: &[usize],
+) -> Column {
+    let mut res = vec![false; l.len()];
+    let mut validity = Bitmap::new(l.len(), false);
+    for &i in selection {
+        if !left.is_null(i) && !right.is_null(i) {
+            res[i] = f(l[i].cmp(&r[i]));
+            validity.set(i, true);
+        }
+    }
+    Column {
+        data: ColumnData::Boolean(res),
+        validity,
+    }
+}
+
+/// The selected rows where the predicate is true.
+fn select(predicate: &PhysicalExpr, batch: &Batch) -> anyhow::Result<Vec<usize>> {
+    let column = evaluate(predicate, batch, &batch.selection)?;
+    let mut selection = Vec::with_capacity(batch.num_rows());
+    for &i in &batch.selection {
+        match column.truth(i) {
+            Some(true) => selection.push(i),
+            Some(false) => {}
+            None => match column.value(i) {
+                Value::Null => {}
+                v => anyhow::bail!("Expected a boolean but got {}", v),
+            },
+        }
+    }
+    Ok(selection)
+}
+
+pub trait BatchOperator {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()>;
+
+    /// The next batch or `None` once there aren't any more. Batches always have at least one
+    /// row selected.
+    fn next_batch(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<Batch>>;
+
+    fn close(&mut self) -> anyhow::Result<()>;
+}
+
+pub(crate) type BoxedBatchOperator = Box<dyn BatchOperator>;
+
+/// Runs the plan to completion a batch at a time, returning all the rows it produces.
+pub fn execute(plan: &PhysicalPlan, ctx: &ExecutionContext) -> anyhow::Result<Vec<Row>> {
+    let mut operator = build_operator(plan, &mut None)?;
+    drain(operator.as_mut(), ctx)
+}
+
+/// Runs the plan recording what each operator does in the plan's metrics, for `EXPLAIN ANALYZE`.
+pub fn execute_analyze(plan: &mut PhysicalPlan, ctx: &ExecutionContext) -> anyhow::Result<()> {
+    let mut metrics = Some(vec![]);
+    let mut operator = build_operator(plan, &mut metrics)?;
+    drain(operator.as_mut(), ctx)?;
+    executor::assign_metrics(plan, metrics.unwrap_or_default());
+    Ok(())
+}
+
+fn drain(operator: &mut dyn BatchOperator, ctx: &ExecutionContext) -> anyhow::Result<Vec<Row>> {
+    operator.open(ctx)?;
+    let mut rows = vec![];
+    while let Some(batch) = operator.next_batch(ctx)? {
+        rows.extend(batch.rows());
+    }
+    operator.close()?;
+    Ok(rows)
+}
+
+pub(crate) fn build_operator(
+    plan: &PhysicalPlan,
+    metrics: &mut Option<Vec<MetricsHandle>>,
+) -> anyhow::Result<BoxedBatchOperator> {
+    // Rows are locked as they're asked for, anything above pulling a batch at a time would lock
+    // rows a limit is going to throw away
+    if plan.locks_rows() {
+        return Ok(Box::new(FromRows {
+            input: executor::build_operator(plan, metrics)?,
+            width: plan.schema.len(),
+        }));
+    }
+    let handle = executor::new_metrics(metrics);
+    let mut inputs = vec![];
+    for (i, input) in plan.inputs.iter().enumerate() {
+        if executor::is_deferred(plan, i) {
+            executor::skip_metrics(input, metrics);
+        } else {
+            inputs.push(build_operator(input, metrics)?);
+        }
+    }
+    let compile_all = |exprs: &[_], plan: &PhysicalPlan| {
+        exprs
+            .iter()
+            .map(|e| PhysicalExpr::new(e, &plan.schema))
+            .collect::<anyhow::Result<Vec<_>>>()
+    };
+    let operator: BoxedBatchOperator = match &plan.operator {
+        // Locking scans need to check the rows again once they're locked so they're left to the
+        // row executor
+        Operator::TableScan {
+            table,
+            filters,
+            lock: None,
+            ..
+        } => Box::new(Scan {
+            scan: TableScan::new(table, &plan.schema),
+            filters: compile_all(filters, plan)?,
+            width: plan.schema.len(),
+        }),
+        Operator::Filter { predicate } => Box::new(Filter {
+            predicate: PhysicalExpr::new(predicate, &plan.inputs[0].schema)?,
+            input: inputs.remove(0),
+        }),
+        Operator::Projection { exprs } => Box::new(Projection {
+            exprs: compile_all(exprs, &plan.inputs[0])?,
+            input: inputs.remove(0),
+        }),
+        Operator::HashAggregate {
+            group_by,
+            aggregates,
+        } => {
+            let aggregates = aggregates
+                .iter()
+                .map(|a| {
+                    let args = a.exprs().cloned().collect::<Vec<_>>();
+                    Ok((a.clone(), compile_all(&args, &plan.inputs[0])?))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Box::new(HashAggregate {
+                group_by: compile_all(group_by, &plan.inputs[0])?,
+                aggregates,
+                max_groups: MAX_HASH_GROUPS,
+                input: inputs.remove(0),
+                output: VecDeque::new(),
+                width: plan.schema.len(),
+            })
+        }
+        Operator::Limit { limit, offset } => Box::new(Limit {
+            limit: *limit,
+            offset: *offset,
+            seen: 0,
+            input: inputs.remove(0),
+        }),
+        _ => {
+            let inputs = inputs
+                .into_iter()
+                .map(|input| {
+                    Box::new(ToRows {
+                        input,
+                        rows: VecDeque::new(),
+                    }) as BoxedOperator
+                })
+                .collect();
+            let operator = executor::instrument(executor::operator(plan, inputs)?, handle);
+            return Ok(Box::new(FromRows {
+                input: operator,
+                width: plan.schema.len(),
+            }));
+        }
+    };
+    Ok(match handle {
+        Some(metrics) => Box::new(Instrumented {
+            inner: operator,
+            metrics,
+        }),
+        None => operator,
+    })
+}
+
+struct Instrumented {
+    inner: BoxedBatchOperator,
+    metrics: MetricsHandle,
+}
+
+impl BatchOperator for Instrumented {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        let start = Instant::now();
+        let res = self.inner.open(ctx);
+        let mut metrics = self.metrics.get();
+        metrics.elapsed += start.elapsed();
+        metrics.loops += 1;
+        self.metrics.set(metrics);
+        res
+    }
+
+    fn next_batch(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<Batch>> {
+        let start = Instant::now();
+        let res = self.inner.next_batch(ctx);
+        let mut metrics = self.metrics.get();
+        metrics.elapsed += start.elapsed();
+        if let Ok(Some(batch)) = &res {
+            metrics.rows += batch.num_rows() as u64;
+        }
+        self.metrics.set(metrics);
+        res
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.inner.close()
+    }
+}
+
+/// Lets a row operator read from a vectorised one.
+struct ToRows {
+    input: BoxedBatchOperator,
+    rows: VecDeque<Row>,
+}
+
+impl PhysicalOperator for ToRows {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        self.rows.clear();
+        self.input.open(ctx)
+    }
+
+    fn next(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<Row>> {
+        loop {
+            if let Some(row) = self.rows.pop_front() {
+                return Ok(Some(row));
+            }
+            match self.input.next_batch(ctx)? {
+                Some(batch) => self.rows.extend(batch.rows()),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.rows.clear();
+        self.input.close()
+    }
+}
+
+/// Puts the rows from a row operator into batches.
+struct FromRows {
+    input: BoxedOperator,
+    width: usize,
+}
+
+/// Reads up to a batch worth of rows from the operator.
+fn read_batch(
+    input: &mut dyn PhysicalOperator,
+    width: usize,
+    ctx: &ExecutionContext,
+) -> anyhow::Result<Option<Batch>> {
+    let mut rows = Vec::with_capacity(BATCH_SIZE);
+    while rows.len() < BATCH_SIZE {
+        match input.next(ctx)? {
+            Some(row) => rows.push(row),
+            None => break,
+        }
+    }
+    Ok((!rows.is_empty()).then(|| Batch::from_rows(rows, width)))
+}
+
+impl BatchOperator for FromRows {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        self.input.open(ctx)
+    }
+
+    fn next_batch(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<Batch>> {
+        read_batch(self.input.as_mut(), self.width, ctx)
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.input.close()
+    }
+}
+
+/// Reads rows with the row executor's table scan and then filters them a batch at a time.
+struct Scan {
+    scan: TableScan,
+    filters: Vec<PhysicalExpr>,
+    width: usize,
+}
+
+impl BatchOperator for Scan {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        self.scan.open(ctx)
+    }
+
+    fn next_batch(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<Batch>> {
+        while let Some(mut batch) = read_batch(&mut self.scan, self.width, ctx)? {
+            for filter in &self.filters {
+                batch.selection = select(filter, &batch)?;
+            }
+            if !batch.selection.is_empty() {
+                return Ok(Some(batch));
+            }
+        }
+        Ok(None)
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.scan.close()
+    }
+}
+
+struct Filter {
+    predicate: PhysicalExpr,
+    input: BoxedBatchOperator,
+}
+
+impl BatchOperator for Filter {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        self.input.open(ctx)
+    }
+
+    fn next_batch(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<Batch>> {
+        while let Some(mut batch) = self.input.next_batch(ctx)? {
+            batch.selection = select(&self.predicate, &batch)?;
+            if !batch.selection.is_empty() {
+                return Ok(Some(batch));
+            }
+        }
+        Ok(None)
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.input.close()
+    }
+}
+
+struct Projection {
+    exprs: Vec<PhysicalExpr>,
+    input: BoxedBatchOperator,
+}
+
+impl BatchOperator for Projection {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        self.input.open(ctx)
+    }
+
+    fn next_batch(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<Batch>> {
+        let Some(batch) = self.input.next_batch(ctx)? else {
+            return Ok(None);
+        };
+        let columns = self
+            .exprs
+            .iter()
+            .map(|e| Ok(evaluate(e, &batch, &batch.selection)?.into_owned()))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Some(Batch {
+            columns,
+            selection: batch.selection,
+            len: batch.len,
+        }))
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.input.close()
+    }
+}
+
+/// Works the same as the row executor's hash aggregate but the group by values and aggregate
+/// arguments are worked out a batch at a time.
+struct HashAggregate {
+    group_by: Vec<PhysicalExpr>,
+    aggregates: Vec<(AggregateExpr, Vec<PhysicalExpr>)>,
+    max_groups: usize,
+    input: BoxedBatchOperator,
+    output: VecDeque<Row>,
+    width: usize,
+}
+
+impl BatchOperator for HashAggregate {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        let aggregates = self
+            .aggregates
+            .iter()
+            .map(|(a, _)| a.clone())
+            .collect::<Vec<_>>();
+        let mut groups = Groups::new(&aggregates, self.max_groups, ctx.work_mem);
+        self.input.open(ctx)?;
+        while let Some(batch) = self.input.next_batch(ctx)? {
+            let evaluate_all = |exprs: &[PhysicalExpr]| {
+                exprs
+                    .iter()
+                    .map(|e| evaluate(e, &batch, &batch.selection))
+                    .collect::<anyhow::Result<Vec<_>>>()
+            };
+            let keys = evaluate_all(&self.group_by)?;
+            let args = self
+                .aggregates
+                .iter()
+                .map(|(_, args)| evaluate_all(args))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            for &i in &batch.selection {
+                let key = keys.iter().map(|c| c.value(i)).collect();
+                let args = args
+                    .iter()
+                    .map(|args| args.iter().map(|c| c.value(i)).collect())
+                    .collect();
+                groups.update(key, args)?;
+            }
+        }
+        self.input.close()?;
+        self.output = groups.finish(!self.group_by.is_empty())?.into();
+        Ok(())
+    }
+
+    fn next_batch(&mut self, _ctx: &ExecutionContext) -> anyhow::Result<Option<Batch>> {
+        if self.output.is_empty() {
+            return Ok(None);
+        }
+        let n = self.output.len().min(BATCH_SIZE);
+        let rows = self.output.drain(..n).collect();
+        Ok(Some(Batch::from_rows(rows, self.width)))
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.output.clear();
+        Ok(())
+    }
+}
+
+struct Limit {
+    limit: Option<u64>,
+    offset: u64,
+    seen: u64,
+    input: BoxedBatchOperator,
+}
+
+impl BatchOperator for Limit {
+    fn open(&mut self, ctx: &ExecutionContext) -> anyhow::Result<()> {
+        self.seen = 0;
+        self.input.open(ctx)
+    }
+
+    fn next_batch(&mut self, ctx: &ExecutionContext) -> anyhow::Result<Option<Batch>> {
+        let end = self.limit.map(|limit| self.offset + limit);
+        while !matches!(end, Some(end) if self.seen >= end) {
+            let Some(mut batch) = self.input.next_batch(ctx)? else {
+                return Ok(None);
+            };
+            let start = self.seen;
+            self.seen += batch.num_rows() as u64;
+            let skip = self.offset.saturating_sub(start) as usize;
+            let take = end.map_or(usize::MAX, |end| end.saturating_sub(start) as usize);
+            batch.selection = batch.selection.into_iter().take(take).skip(skip).collect();
+            if !batch.selection.is_empty() {
+                return Ok(Some(batch));
+            }
+        }
+        Ok(None)
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.input.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::tests::{rows, Fixture};
+    use crate::expression::ScalarExpr;
+    use crate::logical_plan::{Field, Schema};
+    use sqlparser::ast::DataType;
+    use tracing_test::traced_test;
+
+    #[test]
+    fn evaluate_columns() {
+        let schema = Schema::new(vec![
+            Field::new(Some("t".to_string()), "a", DataType::Integer(None)),
+            Field::new(Some("t".to_string()), "b", DataType::Text),
+        ]);
+        let batch = Batch::from_rows(
+            vec![
+                vec![Value::from(1), Value::from("x")],
+                vec![Value::Null, Value::from("y")],
+                vec![Value::from(3), Value::Null],
+                vec![Value::from(0), Value::from("x")],
+            ],
+            2,
+        );
+        assert!(matches!(batch.columns[0].data, ColumnData::Number(_)));
+        assert!(matches!(batch.columns[1].data, ColumnData::Text(_)));
+        let compile = |expr: ScalarExpr| PhysicalExpr::new(&expr, &schema).unwrap();
+        let a = || ScalarExpr::column(Some("t"), "a");
+        let b = || ScalarExpr::column(Some("t"), "b");
+        let values = |expr: ScalarExpr, selection: &[usize]| {
+            let column = evaluate(&compile(expr), &batch, selection).unwrap();
+            selection
+                .iter()
+                .map(|&i| column.value(i))
+                .collect::<Vec<_>>()
+        };
+        let all = [0, 1, 2, 3];
+        assert_eq!(
+            values(
+                ScalarExpr::binary(
+                    ScalarExpr::binary(a(), BinaryOperator::Multiply, ScalarExpr::literal(2)),
+                    BinaryOperator::Plus,
+                    ScalarExpr::literal(1),
+                ),
+                &all
+            ),
+            vec![Value::from(3), Value::Null, Value::from(7), Value::from(1)]
+        );
+        assert_eq!(
+            values(
+                ScalarExpr::binary(
+                    ScalarExpr::binary(a(), BinaryOperator::Gt, ScalarExpr::literal(1)),
+                    BinaryOperator::Or,
+                    ScalarExpr::binary(b(), BinaryOperator::Eq, ScalarExpr::literal("x")),
+                ),
+                &all
+            ),
+            vec![
+                Value::from(true),
+                Value::Null,
+                Value::from(true),
+                Value::from(true)
+            ]
+        );
+        assert_eq!(
+            values(
+                ScalarExpr::binary(
+                    ScalarExpr::IsNotNull(Box::new(a())),
+                    BinaryOperator::And,
+                    ScalarExpr::binary(b(), BinaryOperator::NotEq, ScalarExpr::literal("x")),
+                ),
+                &all
+            ),
+            vec![
+                Value::from(false),
+                Value::from(false),
+                Value::Null,
+                Value::from(false)
+            ]
+        );
+        assert_eq!(
+            values(
+                ScalarExpr::binary(b(), BinaryOperator::StringConcat, ScalarExpr::literal("!")),
+                &[1, 3]
+            ),
+            vec![Value::from("y!"), Value::from("x!")]
+        );
+
+        let mut batch = batch.clone();
+        batch.selection = select(
+            &compile(ScalarExpr::binary(
+                a(),
+                BinaryOperator::Lt,
+                ScalarExpr::literal(2),
+            )),
+            &batch,
+        )
+        .unwrap();
+        assert_eq!(batch.selection, vec![0, 3]);
+        // Rows which aren't selected don't cause errors
+        batch.selection = select(
+            &compile(ScalarExpr::binary(
+                ScalarExpr::binary(b(), BinaryOperator::StringConcat, ScalarExpr::literal("x")),
+                BinaryOperator::Eq,
+                ScalarExpr::literal("xx"),
+            )),
+            &batch,
+        )
+        .unwrap();
+        assert_eq!(batch.selection, vec![0, 3]);
+        assert!(select(
+            &compile(ScalarExpr::binary(a(), BinaryOperator::Eq, b())),
+            &batch
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn bitmaps() {
+        let mut bitmap = Bitmap::new(130, false);
+        bitmap.set(0, true);
+        bitmap.set(64, true);
+        bitmap.set(129, true);
+        assert!(bitmap.get(0) && bitmap.get(64) && bitmap.get(129));
+        assert!(!bitmap.get(1) && !bitmap.get(128));
+        bitmap.set(64, false);
+        assert!(!bitmap.get(64));
+    }
+
+    #[test]
+    #[traced_test]
+    fn same_results_as_rows() {
+        let db = Fixture::new();
+        for sql in [
+            "SELECT name, age + 1 FROM users WHERE age > 32 OR age IS NULL",
+            "SELECT u.name, o.total FROM users u JOIN orders o ON u.id = o.user_id ORDER BY o.id",
+            "SELECT user_id, count(*), sum(total), avg(total), min(id), max(id) FROM orders GROUP BY user_id ORDER BY user_id",
+            "SELECT count(*), max(age) FROM users WHERE id > 10",
+            "SELECT user_id, string_agg(name, ',' ORDER BY name DESC), array_agg(age), bool_or(age > 35) FROM users, orders GROUP BY user_id HAVING count(DISTINCT name) > 1",
+            "SELECT name FROM users LIMIT 2 OFFSET 1",
+            "SELECT name FROM users WHERE id = 3",
+        ] {
+            let plan = db.plan(sql);
+            let expected = executor::execute(&plan, &db.context(None)).unwrap();
+            assert_eq!(execute(&plan, &db.context(None)).unwrap(), expected, "{}", sql);
+        }
+        assert_eq!(
+            execute(
+                &db.plan("SELECT name FROM users LIMIT 2 OFFSET 1"),
+                &db.context(None)
+            )
+            .unwrap(),
+            rows(&[&["Guido"], &["Alan"]])
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn batches_and_limits() {
+        let db = Fixture::new();
+        let values = (0..2500)
+            .map(|i| format!("({}, {}, {})", i + 10, i % 7, i))
+            .collect::<Vec<_>>()
+            .join(", ");
+        db.insert(&format!(
+            "INSERT INTO orders (id, user_id, total) VALUES {}",
+            values
+        ));
+        let count = |sql: &str| execute(&db.plan(sql), &db.context(None)).unwrap().len();
+        assert_eq!(count("SELECT id FROM orders"), 2504);
+        assert_eq!(count("SELECT id FROM orders WHERE user_id = 3"), 358);
+        assert_eq!(count("SELECT id FROM orders LIMIT 1500 OFFSET 1000"), 1500);
+        assert_eq!(count("SELECT id FROM orders LIMIT 10 OFFSET 2500"), 4);
+
+        let mut plan =
+            db.plan("SELECT user_id, count(*) FROM orders WHERE total > 100 GROUP BY user_id");
+        execute_analyze(&mut plan, &db.context(None)).unwrap();
+        assert_eq!(plan.metrics.unwrap().rows, 7);
+    }
+}